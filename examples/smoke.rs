@@ -0,0 +1,112 @@
+//! Publishes a note to a relay and reads it back.
+//!
+//! usage: cargo run --example smoke -- wss://relay.example.com [hex|nsec]
+//!
+//! The exchange below is what a deployed relay is expected to do:
+//!
+//! ```text
+//! > ["EVENT", {..note..}]
+//! < ["OK", "<id>", true, ""]
+//! > ["REQ", "smoke", {"ids": ["<id>"]}]
+//! < ["EVENT", "smoke", {..note..}]
+//! < ["EOSE", "smoke"]
+//! > ["CLOSE", "smoke"]
+//! ```
+//!
+//! A fresh key is generated unless one is given, so pass an allowed key to
+//! relays that only accept known authors. Exits non-zero on the first
+//! unexpected frame or after 10 seconds without an answer.
+use futures_util::{SinkExt, StreamExt};
+use nostr_relay_apigw::identity::Identity;
+use nostr_relay_apigw::message::Event;
+use secp256k1::KeyPair;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+type Ws = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+const SUB_ID: &str = "smoke";
+
+async fn send(ws: &mut Ws, msg: Value) -> Result<(), String> {
+    println!("> {msg}");
+    ws.send(Message::Text(msg.to_string()))
+        .await
+        .map_err(|e| format!("send: {e}"))
+}
+
+/// The next text frame as a JSON array.
+async fn recv(ws: &mut Ws) -> Result<Vec<Value>, String> {
+    loop {
+        let frame = tokio::time::timeout(Duration::from_secs(10), ws.next())
+            .await
+            .map_err(|_| "timed out".to_string())?
+            .ok_or("connection closed")?
+            .map_err(|e| format!("recv: {e}"))?;
+        if let Message::Text(text) = frame {
+            println!("< {text}");
+            return serde_json::from_str(&text).map_err(|e| format!("not a nostr message: {e}"));
+        }
+    }
+}
+
+async fn smoke(url: &str, keys: &KeyPair) -> Result<(), String> {
+    let (mut ws, _) = connect_async(url)
+        .await
+        .map_err(|e| format!("connect: {e}"))?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let ev = Event::sign(keys, now, 1, vec![], "smoke test");
+
+    send(&mut ws, json!(["EVENT", ev])).await?;
+    match &recv(&mut ws).await?[..] {
+        [ok, id, Value::Bool(true), ..] if ok == "OK" && id == ev.id.as_str() => {}
+        other => return Err(format!("expected OK true, got {other:?}")),
+    }
+
+    send(&mut ws, json!(["REQ", SUB_ID, {"ids": [ev.id]}])).await?;
+    match &recv(&mut ws).await?[..] {
+        [cmd, sub, got] if cmd == "EVENT" && sub == SUB_ID => {
+            let got: Event = serde_json::from_value(got.clone()).map_err(|e| e.to_string())?;
+            if got != ev {
+                return Err("stored event differs from the published one".to_string());
+            }
+        }
+        other => return Err(format!("expected EVENT, got {other:?}")),
+    }
+    match &recv(&mut ws).await?[..] {
+        [cmd, sub] if cmd == "EOSE" && sub == SUB_ID => {}
+        other => return Err(format!("expected EOSE, got {other:?}")),
+    }
+
+    send(&mut ws, json!(["CLOSE", SUB_ID])).await?;
+    ws.close(None).await.map_err(|e| format!("close: {e}"))
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(url) = args.first() else {
+        eprintln!("usage: smoke <relay-url> [hex|nsec]");
+        std::process::exit(2);
+    };
+    let id = match args.get(1) {
+        Some(secret) => Identity::parse(secret).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }),
+        None => Identity::generate(),
+    };
+
+    match smoke(url, id.keys()).await {
+        Ok(()) => println!("ok"),
+        Err(e) => {
+            eprintln!("failed: {e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::policy::env_list;
+use crate::store::EventStore;
+use std::collections::HashSet;
+
+/// Pubkeys of the relay's own users, who may publish: those listed in
+/// `NOSTR_ALLOWED_PUBKEYS` and those the store keeps, so that operators can
+/// grant access without redeploying.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allowlist {
+    pubkeys: HashSet<String>,
+}
+
+impl Allowlist {
+    pub fn new<I: IntoIterator<Item = String>>(pubkeys: I) -> Allowlist {
+        Allowlist {
+            pubkeys: pubkeys.into_iter().collect(),
+        }
+    }
+
+    /// Reads `NOSTR_ALLOWED_PUBKEYS`.
+    pub fn from_env() -> Allowlist {
+        Allowlist::new(env_list("NOSTR_ALLOWED_PUBKEYS"))
+    }
+
+    /// The environment list together with the pubkeys the store allows. A
+    /// store that cannot be read contributes nothing.
+    pub async fn load(store: &dyn EventStore) -> Allowlist {
+        let mut allowlist = Allowlist::from_env();
+        match store.get_allowed_pubkeys().await {
+            Ok(pubkeys) => allowlist.pubkeys.extend(pubkeys),
+            Err(e) => println!("allowlist err: {e}"),
+        }
+        allowlist
+    }
+
+    pub fn contains(&self, pubkey: &str) -> bool {
+        self.pubkeys.contains(pubkey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Allowlist;
+    use crate::memory::MemoryStore;
+    use crate::store::EventStore;
+
+    #[tokio::test]
+    async fn load01() {
+        let store = MemoryStore::new();
+        assert!(!Allowlist::load(&store).await.contains("pub01"));
+
+        store.add_allowed_pubkey("pub01").await.unwrap();
+        let allowlist = Allowlist::load(&store).await;
+        assert!(allowlist.contains("pub01"));
+        assert!(!allowlist.contains("pub02"));
+    }
+}
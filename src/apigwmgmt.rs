@@ -1,6 +1,16 @@
-use crate::message::{CommandResult, Event, EventMsg};
+use crate::transport::Transport;
+use async_trait::async_trait;
 use aws_sdk_apigatewaymanagement::types::Blob;
 use aws_sdk_apigatewaymanagement::{config, Client};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// API Gateway refuses to post frames larger than 128KB.
+const MAX_FRAME_SIZE: usize = 128 * 1024;
+
+/// Clients by endpoint, kept across invocations.
+static CLIENTS: Lazy<Mutex<HashMap<String, Client>>> = Lazy::new(Default::default);
 
 pub struct ApiGwMgmt {
     client: Client,
@@ -8,16 +18,67 @@ pub struct ApiGwMgmt {
 
 impl ApiGwMgmt {
     pub async fn new(endpoint: &str) -> ApiGwMgmt {
-        let shared_config = aws_config::load_from_env().await;
-        let config = config::Builder::from(&shared_config)
+        if let Some(client) = CLIENTS.lock().unwrap().get(endpoint) {
+            return ApiGwMgmt {
+                client: client.clone(),
+            };
+        }
+        let shared_config = crate::config::sdk_config().await;
+        let config = config::Builder::from(shared_config)
             .endpoint_url(endpoint)
             .build();
         let client = Client::from_conf(config);
+        CLIENTS
+            .lock()
+            .unwrap()
+            .insert(endpoint.to_string(), client.clone());
 
         ApiGwMgmt { client }
     }
 
-    pub async fn post_connection(&self, conn_id: &str, data: &str) -> bool {
+    /// Checks that the management API answers; an unknown connection being
+    /// gone counts as an answer.
+    pub async fn ping(&self) -> Result<(), String> {
+        match self
+            .client
+            .get_connection()
+            .connection_id("selftest")
+            .send()
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_gone_exception() {
+                    Ok(())
+                } else {
+                    Err(format!("{e:?}"))
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for ApiGwMgmt {
+    async fn post_connection(&self, conn_id: &str, data: &str) -> bool {
+        match self.try_post_connection(conn_id, data).await {
+            Ok(sent) => sent,
+            Err(e) => {
+                println!("post_connection err: {e}");
+                false
+            }
+        }
+    }
+
+    async fn try_post_connection(&self, conn_id: &str, data: &str) -> Result<bool, String> {
+        if data.len() > MAX_FRAME_SIZE {
+            println!(
+                "post_connection: {conn_id}: {} byte frame dropped",
+                data.len()
+            );
+            return Ok(false);
+        }
         let result = self
             .client
             .post_to_connection()
@@ -26,44 +87,17 @@ impl ApiGwMgmt {
             .send()
             .await;
 
-        if let Err(e) = result {
-            println!("post_connection err: {e:?}");
-            false
-        } else {
-            true
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_gone_exception() {
+                    println!("post_connection: {conn_id}: gone");
+                    Ok(false)
+                } else {
+                    Err(format!("{e:?}"))
+                }
+            }
         }
     }
-
-    pub async fn reply_event(&self, sub: &str, conn: &str, ev: &Event) -> bool {
-        let obj = [
-            EventMsg::String("EVENT".to_string()),
-            EventMsg::String(sub.to_string()),
-            EventMsg::Event(ev.clone()),
-        ];
-        let msg = serde_json::to_string(&obj).unwrap();
-        println!("reply_event: {sub}/{conn}: {msg}");
-        self.post_connection(conn, &msg).await
-    }
-
-    pub async fn send_nip20msg(
-        &self,
-        conn: &str,
-        event_id: &str,
-        success: bool,
-        msg: &str,
-    ) -> bool {
-        let obj = [
-            CommandResult::String("OK".to_string()),
-            CommandResult::String(event_id.to_string()),
-            CommandResult::Bool(success),
-            CommandResult::String(msg.to_string()),
-        ];
-        let msg = serde_json::to_string(&obj).unwrap();
-        self.post_connection(conn, &msg).await
-    }
-
-    pub async fn send_nip15eose(&self, conn: &str, sub_id: &str) -> bool {
-        let msg = format!(r#"["EOSE", "{sub_id}"]"#);
-        self.post_connection(conn, &msg).await
-    }
 }
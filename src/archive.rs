@@ -0,0 +1,181 @@
+use crate::config::Config;
+use crate::message::Event;
+use crate::types::EventId;
+use aws_lambda_events::dynamodb;
+use aws_lambda_events::dynamodb::attributes::AttributeValue;
+use aws_sdk_s3::types::{ByteStream, SdkError};
+use aws_sdk_s3::Client;
+
+/// Principal of the deletions made by DynamoDB TTL.
+const TTL_PRINCIPAL: &str = "dynamodb.amazonaws.com";
+
+/// Expired events kept in the S3 bucket named by `NOSTR_ARCHIVE_BUCKET`.
+#[derive(Clone)]
+pub struct Archive {
+    client: Client,
+    bucket: String,
+}
+
+impl Archive {
+    /// The archive in `config.archive_bucket`; None when it is not set.
+    pub async fn from_config(config: &Config) -> Option<Archive> {
+        let bucket = config.archive_bucket.clone()?;
+        Some(Archive {
+            client: Client::new(crate::config::sdk_config().await),
+            bucket,
+        })
+    }
+
+    /// Stores `ev` under its date and, for lookups by id, under its id.
+    pub async fn put(&self, ev: &Event) -> Result<(), String> {
+        let body = serde_json::to_vec(ev).unwrap();
+        for key in [archive_key(ev), archive_id_key(&ev.id)] {
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .content_type("application/json")
+                .body(ByteStream::from(body.clone()))
+                .send()
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// The archived event `id`, None if it was never archived.
+    pub async fn get(&self, id: &EventId) -> Result<Option<Event>, String> {
+        let r = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(archive_id_key(id))
+            .send()
+            .await;
+        let r = match r {
+            Ok(r) => r,
+            Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => return Ok(None),
+            Err(e) => return Err(format!("{e:?}")),
+        };
+        let body = r.body.collect().await.map_err(|e| format!("{e:?}"))?;
+        serde_json::from_slice(&body.into_bytes())
+            .map(Some)
+            .map_err(|e| format!("{id}: {e}"))
+    }
+}
+
+/// `events/YYYY/MM/DD/<id>.json` by created_at, so a time range maps to a
+/// set of prefixes.
+pub fn archive_key(ev: &Event) -> String {
+    let (y, m, d) = civil_date(ev.created_at);
+    format!("events/{y:04}/{m:02}/{d:02}/{}.json", ev.id)
+}
+
+/// `ids/<id>.json`, the copy read back by id lookups.
+pub fn archive_id_key(id: &EventId) -> String {
+    format!("ids/{id}.json")
+}
+
+/// UTC calendar date of a unix timestamp.
+fn civil_date(epoch: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's civil_from_days, shifted so that years start in March.
+    let z = epoch / 86400 + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    (y, m, d)
+}
+
+/// Events removed from the event table by TTL expiry.
+///
+/// Other removals (NIP-09 deletions, replacements) and the label and tag
+/// items sharing the table are skipped. Each event comes with the number of
+/// `tags#<n>` items its tags were moved to, which `Ddb::with_tags` reads
+/// back.
+pub fn expired_events(stream: &dynamodb::Event) -> Vec<(Event, usize)> {
+    stream
+        .records
+        .iter()
+        .filter(|r| r.event_name == "REMOVE")
+        .filter(|r| {
+            r.user_identity
+                .as_ref()
+                .is_some_and(|u| u.principal_id == TTL_PRINCIPAL)
+        })
+        .filter(|r| {
+            matches!(r.change.old_image.get("type"), Some(AttributeValue::String(t)) if t == "event")
+        })
+        .filter_map(|r| {
+            let ev = match r.change.old_image.get("json") {
+                Some(AttributeValue::String(json)) => serde_json::from_str(json).ok(),
+                Some(AttributeValue::Binary(bytes)) => Event::from_zstd(bytes).ok(),
+                _ => None,
+            }?;
+            let chunks = match r.change.old_image.get("tag_chunks") {
+                Some(AttributeValue::Number(n)) => *n as usize,
+                _ => 0,
+            };
+            Some((ev, chunks))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{archive_id_key, archive_key, civil_date, expired_events};
+    use crate::message::test_event;
+
+    #[test]
+    fn civil_date01() {
+        assert_eq!((1970, 1, 1), civil_date(0));
+        assert_eq!((2023, 2, 11), civil_date(1676118868));
+        assert_eq!((2024, 2, 29), civil_date(1709164800));
+    }
+
+    #[test]
+    fn expired_events01() {
+        let ev = test_event();
+        let json = serde_json::to_string(&ev).unwrap();
+        let record = |name: &str, principal: &str, item_type: &str| {
+            serde_json::json!({
+                "awsRegion": "ap-northeast-1",
+                "eventID": "1",
+                "eventName": name,
+                "userIdentity": {"type": "Service", "principalId": principal},
+                "dynamodb": {
+                    "ApproximateCreationDateTime": 1676118868.0,
+                    "OldImage": {"type": {"S": item_type}, "json": {"S": json}},
+                    "SizeBytes": 1,
+                },
+            })
+        };
+        let mut chunked = record("REMOVE", "dynamodb.amazonaws.com", "event");
+        chunked["dynamodb"]["OldImage"]["tag_chunks"] = serde_json::json!({"N": "2"});
+        let stream: aws_lambda_events::dynamodb::Event =
+            serde_json::from_value(serde_json::json!({
+                "Records": [
+                    record("REMOVE", "dynamodb.amazonaws.com", "event"),
+                    record("REMOVE", "", "event"),
+                    record("REMOVE", "dynamodb.amazonaws.com", "tags#0"),
+                    record("MODIFY", "dynamodb.amazonaws.com", "event"),
+                    chunked,
+                ]
+            }))
+            .unwrap();
+
+        assert_eq!(
+            vec![(ev.clone(), 0), (ev.clone(), 2)],
+            expired_events(&stream)
+        );
+        assert_eq!(
+            format!("events/2023/02/11/{}.json", ev.id),
+            archive_key(&ev)
+        );
+        assert_eq!(format!("ids/{}.json", ev.id), archive_id_key(&ev.id));
+    }
+}
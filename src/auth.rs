@@ -0,0 +1,116 @@
+use crate::message::Event;
+use crate::reject::RejectReason;
+use secp256k1::rand::{self, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// NIP-42 client authentication event kind.
+pub const KIND_CLIENT_AUTH: u64 = 22242;
+
+/// How far created_at may be from now, in seconds.
+const MAX_SKEW: u64 = 600;
+
+/// The NIP-42 state kept on a connection record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Connection {
+    /// Challenge issued on `$connect`.
+    pub challenge: String,
+    /// Pubkey the connection has authenticated as, if any.
+    pub auth_pubkey: Option<String>,
+    /// Address the connection was opened from, if known.
+    pub source_ip: Option<String>,
+}
+
+/// A fresh random challenge.
+pub fn challenge() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// The `relay` tag AUTH events must carry, from `NOSTR_RELAY_URL`; any relay
+/// tag is accepted when it is not set.
+pub fn relay_url() -> Option<String> {
+    std::env::var("NOSTR_RELAY_URL")
+        .ok()
+        .filter(|u| !u.is_empty())
+}
+
+/// Checks a NIP-42 AUTH event against the connection's challenge and
+/// returns the pubkey it was signed by.
+pub fn verify(
+    ev: &Event,
+    challenge: &str,
+    relay_url: Option<&str>,
+    now: u64,
+) -> Result<String, RejectReason> {
+    let invalid = |msg: &str| Err(RejectReason::Invalid(msg.to_string()));
+    if ev.kind != KIND_CLIENT_AUTH {
+        return invalid("wrong auth event kind");
+    }
+    if ev.created_at.abs_diff(now) > MAX_SKEW {
+        return invalid("auth event expired");
+    }
+    if tag_value(ev, "challenge") != Some(challenge) {
+        return invalid("challenge mismatch");
+    }
+    let Some(relay) = tag_value(ev, "relay") else {
+        return invalid("auth event has no relay tag");
+    };
+    if relay_url.is_some_and(|url| url.trim_end_matches('/') != relay.trim_end_matches('/')) {
+        return invalid("relay url mismatch");
+    }
+    if ev.id != ev.hex_digest() || ev.validate().is_err() {
+        return invalid("auth event signature is wrong");
+    }
+    Ok(ev.pubkey.to_string())
+}
+
+fn tag_value<'a>(ev: &'a Event, name: &str) -> Option<&'a str> {
+    ev.tags
+        .iter()
+        .find(|t| t.len() >= 2 && t[0] == name)
+        .map(|t| t[1].as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{challenge, verify, KIND_CLIENT_AUTH};
+    use crate::identity::Identity;
+    use crate::message::Event;
+
+    #[test]
+    fn verify01() {
+        let id = Identity::generate();
+        let url = "wss://relay.example.com";
+        let auth = |kind, created_at, challenge: &str| {
+            let tags = vec![
+                vec!["relay".to_string(), format!("{url}/")],
+                vec!["challenge".to_string(), challenge.to_string()],
+            ];
+            Event::sign(id.keys(), created_at, kind, tags, "")
+        };
+        let c = challenge();
+        assert_eq!(32, c.len());
+        let now = 1676118868;
+
+        assert_eq!(
+            Ok(id.pubkey_hex()),
+            verify(&auth(KIND_CLIENT_AUTH, now, &c), &c, Some(url), now)
+        );
+        assert!(verify(&auth(KIND_CLIENT_AUTH, now, &c), &c, None, now).is_ok());
+        assert!(verify(&auth(1, now, &c), &c, Some(url), now).is_err());
+        assert!(verify(&auth(KIND_CLIENT_AUTH, now - 3600, &c), &c, Some(url), now).is_err());
+        assert!(verify(&auth(KIND_CLIENT_AUTH, now, "other"), &c, Some(url), now).is_err());
+        assert!(verify(
+            &auth(KIND_CLIENT_AUTH, now, &c),
+            &c,
+            Some("wss://other.example.com"),
+            now
+        )
+        .is_err());
+
+        let mut forged = auth(KIND_CLIENT_AUTH, now, &c);
+        forged.content = "x".into();
+        assert!(verify(&forged, &c, Some(url), now).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Lambda consuming the event table's DynamoDB Stream (OLD_IMAGE or
+//! NEW_AND_OLD_IMAGES) that copies events removed by TTL expiry to S3.
+use aws_lambda_events::dynamodb;
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nostr_relay_apigw::archive::{expired_events, Archive};
+use nostr_relay_apigw::config::Config;
+use nostr_relay_apigw::ddb::Ddb;
+use nostr_relay_apigw::metrics;
+use nostr_relay_apigw::store::EventStore;
+
+async fn function_handler(event: LambdaEvent<dynamodb::Event>) -> Result<(), Error> {
+    let evs = expired_events(&event.payload);
+    if evs.is_empty() {
+        return Ok(());
+    }
+    // The tag items of an expired event outlive it by a day.
+    let ddb = Ddb::new().await?;
+    let evs = ddb.with_tags(evs).await;
+
+    // A failed put fails the whole batch so that Lambda retries it.
+    let archive = Archive::from_config(Config::global()?)
+        .await
+        .ok_or("NOSTR_ARCHIVE_BUCKET is not set")?;
+    for ev in evs.iter() {
+        archive.put(ev).await?;
+    }
+    println!("archived {} events", evs.len());
+
+    println!(
+        "{}",
+        metrics::emf_record(&[("ArchivedEvents", "Count", evs.len() as i64)])
+    );
+    if let Err(e) = ddb.add_archived(evs.len() as i64).await {
+        println!("stats err: {e}");
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .without_time()
+        .init();
+
+    // A missing or malformed setting stops the function at startup.
+    let config = Config::init().await?;
+    if config.archive_bucket.is_none() {
+        return Err("NOSTR_ARCHIVE_BUCKET is not set".into());
+    }
+    run(service_fn(function_handler)).await
+}
@@ -0,0 +1,56 @@
+//! Lambda consuming the event table's DynamoDB Stream (NEW_IMAGE or
+//! NEW_AND_OLD_IMAGES) that sends newly stored events to the matching
+//! subscriptions, so that the publisher's OK never waits for the fan-out.
+use aws_lambda_events::dynamodb;
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nostr_relay_apigw::apigwmgmt::ApiGwMgmt;
+use nostr_relay_apigw::config::Config;
+use nostr_relay_apigw::ddb::Ddb;
+use nostr_relay_apigw::metrics;
+use nostr_relay_apigw::relay;
+use nostr_relay_apigw::stream::inserted_events;
+
+async fn function_handler(event: LambdaEvent<dynamodb::Event>) -> Result<(), Error> {
+    let evs = inserted_events(&event.payload);
+    if evs.is_empty() {
+        return Ok(());
+    }
+
+    let config = Config::init().await?;
+    let endpoint = config
+        .websocket_endpoint
+        .as_deref()
+        .ok_or("NOSTR_WEBSOCKET_ENDPOINT is not set")?;
+    let store = Ddb::new().await?;
+    let evs = store.with_tags(evs).await;
+    let api = ApiGwMgmt::new(endpoint).await;
+    // Connections that are gone are not retried; the batch always succeeds.
+    let mut delivered = 0;
+    for ev in evs.iter() {
+        delivered += relay::fan_out(&store, &api, ev).await;
+    }
+    println!(
+        "dispatched {} events to {delivered} subscriptions",
+        evs.len()
+    );
+
+    println!(
+        "{}",
+        metrics::emf_record(&[
+            ("DispatchedEvents", "Count", evs.len() as i64),
+            ("DeliveredFrames", "Count", delivered as i64),
+        ])
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
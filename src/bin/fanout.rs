@@ -0,0 +1,56 @@
+//! Lambda consuming the fan-out queue (`NOSTR_FANOUT_QUEUE_URL`) that sends
+//! the events the relay enqueued to the matching subscriptions. Enable
+//! ReportBatchItemFailures on the event source mapping so that only the
+//! messages whose retries could not be queued are received again.
+use aws_lambda_events::sqs::{SqsBatchResponse, SqsEvent};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nostr_relay_apigw::apigwmgmt::ApiGwMgmt;
+use nostr_relay_apigw::config::Config;
+use nostr_relay_apigw::ddb::Ddb;
+use nostr_relay_apigw::metrics;
+use nostr_relay_apigw::queue::FanOutQueue;
+
+async fn function_handler(event: LambdaEvent<SqsEvent>) -> Result<SqsBatchResponse, Error> {
+    let config = Config::init().await?;
+    let endpoint = config
+        .websocket_endpoint
+        .as_deref()
+        .ok_or("NOSTR_WEBSOCKET_ENDPOINT is not set")?;
+    let queue = FanOutQueue::from_env()
+        .await
+        .ok_or("NOSTR_FANOUT_QUEUE_URL is not set")?;
+    let store = Ddb::new().await?;
+    let api = ApiGwMgmt::new(endpoint).await;
+
+    let result = queue.process(&store, &api, &event.payload).await;
+    println!(
+        "fanned out {} messages: {} delivered, {} requeued, {} dropped",
+        event.payload.records.len(),
+        result.delivered,
+        result.requeued,
+        result.dropped
+    );
+
+    println!(
+        "{}",
+        metrics::emf_record(&[
+            ("DeliveredFrames", "Count", result.delivered as i64),
+            ("RequeuedFrames", "Count", result.requeued as i64),
+            ("DroppedFrames", "Count", result.dropped as i64),
+        ])
+    );
+    Ok(SqsBatchResponse {
+        batch_item_failures: result.failures,
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
@@ -0,0 +1,51 @@
+//! Manages the relay identity keypair.
+//!
+//! usage: nostr-relay-keys generate
+//!        nostr-relay-keys show <hex|nsec>
+//!        nostr-relay-keys store (--ssm NAME | --secret-id ID) [hex|nsec]
+//!
+//! `store` writes the given key, or a newly generated one, in the format read
+//! through `NOSTR_RELAY_SECRET_PARAMETER` / `NOSTR_RELAY_SECRET_ID`.
+use nostr_relay_apigw::identity::{Identity, SecretLocation};
+
+fn print_identity(id: &Identity) {
+    println!("pubkey: {}", id.pubkey_hex());
+    println!("npub:   {}", id.npub());
+    println!("secret: {}", id.secret_hex());
+    println!("nsec:   {}", id.nsec());
+}
+
+fn parse_location(flag: &str, value: Option<&String>) -> Result<SecretLocation, String> {
+    let value = value.ok_or(format!("{flag} needs a value"))?.clone();
+    match flag {
+        "--ssm" => Ok(SecretLocation::Ssm(value)),
+        "--secret-id" => Ok(SecretLocation::SecretsManager(value)),
+        _ => Err(format!("unknown option: {flag}")),
+    }
+}
+
+async fn run(args: &[String]) -> Result<(), String> {
+    match args.first().map(|s| s.as_str()) {
+        Some("generate") => print_identity(&Identity::generate()),
+        Some("show") => print_identity(&Identity::parse(args.get(1).ok_or("missing key")?)?),
+        Some("store") => {
+            let flag = args.get(1).ok_or("missing --ssm or --secret-id")?;
+            let location = parse_location(flag, args.get(2))?;
+            let id = match args.get(3) {
+                Some(secret) => Identity::parse(secret)?,
+                None => Identity::generate(),
+            };
+            location.store(&id).await?;
+            println!("pubkey: {}", id.pubkey_hex());
+            println!("npub:   {}", id.npub());
+        }
+        _ => return Err("usage: nostr-relay-keys (generate | show KEY | store ...)".to_string()),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    run(&args).await
+}
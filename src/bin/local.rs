@@ -0,0 +1,44 @@
+//! Runs the relay as a plain websocket server over a SQLite database, for
+//! development and tests without any AWS resources.
+//!
+//! usage: nostr-relay-local [--listen ADDR] [--db PATH]
+//!
+//! `--listen` defaults to 127.0.0.1:7777 and `--db` to nostr-relay.db;
+//! `--db :memory:` keeps nothing after exit.
+use nostr_relay_apigw::local::serve;
+use nostr_relay_apigw::policy::RelayPolicy;
+use nostr_relay_apigw::sqlite::SqliteStore;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+fn parse_args(args: &[String]) -> Result<(String, String), String> {
+    let mut listen = "127.0.0.1:7777".to_string();
+    let mut db = "nostr-relay.db".to_string();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let value = it.next().ok_or(format!("{arg} needs a value"))?;
+        match &**arg {
+            "--listen" => listen = value.clone(),
+            "--db" => db = value.clone(),
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    Ok((listen, db))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (listen, db) = parse_args(&args)?;
+    RelayPolicy::init()?;
+    let store = if db == ":memory:" {
+        SqliteStore::open_in_memory()?
+    } else {
+        SqliteStore::open(&db)?
+    };
+    let listener = TcpListener::bind(&listen)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("listening on ws://{listen} ({db})");
+    serve(listener, Arc::new(store)).await
+}
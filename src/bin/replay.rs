@@ -0,0 +1,113 @@
+//! Re-runs hooks over stored events, e.g. after enabling a new hook or
+//! changing a rule that should also apply to historical data.
+//!
+//! usage: nostr-relay-replay --hooks NAME[,NAME...] [--dry-run] FILTER
+//!
+//! FILTER is a REQ filter with `ids` or `authors`, such as
+//! `'{"authors":["<pubkey>"],"kinds":[1]}'`. The post-write hooks run as if
+//! each matching event had just been stored; pre-write hooks are not run.
+//! `--dry-run` only logs what the hooks would change.
+use nostr_relay_apigw::config::Config;
+use nostr_relay_apigw::ddb::Ddb;
+use nostr_relay_apigw::hook::{HookContext, Hooks};
+use nostr_relay_apigw::message::Filter;
+use nostr_relay_apigw::store::QueryPlan;
+use std::collections::HashSet;
+
+struct Args {
+    hooks: HashSet<String>,
+    dry_run: bool,
+    filter: Filter,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut hooks = HashSet::new();
+    let mut dry_run = false;
+    let mut filter = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match &**arg {
+            "--dry-run" => dry_run = true,
+            "--hooks" => {
+                let names = it.next().ok_or("--hooks needs a value")?;
+                hooks.extend(
+                    names
+                        .split(',')
+                        .map(|n| n.trim().to_string())
+                        .filter(|n| !n.is_empty()),
+                );
+            }
+            _ if filter.is_none() => {
+                let f: Filter =
+                    serde_json::from_str(arg).map_err(|e| format!("invalid filter: {e}"))?;
+                f.validate()?;
+                filter = Some(f);
+            }
+            _ => return Err(format!("unexpected argument: {arg}")),
+        }
+    }
+    if hooks.is_empty() {
+        return Err("--hooks is required".to_string());
+    }
+    Ok(Args {
+        hooks,
+        dry_run,
+        filter: filter.ok_or("a filter is required")?,
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = parse_args(&args)?;
+    let hooks = Hooks::select(&args.hooks, args.dry_run)?;
+
+    Config::init().await?;
+    let ddb = Ddb::new().await?;
+    let evs = match args.filter.query_plan() {
+        QueryPlan::ByIds(plan) => plan.exec_including_expired(&ddb).await?,
+        QueryPlan::ByPubkeys(plan) => plan.exec_including_expired(&ddb).await?,
+        QueryPlan::ByAddress(plan) => plan.exec(&ddb).await?,
+        QueryPlan::ByChannels(plan) => plan.exec(&ddb).await?,
+        QueryPlan::ByRecipients(plan) => plan.exec(&ddb).await?,
+        QueryPlan::ByKinds(plan) => plan.exec(&ddb).await?,
+        QueryPlan::ByTime(plan) => plan.exec(&ddb).await?,
+        QueryPlan::BoundedScan(plan) => plan.exec(&ddb).await?,
+        QueryPlan::Count(_) => unreachable!("query_plan does not count"),
+        QueryPlan::NoPlan(reason) => return Err(reason.into()),
+    };
+
+    for (i, ev) in evs.iter().enumerate() {
+        hooks
+            .post_event_write_hook(&HookContext::new(&ddb), ev)
+            .await;
+        println!("replayed {}/{}: {}", i + 1, evs.len(), ev.id);
+    }
+    println!("replayed {} events", evs.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_args;
+
+    #[test]
+    fn parse_args01() {
+        let args: Vec<String> = [
+            "--hooks",
+            "replaceable, nip32",
+            "--dry-run",
+            r#"{"ids":["a"]}"#,
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let args = parse_args(&args).unwrap();
+        assert!(args.dry_run);
+        assert!(args.hooks.contains("replaceable") && args.hooks.contains("nip32"));
+        assert_eq!(Some(vec!["a".to_string()]), args.filter.ids);
+
+        assert!(parse_args(&[r#"{"ids":["a"]}"#.to_string()]).is_err());
+        assert!(parse_args(&["--hooks".to_string(), "nip9".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Restores events from a DynamoDB S3 export (`DYNAMODB_JSON` format) into
+//! the event table of the stage selected by the usual AWS environment.
+//!
+//! usage: nostr-relay-restore [--check] FILE...
+//!
+//! FILE is a `data/*.json.gz` file of the export, e.g. after
+//! `aws s3 sync s3://bucket/AWSDynamoDB/<export-id>/data ./data`. Every event
+//! has its signature checked and is written through the `EventStore`, so
+//! the target table gets the current item layout. Events whose tags were
+//! stored in `tags#<n>` items are written once all files were read.
+//! `--check` only validates.
+use flate2::read::GzDecoder;
+use nostr_relay_apigw::config::Config;
+use nostr_relay_apigw::ddb::Ddb;
+use nostr_relay_apigw::message::Event;
+use nostr_relay_apigw::restore::{join_tags, parse_export_line, ChunkedTags, ExportItem};
+use nostr_relay_apigw::store::{DryRunStore, EventStore};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+#[derive(Debug, Default)]
+struct Summary {
+    restored: usize,
+    skipped: usize,
+    invalid: usize,
+    failed: usize,
+}
+
+fn open(path: &str) -> Result<Box<dyn BufRead>, String> {
+    let file = File::open(path).map_err(|e| format!("{path}: {e}"))?;
+    let reader: Box<dyn Read> = if path.ends_with(".gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Events waiting for their `tags#<n>` items, which may be in other files.
+#[derive(Default)]
+struct Pending {
+    untagged: Vec<(Event, usize)>,
+    tags: ChunkedTags,
+}
+
+async fn write(store: &dyn EventStore, ev: &Event, summary: &mut Summary) {
+    match store.write_event(ev).await {
+        Ok(()) => summary.restored += 1,
+        Err(e) => {
+            println!("write err: {}: {e}", ev.id);
+            summary.failed += 1;
+        }
+    }
+}
+
+async fn restore(
+    store: &dyn EventStore,
+    path: &str,
+    pending: &mut Pending,
+    summary: &mut Summary,
+) -> Result<(), String> {
+    for line in open(path)?.lines() {
+        let line = line.map_err(|e| format!("{path}: {e}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_export_line(&line) {
+            Ok(None) => summary.skipped += 1,
+            Ok(Some(ExportItem::Event(ev))) => write(store, &ev, summary).await,
+            Ok(Some(ExportItem::Untagged(ev, chunks))) => pending.untagged.push((ev, chunks)),
+            Ok(Some(ExportItem::Tags(id, n, tags))) => {
+                pending.tags.entry(id).or_default().insert(n, tags);
+            }
+            Err(e) => {
+                println!("invalid: {e}");
+                summary.invalid += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let check = args.iter().any(|a| a == "--check");
+    args.retain(|a| a != "--check");
+    if args.is_empty() {
+        return Err("usage: nostr-relay-restore [--check] FILE...".to_string());
+    }
+
+    Config::init().await?;
+    let ddb = Ddb::new().await?;
+    let dry = DryRunStore::new(&ddb, "restore");
+    let store: &dyn EventStore = if check { &dry } else { &ddb };
+
+    let mut summary = Summary::default();
+    let mut pending = Pending::default();
+    for path in args.iter() {
+        restore(store, path, &mut pending, &mut summary).await?;
+    }
+    for (ev, chunks) in pending.untagged {
+        match join_tags(ev, chunks, &mut pending.tags) {
+            Ok(ev) => write(store, &ev, &mut summary).await,
+            Err(e) => {
+                println!("invalid: {e}");
+                summary.invalid += 1;
+            }
+        }
+    }
+    println!("{summary:?}");
+    if summary.invalid + summary.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
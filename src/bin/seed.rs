@@ -0,0 +1,81 @@
+//! Generates a corpus of signed events and writes it to the DynamoDB tables
+//! of the stage selected by the usual AWS environment.
+//!
+//! usage: nostr-relay-seed [--authors N] [--notes N] [--replies PCT]
+//!                         [--reactions PCT] [--seed N] [--start EPOCH] [--print]
+//!
+//! `--print` writes the events as JSON lines to stdout instead, e.g. to
+//! publish them through a relay endpoint so hooks and dispatch run too.
+use nostr_relay_apigw::config::Config;
+use nostr_relay_apigw::ddb::Ddb;
+use nostr_relay_apigw::seed::{generate, SeedConfig};
+use nostr_relay_apigw::store::EventStore;
+
+fn parse_args(args: &[String]) -> Result<(SeedConfig, bool), String> {
+    let mut config = SeedConfig::default();
+    let mut print = false;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "--print" {
+            print = true;
+            continue;
+        }
+        let value = it.next().ok_or(format!("{arg} needs a value"))?;
+        let number = || value.parse::<u64>().map_err(|_| format!("{arg}: {value}"));
+        match &**arg {
+            "--authors" => config.authors = number()? as usize,
+            "--notes" => config.notes_per_author = number()? as usize,
+            "--replies" => config.reply_ratio = number()? as usize,
+            "--reactions" => config.reaction_ratio = number()? as usize,
+            "--seed" => config.seed = number()?,
+            "--start" => config.start = number()?,
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    Ok((config, print))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, print) = parse_args(&args)?;
+    let evs = generate(&config);
+
+    if print {
+        for ev in evs.iter() {
+            println!("{}", serde_json::to_string(ev).unwrap());
+        }
+        return Ok(());
+    }
+
+    Config::init().await?;
+    let ddb = Ddb::new().await?;
+    let mut failed = 0;
+    for ev in evs.iter() {
+        if let Err(e) = ddb.write_event(ev).await {
+            println!("write err: {}: {e}", ev.id);
+            failed += 1;
+        }
+    }
+    println!("seeded {} events ({failed} failed)", evs.len() - failed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_args;
+
+    #[test]
+    fn parse_args01() {
+        let args: Vec<String> = ["--authors", "3", "--print", "--seed", "7"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let (config, print) = parse_args(&args).unwrap();
+        assert_eq!(3, config.authors);
+        assert_eq!(7, config.seed);
+        assert!(print);
+
+        assert!(parse_args(&["--authors".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::message::Event;
+use crate::types::EventId;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+
+/// Redis (e.g. ElastiCache) in front of event reads, so popular events and
+/// the NIP-11 document do not use DynamoDB read capacity on every request.
+///
+/// Failures are logged and treated as misses; the cache never fails a read.
+#[derive(Clone)]
+pub struct Cache {
+    conn: MultiplexedConnection,
+    /// Seconds a cached value lives.
+    ttl: usize,
+}
+
+impl Cache {
+    /// Connects to `NOSTR_REDIS_URL`, keeping values for
+    /// `NOSTR_REDIS_TTL` seconds (300 by default). None when it is not set
+    /// or cannot be reached.
+    pub async fn from_env() -> Option<Cache> {
+        let url = std::env::var("NOSTR_REDIS_URL")
+            .ok()
+            .filter(|u| !u.is_empty())?;
+        let ttl = std::env::var("NOSTR_REDIS_TTL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(300);
+        let conn = redis::Client::open(url)
+            .map_err(|e| println!("redis err: {e}"))
+            .ok()?
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(|e| println!("redis err: {e}"))
+            .ok()?;
+        Some(Cache { conn, ttl })
+    }
+
+    /// The cached ones of `ids`.
+    pub async fn get_events(&self, ids: &[EventId]) -> Vec<Event> {
+        if ids.is_empty() {
+            return vec![];
+        }
+        let keys: Vec<String> = ids.iter().map(event_key).collect();
+        let values: Vec<Option<String>> = match self.conn.clone().get(keys).await {
+            Ok(values) => values,
+            Err(e) => {
+                println!("redis err: {e}");
+                return vec![];
+            }
+        };
+        values
+            .into_iter()
+            .flatten()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect()
+    }
+
+    pub async fn put_events(&self, evs: &[Event]) {
+        if evs.is_empty() {
+            return;
+        }
+        let mut pipe = redis::pipe();
+        for ev in evs {
+            let json = serde_json::to_string(ev).unwrap();
+            pipe.set_ex(event_key(&ev.id), json, self.ttl).ignore();
+        }
+        if let Err(e) = pipe.query_async::<_, ()>(&mut self.conn.clone()).await {
+            println!("redis err: {e}");
+        }
+    }
+
+    /// Drops deleted events so they stop being served.
+    pub async fn forget_events(&self, ids: &[EventId]) {
+        if ids.is_empty() {
+            return;
+        }
+        let keys: Vec<String> = ids.iter().map(event_key).collect();
+        if let Err(e) = self.conn.clone().del::<_, ()>(keys).await {
+            println!("redis err: {e}");
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<String> {
+        match self.conn.clone().get(key).await {
+            Ok(value) => value,
+            Err(e) => {
+                println!("redis err: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, key: &str, value: &str) {
+        if let Err(e) = self
+            .conn
+            .clone()
+            .set_ex::<_, _, ()>(key, value, self.ttl)
+            .await
+        {
+            println!("redis err: {e}");
+        }
+    }
+}
+
+fn event_key(id: &EventId) -> String {
+    format!("event:{id}")
+}
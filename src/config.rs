@@ -0,0 +1,296 @@
+use crate::allowlist::Allowlist;
+use crate::content::ContentFilter;
+use crate::hook::Hooks;
+use crate::policy::{RelayPolicy, Retention};
+use once_cell::sync::OnceCell;
+#[cfg(feature = "aws")]
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+#[cfg(feature = "aws")]
+static SDK_CONFIG: tokio::sync::OnceCell<aws_config::SdkConfig> =
+    tokio::sync::OnceCell::const_new();
+
+/// The AWS SDK configuration, loaded once per process and shared by every
+/// client so that warm invocations skip the credential and region lookup.
+#[cfg(feature = "aws")]
+pub async fn sdk_config() -> &'static aws_config::SdkConfig {
+    SDK_CONFIG.get_or_init(aws_config::load_from_env).await
+}
+
+/// Tables and lifetimes the storage needs, read and checked once at startup
+/// instead of on every call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// `NOSTR_EVENT_TABLE`.
+    pub event_table: String,
+    /// `NOSTR_SUBSCRIPTION_TABLE`.
+    pub subscription_table: String,
+    /// `NOSTR_MODERATION_TABLE`, which holds NIP-56 reports and bans.
+    pub moderation_table: Option<String>,
+    /// `NOSTR_MEMBERS_TABLE`, which holds the pubkeys that paid the
+    /// admission fee and the invoices issued to them.
+    pub members_table: Option<String>,
+    /// `NOSTR_EVENT_RETENTION` over `NOSTR_EVENT_TTL`: how long events are
+    /// kept after their created_at.
+    pub retention: Retention,
+    /// `NOSTR_SUBSCRIPTION_TTL`: seconds a subscription lives.
+    pub subscription_ttl: u64,
+    /// `DYNAMODB_ENDPOINT_URL`: a DynamoDB-compatible endpoint such as
+    /// dynamodb-local or LocalStack used in place of DynamoDB.
+    pub endpoint: Option<String>,
+    /// `NOSTR_DYNAMODB_READ_ENDPOINT`.
+    pub read_endpoint: Option<String>,
+    /// `NOSTR_WEBSOCKET_ENDPOINT`: management API the self-test pings.
+    pub websocket_endpoint: Option<String>,
+    /// `NOSTR_COMPRESS_EVENTS`: store the event JSON compressed with zstd.
+    pub compress_events: bool,
+    /// `NOSTR_ALLOWLIST_TTL`: seconds the allowlist read from the
+    /// moderation table is reused.
+    pub allowlist_ttl: u64,
+    /// `NOSTR_DENYLIST_TTL`: seconds the denylist read from the moderation
+    /// table is reused.
+    pub denylist_ttl: u64,
+    /// False when `replaceable` is in `NOSTR_DISABLED_HOOKS`: replaceable
+    /// events are stored side by side instead of replacing each other.
+    pub replace_events: bool,
+    /// `NOSTR_MEDIA_BUCKET`: where NIP-96 uploads are stored.
+    pub media_bucket: Option<String>,
+    /// `NOSTR_ARCHIVE_BUCKET`: where events expired from the table are
+    /// archived.
+    pub archive_bucket: Option<String>,
+    /// `NOSTR_RECENT_IDS`: how many ids of stored events a warm process
+    /// remembers to answer re-broadcasts without a read.
+    pub recent_ids: usize,
+    /// Limits, rate limits, accepted kinds and how events are dispatched.
+    pub policy: RelayPolicy,
+}
+
+impl Config {
+    /// Reads the settings through `get`, failing with a message naming the
+    /// first one that is missing or malformed.
+    pub fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+        let get = |name: &str| get(name).filter(|v| !v.is_empty());
+        let required = |name: &str| get(name).ok_or(format!("{name} is not set"));
+        let parse_seconds = |name: &str, value: String| {
+            value
+                .parse()
+                .map_err(|_| format!("{name} must be a number of seconds: {value}"))
+        };
+        let seconds = |name: &str| parse_seconds(name, required(name)?);
+        let policy = RelayPolicy::from_lookup(get)?;
+        ContentFilter::from_lookup(get)?;
+        let disabled: HashSet<String> = get("NOSTR_DISABLED_HOOKS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        Hooks::check_names(&disabled).map_err(|e| format!("NOSTR_DISABLED_HOOKS: {e}"))?;
+        // Uploads are enabled by their URL and need somewhere to go.
+        if cfg!(feature = "media")
+            && get("NOSTR_MEDIA_API_URL").is_some()
+            && get("NOSTR_MEDIA_BUCKET").is_none()
+        {
+            return Err("NOSTR_MEDIA_BUCKET is not set".to_string());
+        }
+        Ok(Config {
+            event_table: required("NOSTR_EVENT_TABLE")?,
+            subscription_table: required("NOSTR_SUBSCRIPTION_TABLE")?,
+            moderation_table: get("NOSTR_MODERATION_TABLE"),
+            members_table: get("NOSTR_MEMBERS_TABLE"),
+            retention: Retention {
+                local: Allowlist::new(
+                    get("NOSTR_ALLOWED_PUBKEYS")
+                        .unwrap_or_default()
+                        .split(',')
+                        .map(|v| v.trim().to_string())
+                        .filter(|v| !v.is_empty()),
+                ),
+                ..Retention::parse(
+                    &get("NOSTR_EVENT_RETENTION").unwrap_or_default(),
+                    seconds("NOSTR_EVENT_TTL")?,
+                )
+                .map_err(|e| format!("NOSTR_EVENT_RETENTION: {e}"))?
+            },
+            subscription_ttl: seconds("NOSTR_SUBSCRIPTION_TTL")?,
+            endpoint: get("DYNAMODB_ENDPOINT_URL"),
+            read_endpoint: get("NOSTR_DYNAMODB_READ_ENDPOINT"),
+            websocket_endpoint: get("NOSTR_WEBSOCKET_ENDPOINT"),
+            compress_events: matches!(
+                get("NOSTR_COMPRESS_EVENTS").as_deref(),
+                Some("1") | Some("true") | Some("yes")
+            ),
+            allowlist_ttl: get("NOSTR_ALLOWLIST_TTL")
+                .map(|v| parse_seconds("NOSTR_ALLOWLIST_TTL", v))
+                .transpose()?
+                .unwrap_or(60),
+            denylist_ttl: get("NOSTR_DENYLIST_TTL")
+                .map(|v| parse_seconds("NOSTR_DENYLIST_TTL", v))
+                .transpose()?
+                .unwrap_or(60),
+            replace_events: !disabled.contains("replaceable"),
+            media_bucket: get("NOSTR_MEDIA_BUCKET"),
+            archive_bucket: get("NOSTR_ARCHIVE_BUCKET"),
+            recent_ids: get("NOSTR_RECENT_IDS")
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| format!("NOSTR_RECENT_IDS must be a number: {v}"))
+                })
+                .transpose()?
+                .unwrap_or(10000),
+            policy,
+        })
+    }
+
+    pub fn from_env() -> Result<Config, String> {
+        Config::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Reads the environment and, when `NOSTR_CONFIG_PARAMETER_PATH` is set,
+    /// the SSM parameters under that path named like the variables (e.g.
+    /// `/nostr/prod/NOSTR_EVENT_TABLE`). The environment wins over SSM.
+    #[cfg(feature = "aws")]
+    pub async fn load() -> Result<Config, String> {
+        let Ok(path) = std::env::var("NOSTR_CONFIG_PARAMETER_PATH") else {
+            return Config::from_env();
+        };
+        let parameters = parameters_by_path(&path).await?;
+        Config::from_lookup(|name| {
+            std::env::var(name)
+                .ok()
+                .or_else(|| parameters.get(name).cloned())
+        })
+    }
+
+    /// Loads the configuration for the process; binaries call this first so
+    /// that a bad setting stops them with its message.
+    #[cfg(feature = "aws")]
+    pub async fn init() -> Result<&'static Config, String> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let config = Config::load().await?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    /// The configuration of the process if it has been loaded.
+    pub fn loaded() -> Option<&'static Config> {
+        CONFIG.get()
+    }
+
+    /// The configuration of the process, read from the environment on first
+    /// use when `init` was not called.
+    pub fn global() -> Result<&'static Config, String> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let config = Config::from_env()?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+}
+
+/// Parameters under `path` by the last segment of their names.
+#[cfg(feature = "aws")]
+async fn parameters_by_path(path: &str) -> Result<HashMap<String, String>, String> {
+    use tokio_stream::StreamExt;
+
+    let config = sdk_config().await;
+    let pages: Result<Vec<_>, _> = aws_sdk_ssm::Client::new(config)
+        .get_parameters_by_path()
+        .path(path)
+        .recursive(true)
+        .with_decryption(true)
+        .into_paginator()
+        .send()
+        .collect()
+        .await;
+    let pages = pages.map_err(|e| format!("get_parameters_by_path {path}: {e}"))?;
+    Ok(pages
+        .iter()
+        .flat_map(|page| page.parameters().unwrap_or_default())
+        .filter_map(|p| {
+            let name = p.name()?.rsplit('/').next()?;
+            Some((name.to_string(), p.value()?.to_string()))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use std::collections::HashMap;
+
+    #[test]
+    fn from_lookup01() {
+        let mut vars = HashMap::from([
+            ("NOSTR_EVENT_TABLE", "events"),
+            ("NOSTR_SUBSCRIPTION_TABLE", "subscriptions"),
+            ("NOSTR_EVENT_TTL", "86400"),
+            ("NOSTR_SUBSCRIPTION_TTL", "3600"),
+            ("NOSTR_MODERATION_TABLE", ""),
+        ]);
+        let load = |vars: &HashMap<&str, &str>| {
+            Config::from_lookup(|name| vars.get(name).map(|v| v.to_string()))
+        };
+        let config = load(&vars).unwrap();
+        assert_eq!("events", config.event_table);
+        assert!(config.replace_events);
+        assert_eq!(86400, config.retention.default_ttl);
+        assert_eq!(None, config.moderation_table);
+        assert_eq!(None, config.endpoint);
+        assert_eq!(500, config.policy.limits.max_limit);
+        vars.insert("NOSTR_MAX_LIMIT", "200");
+        vars.insert("NOSTR_REJECTED_KINDS", "4");
+        let policy = load(&vars).unwrap().policy;
+        assert_eq!(200, policy.limits.max_limit);
+        assert!(!policy.kinds.accepts(4));
+        vars.insert("DYNAMODB_ENDPOINT_URL", "http://localhost:8000");
+        assert_eq!(
+            Some("http://localhost:8000"),
+            load(&vars).unwrap().endpoint.as_deref()
+        );
+
+        vars.insert("NOSTR_EVENT_RETENTION", "1=90d");
+        assert_eq!(
+            Err("NOSTR_EVENT_RETENTION: bad time: 1=90d".to_string()),
+            load(&vars)
+        );
+        vars.insert("NOSTR_EVENT_TTL", "1d");
+        assert_eq!(
+            Err("NOSTR_EVENT_TTL must be a number of seconds: 1d".to_string()),
+            load(&vars)
+        );
+        vars.remove("NOSTR_SUBSCRIPTION_TABLE");
+        assert_eq!(
+            Err("NOSTR_SUBSCRIPTION_TABLE is not set".to_string()),
+            load(&vars)
+        );
+        vars.insert("NOSTR_ACCEPTED_KINDS", "0,1,note");
+        assert_eq!(
+            Err("NOSTR_ACCEPTED_KINDS: bad kind: note".to_string()),
+            load(&vars)
+        );
+        vars.remove("NOSTR_ACCEPTED_KINDS");
+        vars.insert("NOSTR_MAX_HASHTAGS", "five");
+        assert_eq!(
+            Err("NOSTR_MAX_HASHTAGS must be a number: five".to_string()),
+            load(&vars)
+        );
+        vars.remove("NOSTR_MAX_HASHTAGS");
+        if cfg!(feature = "media") {
+            vars.insert("NOSTR_MEDIA_API_URL", "https://example.com/upload");
+            assert_eq!(
+                Err("NOSTR_MEDIA_BUCKET is not set".to_string()),
+                load(&vars)
+            );
+            vars.remove("NOSTR_MEDIA_API_URL");
+        }
+        vars.insert("NOSTR_DISABLED_HOOKS", "nip9, nip16");
+        assert_eq!(
+            Err("NOSTR_DISABLED_HOOKS: unknown hook: nip16".to_string()),
+            load(&vars)
+        );
+    }
+}
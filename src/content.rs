@@ -0,0 +1,270 @@
+use crate::message::Event;
+use crate::reject::RejectReason;
+use crate::store::EventStore;
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Operator rules on what events may say: banned words, patterns and link
+/// domains, and how many emojis and hashtags an event may carry.
+#[derive(Debug, Clone, Default)]
+pub struct ContentFilter {
+    words: Vec<String>,
+    patterns: Vec<Regex>,
+    domains: Vec<String>,
+    pub max_emojis: Option<usize>,
+    pub max_hashtags: Option<usize>,
+}
+
+impl ContentFilter {
+    /// Reads `NOSTR_BANNED_WORDS` and `NOSTR_BANNED_DOMAINS` (comma
+    /// separated), `NOSTR_BANNED_PATTERNS` (one regex per line),
+    /// `NOSTR_MAX_EMOJIS` and `NOSTR_MAX_HASHTAGS` through `get`.
+    pub fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<ContentFilter, String> {
+        let mut filter = ContentFilter::default();
+        let list = |name: &str, sep: char| {
+            get(name)
+                .unwrap_or_default()
+                .split(sep)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+        };
+        for word in list("NOSTR_BANNED_WORDS", ',') {
+            filter.add_rule(&format!("word#{word}"))?;
+        }
+        for domain in list("NOSTR_BANNED_DOMAINS", ',') {
+            filter.add_rule(&format!("domain#{domain}"))?;
+        }
+        for pattern in list("NOSTR_BANNED_PATTERNS", '\n') {
+            filter
+                .add_rule(&format!("pattern#{pattern}"))
+                .map_err(|e| format!("NOSTR_BANNED_PATTERNS: {e}"))?;
+        }
+        let count = |name: &str| {
+            get(name)
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| format!("{name} must be a number: {v}"))
+                })
+                .transpose()
+        };
+        filter.max_emojis = count("NOSTR_MAX_EMOJIS")?;
+        filter.max_hashtags = count("NOSTR_MAX_HASHTAGS")?;
+        Ok(filter)
+    }
+
+    pub fn from_env() -> Result<ContentFilter, String> {
+        ContentFilter::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// The environment's rules together with those the store keeps. Store
+    /// rules that do not parse are logged and skipped.
+    pub async fn load(store: &dyn EventStore) -> ContentFilter {
+        let mut filter = ContentFilter::from_env().unwrap_or_default();
+        match store.get_content_rules().await {
+            Ok(rules) => {
+                for rule in rules {
+                    if let Err(e) = filter.add_rule(&rule) {
+                        println!("content rule err: {e}");
+                    }
+                }
+            }
+            Err(e) => println!("content rules err: {e}"),
+        }
+        filter
+    }
+
+    /// Adds `word#<word>`, `pattern#<regex>` or `domain#<domain>`.
+    pub fn add_rule(&mut self, rule: &str) -> Result<(), String> {
+        match rule.split_once('#') {
+            Some(("word", word)) => self.words.push(word.to_lowercase()),
+            Some(("pattern", pattern)) => self
+                .patterns
+                .push(Regex::new(pattern).map_err(|e| format!("bad pattern: {e}"))?),
+            Some(("domain", domain)) => self.domains.push(domain.to_lowercase()),
+            _ => return Err(format!("unknown rule: {rule}")),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+            && self.patterns.is_empty()
+            && self.domains.is_empty()
+            && self.max_emojis.is_none()
+            && self.max_hashtags.is_none()
+    }
+
+    pub fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        let blocked = |msg: &str| Err(RejectReason::Blocked(msg.to_string()));
+        let content = ev.content.to_lowercase();
+        if self.words.iter().any(|w| contains_word(&content, w)) {
+            return blocked("content contains a banned word");
+        }
+        if self.patterns.iter().any(|p| p.is_match(&ev.content)) {
+            return blocked("content matches a banned pattern");
+        }
+        if link_hosts(&content).any(|host| {
+            self.domains
+                .iter()
+                .any(|d| host == d || host.ends_with(&format!(".{d}")))
+        }) {
+            return blocked("links to a banned domain");
+        }
+        if self.max_emojis.is_some_and(|max| emoji_count(ev) > max) {
+            return blocked("too many emojis");
+        }
+        if self.max_hashtags.is_some_and(|max| hashtag_count(ev) > max) {
+            return blocked("too many hashtags");
+        }
+        Ok(())
+    }
+}
+
+/// Whether `word` appears in `text` without ASCII letters or digits right
+/// around it, so that words match inside Japanese text but not inside
+/// longer English words.
+fn contains_word(text: &str, word: &str) -> bool {
+    text.match_indices(word).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(|c| c.is_ascii_alphanumeric())
+            && !after.is_some_and(|c| c.is_ascii_alphanumeric())
+    })
+}
+
+/// Hosts of the http(s) links in `text`.
+fn link_hosts(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '"')
+        .filter_map(|word| {
+            let rest = word
+                .find("https://")
+                .map(|i| &word[i + 8..])
+                .or_else(|| word.find("http://").map(|i| &word[i + 7..]))?;
+            let host = rest.split(['/', '?', '#', ':']).next()?;
+            Some(host.rsplit('@').next().unwrap_or(host))
+        })
+}
+
+/// Pictographic code points in the content and NIP-30 custom emojis.
+fn emoji_count(ev: &Event) -> usize {
+    let pictographs = ev
+        .content
+        .chars()
+        .filter(|c| matches!(*c as u32, 0x1F000..=0x1FAFF | 0x2600..=0x27BF))
+        .count();
+    let custom = ev
+        .tags
+        .iter()
+        .filter(|t| t.first().is_some_and(|n| n == "emoji"))
+        .count();
+    pictographs + custom
+}
+
+/// Distinct hashtags among the `t` tags and the `#words` of the content.
+fn hashtag_count(ev: &Event) -> usize {
+    let tagged = ev
+        .tags
+        .iter()
+        .filter(|t| t.len() >= 2 && t[0] == "t")
+        .map(|t| t[1].to_lowercase());
+    let written = ev
+        .content
+        .split_whitespace()
+        .filter_map(|w| w.strip_prefix('#'))
+        .map(|w| {
+            w.trim_end_matches(|c: char| c.is_ascii_punctuation())
+                .to_lowercase()
+        })
+        .filter(|w| !w.is_empty());
+    tagged.chain(written).collect::<HashSet<_>>().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContentFilter;
+    use crate::memory::MemoryStore;
+    use crate::message::{test_event, Event};
+    use crate::store::EventStore;
+    use std::collections::HashMap;
+
+    fn build_event(content: &str, tags: Vec<Vec<String>>) -> Event {
+        Event {
+            tags,
+            content: content.into(),
+            ..test_event()
+        }
+    }
+
+    fn reason(filter: &ContentFilter, ev: &Event) -> String {
+        filter
+            .check_event(ev)
+            .map_or_else(|r| r.to_string(), |_| "ok".to_string())
+    }
+
+    #[test]
+    fn check_event01() {
+        let vars = HashMap::from([
+            ("NOSTR_BANNED_WORDS", "spam, 詐欺"),
+            ("NOSTR_BANNED_DOMAINS", "bad.example"),
+            ("NOSTR_BANNED_PATTERNS", "(?i)free\\s+sats\n^buy "),
+            ("NOSTR_MAX_EMOJIS", "3"),
+            ("NOSTR_MAX_HASHTAGS", "2"),
+        ]);
+        let filter =
+            ContentFilter::from_lookup(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+
+        let ok = build_event("spammer ate some spamalot #nostr 🍣", vec![]);
+        assert_eq!("ok", reason(&filter, &ok));
+        let ev = build_event("no SPAM, please", vec![]);
+        assert_eq!(
+            "blocked: content contains a banned word",
+            reason(&filter, &ev)
+        );
+        let ev = build_event("これは詐欺です", vec![]);
+        assert_eq!(
+            "blocked: content contains a banned word",
+            reason(&filter, &ev)
+        );
+        let ev = build_event("get FREE  sats now", vec![]);
+        assert_eq!(
+            "blocked: content matches a banned pattern",
+            reason(&filter, &ev)
+        );
+        let ev = build_event(
+            "see https://www.Bad.example/x and (http://ok.example)",
+            vec![],
+        );
+        assert_eq!("blocked: links to a banned domain", reason(&filter, &ev));
+        let ev = build_event("https://notbad.example", vec![]);
+        assert_eq!("ok", reason(&filter, &ev));
+        let emoji = vec![
+            "emoji".to_string(),
+            "soapbox".into(),
+            "https://x/s.png".into(),
+        ];
+        let ev = build_event("🍣🍣🍣 :soapbox:", vec![emoji]);
+        assert_eq!("blocked: too many emojis", reason(&filter, &ev));
+        let t = vec!["t".to_string(), "Nostr".into()];
+        let ev = build_event("#nostr #zap, #plebs", vec![t]);
+        assert_eq!("blocked: too many hashtags", reason(&filter, &ev));
+
+        let bad = HashMap::from([("NOSTR_BANNED_PATTERNS", "(")]);
+        assert!(
+            ContentFilter::from_lookup(|name| bad.get(name).map(|v| v.to_string()))
+                .is_err_and(|e| e.starts_with("NOSTR_BANNED_PATTERNS: bad pattern"))
+        );
+    }
+
+    #[tokio::test]
+    async fn load01() {
+        let store = MemoryStore::new();
+        assert!(ContentFilter::load(&store).await.is_empty());
+
+        store.add_content_rule("domain#bad.example").await.unwrap();
+        let filter = ContentFilter::load(&store).await;
+        let ev = build_event("https://bad.example", vec![]);
+        assert!(filter.check_event(&ev).is_err());
+    }
+}
@@ -1,234 +1,314 @@
+use async_trait::async_trait;
 use aws_sdk_dynamodb::{
-    model::{AttributeValue, DeleteRequest, KeysAndAttributes, PutRequest, WriteRequest},
-    Client,
+    client::fluent_builders,
+    model::{
+        AttributeValue, ConsumedCapacity, Delete, DeleteRequest, KeysAndAttributes, Put,
+        PutRequest, ReturnConsumedCapacity, ReturnValue, Select, TransactWriteItem, WriteRequest,
+    },
+    types::Blob,
+    Client, Credentials, Region,
 };
+use futures_util::future::try_join_all;
+use secp256k1::rand::{self, Rng};
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio_stream::StreamExt;
 
-use crate::message::{Event, Filter};
+#[cfg(feature = "archive")]
+use crate::archive::Archive;
+use crate::auth::Connection;
+#[cfg(feature = "cache")]
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::denylist::Denylist;
+use crate::label::LabelEntry;
+use crate::message::{Event, Filter, MIN_PREFIX_LENGTH};
+use crate::metrics::{Gauges, Stats};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::policy::Retention;
+use crate::recent::RecentIds;
+use crate::reject::RejectReason;
+use crate::report::Report;
+use crate::store::EventStore;
+use crate::types::{EventId, Pubkey};
 
-pub struct Ddb {
-    client: Client,
+/// DynamoDB rejects items larger than 400KB.
+const MAX_ITEM_SIZE: usize = 400 * 1024;
+
+/// Events with more indexable tags than this keep them in sibling items.
+const TAG_INLINE_LIMIT: usize = 100;
+/// Tags stored per sibling item.
+const TAG_CHUNK_SIZE: usize = 500;
+
+/// Most ids missing from the table that one lookup reads from the archive.
+#[cfg(feature = "archive")]
+const ARCHIVE_LOOKUPS: usize = 20;
+
+/// A batch write that did not write every item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchWriteError {
+    /// A request failed after `written` items had been written.
+    Request { written: usize, error: String },
+    /// Items still unprocessed after every retry, as `id/type` keys.
+    Unprocessed { written: usize, failed: Vec<String> },
 }
 
-impl Ddb {
-    pub async fn new() -> Ddb {
-        let config = aws_config::load_from_env().await;
-        let client = Client::new(&config);
+impl fmt::Display for BatchWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchWriteError::Request { written, error } => {
+                write!(f, "batch write failed after {written} items: {error}")
+            }
+            BatchWriteError::Unprocessed { failed, .. } => {
+                write!(f, "unprocessed items: {}", failed.join(", "))
+            }
+        }
+    }
+}
 
-        Ddb { client }
+impl From<BatchWriteError> for String {
+    fn from(e: BatchWriteError) -> String {
+        e.to_string()
     }
+}
 
-    pub async fn write_event(
-        &self,
-        ev: &Event,
-    ) -> Result<
-        aws_sdk_dynamodb::output::BatchWriteItemOutput,
-        aws_sdk_dynamodb::types::SdkError<aws_sdk_dynamodb::error::BatchWriteItemError>,
-    > {
-        let table = std::env::var("NOSTR_EVENT_TABLE").unwrap();
-        let ttl: i64 = std::env::var("NOSTR_EVENT_TTL").unwrap().parse().unwrap();
-        let ttl = ev.created_at as i64 + ttl;
-        let id = &ev.id;
+/// Most items TransactWriteItems takes in one request.
+const MAX_TRANSACT_ITEMS: usize = 100;
 
-        let mut wrs = Vec::<WriteRequest>::new();
+/// Most keys BatchGetItem takes in one request.
+const BATCH_GET_SIZE: usize = 100;
 
-        let mut data = vec![
-            (
-                "pubkey".to_string(),
-                AttributeValue::S(ev.pubkey.to_string()),
-            ),
-            (
-                "created_at".to_string(),
-                AttributeValue::N(ev.created_at.to_string()),
-            ),
-            ("kind".to_string(), AttributeValue::N(ev.kind.to_string())),
-            (
-                "content".to_string(),
-                AttributeValue::S(ev.content.to_string()),
-            ),
-        ];
+/// Attempts at items a batch call returns as unprocessed.
+const BATCH_ATTEMPTS: u32 = 4;
 
-        for tag in ev.tags.iter() {
-            let k = &tag[0];
-            let v = tag[1..]
-                .iter()
-                .map(|v| AttributeValue::S(v.clone()))
-                .collect();
-            let tag_name = format!("tag_{k}");
+/// Width of the `day` buckets of day-created_at-index, in seconds.
+const DAY: u64 = 86400;
+/// Most per-author queries of one filter in flight at a time.
+const PUBKEY_QUERY_CONCURRENCY: usize = 16;
+/// Most subscription index partitions read at a time for one event.
+const INDEX_QUERY_CONCURRENCY: usize = 16;
 
-            data.push((tag_name.to_string(), AttributeValue::L(v)));
-        }
+/// Most buckets a time range query walks back through.
+const MAX_DAYS: u64 = 31;
+/// Seconds an issued invoice is kept in the members table, longer than it
+/// stays payable.
+const INVOICE_TTL: u64 = 86400;
 
-        data.push((
-            "json".to_string(),
-            AttributeValue::S(serde_json::to_string(ev).unwrap()),
-        ));
+/// The store of the process configuration, built on first use.
+static SHARED: tokio::sync::OnceCell<Ddb> = tokio::sync::OnceCell::const_new();
+/// Allowlisted pubkeys read from the moderation table and when, so that warm
+/// invocations read them again only after `allowlist_ttl`.
+static ALLOWLIST: Mutex<Option<(Instant, Vec<String>)>> = Mutex::new(None);
+/// The denylist read from the moderation table and when, reused for
+/// `denylist_ttl`.
+static DENYLIST: Mutex<Option<(Instant, Denylist)>> = Mutex::new(None);
+/// Content filter rules read from the moderation table and when, reused for
+/// `denylist_ttl`.
+static CONTENT_RULES: Mutex<Option<(Instant, Vec<String>)>> = Mutex::new(None);
+/// Ids of events this process wrote or found stored, so that warm
+/// invocations answer re-broadcasts without reading the table.
+static RECENT_IDS: Mutex<Option<RecentIds>> = Mutex::new(None);
 
-        wrs.push(write_request(
-            id,
-            "event",
-            AttributeValue::S("event".to_string()),
-            Some(data),
-            ttl,
-        ));
+#[derive(Clone)]
+pub struct Ddb {
+    client: Client,
+    config: Config,
+    /// Client for event lookups when `read_endpoint` points them at a
+    /// caching endpoint.
+    reader: Option<Client>,
+    /// Where events expired from the table are looked up by id.
+    #[cfg(feature = "archive")]
+    archive: Option<Archive>,
+    /// Read-through cache of events by id.
+    #[cfg(feature = "cache")]
+    cache: Option<Cache>,
+    /// Capacity units consumed through this store.
+    consumed: Arc<Mutex<f64>>,
+}
 
-        self.client
-            .batch_write_item()
-            .request_items(table, wrs)
-            .send()
-            .await
+impl Ddb {
+    /// Store over the tables of the process configuration. The clients are
+    /// created once and shared, so this is cheap to call per message.
+    pub async fn new() -> Result<Ddb, String> {
+        let config = Config::global()?;
+        let shared = SHARED
+            .get_or_init(|| Ddb::with_config(config.clone()))
+            .await;
+        // Each caller counts its own capacity.
+        Ok(Ddb {
+            consumed: Default::default(),
+            ..shared.clone()
+        })
     }
 
-    pub async fn write_subscription(
-        &self,
-        conn_id: &str,
-        sub_id: &str,
-        filters: &[Filter],
-    ) -> Result<
-        aws_sdk_dynamodb::output::BatchWriteItemOutput,
-        aws_sdk_dynamodb::types::SdkError<aws_sdk_dynamodb::error::BatchWriteItemError>,
-    > {
-        let table = std::env::var("NOSTR_SUBSCRIPTION_TABLE").unwrap();
-        let ttl: i64 = std::env::var("NOSTR_SUBSCRIPTION_TTL")
-            .unwrap()
-            .parse()
-            .unwrap();
-        let ttl = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64
-            + ttl;
-        let id = sub_id;
-        let mut wrs = Vec::<WriteRequest>::new();
-        let fs = filters
-            .iter()
-            .map(|f| AttributeValue::S(serde_json::to_string(f).unwrap()))
-            .collect();
+    pub async fn with_config(config: Config) -> Ddb {
+        let sdk_config = crate::config::sdk_config().await;
+        let mut builder = aws_sdk_dynamodb::config::Builder::from(sdk_config);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.endpoint_url(endpoint);
+            // dynamodb-local takes any region and keys, but they must be set.
+            if sdk_config.region().is_none() {
+                builder = builder.region(Region::new("us-east-1"));
+            }
+            if std::env::var("AWS_ACCESS_KEY_ID").is_err() {
+                builder = builder
+                    .credentials_provider(Credentials::new("local", "local", None, None, "local"));
+            }
+        }
+        let client = Client::from_conf(builder.build());
+        let reader = config.read_endpoint.as_ref().map(|endpoint| {
+            let conf = aws_sdk_dynamodb::config::Builder::from(sdk_config)
+                .endpoint_url(endpoint)
+                .build();
+            Client::from_conf(conf)
+        });
+        #[cfg(feature = "archive")]
+        let archive = Archive::from_config(&config).await;
 
-        wrs.push(write_request(
-            id,
-            "conn_id",
-            AttributeValue::S(conn_id.to_string()),
-            Some(vec![("filters".to_string(), AttributeValue::L(fs))]),
-            ttl,
-        ));
+        Ddb {
+            client,
+            config,
+            reader,
+            #[cfg(feature = "archive")]
+            archive,
+            #[cfg(feature = "cache")]
+            cache: Cache::from_env().await,
+            consumed: Default::default(),
+        }
+    }
 
-        self.client
-            .batch_write_item()
-            .request_items(table, wrs)
-            .send()
-            .await
+    fn record(&self, capacity: Option<&ConsumedCapacity>) {
+        self.record_all(capacity.map(std::slice::from_ref));
     }
 
-    pub async fn delete_subscriptions(
-        &self,
-        sub_ids: Vec<String>,
-    ) -> Result<
-        aws_sdk_dynamodb::output::BatchWriteItemOutput,
-        aws_sdk_dynamodb::types::SdkError<aws_sdk_dynamodb::error::BatchWriteItemError>,
-    > {
-        let table = std::env::var("NOSTR_SUBSCRIPTION_TABLE").unwrap();
-        let mut wrs = Vec::<WriteRequest>::new();
+    fn record_all(&self, capacities: Option<&[ConsumedCapacity]>) {
+        let units: f64 = capacities
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|c| c.capacity_units())
+            .sum();
+        *self.consumed.lock().unwrap() += units;
+    }
 
-        for sub_id in sub_ids {
-            let id = sub_id;
-            wrs.push(delete_request(&id, "conn_id"));
-        }
+    /// The table holding NIP-56 reports and bans.
+    fn moderation_table(&self) -> Result<String, String> {
+        self.config
+            .moderation_table
+            .clone()
+            .ok_or("NOSTR_MODERATION_TABLE is not set".to_string())
+    }
 
-        self.client
-            .batch_write_item()
-            .request_items(table, wrs)
-            .send()
-            .await
+    fn remember_event(&self, id: &EventId) {
+        RECENT_IDS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| RecentIds::new(self.config.recent_ids))
+            .insert(id);
     }
 
-    pub async fn close_connection(
-        &self,
-        conn_id: &str,
-    ) -> Result<
-        aws_sdk_dynamodb::output::BatchWriteItemOutput,
-        aws_sdk_dynamodb::types::SdkError<aws_sdk_dynamodb::error::BatchWriteItemError>,
-    > {
-        let table = std::env::var("NOSTR_SUBSCRIPTION_TABLE").unwrap();
-        let mut sub_ids = Vec::<String>::new();
+    /// The table holding paid admissions.
+    fn members_table(&self) -> Result<String, String> {
+        self.config
+            .members_table
+            .clone()
+            .ok_or("NOSTR_MEMBERS_TABLE is not set".to_string())
+    }
 
+    /// The `type` of every item in partition `id` of the moderation table.
+    async fn get_moderation_partition(&self, id: &str) -> Result<Vec<String>, String> {
         let items: Result<Vec<_>, _> = self
             .client
             .query()
-            .table_name(&table)
-            .index_name("value-id-index")
-            .key_condition_expression("#value = :conn_id")
-            .expression_attribute_names("#value", "value")
-            .expression_attribute_values(":conn_id", AttributeValue::S(conn_id.to_string()))
+            .table_name(self.moderation_table()?)
+            .key_condition_expression("id = :id")
+            .expression_attribute_values(":id", AttributeValue::S(id.to_string()))
             .into_paginator()
             .items()
             .send()
             .collect()
             .await;
+        Ok(items
+            .map_err(|e| format!("{e:?}"))?
+            .iter()
+            .filter_map(|item| item.get("type")?.as_s().ok().cloned())
+            .collect())
+    }
 
-        if let Ok(items) = items {
-            for item in items {
-                if let Some(sub_id) = item.get("id") {
-                    let sub_id = sub_id.as_s().unwrap();
-                    sub_ids.push(sub_id.to_string());
-                }
-            }
-        }
+    async fn put_denylist_item(&self, entry: &str) -> Result<(), String> {
+        self.client
+            .put_item()
+            .table_name(self.moderation_table()?)
+            .item("id", AttributeValue::S(DENYLIST_KEY.to_string()))
+            .item("type", AttributeValue::S(entry.to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        *DENYLIST.lock().unwrap() = None;
+        Ok(())
+    }
 
-        self.delete_subscriptions(sub_ids).await
+    async fn delete_denylist_item(&self, entry: &str) -> Result<(), String> {
+        self.client
+            .delete_item()
+            .table_name(self.moderation_table()?)
+            .key("id", AttributeValue::S(DENYLIST_KEY.to_string()))
+            .key("type", AttributeValue::S(entry.to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        *DENYLIST.lock().unwrap() = None;
+        Ok(())
     }
 
-    pub async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)> {
-        let table = std::env::var("NOSTR_SUBSCRIPTION_TABLE").unwrap();
-        let mut results = vec![];
+    /// Client used for event reads.
+    fn reader(&self) -> &Client {
+        self.reader.as_ref().unwrap_or(&self.client)
+    }
 
-        let items: Result<Vec<_>, _> = self
-            .client
-            .scan()
-            .table_name(table)
-            .into_paginator()
-            .items()
-            .send()
-            .collect()
-            .await;
+    /// Events by id from the table, and from the archive when expired.
+    ///
+    /// BatchGetItem takes at most 100 distinct keys, so larger lookups are
+    /// split into concurrent batches.
+    async fn fetch_events(&self, ids: &[EventId]) -> Result<Vec<Event>, String> {
+        let table = self.config.event_table.clone();
+        let mut unique = ids.to_vec();
+        unique.sort();
+        unique.dedup();
+        let batches = unique
+            .chunks(BATCH_GET_SIZE)
+            .map(|chunk| self.batch_get(&table, chunk));
+        let evs: Vec<Event> = try_join_all(batches).await?.concat();
+        #[cfg(feature = "archive")]
+        let evs = self.add_archived_events(&unique, evs).await;
+        Ok(evs)
+    }
 
-        if let Ok(items) = items {
-            for item in items {
-                let sub_id = if let Some(sub_id) = item.get("id") {
-                    let sub_id = sub_id.as_s().unwrap();
-                    sub_id.to_string()
-                } else {
-                    break;
-                };
-                let conn_id = if let Some(conn_id) = item.get("value") {
-                    conn_id.as_s().unwrap().clone()
-                } else {
-                    break;
-                };
-                let filters = if let Some(fs) = item.get("filters") {
-                    let rfs = fs.as_l().unwrap();
-                    let vs: Vec<String> =
-                        rfs.iter().map(|f| f.as_s().unwrap().to_string()).collect();
-                    vs
-                } else {
-                    break;
-                };
-                let filters = filters
-                    .iter()
-                    .map(|f| serde_json::from_str(f).unwrap())
-                    .collect();
-                results.push((sub_id, conn_id, filters));
+    /// Adds to `evs` those of `ids` no longer in the table that expired
+    /// into the archive.
+    #[cfg(feature = "archive")]
+    async fn add_archived_events(&self, ids: &[EventId], mut evs: Vec<Event>) -> Vec<Event> {
+        let Some(archive) = &self.archive else {
+            return evs;
+        };
+        let missing: Vec<&EventId> = ids
+            .iter()
+            .filter(|id| !evs.iter().any(|ev| &ev.id == *id))
+            .take(ARCHIVE_LOOKUPS)
+            .collect();
+        for id in missing {
+            match archive.get(id).await {
+                Ok(Some(ev)) => evs.push(ev),
+                Ok(None) => {}
+                Err(e) => println!("archive err: {e}"),
             }
         }
-
-        results
+        evs
     }
 
-    pub async fn get_event_by_ids(&self, ids: &[String]) -> Result<Vec<Event>, String> {
-        let table = std::env::var("NOSTR_EVENT_TABLE").unwrap();
-
+    /// One BatchGetItem of up to 100 `ids`.
+    async fn batch_get(&self, table: &str, ids: &[EventId]) -> Result<Vec<Event>, String> {
         let keys = ids
             .iter()
             .fold(KeysAndAttributes::builder(), |builder, id| {
@@ -239,146 +319,2088 @@ impl Ddb {
             })
             .build();
 
-        let items = self
-            .client
-            .batch_get_item()
-            .request_items(&table, keys)
-            .send()
-            .await;
-
-        match items {
-            Err(e) => Err(format!("{e:?}")),
-            Ok(item) => {
-                if let Some(ret) = item.responses() {
-                    let v = ret.get(&table).unwrap();
-                    let vv: Vec<&AttributeValue> =
-                        v.iter().map(|hm| hm.get("json").unwrap()).collect();
-                    let vvv: Vec<String> =
-                        vv.iter().map(|a| a.as_s().unwrap().to_string()).collect();
-                    let vvvv = vvv
-                        .iter()
-                        .map(|json| serde_json::from_str(json).unwrap())
-                        .collect();
-                    Ok(vvvv)
-                } else {
-                    Err("none".to_string())
-                }
+        // Keys DynamoDB leaves unprocessed are asked for again; those still
+        // missing afterwards are logged and left out of the result.
+        let mut evs = vec![];
+        let mut pending = Some(keys);
+        for attempt in 0..BATCH_ATTEMPTS {
+            let Some(keys) = pending.take() else {
+                break;
+            };
+            if attempt > 0 {
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+            let r = self
+                .reader()
+                .batch_get_item()
+                .request_items(table, keys)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            self.record_all(r.consumed_capacity());
+            for item in r
+                .responses()
+                .and_then(|r| r.get(table))
+                .into_iter()
+                .flatten()
+            {
+                evs.extend(self.item_event_with_tags(table, item).await?);
             }
+            pending = r
+                .unprocessed_keys()
+                .and_then(|u| u.get(table))
+                .filter(|k| k.keys().is_some_and(|k| !k.is_empty()))
+                .cloned();
         }
+        if let Some(keys) = pending {
+            let ids: Vec<&str> = keys
+                .keys()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|k| k.get("id").and_then(|id| id.as_s().ok()))
+                .map(|id| id.as_str())
+                .collect();
+            println!("ddb unprocessed keys: {}", ids.join(", "));
+        }
+        Ok(evs)
     }
 
-    pub async fn get_event_by_pubkeys(
+    /// Writes `wrs` 25 at a time, resubmitting unprocessed items with a
+    /// jittered backoff.
+    async fn batch_write(
         &self,
-        pubkeys: &[String],
-        kinds: Option<Vec<u64>>,
-        since: Option<u64>,
-        until: Option<u64>,
-        limit: Option<i32>,
-    ) -> Result<Vec<Event>, String> {
-        let since = since.unwrap_or(0);
-        let until = until.unwrap_or(1893456000);
-        let mut count = limit.unwrap_or(100);
-        let mut result = vec![];
-
-        for pubkey in pubkeys {
-            if let Ok(evs) = self
-                .get_event_by_pubkey(pubkey, &kinds, since, until, count)
-                .await
-            {
-                count -= evs.len() as i32;
-                result.extend(evs);
-            }
-            if count <= 0 {
-                break;
+        table: &str,
+        wrs: Vec<WriteRequest>,
+    ) -> Result<(), BatchWriteError> {
+        let mut failed = vec![];
+        let mut written = 0;
+        for chunk in wrs.chunks(25) {
+            let mut pending = chunk.to_vec();
+            for attempt in 0..BATCH_ATTEMPTS {
+                if attempt > 0 {
+                    tokio::time::sleep(backoff(attempt)).await;
+                }
+                let r = self
+                    .client
+                    .batch_write_item()
+                    .request_items(table, pending)
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .send()
+                    .await
+                    .map_err(|e| BatchWriteError::Request {
+                        written,
+                        error: format!("{e:?}"),
+                    })?;
+                self.record_all(r.consumed_capacity());
+                pending = r
+                    .unprocessed_items()
+                    .and_then(|u| u.get(table))
+                    .cloned()
+                    .unwrap_or_default();
+                if pending.is_empty() {
+                    break;
+                }
+                println!("ddb unprocessed: {} items", pending.len());
             }
+            written += chunk.len() - pending.len();
+            failed.extend(pending.iter().map(request_key));
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(BatchWriteError::Unprocessed { written, failed })
         }
-
-        Ok(result)
     }
 
-    async fn get_event_by_pubkey(
+    /// Query of `pubkey`'s events on pubkey-created_at-index.
+    fn pubkey_query(
         &self,
         pubkey: &str,
         kinds: &Option<Vec<u64>>,
         since: u64,
         until: u64,
-        limit: i32,
-    ) -> Result<Vec<Event>, String> {
-        let table = std::env::var("NOSTR_EVENT_TABLE").unwrap();
+    ) -> fluent_builders::Query {
+        self.created_at_query(
+            "pubkey-created_at-index",
+            "pubkey",
+            AttributeValue::S(pubkey.to_string()),
+            kinds,
+            since,
+            until,
+        )
+    }
+
+    /// Query of the events whose `key` attribute is `value` on an index
+    /// sorted by created_at, newest first so that a limited query keeps the
+    /// most recent events.
+    fn created_at_query(
+        &self,
+        index: &str,
+        key: &str,
+        value: AttributeValue,
+        kinds: &Option<Vec<u64>>,
+        since: u64,
+        until: u64,
+    ) -> fluent_builders::Query {
+        let table = self.config.event_table.clone();
 
         let query = self
-            .client
+            .reader()
             .query()
-            .limit(limit)
             .table_name(table)
-            .index_name("pubkey-created_at-index")
-            .key_condition_expression("pubkey = :pubkey AND (created_at BETWEEN :since AND :until)")
-            .expression_attribute_values(":pubkey", AttributeValue::S(pubkey.to_string()))
+            .index_name(index)
+            .scan_index_forward(false)
+            .key_condition_expression(format!(
+                "{key} = :key AND (created_at BETWEEN :since AND :until)"
+            ))
+            .expression_attribute_values(":key", value)
             .expression_attribute_values(":since", AttributeValue::N(since.to_string()))
             .expression_attribute_values(":until", AttributeValue::N(until.to_string()));
 
-        let query = if let Some(kinds) = kinds {
-            let mut keys = vec![];
-            let mut vals = vec![];
-            for (i, kind) in kinds.iter().enumerate() {
-                keys.push(format!(":kind{i}"));
-                vals.push((format!(":kind{i}"), AttributeValue::N(kind.to_string())));
-            }
-            let kind_labels = keys.join(",");
-            vals.iter().fold(
-                query.filter_expression(format!("kind IN({kind_labels})")),
-                |builder, (label, value)| builder.expression_attribute_values(label, value.clone()),
+        if let Some(kinds) = kinds {
+            let (condition, vals) = kind_condition(kinds);
+            vals.into_iter().fold(
+                query.filter_expression(condition),
+                |builder, (label, value)| builder.expression_attribute_values(label, value),
             )
         } else {
             query
-        };
+        }
+    }
+
+    /// Match keys the subscription item `id` is indexed under; None when it
+    /// does not exist.
+    async fn subscription_index(
+        &self,
+        table: &str,
+        id: &str,
+    ) -> Result<Option<Vec<String>>, String> {
+        let ret = self
+            .client
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(id.to_string()))
+            .key("type", AttributeValue::S("conn_id".to_string()))
+            .projection_expression("#index")
+            .expression_attribute_names("#index", "index")
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(ret.item().map(|item| {
+            item.get("index")
+                .and_then(|keys| keys.as_l().ok())
+                .map(|keys| keys.iter().filter_map(|k| k.as_s().ok().cloned()).collect())
+                .unwrap_or_default()
+        }))
+    }
 
-        let items: Result<Vec<_>, _> = query
+    /// Index items of the subscriptions under the match key `key`.
+    async fn get_index_items(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> Result<Vec<HashMap<String, AttributeValue>>, String> {
+        let pages: Result<Vec<_>, _> = self
+            .client
+            .query()
+            .table_name(table)
+            .key_condition_expression("id = :id")
+            .expression_attribute_values(":id", AttributeValue::S(index_key(key)))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
             .into_paginator()
-            .items()
             .send()
-            .take(limit as usize)
             .collect()
             .await;
+        let pages = pages.map_err(|e| format!("{e:?}"))?;
+        for page in pages.iter() {
+            self.record(page.consumed_capacity());
+        }
+        Ok(pages
+            .iter()
+            .flat_map(|p| p.items().unwrap_or_default().to_vec())
+            .collect())
+    }
+
+    async fn get_event_by_pubkey(
+        &self,
+        pubkey: &str,
+        kinds: &Option<Vec<u64>>,
+        since: u64,
+        until: u64,
+        limit: i32,
+    ) -> Result<Vec<Event>, String> {
+        let query = self.pubkey_query(pubkey, kinds, since, until);
+        self.get_indexed_events(query, limit).await
+    }
+
+    /// Up to `limit` events found by an index query, read by id.
+    async fn get_indexed_events(
+        &self,
+        query: fluent_builders::Query,
+        limit: i32,
+    ) -> Result<Vec<Event>, String> {
+        let mut pages = query
+            .limit(limit)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .into_paginator()
+            .send();
         let mut ids = vec![];
-        if let Ok(items) = items {
-            for item in items {
-                if let Some(id) = item.get("id") {
-                    ids.push(id.as_s().unwrap().to_string())
+        while ids.len() < limit as usize {
+            let page = match pages.next().await {
+                Some(Ok(page)) => page,
+                Some(Err(e)) => {
+                    println!("ddb err: {e:?}");
+                    break;
                 }
-            }
+                None => break,
+            };
+            self.record(page.consumed_capacity());
+            ids.extend(
+                page.items()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|item| item.get("id")?.as_s().ok()?.parse().ok()),
+            );
         }
+        ids.truncate(limit as usize);
         self.get_event_by_ids(&ids).await
     }
 
-    pub async fn delete_event_by_ids(
-        &self,
-        ids: Vec<String>,
-    ) -> Result<
-        aws_sdk_dynamodb::output::BatchWriteItemOutput,
-        aws_sdk_dynamodb::types::SdkError<aws_sdk_dynamodb::error::BatchWriteItemError>,
-    > {
-        let table = std::env::var("NOSTR_EVENT_TABLE").unwrap();
-        let mut wrs = Vec::<WriteRequest>::new();
+    /// Points `relays#<pubkey>` at the relay list `ev`, unless it already
+    /// holds a newer one, so NIP-65 lookups take a single read.
+    ///
+    /// The item has no `pubkey` or `value`, which keeps it out of the
+    /// indexes.
+    async fn put_relay_list(&self, table: &str, ev: &Event) -> Result<(), String> {
+        let ret = self
+            .client
+            .put_item()
+            .table_name(table)
+            .item("id", AttributeValue::S(relay_list_key(&ev.pubkey)))
+            .item("type", AttributeValue::S("relays".to_string()))
+            .item("event_id", AttributeValue::S(ev.id.to_string()))
+            .item("created_at", AttributeValue::N(ev.created_at.to_string()))
+            .item("json", json_attribute(ev, self.config.compress_events))
+            .item(
+                "_ttl",
+                AttributeValue::N(event_expiry(ev, &self.config.retention).to_string()),
+            )
+            .condition_expression(
+                "attribute_not_exists(id) OR created_at < :created_at \
+                 OR (created_at = :created_at AND event_id > :id)",
+            )
+            .expression_attribute_values(
+                ":created_at",
+                AttributeValue::N(ev.created_at.to_string()),
+            )
+            .expression_attribute_values(":id", AttributeValue::S(ev.id.to_string()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await;
 
-        for id in ids {
-            wrs.push(delete_request(&id, "event"));
+        match ret {
+            Ok(out) => {
+                self.record(out.consumed_capacity());
+                Ok(())
+            }
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_conditional_check_failed_exception() {
+                    Ok(())
+                } else {
+                    Err(format!("{e:?}"))
+                }
+            }
         }
+    }
+
+    /// Types of the `tags#<n>` items stored beside event `id`.
+    async fn tag_chunk_types(&self, table: &str, id: &str) -> Vec<String> {
+        self.tag_chunk_items(table, id)
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|item| item.get("type")?.as_s().ok().cloned())
+            .collect()
+    }
 
+    /// The `tags#<n>` items stored beside event `id`.
+    async fn tag_chunk_items(
+        &self,
+        table: &str,
+        id: &str,
+    ) -> Result<Vec<HashMap<String, AttributeValue>>, String> {
         self.client
-            .batch_write_item()
-            .request_items(table, wrs)
+            .query()
+            .table_name(table)
+            .key_condition_expression("id = :id AND begins_with(#type, :tags)")
+            .expression_attribute_names("#type", "type")
+            .expression_attribute_values(":id", AttributeValue::S(id.to_string()))
+            .expression_attribute_values(":tags", AttributeValue::S("tags#".to_string()))
+            .into_paginator()
+            .items()
             .send()
+            .collect::<Result<Vec<_>, _>>()
             .await
+            .map_err(|e| format!("{e:?}"))
     }
-}
 
-fn write_request(
-    id: &str,
-    item_type: &str,
-    value: AttributeValue,
-    data: Option<Vec<(String, AttributeValue)>>,
-    ttl: i64,
+    /// Puts back the tags of `ev` kept in its `chunks` `tags#<n>` items.
+    async fn load_tags(&self, table: &str, ev: &mut Event, chunks: usize) -> Result<(), String> {
+        let items = self.tag_chunk_items(table, &ev.id).await?;
+        ev.tags = chunked_tags(&items, chunks).ok_or(format!("{}: tags are missing", ev.id))?;
+        Ok(())
+    }
+
+    /// The event in `item` with all its tags; None, after logging, when
+    /// some of its `tags#<n>` items are gone.
+    async fn item_event_with_tags(
+        &self,
+        table: &str,
+        item: &HashMap<String, AttributeValue>,
+    ) -> Result<Option<Event>, String> {
+        let Some(mut ev) = item_event(item)? else {
+            return Ok(None);
+        };
+        match item_tag_chunks(item) {
+            Some(chunks) => match self.load_tags(table, &mut ev, chunks).await {
+                Ok(()) => Ok(Some(ev)),
+                Err(e) => {
+                    println!("ddb err: {e}");
+                    Ok(None)
+                }
+            },
+            None => Ok(Some(ev)),
+        }
+    }
+
+    /// Events read from stream images, with the tags left in their
+    /// `chunks` `tags#<n>` items put back. Those whose items are gone are
+    /// logged and left out.
+    pub async fn with_tags(&self, evs: Vec<(Event, usize)>) -> Vec<Event> {
+        let table = self.config.event_table.clone();
+        let mut result = vec![];
+        for (mut ev, chunks) in evs {
+            if chunks > 0 {
+                if let Err(e) = self.load_tags(&table, &mut ev, chunks).await {
+                    println!("ddb err: {e}");
+                    continue;
+                }
+            }
+            result.push(ev);
+        }
+        result
+    }
+
+    /// Writes the replaceable `ev` in one transaction with the `head` item
+    /// of its pubkey and kind, deleting the version it supersedes, so that
+    /// concurrent writers cannot both keep their version. An event older
+    /// than the head is not stored.
+    ///
+    /// The head has no `pubkey` or `value`, which keeps it out of the
+    /// indexes.
+    async fn write_replaceable(&self, table: &str, ev: &Event) -> Result<(), String> {
+        let key = replaceable_key(&ev.pubkey, ev.kind);
+        let expiry = event_expiry(ev, &self.config.retention);
+        for _ in 0..BATCH_ATTEMPTS {
+            let head = self
+                .client
+                .get_item()
+                .table_name(table)
+                .key("id", AttributeValue::S(key.clone()))
+                .key("type", AttributeValue::S("head".to_string()))
+                .consistent_read(true)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            self.record(head.consumed_capacity());
+            let previous = head.item().and_then(|item| {
+                let id = item.get("event_id")?.as_s().ok()?.clone();
+                let created_at: u64 = item.get("created_at")?.as_n().ok()?.parse().ok()?;
+                Some((id, created_at))
+            });
+
+            let mut head = Put::builder()
+                .table_name(table)
+                .item("id", AttributeValue::S(key.clone()))
+                .item("type", AttributeValue::S("head".to_string()))
+                .item("event_id", AttributeValue::S(ev.id.to_string()))
+                .item("created_at", AttributeValue::N(ev.created_at.to_string()));
+            if expiry >= 0 {
+                head = head.item("_ttl", AttributeValue::N(expiry.to_string()));
+            }
+            let head = match &previous {
+                Some((id, created_at)) if !supersedes(ev, id, *created_at) => return Ok(()),
+                Some((id, _)) => head
+                    .condition_expression("event_id = :previous")
+                    .expression_attribute_values(":previous", AttributeValue::S(id.clone())),
+                None => head.condition_expression("attribute_not_exists(id)"),
+            };
+
+            let mut items = vec![TransactWriteItem::builder().put(head.build()).build()];
+            for wr in event_write_requests(ev, &self.config.retention, self.config.compress_events)
+            {
+                let put = Put::builder()
+                    .table_name(table)
+                    .set_item(wr.put_request().and_then(|p| p.item()).cloned())
+                    .build();
+                items.push(TransactWriteItem::builder().put(put).build());
+            }
+            if let Some((id, _)) = &previous {
+                let mut types = self.tag_chunk_types(table, id).await;
+                types.push("event".to_string());
+                for item_type in types {
+                    let delete = Delete::builder()
+                        .table_name(table)
+                        .key("id", AttributeValue::S(id.clone()))
+                        .key("type", AttributeValue::S(item_type))
+                        .build();
+                    items.push(TransactWriteItem::builder().delete(delete).build());
+                }
+            }
+            // Writing without the head would leave two live versions.
+            if items.len() > MAX_TRANSACT_ITEMS {
+                return Err(format!("{}: too many items to replace atomically", ev.id));
+            }
+
+            let ret = self
+                .client
+                .transact_write_items()
+                .set_transact_items(Some(items))
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await;
+            match ret.map_err(|e| e.into_service_error()) {
+                Ok(out) => {
+                    self.record_all(out.consumed_capacity());
+                    return Ok(());
+                }
+                // The head moved on since it was read; decide again.
+                Err(e) if e.is_transaction_canceled_exception() => {
+                    println!("{}: replacement conflicted, retrying", ev.id);
+                }
+                Err(e) => return Err(format!("{e:?}")),
+            }
+        }
+        Err(format!("{}: replacement kept conflicting", ev.id))
+    }
+
+    /// Removes `relays#<pubkey>` if it still points at the deleted `ev`.
+    async fn delete_relay_list(&self, table: &str, ev: &Event) -> Result<(), String> {
+        let ret = self
+            .client
+            .delete_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(relay_list_key(&ev.pubkey)))
+            .key("type", AttributeValue::S("relays".to_string()))
+            .condition_expression("event_id = :id")
+            .expression_attribute_values(":id", AttributeValue::S(ev.id.to_string()))
+            .send()
+            .await;
+
+        match ret {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_conditional_check_failed_exception() {
+                    Ok(())
+                } else {
+                    Err(format!("{e:?}"))
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventStore for Ddb {
+    fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        let size = largest_item_size(ev, &self.config.retention, self.config.compress_events);
+        if size > MAX_ITEM_SIZE {
+            println!("event too large: {size} bytes");
+            return Err(RejectReason::Invalid("event too large".to_string()));
+        }
+        Ok(())
+    }
+
+    fn replaces_on_write(&self, ev: &Event) -> bool {
+        replaced_at_write(ev, &self.config)
+    }
+
+    fn consumed_capacity(&self) -> f64 {
+        *self.consumed.lock().unwrap()
+    }
+
+    async fn write_event(&self, ev: &Event) -> Result<(), String> {
+        let table = self.config.event_table.clone();
+        if replaced_at_write(ev, &self.config) {
+            self.write_replaceable(&table, ev).await?;
+        } else {
+            // The tag items go first so that the event is never read
+            // without them.
+            let mut wrs =
+                event_write_requests(ev, &self.config.retention, self.config.compress_events);
+            let event = wrs.remove(0);
+            self.batch_write(&table, wrs).await?;
+            self.batch_write(&table, vec![event]).await?;
+        }
+        if ev.kind == KIND_RELAY_LIST {
+            self.put_relay_list(&table, ev).await?;
+        }
+        self.remember_event(&ev.id);
+        Ok(())
+    }
+
+    async fn write_subscription(
+        &self,
+        conn_id: &str,
+        sub_id: &str,
+        filters: &[Filter],
+    ) -> Result<bool, String> {
+        let table = self.config.subscription_table.clone();
+        let ttl = subscription_expiry(self.config.subscription_ttl);
+        let id = &subscription_key(conn_id, sub_id);
+        let mut wrs = Vec::<WriteRequest>::new();
+        let fs: Vec<_> = filters
+            .iter()
+            .map(|f| AttributeValue::S(serde_json::to_string(f).unwrap()))
+            .collect();
+        let keys = subscription_match_keys(filters);
+
+        // A REQ reusing the sub_id replaces the filters; index items the new
+        // filters no longer need are dropped.
+        let previous = self.subscription_index(&table, id).await?;
+        let replaced = previous.is_some();
+        for key in previous.iter().flatten().filter(|k| !keys.contains(k)) {
+            wrs.push(delete_request(&index_key(key), id));
+        }
+
+        let index = keys.iter().map(|k| AttributeValue::S(k.clone())).collect();
+        wrs.push(write_request(
+            id,
+            "conn_id",
+            AttributeValue::S(conn_id.to_string()),
+            Some(vec![
+                ("filters".to_string(), AttributeValue::L(fs.clone())),
+                ("index".to_string(), AttributeValue::L(index)),
+            ]),
+            ttl,
+        ));
+        for key in keys.iter() {
+            wrs.push(index_write_request(
+                key,
+                conn_id,
+                sub_id,
+                AttributeValue::L(fs.clone()),
+                ttl,
+            ));
+        }
+
+        self.batch_write(&table, wrs).await?;
+        Ok(replaced)
+    }
+
+    async fn delete_subscriptions(
+        &self,
+        conn_id: &str,
+        sub_ids: Vec<String>,
+    ) -> Result<(), String> {
+        let table = self.config.subscription_table.clone();
+        let mut wrs = Vec::<WriteRequest>::new();
+
+        for sub_id in sub_ids {
+            let id = subscription_key(conn_id, &sub_id);
+            for key in self
+                .subscription_index(&table, &id)
+                .await?
+                .unwrap_or_default()
+            {
+                wrs.push(delete_request(&index_key(&key), &id));
+            }
+            wrs.push(delete_request(&id, "conn_id"));
+        }
+
+        Ok(self.batch_write(&table, wrs).await?)
+    }
+
+    async fn close_connection(&self, conn_id: &str) -> Result<usize, String> {
+        let table = self.config.subscription_table.clone();
+
+        let deleted = self
+            .client
+            .delete_item()
+            .table_name(&table)
+            .key("id", AttributeValue::S(format!("conn#{conn_id}")))
+            .key("type", AttributeValue::S("conn".to_string()))
+            .send()
+            .await;
+        if let Err(e) = deleted {
+            println!("connection record err: {e:?}");
+        }
+
+        let sub_ids = self.get_subscription_ids(conn_id).await.unwrap_or_default();
+        if sub_ids.is_empty() {
+            return Ok(0);
+        }
+        let count = sub_ids.len();
+        self.delete_subscriptions(conn_id, sub_ids)
+            .await
+            .map(|_| count)
+    }
+
+    async fn get_subscription_ids(&self, conn_id: &str) -> Result<Vec<String>, String> {
+        let table = self.config.subscription_table.clone();
+        let items: Result<Vec<_>, _> = self
+            .client
+            .query()
+            .table_name(&table)
+            .index_name("value-id-index")
+            .key_condition_expression("#value = :conn_id")
+            .expression_attribute_names("#value", "value")
+            .expression_attribute_values(":conn_id", AttributeValue::S(conn_id.to_string()))
+            .into_paginator()
+            .items()
+            .send()
+            .collect()
+            .await;
+        let items = items.map_err(|e| format!("{e:?}"))?;
+        Ok(items
+            .iter()
+            .filter_map(|item| item.get("id")?.as_s().ok())
+            .map(|id| subscription_id(conn_id, id).to_string())
+            .collect())
+    }
+
+    async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)> {
+        let table = self.config.subscription_table.clone();
+        let mut results = vec![];
+
+        let pages: Result<Vec<_>, _> = self
+            .client
+            .scan()
+            .table_name(table)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .into_paginator()
+            .send()
+            .collect()
+            .await;
+
+        if let Ok(pages) = pages {
+            for page in pages.iter() {
+                self.record(page.consumed_capacity());
+            }
+            let items = pages.iter().flat_map(|p| p.items().unwrap_or_default());
+            for item in items {
+                // The table also holds non-subscription items such as the gauges.
+                let sub_id = if let Some(sub_id) = item.get("id") {
+                    let sub_id = sub_id.as_s().unwrap();
+                    sub_id.to_string()
+                } else {
+                    continue;
+                };
+                let conn_id = if let Some(conn_id) = item.get("value") {
+                    conn_id.as_s().unwrap().clone()
+                } else {
+                    continue;
+                };
+                let Some(filters) = item_filters(item) else {
+                    continue;
+                };
+                let sub_id = subscription_id(&conn_id, &sub_id).to_string();
+                results.push((sub_id, conn_id, filters));
+            }
+        }
+
+        results
+    }
+
+    async fn get_subscriptions_for(&self, ev: &Event) -> Vec<(String, String, Vec<Filter>)> {
+        let table = self.config.subscription_table.clone();
+        let keys = ev.match_keys();
+        let queries: Vec<_> = keys
+            .iter()
+            .map(|key| self.get_index_items(&table, key))
+            .collect();
+        let results: Vec<_> = futures_util::StreamExt::buffer_unordered(
+            futures_util::stream::iter(queries),
+            INDEX_QUERY_CONCURRENCY,
+        )
+        .collect()
+        .await;
+
+        // A subscription indexed under several of the event's keys is
+        // returned once.
+        let mut seen = std::collections::HashSet::new();
+        let mut subscriptions = vec![];
+        for items in results {
+            let items = match items {
+                Ok(items) => items,
+                Err(e) => {
+                    println!("subscription index err: {e}");
+                    continue;
+                }
+            };
+            for item in items.iter() {
+                let string = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+                let (Some(conn_id), Some(sub_id), Some(filters)) =
+                    (string("conn_id"), string("sub_id"), item_filters(item))
+                else {
+                    continue;
+                };
+                if seen.insert(subscription_key(&conn_id, &sub_id)) {
+                    subscriptions.push((sub_id, conn_id, filters));
+                }
+            }
+        }
+        subscriptions
+    }
+
+    async fn get_event_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, String> {
+        #[cfg(feature = "cache")]
+        if let Some(cache) = &self.cache {
+            let mut evs = cache.get_events(ids).await;
+            let missing: Vec<EventId> = ids
+                .iter()
+                .filter(|id| !evs.iter().any(|ev| &ev.id == *id))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                let fetched = self.fetch_events(&missing).await?;
+                cache.put_events(&fetched).await;
+                evs.extend(fetched);
+            }
+            return Ok(evs);
+        }
+        self.fetch_events(ids).await
+    }
+
+    async fn has_event(&self, id: &EventId) -> Result<bool, String> {
+        let remembered = RECENT_IDS
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|recent| recent.contains(id));
+        if remembered {
+            return Ok(true);
+        }
+        let found = !self
+            .get_event_by_ids(std::slice::from_ref(id))
+            .await?
+            .is_empty();
+        if found {
+            self.remember_event(id);
+        }
+        Ok(found)
+    }
+
+    async fn get_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or(1893456000);
+        let limit = limit.unwrap_or(100).max(1);
+
+        // Every author gets the whole limit so that the newest events overall
+        // win, not whichever authors happen to come first.
+        let queries: Vec<_> = pubkeys
+            .iter()
+            .map(|pubkey| self.get_event_by_pubkey(pubkey, &kinds, since, until, limit))
+            .collect();
+        let results: Vec<_> = futures_util::StreamExt::buffer_unordered(
+            futures_util::stream::iter(queries),
+            PUBKEY_QUERY_CONCURRENCY,
+        )
+        .collect()
+        .await;
+        let result = results.into_iter().flatten().flatten().collect();
+
+        Ok(newest(result, limit as usize))
+    }
+
+    async fn get_event_by_channels(
+        &self,
+        channels: &[EventId],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or(1893456000);
+        let limit = limit.unwrap_or(100).max(1);
+        let mut result = vec![];
+
+        for channel in channels {
+            let query = self.created_at_query(
+                "channel-created_at-index",
+                "channel",
+                AttributeValue::S(channel.to_string()),
+                &kinds,
+                since,
+                until,
+            );
+            result.extend(self.get_indexed_events(query, limit).await?);
+        }
+
+        Ok(newest(result, limit as usize))
+    }
+
+    /// Reads kind-created_at-index, which is keyed by the `kind` attribute
+    /// every event item has.
+    async fn get_event_by_kinds(
+        &self,
+        kinds: &[u64],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or(1893456000);
+        let limit = limit.unwrap_or(100).max(1);
+        let mut result = vec![];
+
+        for kind in kinds {
+            let query = self.created_at_query(
+                "kind-created_at-index",
+                "kind",
+                AttributeValue::N(kind.to_string()),
+                &None,
+                since,
+                until,
+            );
+            result.extend(self.get_indexed_events(query, limit).await?);
+        }
+
+        Ok(newest(result, limit as usize))
+    }
+
+    /// Reads pubkey-prefix-index, which is keyed by the first
+    /// `MIN_PREFIX_LENGTH` characters of the pubkey, and filters the rest
+    /// of the prefix.
+    async fn get_event_by_pubkey_prefixes(
+        &self,
+        prefixes: &[String],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or(1893456000);
+        let limit = limit.unwrap_or(100).max(1);
+        let mut result = vec![];
+
+        for prefix in prefixes {
+            let mut condition = "begins_with(pubkey, :prefix)".to_string();
+            let mut vals = vec![(":prefix".to_string(), AttributeValue::S(prefix.clone()))];
+            if let Some(kinds) = &kinds {
+                let (kind_condition, kind_vals) = kind_condition(kinds);
+                condition = format!("{condition} AND {kind_condition}");
+                vals.extend(kind_vals);
+            }
+            let query = self.created_at_query(
+                "pubkey-prefix-index",
+                "pubkey_prefix",
+                AttributeValue::S(prefix[..MIN_PREFIX_LENGTH].to_string()),
+                &None,
+                since,
+                until,
+            );
+            let query = vals.into_iter().fold(
+                query.filter_expression(condition),
+                |builder, (label, value)| builder.expression_attribute_values(label, value),
+            );
+            result.extend(self.get_indexed_events(query, limit).await?);
+        }
+
+        Ok(newest(result, limit as usize))
+    }
+
+    /// Reads id-prefix-index, which is keyed by the first
+    /// `MIN_PREFIX_LENGTH` characters of the id and sorted by the id.
+    async fn get_event_by_id_prefixes(
+        &self,
+        prefixes: &[String],
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let table = self.config.event_table.clone();
+        let limit = limit.unwrap_or(100).max(1);
+        let mut result = vec![];
+
+        for prefix in prefixes {
+            let query = self
+                .reader()
+                .query()
+                .table_name(&table)
+                .index_name("id-prefix-index")
+                .key_condition_expression("id_prefix = :key AND begins_with(id, :prefix)")
+                .expression_attribute_values(
+                    ":key",
+                    AttributeValue::S(prefix[..MIN_PREFIX_LENGTH].to_string()),
+                )
+                .expression_attribute_values(":prefix", AttributeValue::S(prefix.clone()));
+            result.extend(self.get_indexed_events(query, limit).await?);
+        }
+
+        Ok(newest(result, limit as usize))
+    }
+
+    /// Walks day-created_at-index back from `until` one day at a time
+    /// until `limit` events are found, `since` is passed or `MAX_DAYS`
+    /// buckets were read.
+    async fn get_event_by_time(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        });
+        let limit = limit.unwrap_or(100).max(1);
+        let mut result = vec![];
+
+        let last = until / DAY;
+        let first = (since / DAY).max(last.saturating_sub(MAX_DAYS - 1));
+        for day in (first..=last).rev() {
+            let query = self.created_at_query(
+                "day-created_at-index",
+                "day",
+                AttributeValue::N(day.to_string()),
+                &None,
+                since,
+                until,
+            );
+            let remaining = limit - result.len() as i32;
+            result.extend(self.get_indexed_events(query, remaining).await?);
+            if result.len() as i32 >= limit {
+                break;
+            }
+        }
+
+        Ok(newest(result, limit as usize))
+    }
+
+    /// Scans the event table page by page until `max_items` items were
+    /// read, whatever their type, so the cap also bounds the capacity used.
+    async fn scan_events(&self, max_items: usize) -> Result<Vec<Event>, String> {
+        let table = self.config.event_table.clone();
+        let mut evs = vec![];
+        let mut scanned = 0;
+        let mut start_key = None;
+
+        loop {
+            let r = self
+                .reader()
+                .scan()
+                .table_name(&table)
+                .filter_expression("#type = :event")
+                .expression_attribute_names("#type", "type")
+                .expression_attribute_values(":event", AttributeValue::S("event".to_string()))
+                .limit((max_items - scanned).min(1000) as i32)
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            self.record(r.consumed_capacity());
+            scanned += r.scanned_count() as usize;
+            for item in r.items().unwrap_or_default() {
+                evs.extend(self.item_event_with_tags(&table, item).await?);
+            }
+            start_key = r.last_evaluated_key().cloned();
+            if start_key.is_none() || scanned >= max_items {
+                break;
+            }
+        }
+        Ok(evs)
+    }
+
+    async fn get_event_by_recipients(
+        &self,
+        recipients: &[Pubkey],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or(1893456000);
+        let limit = limit.unwrap_or(100).max(1);
+        let mut result = vec![];
+
+        for recipient in recipients {
+            let query = self.created_at_query(
+                "recipient-created_at-index",
+                "recipient",
+                AttributeValue::S(recipient.to_string()),
+                &None,
+                since,
+                until,
+            );
+            result.extend(self.get_indexed_events(query, limit).await?);
+        }
+
+        Ok(newest(result, limit as usize))
+    }
+
+    async fn get_relay_list(&self, pubkey: &Pubkey) -> Result<Option<Event>, String> {
+        let table = self.config.event_table.clone();
+
+        let ret = self
+            .reader()
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(relay_list_key(pubkey)))
+            .key("type", AttributeValue::S("relays".to_string()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        self.record(ret.consumed_capacity());
+        match ret.item() {
+            Some(item) => item_event(item),
+            None => Ok(None),
+        }
+    }
+
+    async fn count_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<u64, String> {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or(1893456000);
+        let mut count = 0;
+
+        for pubkey in pubkeys {
+            let pages: Result<Vec<_>, _> = self
+                .pubkey_query(pubkey, &kinds, since, until)
+                .select(Select::Count)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .into_paginator()
+                .send()
+                .collect()
+                .await;
+            let pages = pages.map_err(|e| format!("{e:?}"))?;
+            for page in pages.iter() {
+                self.record(page.consumed_capacity());
+            }
+            count += pages.iter().map(|p| p.count() as u64).sum::<u64>();
+        }
+        Ok(count)
+    }
+
+    async fn delete_event_by_ids(&self, ids: Vec<EventId>) -> Result<(), String> {
+        let table = self.config.event_table.clone();
+        let mut wrs = Vec::<WriteRequest>::new();
+
+        for ev in self.get_event_by_ids(&ids).await? {
+            if ev.kind == KIND_RELAY_LIST {
+                self.delete_relay_list(&table, &ev).await?;
+            }
+        }
+
+        for id in ids.iter() {
+            wrs.push(delete_request(id, "event"));
+            for item_type in self.tag_chunk_types(&table, id).await {
+                wrs.push(delete_request(id, &item_type));
+            }
+        }
+
+        self.batch_write(&table, wrs).await?;
+        // Dropped only now, as the lookup above may have cached them again.
+        #[cfg(feature = "cache")]
+        if let Some(cache) = &self.cache {
+            cache.forget_events(&ids).await;
+        }
+        Ok(())
+    }
+
+    async fn adjust_gauges(&self, connections: i64, subscriptions: i64) -> Result<Gauges, String> {
+        let table = self.config.subscription_table.clone();
+
+        let ret = self
+            .client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S("_gauges".to_string()))
+            .key("type", AttributeValue::S("gauges".to_string()))
+            .update_expression("ADD connections :c, subscriptions :s")
+            .expression_attribute_values(":c", AttributeValue::N(connections.to_string()))
+            .expression_attribute_values(":s", AttributeValue::N(subscriptions.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+
+        let attr = |k: &str| {
+            ret.attributes()
+                .and_then(|a| a.get(k))
+                .and_then(|v| v.as_n().ok())
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(0)
+        };
+        Ok(Gauges {
+            connections: attr("connections"),
+            subscriptions: attr("subscriptions"),
+        })
+    }
+
+    async fn write_labels(&self, ev: &Event, entries: &[LabelEntry]) -> Result<(), String> {
+        let table = self.config.event_table.clone();
+        let ttl = event_expiry(ev, &self.config.retention);
+
+        let wrs: Vec<WriteRequest> = entries
+            .iter()
+            .map(|e| {
+                write_request(
+                    &label_key(&e.namespace, &e.value),
+                    &format!("{}#{}#{}", e.target_tag, e.target, e.label_event),
+                    AttributeValue::S(e.target.clone()),
+                    Some(vec![
+                        (
+                            "namespace".to_string(),
+                            AttributeValue::S(e.namespace.clone()),
+                        ),
+                        ("label".to_string(), AttributeValue::S(e.value.clone())),
+                        (
+                            "target_tag".to_string(),
+                            AttributeValue::S(e.target_tag.clone()),
+                        ),
+                        ("labeler".to_string(), AttributeValue::S(e.labeler.clone())),
+                        (
+                            "label_event".to_string(),
+                            AttributeValue::S(e.label_event.clone()),
+                        ),
+                    ]),
+                    ttl,
+                )
+            })
+            .collect();
+
+        Ok(self.batch_write(&table, wrs).await?)
+    }
+
+    async fn get_label_targets(
+        &self,
+        namespace: &str,
+        value: &str,
+    ) -> Result<Vec<LabelEntry>, String> {
+        let table = self.config.event_table.clone();
+
+        let items: Result<Vec<_>, _> = self
+            .client
+            .query()
+            .table_name(table)
+            .key_condition_expression("id = :id")
+            .expression_attribute_values(":id", AttributeValue::S(label_key(namespace, value)))
+            .into_paginator()
+            .items()
+            .send()
+            .collect()
+            .await;
+
+        let items = items.map_err(|e| format!("{e:?}"))?;
+        let attr = |item: &HashMap<String, AttributeValue>, k: &str| {
+            item.get(k)
+                .and_then(|v| v.as_s().ok())
+                .cloned()
+                .unwrap_or_default()
+        };
+        Ok(items
+            .iter()
+            .map(|item| LabelEntry {
+                namespace: attr(item, "namespace"),
+                value: attr(item, "label"),
+                target_tag: attr(item, "target_tag"),
+                target: attr(item, "value"),
+                labeler: attr(item, "labeler"),
+                label_event: attr(item, "label_event"),
+            })
+            .collect())
+    }
+
+    async fn add_rejection(&self, kind: &str, reason: &str) -> Result<(), String> {
+        let table = self.config.subscription_table.clone();
+
+        self.client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S("_gauges".to_string()))
+            .key("type", AttributeValue::S("gauges".to_string()))
+            .update_expression("ADD #n :one")
+            .expression_attribute_names("#n", format!("rejected#{kind}#{reason}"))
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn add_archived(&self, count: i64) -> Result<(), String> {
+        let table = self.config.subscription_table.clone();
+
+        self.client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S("_gauges".to_string()))
+            .key("type", AttributeValue::S("gauges".to_string()))
+            .update_expression("ADD archived :n")
+            .expression_attribute_values(":n", AttributeValue::N(count.to_string()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn add_language(&self, language: &str) -> Result<(), String> {
+        let table = self.config.subscription_table.clone();
+
+        self.client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S("_gauges".to_string()))
+            .key("type", AttributeValue::S("gauges".to_string()))
+            .update_expression("ADD #n :one")
+            .expression_attribute_names("#n", format!("lang#{language}"))
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn get_stats(&self) -> Result<Stats, String> {
+        let table = self.config.subscription_table.clone();
+
+        let ret = self
+            .client
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S("_gauges".to_string()))
+            .key("type", AttributeValue::S("gauges".to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+
+        let mut stats = Stats::default();
+        for (k, v) in ret.item().into_iter().flatten() {
+            let Some(n) = v.as_n().ok().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            match k.split('#').collect::<Vec<_>>()[..] {
+                ["connections"] => stats.connections = n,
+                ["subscriptions"] => stats.subscriptions = n,
+                ["archived"] => stats.archived = n,
+                ["lang", language] => {
+                    stats.languages.insert(language.to_string(), n);
+                }
+                ["rejected", kind, reason] => {
+                    stats
+                        .rejections
+                        .entry(kind.to_string())
+                        .or_default()
+                        .insert(reason.to_string(), n);
+                }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn write_report(&self, report: &Report) -> Result<(), String> {
+        let table = self.moderation_table()?;
+        let mut put = self
+            .client
+            .put_item()
+            .table_name(table)
+            .item("id", AttributeValue::S(report.id.clone()))
+            .item("type", AttributeValue::S("report".to_string()))
+            .item("status", AttributeValue::S("open".to_string()))
+            .item("reporter", AttributeValue::S(report.reporter.clone()))
+            .item("pubkey", AttributeValue::S(report.pubkey.clone()))
+            .item("report_type", AttributeValue::S(report.report_type.clone()))
+            .item("content", AttributeValue::S(report.content.clone()))
+            .item(
+                "created_at",
+                AttributeValue::N(report.created_at.to_string()),
+            );
+        if let Some(event_id) = &report.event_id {
+            put = put.item("event_id", AttributeValue::S(event_id.clone()));
+        }
+        put.send().await.map(|_| ()).map_err(|e| format!("{e:?}"))
+    }
+
+    async fn get_open_reports(&self) -> Result<Vec<Report>, String> {
+        let table = self.moderation_table()?;
+
+        let items: Result<Vec<_>, _> = self
+            .client
+            .scan()
+            .table_name(table)
+            .filter_expression("#type = :report AND #status = :open")
+            .expression_attribute_names("#type", "type")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":report", AttributeValue::S("report".to_string()))
+            .expression_attribute_values(":open", AttributeValue::S("open".to_string()))
+            .into_paginator()
+            .items()
+            .send()
+            .collect()
+            .await;
+
+        let items = items.map_err(|e| format!("{e:?}"))?;
+        let attr = |item: &HashMap<String, AttributeValue>, k: &str| {
+            item.get(k)
+                .and_then(|v| v.as_s().ok())
+                .cloned()
+                .unwrap_or_default()
+        };
+        let mut reports: Vec<Report> = items
+            .iter()
+            .map(|item| Report {
+                id: attr(item, "id"),
+                reporter: attr(item, "reporter"),
+                pubkey: attr(item, "pubkey"),
+                event_id: item.get("event_id").and_then(|v| v.as_s().ok()).cloned(),
+                report_type: attr(item, "report_type"),
+                content: attr(item, "content"),
+                created_at: item
+                    .get("created_at")
+                    .and_then(|v| v.as_n().ok())
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(0),
+            })
+            .collect();
+        reports.sort_by_key(|r| r.created_at);
+        Ok(reports)
+    }
+
+    async fn resolve_report(&self, id: &str, action: &str) -> Result<(), String> {
+        let table = self.moderation_table()?;
+
+        self.client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(id.to_string()))
+            .key("type", AttributeValue::S("report".to_string()))
+            .update_expression("SET #status = :action")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":action", AttributeValue::S(action.to_string()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn add_ban(&self, pubkey: &str) -> Result<(), String> {
+        let table = self.moderation_table()?;
+
+        self.client
+            .put_item()
+            .table_name(table)
+            .item("id", AttributeValue::S(pubkey.to_string()))
+            .item("type", AttributeValue::S("ban".to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        // The ban item answers is_banned; the denylist item lets REQ leave
+        // the pubkey's events out without a read per event.
+        self.put_denylist_item(&format!("pubkey#{pubkey}")).await
+    }
+
+    async fn remove_ban(&self, pubkey: &str) -> Result<(), String> {
+        let table = self.moderation_table()?;
+
+        self.client
+            .delete_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(pubkey.to_string()))
+            .key("type", AttributeValue::S("ban".to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        self.delete_denylist_item(&format!("pubkey#{pubkey}")).await
+    }
+
+    async fn is_banned(&self, pubkey: &str) -> Result<bool, String> {
+        let Ok(table) = self.moderation_table() else {
+            return Ok(false);
+        };
+
+        let ret = self
+            .client
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(pubkey.to_string()))
+            .key("type", AttributeValue::S("ban".to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(ret.item().is_some())
+    }
+
+    async fn add_banned_event(&self, id: &str) -> Result<(), String> {
+        self.put_denylist_item(&format!("event#{id}")).await
+    }
+
+    async fn remove_banned_event(&self, id: &str) -> Result<(), String> {
+        self.delete_denylist_item(&format!("event#{id}")).await
+    }
+
+    async fn get_denylist(&self) -> Result<Denylist, String> {
+        if self.moderation_table().is_err() {
+            return Ok(Denylist::default());
+        }
+        let ttl = Duration::from_secs(self.config.denylist_ttl);
+        if let Some((read_at, denylist)) = &*DENYLIST.lock().unwrap() {
+            if read_at.elapsed() < ttl {
+                return Ok(denylist.clone());
+            }
+        }
+
+        let entries = self.get_moderation_partition(DENYLIST_KEY).await?;
+        let denylist = Denylist::new(
+            entries
+                .iter()
+                .filter_map(|e| e.strip_prefix("pubkey#"))
+                .map(str::to_string),
+            entries
+                .iter()
+                .filter_map(|e| e.strip_prefix("event#"))
+                .map(str::to_string),
+        );
+        *DENYLIST.lock().unwrap() = Some((Instant::now(), denylist.clone()));
+        Ok(denylist)
+    }
+
+    async fn add_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        let table = self.moderation_table()?;
+
+        self.client
+            .put_item()
+            .table_name(table)
+            .item("id", AttributeValue::S(ALLOWLIST_KEY.to_string()))
+            .item("type", AttributeValue::S(pubkey.to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        *ALLOWLIST.lock().unwrap() = None;
+        Ok(())
+    }
+
+    async fn remove_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        let table = self.moderation_table()?;
+
+        self.client
+            .delete_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(ALLOWLIST_KEY.to_string()))
+            .key("type", AttributeValue::S(pubkey.to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        *ALLOWLIST.lock().unwrap() = None;
+        Ok(())
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>, String> {
+        if self.moderation_table().is_err() {
+            return Ok(vec![]);
+        }
+        let ttl = Duration::from_secs(self.config.allowlist_ttl);
+        if let Some((read_at, pubkeys)) = &*ALLOWLIST.lock().unwrap() {
+            if read_at.elapsed() < ttl {
+                return Ok(pubkeys.clone());
+            }
+        }
+
+        let pubkeys = self.get_moderation_partition(ALLOWLIST_KEY).await?;
+        *ALLOWLIST.lock().unwrap() = Some((Instant::now(), pubkeys.clone()));
+        Ok(pubkeys)
+    }
+
+    async fn add_content_rule(&self, rule: &str) -> Result<(), String> {
+        self.client
+            .put_item()
+            .table_name(self.moderation_table()?)
+            .item("id", AttributeValue::S(CONTENT_RULES_KEY.to_string()))
+            .item("type", AttributeValue::S(rule.to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        *CONTENT_RULES.lock().unwrap() = None;
+        Ok(())
+    }
+
+    async fn get_content_rules(&self) -> Result<Vec<String>, String> {
+        if self.moderation_table().is_err() {
+            return Ok(vec![]);
+        }
+        let ttl = Duration::from_secs(self.config.denylist_ttl);
+        if let Some((read_at, rules)) = &*CONTENT_RULES.lock().unwrap() {
+            if read_at.elapsed() < ttl {
+                return Ok(rules.clone());
+            }
+        }
+
+        let rules = self.get_moderation_partition(CONTENT_RULES_KEY).await?;
+        *CONTENT_RULES.lock().unwrap() = Some((Instant::now(), rules.clone()));
+        Ok(rules)
+    }
+
+    async fn add_member(&self, pubkey: &str) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        self.client
+            .put_item()
+            .table_name(self.members_table()?)
+            .item("id", AttributeValue::S(pubkey.to_string()))
+            .item("type", AttributeValue::S("member".to_string()))
+            .item("paid_at", AttributeValue::N(now.to_string()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn is_member(&self, pubkey: &str) -> Result<bool, String> {
+        let Ok(table) = self.members_table() else {
+            return Ok(false);
+        };
+
+        let ret = self
+            .client
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(pubkey.to_string()))
+            .key("type", AttributeValue::S("member".to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(ret.item().is_some())
+    }
+
+    async fn write_invoice(&self, payment_hash: &str, pubkey: &str) -> Result<(), String> {
+        let expiry = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + INVOICE_TTL;
+
+        self.client
+            .put_item()
+            .table_name(self.members_table()?)
+            .item("id", AttributeValue::S(payment_hash.to_string()))
+            .item("type", AttributeValue::S("invoice".to_string()))
+            .item("pubkey", AttributeValue::S(pubkey.to_string()))
+            .item("_ttl", AttributeValue::N(expiry.to_string()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn get_invoice_pubkey(&self, payment_hash: &str) -> Result<Option<String>, String> {
+        let ret = self
+            .client
+            .get_item()
+            .table_name(self.members_table()?)
+            .key("id", AttributeValue::S(payment_hash.to_string()))
+            .key("type", AttributeValue::S("invoice".to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(ret
+            .item()
+            .and_then(|item| item.get("pubkey")?.as_s().ok().cloned()))
+    }
+
+    async fn mark_greeted(&self, conn_id: &str) -> Result<bool, String> {
+        let table = self.config.subscription_table.clone();
+        let ttl = subscription_expiry(self.config.subscription_ttl);
+
+        // No `value` attribute, so the item stays out of value-id-index and
+        // is never taken for a subscription of the connection.
+        let ret = self
+            .client
+            .put_item()
+            .table_name(table)
+            .item("id", AttributeValue::S(format!("greeted#{conn_id}")))
+            .item("type", AttributeValue::S("greeted".to_string()))
+            .item("_ttl", AttributeValue::N(ttl.to_string()))
+            .condition_expression("attribute_not_exists(id)")
+            .send()
+            .await;
+
+        match ret {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_conditional_check_failed_exception() {
+                    Ok(false)
+                } else {
+                    Err(format!("{e:?}"))
+                }
+            }
+        }
+    }
+
+    async fn increment_counter(&self, key: &str, ttl: u64) -> Result<u64, String> {
+        let table = self.config.subscription_table.clone();
+        let expiry = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + ttl;
+
+        // No `value` attribute, so counters stay out of value-id-index.
+        let ret = self
+            .client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(key.to_string()))
+            .key("type", AttributeValue::S("counter".to_string()))
+            .update_expression("ADD #count :one SET #ttl = if_not_exists(#ttl, :ttl)")
+            .expression_attribute_names("#count", "count")
+            .expression_attribute_names("#ttl", "_ttl")
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .expression_attribute_values(":ttl", AttributeValue::N(expiry.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        ret.attributes()
+            .and_then(|a| a.get("count"))
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse().ok())
+            .ok_or(format!("{key}: no count returned"))
+    }
+
+    async fn write_connection(
+        &self,
+        conn_id: &str,
+        challenge: &str,
+        source_ip: Option<&str>,
+    ) -> Result<(), String> {
+        let table = self.config.subscription_table.clone();
+
+        // Like the greeted item, kept out of value-id-index.
+        let mut item = self
+            .client
+            .put_item()
+            .table_name(table)
+            .item("id", AttributeValue::S(format!("conn#{conn_id}")))
+            .item("type", AttributeValue::S("conn".to_string()))
+            .item("challenge", AttributeValue::S(challenge.to_string()))
+            .item(
+                "_ttl",
+                AttributeValue::N(subscription_expiry(self.config.subscription_ttl).to_string()),
+            );
+        if let Some(ip) = source_ip {
+            item = item.item("source_ip", AttributeValue::S(ip.to_string()));
+        }
+        item.send().await.map(|_| ()).map_err(|e| format!("{e:?}"))
+    }
+
+    async fn get_connection(&self, conn_id: &str) -> Result<Option<Connection>, String> {
+        let table = self.config.subscription_table.clone();
+
+        let ret = self
+            .client
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(format!("conn#{conn_id}")))
+            .key("type", AttributeValue::S("conn".to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+
+        let Some(item) = ret.item() else {
+            return Ok(None);
+        };
+        let string = |name: &str| {
+            item.get(name)
+                .and_then(|v| v.as_s().ok())
+                .map(|s| s.to_string())
+        };
+        Ok(Some(Connection {
+            challenge: string("challenge").unwrap_or_default(),
+            auth_pubkey: string("auth_pubkey"),
+            source_ip: string("source_ip"),
+        }))
+    }
+
+    async fn set_auth_pubkey(&self, conn_id: &str, pubkey: &str) -> Result<(), String> {
+        let table = self.config.subscription_table.clone();
+
+        self.client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(format!("conn#{conn_id}")))
+            .key("type", AttributeValue::S("conn".to_string()))
+            .update_expression("SET auth_pubkey = :pubkey")
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(":pubkey", AttributeValue::S(pubkey.to_string()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+/// `_ttl` of items that live as long as a subscription of `ttl` seconds.
+fn subscription_expiry(ttl: u64) -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+        + ttl as i64
+}
+
+/// Filter expression limiting results to `kinds`, with its values.
+fn kind_condition(kinds: &[u64]) -> (String, Vec<(String, AttributeValue)>) {
+    let vals: Vec<(String, AttributeValue)> = kinds
+        .iter()
+        .enumerate()
+        .map(|(i, kind)| (format!(":kind{i}"), AttributeValue::N(kind.to_string())))
+        .collect();
+    let labels: Vec<&str> = vals.iter().map(|(label, _)| label.as_str()).collect();
+    (format!("kind IN({})", labels.join(",")), vals)
+}
+
+/// The `limit` most recent events, newest first.
+fn newest(mut evs: Vec<Event>, limit: usize) -> Vec<Event> {
+    evs.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+    evs.truncate(limit);
+    evs
+}
+
+/// Whether `ev` replaces the stored version as it is written; with the
+/// `replaceable` hook disabled, every version is kept like a regular event.
+fn replaced_at_write(ev: &Event, config: &Config) -> bool {
+    ev.is_replaceable() && config.replace_events
+}
+
+/// NIP-01: the newest version wins, and the lowest id among equals.
+fn supersedes(ev: &Event, id: &str, created_at: u64) -> bool {
+    ev.created_at > created_at || (ev.created_at == created_at && ev.id.as_str() < id)
+}
+
+/// `<conn_id>#<sub_id>`, so connections using the same sub_id do not
+/// overwrite or close each other's subscriptions.
+fn subscription_key(conn_id: &str, sub_id: &str) -> String {
+    format!("{conn_id}#{sub_id}")
+}
+
+/// Partition of the subscription index items under the match key `key`,
+/// see [`Filter::match_keys`].
+fn index_key(key: &str) -> String {
+    format!("index#{key}")
+}
+
+/// Match keys of every filter of a subscription, sorted and deduplicated.
+fn subscription_match_keys(filters: &[Filter]) -> Vec<String> {
+    let mut keys: Vec<String> = filters.iter().flat_map(|f| f.match_keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// The filters stored on a subscription or subscription index item.
+fn item_filters(item: &HashMap<String, AttributeValue>) -> Option<Vec<Filter>> {
+    item.get("filters")?
+        .as_l()
+        .ok()?
+        .iter()
+        .map(|f| serde_json::from_str(f.as_s().ok()?).ok())
+        .collect()
+}
+
+/// The sub_id of the subscription item `id` of `conn_id`.
+fn subscription_id<'a>(conn_id: &str, id: &'a str) -> &'a str {
+    id.strip_prefix(conn_id)
+        .and_then(|rest| rest.strip_prefix('#'))
+        .unwrap_or(id)
+}
+
+/// Partition of the moderation table holding the allowlist, one item per
+/// pubkey in `type`.
+const ALLOWLIST_KEY: &str = "allowlist";
+/// Partition of the moderation table holding the denylist, one item per
+/// `pubkey#<pubkey>` or `event#<id>` in `type`.
+const DENYLIST_KEY: &str = "denylist";
+/// Partition of the moderation table holding content filter rules, one item
+/// per `word#<word>`, `pattern#<regex>` or `domain#<domain>` in `type`.
+const CONTENT_RULES_KEY: &str = "contentfilter";
+
+fn replaceable_key(pubkey: &str, kind: u64) -> String {
+    format!("replaceable#{pubkey}#{kind}")
+}
+
+fn relay_list_key(pubkey: &str) -> String {
+    format!("relays#{pubkey}")
+}
+
+/// `_ttl` of the items of `ev`, or -1 when they are kept forever.
+fn event_expiry(ev: &Event, retention: &Retention) -> i64 {
+    let ttl = retention.ttl(ev).map(|t| ev.created_at as i64 + t as i64);
+    // NIP-40: expire with the event when that comes first.
+    match (ttl, ev.expiration().map(|e| e as i64)) {
+        (Some(ttl), Some(expiration)) => ttl.min(expiration),
+        (ttl, expiration) => ttl.or(expiration).unwrap_or(-1),
+    }
+}
+
+/// Attribute name for a tag, only for single-letter (indexable) tag names so
+/// that tags can never collide with reserved attributes.
+fn tag_attribute_name(name: &str) -> Option<String> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Some(format!("tag_{c}")),
+        _ => None,
+    }
+}
+
+fn label_key(namespace: &str, value: &str) -> String {
+    format!("label#{namespace}#{value}")
+}
+
+/// The put requests storing `ev` in the event table.
+///
+/// The first request is the event item itself. When the event has more than
+/// `TAG_INLINE_LIMIT` indexable tags, all its tags are moved in order into
+/// sibling items of type `tags#<n>` and left out of the `json` attribute, so
+/// the event item stays small; reads put them back. The siblings expire a
+/// day after the event item so that the archiver can still read them.
+fn event_write_requests(ev: &Event, retention: &Retention, compress: bool) -> Vec<WriteRequest> {
+    let ttl = event_expiry(ev, retention);
+    let id = &ev.id;
+
+    let mut data = vec![
+        (
+            "pubkey".to_string(),
+            AttributeValue::S(ev.pubkey.to_string()),
+        ),
+        (
+            "created_at".to_string(),
+            AttributeValue::N(ev.created_at.to_string()),
+        ),
+        ("kind".to_string(), AttributeValue::N(ev.kind.to_string())),
+        (
+            "day".to_string(),
+            AttributeValue::N((ev.created_at / DAY).to_string()),
+        ),
+        (
+            "id_prefix".to_string(),
+            AttributeValue::S(id[..MIN_PREFIX_LENGTH].to_string()),
+        ),
+        (
+            "pubkey_prefix".to_string(),
+            AttributeValue::S(ev.pubkey[..MIN_PREFIX_LENGTH].to_string()),
+        ),
+    ];
+    // A plain copy of the content would undo the compression.
+    if !compress {
+        data.push((
+            "content".to_string(),
+            AttributeValue::S(ev.content.to_string()),
+        ));
+    }
+    // NIP-28: puts channel messages on channel-created_at-index.
+    if let Some(channel) = ev.channel_id() {
+        data.push((
+            "channel".to_string(),
+            AttributeValue::S(channel.to_string()),
+        ));
+    }
+    // NIP-59: puts gift wraps on recipient-created_at-index.
+    if let Some(recipient) = ev.recipient() {
+        data.push((
+            "recipient".to_string(),
+            AttributeValue::S(recipient.to_string()),
+        ));
+    }
+
+    // Other tags are only kept in the json attribute.
+    let indexed: Vec<&Vec<String>> = ev
+        .tags
+        .iter()
+        .filter(|tag| tag.first().and_then(|k| tag_attribute_name(k)).is_some())
+        .collect();
+    let chunks: Vec<&[Vec<String>]> = if indexed.len() > TAG_INLINE_LIMIT {
+        ev.tags.chunks(TAG_CHUNK_SIZE).collect()
+    } else {
+        vec![]
+    };
+
+    if chunks.is_empty() {
+        for tag in indexed.iter() {
+            let tag_name = tag_attribute_name(&tag[0]).unwrap();
+            let v = tag[1..]
+                .iter()
+                .map(|v| AttributeValue::S(v.clone()))
+                .collect();
+
+            data.push((tag_name, AttributeValue::L(v)));
+        }
+    } else {
+        data.push((
+            "tag_chunks".to_string(),
+            AttributeValue::N(chunks.len().to_string()),
+        ));
+    }
+
+    let json = if chunks.is_empty() {
+        json_attribute(ev, compress)
+    } else {
+        let untagged = Event {
+            tags: vec![],
+            ..ev.clone()
+        };
+        json_attribute(&untagged, compress)
+    };
+    data.push(("json".to_string(), json));
+
+    let mut wrs = vec![write_request(
+        id,
+        "event",
+        AttributeValue::S("event".to_string()),
+        Some(data),
+        ttl,
+    )];
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let tags = chunk
+            .iter()
+            .map(|tag| {
+                AttributeValue::L(tag.iter().map(|v| AttributeValue::S(v.clone())).collect())
+            })
+            .collect();
+        wrs.push(write_request(
+            id,
+            &format!("tags#{i}"),
+            AttributeValue::S("tags".to_string()),
+            Some(vec![("tags".to_string(), AttributeValue::L(tags))]),
+            if ttl < 0 { ttl } else { ttl + DAY as i64 },
+        ));
+    }
+
+    wrs
+}
+
+/// How many `tags#<n>` items hold the tags of the event `item`, if any.
+fn item_tag_chunks(item: &HashMap<String, AttributeValue>) -> Option<usize> {
+    item.get("tag_chunks")?.as_n().ok()?.parse().ok()
+}
+
+/// The tags in the `tags#<n>` `items` of an event, in order; None unless
+/// all `chunks` of them are there.
+fn chunked_tags(
+    items: &[HashMap<String, AttributeValue>],
+    chunks: usize,
+) -> Option<Vec<Vec<String>>> {
+    let mut parts: Vec<(usize, &Vec<AttributeValue>)> = items
+        .iter()
+        .filter_map(|item| {
+            let n = item.get("type")?.as_s().ok()?.strip_prefix("tags#")?;
+            Some((n.parse().ok()?, item.get("tags")?.as_l().ok()?))
+        })
+        .collect();
+    parts.sort_by_key(|(n, _)| *n);
+    if parts.iter().map(|(n, _)| *n).ne(0..chunks) {
+        return None;
+    }
+    parts
+        .into_iter()
+        .flat_map(|(_, tags)| tags)
+        .map(|tag| {
+            tag.as_l()
+                .ok()?
+                .iter()
+                .map(|v| v.as_s().ok().cloned())
+                .collect()
+        })
+        .collect()
+}
+
+/// The `json` attribute of `ev`: a string, or zstd-compressed binary when
+/// `compress`.
+fn json_attribute(ev: &Event, compress: bool) -> AttributeValue {
+    if compress {
+        AttributeValue::B(Blob::new(ev.to_zstd()))
+    } else {
+        AttributeValue::S(serde_json::to_string(ev).unwrap())
+    }
+}
+
+/// The event in the `json` attribute of `item`, compressed or not.
+fn item_event(item: &HashMap<String, AttributeValue>) -> Result<Option<Event>, String> {
+    match item.get("json") {
+        Some(AttributeValue::S(json)) => serde_json::from_str(json)
+            .map(Some)
+            .map_err(|e| format!("{e:?}")),
+        Some(AttributeValue::B(bytes)) => Event::from_zstd(bytes.as_ref()).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Size of the largest item `ev` is stored in.
+fn largest_item_size(ev: &Event, retention: &Retention, compress: bool) -> usize {
+    event_write_requests(ev, retention, compress)
+        .iter()
+        .filter_map(|wr| wr.put_request()?.item())
+        .map(item_size)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Approximate DynamoDB item size following the documented sizing rules.
+fn item_size(item: &HashMap<String, AttributeValue>) -> usize {
+    item.iter().map(|(k, v)| k.len() + value_size(v)).sum()
+}
+
+fn value_size(v: &AttributeValue) -> usize {
+    match v {
+        AttributeValue::S(s) => s.len(),
+        AttributeValue::N(n) => n.len() / 2 + 1,
+        AttributeValue::B(b) => b.as_ref().len(),
+        AttributeValue::Ss(ss) => ss.iter().map(|s| s.len()).sum(),
+        AttributeValue::Ns(ns) => ns.iter().map(|n| n.len() / 2 + 1).sum(),
+        AttributeValue::L(l) => 3 + l.iter().map(|v| 1 + value_size(v)).sum::<usize>(),
+        AttributeValue::M(m) => {
+            3 + m
+                .iter()
+                .map(|(k, v)| 1 + k.len() + value_size(v))
+                .sum::<usize>()
+        }
+        _ => 1,
+    }
+}
+
+fn write_request(
+    id: &str,
+    item_type: &str,
+    value: AttributeValue,
+    data: Option<Vec<(String, AttributeValue)>>,
+    ttl: i64,
 ) -> WriteRequest {
     let mut map = HashMap::new();
     map.insert("id".to_string(), AttributeValue::S(id.to_string()));
@@ -400,6 +2422,58 @@ fn write_request(
     WriteRequest::builder().put_request(pr).build()
 }
 
+/// Delay before retry `attempt` of a batch call: doubling from 100ms, with
+/// jitter so that throttled writers do not retry in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let max = 50u64 << attempt;
+    Duration::from_millis(rand::thread_rng().gen_range(max / 2..=max))
+}
+
+/// `id/type` of the item a write request puts or deletes.
+fn request_key(wr: &WriteRequest) -> String {
+    let key = wr
+        .put_request()
+        .and_then(|p| p.item())
+        .or_else(|| wr.delete_request().and_then(|d| d.key()));
+    let attr = |name: &str| {
+        key.and_then(|k| k.get(name))
+            .and_then(|v| v.as_s().ok())
+            .map_or("?", |v| v.as_str())
+    };
+    format!("{}/{}", attr("id"), attr("type"))
+}
+
+/// Index item of a subscription under `key`, sorted by the subscription
+/// key. It has no `value` attribute so that it stays out of value-id-index
+/// and the subscription scan.
+fn index_write_request(
+    key: &str,
+    conn_id: &str,
+    sub_id: &str,
+    filters: AttributeValue,
+    ttl: i64,
+) -> WriteRequest {
+    let mut map = HashMap::new();
+    map.insert("id".to_string(), AttributeValue::S(index_key(key)));
+    map.insert(
+        "type".to_string(),
+        AttributeValue::S(subscription_key(conn_id, sub_id)),
+    );
+    map.insert(
+        "conn_id".to_string(),
+        AttributeValue::S(conn_id.to_string()),
+    );
+    map.insert("sub_id".to_string(), AttributeValue::S(sub_id.to_string()));
+    map.insert("filters".to_string(), filters);
+    if ttl >= 0 {
+        map.insert("_ttl".to_string(), AttributeValue::N(ttl.to_string()));
+    }
+
+    let pr = PutRequest::builder().set_item(Some(map)).build();
+
+    WriteRequest::builder().put_request(pr).build()
+}
+
 fn delete_request(id: &str, item_type: &str) -> WriteRequest {
     let mut map = HashMap::new();
     map.insert("id".to_string(), AttributeValue::S(id.to_string()));
@@ -410,89 +2484,269 @@ fn delete_request(id: &str, item_type: &str) -> WriteRequest {
     WriteRequest::builder().delete_request(dr).build()
 }
 
-pub struct QueryByIds<'a> {
-    filter: &'a Filter,
-    ids: Vec<String>,
-}
+#[cfg(test)]
+mod tests {
+    use super::{
+        backoff, chunked_tags, delete_request, event_write_requests, index_write_request,
+        item_event, item_filters, item_size, item_tag_chunks, largest_item_size, newest,
+        replaced_at_write, request_key, subscription_id, subscription_key, subscription_match_keys,
+        supersedes, tag_attribute_name, BatchWriteError, MAX_ITEM_SIZE,
+    };
+    use crate::config::Config;
+    use crate::message::{test_event, Event, Filter};
+    use crate::policy::Retention;
+    use crate::types::{EventId, Pubkey, Signature};
+    use aws_sdk_dynamodb::model::AttributeValue;
+    use std::collections::HashMap;
 
-impl<'a> QueryByIds<'a> {
-    pub fn new(filter: &'a Filter, ids: Vec<String>) -> QueryByIds<'a> {
-        QueryByIds { filter, ids }
+    fn retention(ttl: u64) -> Retention {
+        Retention::parse("", ttl).unwrap()
     }
 
-    pub async fn exec(&self) -> Result<Vec<Event>, String> {
-        let ddb = Ddb::new().await;
-        let ret = ddb.get_event_by_ids(&self.ids).await;
+    #[test]
+    fn tag_attribute_name01() {
+        assert_eq!(Some("tag_e".to_string()), tag_attribute_name("e"));
+        assert_eq!(Some("tag_P".to_string()), tag_attribute_name("P"));
+        assert_eq!(None, tag_attribute_name(""));
+        assert_eq!(None, tag_attribute_name("id"));
+        assert_eq!(None, tag_attribute_name("json"));
+        assert_eq!(None, tag_attribute_name("1"));
+        assert_eq!(None, tag_attribute_name("é"));
+    }
 
-        filter_match(self.filter, &ret)
+    #[test]
+    fn item_size01() {
+        let item = HashMap::from([
+            ("id".to_string(), AttributeValue::S("abc".to_string())),
+            ("kind".to_string(), AttributeValue::N("1".to_string())),
+            (
+                "tag_e".to_string(),
+                AttributeValue::L(vec![AttributeValue::S("x".to_string())]),
+            ),
+        ]);
+        assert_eq!((2 + 3) + (4 + 1) + (5 + 3 + 2), item_size(&item));
     }
-}
 
-fn filter_match(filter: &Filter, evs: &Result<Vec<Event>, String>) -> Result<Vec<Event>, String> {
-    match evs {
-        Ok(ret) => {
-            let vmatch = ret
-                .iter()
-                .filter_map(|e| {
-                    if filter.event_match(e) {
-                        Some(e.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-            Ok(vmatch)
-        }
-        Err(e) => Err(e.to_string()),
+    #[test]
+    fn event_write_requests_follow_list() {
+        let ev = Event {
+            kind: 3,
+            tags: (0..5000)
+                .map(|i| vec!["p".to_string(), Pubkey::padded(&format!("{i}")).to_string()])
+                .chain([vec!["t".to_string(), "nostr".to_string()]])
+                .collect(),
+            ..test_event()
+        };
+        assert!(largest_item_size(&ev, &retention(86400), false) < MAX_ITEM_SIZE);
+
+        let wrs = event_write_requests(&ev, &retention(86400), false);
+        let items: Vec<HashMap<String, AttributeValue>> = wrs
+            .iter()
+            .map(|wr| wr.put_request().unwrap().item().unwrap().clone())
+            .collect();
+        let chunks = item_tag_chunks(&items[0]).unwrap();
+        assert_eq!(11, chunks);
+        let mut read = item_event(&items[0]).unwrap().unwrap();
+        read.tags = chunked_tags(&items[1..], chunks).unwrap();
+        assert_eq!(ev, read);
+        assert_eq!(None, chunked_tags(&items[2..], chunks));
     }
-}
 
-pub struct QueryByPubkeys<'a> {
-    filter: &'a Filter,
-    authors: Vec<String>,
-    kinds: Option<Vec<u64>>,
-    since: Option<u64>,
-    until: Option<u64>,
-    limit: Option<i32>,
-}
+    #[test]
+    fn event_write_requests_overflow() {
+        let ev = Event {
+            kind: 3,
+            tags: (0..1200)
+                .map(|i| vec!["p".to_string(), format!("pub{i}")])
+                .collect(),
+            ..test_event()
+        };
 
-impl<'a> QueryByPubkeys<'a> {
-    pub fn new(
-        filter: &'a Filter,
-        authors: Vec<String>,
-        kinds: Option<Vec<u64>>,
-        since: Option<u64>,
-        until: Option<u64>,
-        limit: Option<i32>,
-    ) -> QueryByPubkeys {
-        QueryByPubkeys {
-            filter,
-            authors,
-            kinds,
-            since,
-            until,
-            limit,
-        }
+        let wrs = event_write_requests(&ev, &retention(86400), false);
+        assert_eq!(4, wrs.len());
+        let item = wrs[0].put_request().unwrap().item().unwrap();
+        assert!(!item.contains_key("tag_p"));
+        assert_eq!("3", item["tag_chunks"].as_n().unwrap());
+        assert!(item_event(item).unwrap().unwrap().tags.is_empty());
+        assert_eq!("1676205268", item["_ttl"].as_n().unwrap());
+        // Only the event item goes on kind-created_at-index and
+        // day-created_at-index.
+        assert_eq!("3", item["kind"].as_n().unwrap());
+        assert_eq!("19399", item["day"].as_n().unwrap());
+        assert_eq!("1d01", item["id_prefix"].as_s().unwrap());
+        assert_eq!("b010", item["pubkey_prefix"].as_s().unwrap());
+        let item = wrs[3].put_request().unwrap().item().unwrap();
+        assert_eq!("tags#2", item["type"].as_s().unwrap());
+        assert!(!item.contains_key("kind") && !item.contains_key("day"));
+        assert_eq!(200, item["tags"].as_l().unwrap().len());
+        assert_eq!("1676291668", item["_ttl"].as_n().unwrap());
+
+        let ev = Event {
+            tags: vec![vec!["p".to_string(), "pub0".to_string()]],
+            ..ev
+        };
+        let wrs = event_write_requests(&ev, &retention(86400), false);
+        assert_eq!(1, wrs.len());
+        let item = wrs[0].put_request().unwrap().item().unwrap();
+        assert!(item.contains_key("tag_p"));
+        assert_eq!("1676205268", item["_ttl"].as_n().unwrap());
+        let wrs = event_write_requests(&ev, &retention(86400), true);
+        let item = wrs[0].put_request().unwrap().item().unwrap();
+        assert!(item["json"].is_b() && !item.contains_key("content"));
+        assert_eq!(Some(ev.clone()), item_event(item).unwrap());
+        let forever = Retention::parse("3=forever", 86400).unwrap();
+        let wrs = event_write_requests(&ev, &forever, false);
+        assert!(!wrs[0]
+            .put_request()
+            .unwrap()
+            .item()
+            .unwrap()
+            .contains_key("_ttl"));
+
+        let ev = Event {
+            tags: vec![vec!["expiration".to_string(), "1676120000".to_string()]],
+            ..ev
+        };
+        let wrs = event_write_requests(&ev, &retention(86400), false);
+        let item = wrs[0].put_request().unwrap().item().unwrap();
+        assert_eq!("1676120000", item["_ttl"].as_n().unwrap());
+        assert!(!item.contains_key("channel"));
+
+        let channel = EventId::padded("c01");
+        let ev = Event {
+            kind: 42,
+            tags: vec![vec![
+                "e".to_string(),
+                channel.to_string(),
+                "".to_string(),
+                "root".to_string(),
+            ]],
+            ..ev
+        };
+        let wrs = event_write_requests(&ev, &retention(86400), false);
+        let item = wrs[0].put_request().unwrap().item().unwrap();
+        assert_eq!(channel, *item["channel"].as_s().unwrap());
     }
 
-    pub async fn exec(&self) -> Result<Vec<Event>, String> {
-        let ddb = Ddb::new().await;
-        let ret = ddb
-            .get_event_by_pubkeys(
-                &self.authors,
-                self.kinds.clone(),
-                self.since,
-                self.until,
-                self.limit,
-            )
-            .await;
+    #[test]
+    fn newest01() {
+        let ev = |pubkey: &str, created_at: u64| Event {
+            id: EventId::padded(&format!("{pubkey}{created_at}")),
+            pubkey: Pubkey::padded(pubkey),
+            created_at,
+            kind: 1,
+            tags: vec![],
+            content: "".into(),
+            sig: Signature::padded(""),
+        };
+        let evs = vec![ev("a", 1), ev("a", 2), ev("a", 3), ev("b", 5), ev("b", 4)];
+
+        let ids: Vec<String> = newest(evs, 3)
+            .into_iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        assert_eq!(vec!["b5", "b4", "a3"], ids);
+    }
 
-        filter_match(self.filter, &ret)
+    #[test]
+    fn subscription_key01() {
+        let key = subscription_key("conn01=", "sub");
+        assert_eq!("conn01=#sub", key);
+        assert_eq!("sub", subscription_id("conn01=", &key));
+        assert_eq!("conn01=#sub", subscription_id("conn02=", &key));
+        // Items written before the key included the connection.
+        assert_eq!("sub", subscription_id("conn01=", "sub"));
+    }
+
+    #[test]
+    fn subscription_index01() {
+        let filters: Vec<Filter> = vec![
+            serde_json::from_str(r#"{"kinds":[1,7]}"#).unwrap(),
+            serde_json::from_str(r#"{"kinds":[1],"since":1676118868}"#).unwrap(),
+        ];
+        let keys = subscription_match_keys(&filters);
+        assert_eq!(vec!["kind:1".to_string(), "kind:7".to_string()], keys);
+
+        let fs = filters
+            .iter()
+            .map(|f| AttributeValue::S(serde_json::to_string(f).unwrap()))
+            .collect();
+        let wr = index_write_request(&keys[0], "conn01=", "sub", AttributeValue::L(fs), 10);
+        let item = wr.put_request().unwrap().item().unwrap();
+        assert_eq!("index#kind:1", item["id"].as_s().unwrap());
+        assert_eq!("conn01=#sub", item["type"].as_s().unwrap());
+        assert!(!item.contains_key("value"));
+        assert_eq!(Some(filters), item_filters(item));
+    }
+
+    #[test]
+    fn supersedes01() {
+        let ev = Event {
+            id: EventId::padded("b"),
+            pubkey: Pubkey::padded("a"),
+            created_at: 10,
+            kind: 0,
+            tags: vec![],
+            content: "".into(),
+            sig: Signature::padded(""),
+        };
+        let a = EventId::padded("a").to_string();
+        let c = EventId::padded("c").to_string();
+        assert!(supersedes(&ev, &a, 9));
+        assert!(supersedes(&ev, &c, 10));
+        assert!(!supersedes(&ev, &a, 10));
+        assert!(!supersedes(&ev, &ev.id, 10));
+        assert!(!supersedes(&ev, &c, 11));
+    }
+
+    #[test]
+    fn replaced_at_write01() {
+        let mut vars = HashMap::from([
+            ("NOSTR_EVENT_TABLE", "events"),
+            ("NOSTR_SUBSCRIPTION_TABLE", "subscriptions"),
+            ("NOSTR_EVENT_TTL", "86400"),
+            ("NOSTR_SUBSCRIPTION_TTL", "3600"),
+        ]);
+        let load = |vars: &HashMap<&str, &str>| {
+            Config::from_lookup(|name| vars.get(name).map(|v| v.to_string())).unwrap()
+        };
+        let mut ev = Event {
+            kind: 0,
+            ..test_event()
+        };
+        assert!(replaced_at_write(&ev, &load(&vars)));
+        ev.kind = 1;
+        assert!(!replaced_at_write(&ev, &load(&vars)));
+
+        // An archival relay keeps every version through the plain batch write.
+        ev.kind = 0;
+        vars.insert("NOSTR_DISABLED_HOOKS", "nip9, replaceable");
+        assert!(!replaced_at_write(&ev, &load(&vars)));
+    }
+
+    #[test]
+    fn backoff01() {
+        for attempt in 1..4 {
+            let ms = backoff(attempt).as_millis() as u64;
+            assert!((25 << attempt..=50 << attempt).contains(&ms));
+        }
+        let e = BatchWriteError::Unprocessed {
+            written: 1,
+            failed: vec!["1d01/event".into()],
+        };
+        assert_eq!("unprocessed items: 1d01/event", String::from(e));
     }
-}
 
-pub enum QueryPlan<'a> {
-    ByIds(QueryByIds<'a>),
-    ByPubkeys(QueryByPubkeys<'a>),
-    NoPlan(String),
+    #[test]
+    fn request_key01() {
+        let ev = test_event();
+        assert_eq!(
+            format!("{}/event", ev.id),
+            request_key(&event_write_requests(&ev, &retention(86400), false)[0])
+        );
+        assert_eq!(
+            "sub01/conn_id",
+            request_key(&delete_request("sub01", "conn_id"))
+        );
+    }
 }
@@ -0,0 +1,95 @@
+use crate::message::Event;
+use crate::reject::RejectReason;
+use crate::store::EventStore;
+use std::collections::HashSet;
+
+/// Banned pubkeys and event ids kept by the store: their events are refused
+/// on write and left out of REQ results.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Denylist {
+    pubkeys: HashSet<String>,
+    event_ids: HashSet<String>,
+}
+
+impl Denylist {
+    pub fn new<P, E>(pubkeys: P, event_ids: E) -> Denylist
+    where
+        P: IntoIterator<Item = String>,
+        E: IntoIterator<Item = String>,
+    {
+        Denylist {
+            pubkeys: pubkeys.into_iter().collect(),
+            event_ids: event_ids.into_iter().collect(),
+        }
+    }
+
+    /// The store's denylist; a store that cannot be read bans nothing.
+    pub async fn load(store: &dyn EventStore) -> Denylist {
+        store.get_denylist().await.unwrap_or_else(|e| {
+            println!("denylist err: {e}");
+            Denylist::default()
+        })
+    }
+
+    pub fn pubkeys(&self) -> impl Iterator<Item = &String> {
+        self.pubkeys.iter()
+    }
+
+    pub fn event_ids(&self) -> impl Iterator<Item = &String> {
+        self.event_ids.iter()
+    }
+
+    pub fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        if self.pubkeys.contains(ev.pubkey.as_str()) {
+            return Err(RejectReason::Blocked("pubkey is banned".to_string()));
+        }
+        if self.event_ids.contains(ev.id.as_str()) {
+            return Err(RejectReason::Blocked("event is banned".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn allows(&self, ev: &Event) -> bool {
+        self.check_event(ev).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Denylist;
+    use crate::memory::MemoryStore;
+    use crate::message::Event;
+    use crate::store::EventStore;
+    use crate::types::{EventId, Pubkey, Signature};
+
+    fn build_event(id: &str, pubkey: &str) -> Event {
+        Event {
+            id: EventId::padded(id),
+            pubkey: Pubkey::padded(pubkey),
+            created_at: 1676118868,
+            kind: 1,
+            tags: vec![],
+            content: "".into(),
+            sig: Signature::padded(""),
+        }
+    }
+
+    #[tokio::test]
+    async fn load01() {
+        let store = MemoryStore::new();
+        let ev01 = build_event("1d01", "b01");
+        let ev02 = build_event("1d02", "b02");
+        let ev03 = build_event("1d03", "b03");
+        store.add_ban(ev01.pubkey.as_str()).await.unwrap();
+        store.add_banned_event(ev02.id.as_str()).await.unwrap();
+
+        let denylist = Denylist::load(&store).await;
+        assert!(denylist
+            .check_event(&ev01)
+            .is_err_and(|r| r.to_string() == "blocked: pubkey is banned"));
+        assert!(denylist
+            .check_event(&ev02)
+            .is_err_and(|r| r.to_string() == "blocked: event is banned"));
+        assert!(denylist.allows(&ev03));
+    }
+}
@@ -0,0 +1,104 @@
+//! Publishing accepted events to an EventBridge bus, so that operators can
+//! route them with rules (e.g. kind 1984 reports to a moderation workflow,
+//! kind 9735 zap receipts to accounting) without changing the relay.
+use crate::config::sdk_config;
+use crate::message::Event;
+use aws_sdk_eventbridge::model::PutEventsRequestEntry;
+use aws_sdk_eventbridge::Client;
+
+/// EventBridge refuses entries larger than 256KB.
+const MAX_ENTRY_SIZE: usize = 256 * 1024;
+
+/// The bus of the process environment, built on first use so that warm
+/// invocations reuse the client.
+static SHARED: tokio::sync::OnceCell<Option<EventBus>> = tokio::sync::OnceCell::const_new();
+
+pub struct EventBus {
+    client: Client,
+    bus: String,
+    source: String,
+}
+
+impl EventBus {
+    /// The bus `from_env` reads, built once per process.
+    pub async fn shared() -> Option<&'static EventBus> {
+        SHARED.get_or_init(EventBus::from_env).await.as_ref()
+    }
+
+    /// Reads `NOSTR_EVENTBRIDGE_BUS` (a bus name or ARN) and
+    /// `NOSTR_EVENTBRIDGE_SOURCE`; None when no bus is set.
+    async fn from_env() -> Option<EventBus> {
+        let bus = std::env::var("NOSTR_EVENTBRIDGE_BUS")
+            .ok()
+            .filter(|bus| !bus.is_empty())?;
+        let source =
+            std::env::var("NOSTR_EVENTBRIDGE_SOURCE").unwrap_or_else(|_| "nostr.relay".into());
+        Some(EventBus {
+            client: Client::new(sdk_config().await),
+            bus,
+            source,
+        })
+    }
+
+    pub async fn publish(&self, ev: &Event) -> Result<(), String> {
+        let entry = entry(&self.bus, &self.source, ev);
+        let size = entry.detail().unwrap_or_default().len();
+        if size > MAX_ENTRY_SIZE {
+            return Err(format!("{}: {size} byte event not published", ev.id));
+        }
+        let ret = self
+            .client
+            .put_events()
+            .entries(entry)
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        if ret.failed_entry_count() > 0 {
+            let failed = ret.entries().unwrap_or_default().first();
+            return Err(format!(
+                "{}: {} {}",
+                ev.id,
+                failed.and_then(|e| e.error_code()).unwrap_or_default(),
+                failed.and_then(|e| e.error_message()).unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `nostr.kind.<kind>`, so that rules can select events by kind without
+/// looking into the detail.
+pub fn detail_type(kind: u64) -> String {
+    format!("nostr.kind.{kind}")
+}
+
+/// The entry of `ev`; the detail is the event JSON.
+fn entry(bus: &str, source: &str, ev: &Event) -> PutEventsRequestEntry {
+    PutEventsRequestEntry::builder()
+        .event_bus_name(bus)
+        .source(source)
+        .detail_type(detail_type(ev.kind))
+        .detail(serde_json::to_string(ev).unwrap())
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::entry;
+    use crate::message::{test_event, Event};
+
+    #[test]
+    fn entry01() {
+        let ev = Event {
+            kind: 1984,
+            tags: vec![vec!["p".into(), "b02".into(), "spam".into()]],
+            ..test_event()
+        };
+        let entry = entry("nostr", "nostr.relay", &ev);
+        assert_eq!(Some("nostr"), entry.event_bus_name());
+        assert_eq!(Some("nostr.relay"), entry.source());
+        assert_eq!(Some("nostr.kind.1984"), entry.detail_type());
+        let detail: Event = serde_json::from_str(entry.detail().unwrap()).unwrap();
+        assert_eq!(ev, detail);
+    }
+}
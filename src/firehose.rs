@@ -0,0 +1,120 @@
+//! A firehose of accepted events on an SNS topic or an SQS queue, for
+//! operators who only need a feed of what the relay takes. Each message is
+//! the event JSON with its kind as the `kind` attribute, so that SNS
+//! subscription filter policies can select kinds.
+use crate::config::sdk_config;
+use crate::message::Event;
+
+/// SNS and SQS refuse messages larger than 256KB.
+const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// The topic and the queue of the process environment, built on first use
+/// so that warm invocations reuse the clients.
+static TOPIC: tokio::sync::OnceCell<Option<Topic>> = tokio::sync::OnceCell::const_new();
+static QUEUE: tokio::sync::OnceCell<Option<Queue>> = tokio::sync::OnceCell::const_new();
+
+pub struct Topic {
+    client: aws_sdk_sns::Client,
+    arn: String,
+}
+
+impl Topic {
+    /// The topic `from_env` reads, built once per process.
+    pub async fn shared() -> Option<&'static Topic> {
+        TOPIC.get_or_init(Topic::from_env).await.as_ref()
+    }
+
+    /// Reads `NOSTR_FIREHOSE_TOPIC_ARN`; None when it is not set.
+    async fn from_env() -> Option<Topic> {
+        let arn = std::env::var("NOSTR_FIREHOSE_TOPIC_ARN")
+            .ok()
+            .filter(|arn| !arn.is_empty())?;
+        let client = aws_sdk_sns::Client::new(sdk_config().await);
+        Some(Topic { client, arn })
+    }
+
+    pub async fn publish(&self, ev: &Event) -> Result<(), String> {
+        let kind = aws_sdk_sns::model::MessageAttributeValue::builder()
+            .data_type("Number")
+            .string_value(ev.kind.to_string())
+            .build();
+        self.client
+            .publish()
+            .topic_arn(&self.arn)
+            .message(message(ev)?)
+            .message_attributes("kind", kind)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+pub struct Queue {
+    client: aws_sdk_sqs::Client,
+    url: String,
+}
+
+impl Queue {
+    /// The queue `from_env` reads, built once per process.
+    pub async fn shared() -> Option<&'static Queue> {
+        QUEUE.get_or_init(Queue::from_env).await.as_ref()
+    }
+
+    /// Reads `NOSTR_FIREHOSE_QUEUE_URL`; None when it is not set.
+    async fn from_env() -> Option<Queue> {
+        let url = std::env::var("NOSTR_FIREHOSE_QUEUE_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let client = aws_sdk_sqs::Client::new(sdk_config().await);
+        Some(Queue { client, url })
+    }
+
+    pub async fn publish(&self, ev: &Event) -> Result<(), String> {
+        let kind = aws_sdk_sqs::model::MessageAttributeValue::builder()
+            .data_type("Number")
+            .string_value(ev.kind.to_string())
+            .build();
+        self.client
+            .send_message()
+            .queue_url(&self.url)
+            .message_body(message(ev)?)
+            .message_attributes("kind", kind)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+/// The event JSON, unless it is too large to publish.
+fn message(ev: &Event) -> Result<String, String> {
+    let body = serde_json::to_string(ev).unwrap();
+    if body.len() > MAX_MESSAGE_SIZE {
+        return Err(format!(
+            "{}: {} byte event not published",
+            ev.id,
+            body.len()
+        ));
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{message, MAX_MESSAGE_SIZE};
+    use crate::message::{test_event, Event};
+
+    #[test]
+    fn message01() {
+        let mut ev = Event {
+            content: "gm".into(),
+            ..test_event()
+        };
+        let body: Event = serde_json::from_str(&message(&ev).unwrap()).unwrap();
+        assert_eq!(ev, body);
+
+        ev.content = "a".repeat(MAX_MESSAGE_SIZE);
+        assert!(message(&ev).is_err_and(|e| e.ends_with("byte event not published")));
+    }
+}
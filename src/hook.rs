@@ -1,105 +1,338 @@
-use crate::ddb::Ddb;
-use crate::message::Event;
+use crate::config::Config;
+use crate::content::ContentFilter;
+use crate::label;
+use crate::message::{Event, MessageContext, KIND_DELETION};
+use crate::policy::{env_list, RelayPolicy};
+use crate::reject::RejectReason;
+use crate::report::{Report, KIND_REPORT};
+use crate::store::{newest_version, DryRunStore, EventStore};
+use crate::transport::Transport;
+use crate::types::EventId;
 use async_trait::async_trait;
-use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use std::collections::HashSet;
+use std::time::SystemTime;
 
-pub static HOOKS: Lazy<Hooks> = Lazy::new(Hooks::new);
+static HOOKS: OnceCell<Hooks> = OnceCell::new();
+
+/// What hooks get besides the event: the store to use and, when a client
+/// sent the event, its message context and the transport to reply with.
+#[derive(Clone, Copy)]
+pub struct HookContext<'a> {
+    pub store: &'a dyn EventStore,
+    pub msg: Option<&'a MessageContext>,
+    pub api: Option<&'a dyn Transport>,
+    /// The process configuration, once `Config::init` has loaded it.
+    pub config: Option<&'static Config>,
+}
+
+impl<'a> HookContext<'a> {
+    /// A context without a client, e.g. to replay hooks over stored events.
+    pub fn new(store: &'a dyn EventStore) -> HookContext<'a> {
+        HookContext {
+            store,
+            msg: None,
+            api: None,
+            config: Config::loaded(),
+        }
+    }
+
+    /// The context of an event sent in `msg`, replied to through `api`.
+    pub fn with_message(self, msg: &'a MessageContext, api: &'a dyn Transport) -> HookContext<'a> {
+        HookContext {
+            msg: Some(msg),
+            api: Some(api),
+            ..self
+        }
+    }
+
+    fn with_store(self, store: &'a dyn EventStore) -> HookContext<'a> {
+        HookContext { store, ..self }
+    }
+
+    /// Sends a NOTICE to the client that sent the event; false when there
+    /// is none or it is gone.
+    pub async fn notice(&self, notice: &str) -> bool {
+        match (self.msg, self.api) {
+            (Some(msg), Some(api)) => api.send_notice(&msg.connection_id, notice).await,
+            _ => false,
+        }
+    }
+}
 
 #[async_trait]
 pub trait Hook: Sync {
-    async fn pre_event_write_hook(&self, _ev: &Event) {}
-    async fn post_event_write_hook(&self, _ev: &Event) {}
+    /// Name used to select the hook in `NOSTR_HOOK_DRY_RUN`.
+    fn name(&self) -> &'static str;
+
+    /// Kinds the hook acts on; None for any kind.
+    fn kinds(&self) -> Option<&'static [u64]> {
+        None
+    }
+
+    /// NIPs the relay supports only through this hook, left out of NIP-11
+    /// `supported_nips` when it is disabled.
+    fn nips(&self) -> &'static [u64] {
+        &[]
+    }
+
+    /// Runs before `ev` is written; an error vetoes the write and its
+    /// reason is sent in the OK.
+    async fn pre_event_write_hook(
+        &self,
+        _ctx: &HookContext<'_>,
+        _ev: &Event,
+    ) -> Result<(), RejectReason> {
+        Ok(())
+    }
+
+    async fn post_event_write_hook(&self, _ctx: &HookContext<'_>, _ev: &Event) {}
 }
 
 pub struct Hooks {
     hooks: Vec<Box<dyn Hook + Sync + Send>>,
+    dry_run: HashSet<String>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks::new()
+    }
 }
 
 impl Hooks {
+    /// The relay's own hooks but those listed in `NOSTR_DISABLED_HOOKS`;
+    /// those listed in `NOSTR_HOOK_DRY_RUN` (or "all") only log their
+    /// changes.
     pub fn new() -> Hooks {
-        let hooks: Vec<Box<dyn Hook + Sync + Send>> = vec![
-            Box::new(HookNIP2 {}),
+        Hooks::builder()
+            .with_defaults()
+            .disable(env_list("NOSTR_DISABLED_HOOKS"))
+            .dry_run(env_list("NOSTR_HOOK_DRY_RUN"))
+            .build()
+    }
+
+    pub fn builder() -> HooksBuilder {
+        HooksBuilder::default()
+    }
+
+    /// Makes these the hooks the relay runs. Call it before the first
+    /// event is processed; later calls fail.
+    pub fn install(self) -> Result<(), String> {
+        HOOKS
+            .set(self)
+            .map_err(|_| "hooks are already installed".to_string())
+    }
+
+    /// The installed hooks, `Hooks::new()` when none were installed.
+    pub fn global() -> &'static Hooks {
+        HOOKS.get_or_init(Hooks::new)
+    }
+
+    /// Only the hooks in `names`, e.g. to replay them over stored events.
+    pub fn select(names: &HashSet<String>, dry_run: bool) -> Result<Hooks, String> {
+        Hooks::check_names(names)?;
+        let hooks = Hooks::all()
+            .into_iter()
+            .filter(|h| names.contains(h.name()))
+            .collect();
+        let dry_run = if dry_run {
+            HashSet::from(["all".to_string()])
+        } else {
+            HashSet::new()
+        };
+        Ok(Hooks { hooks, dry_run })
+    }
+
+    fn all() -> Vec<Box<dyn Hook + Sync + Send>> {
+        vec![
             Box::new(HookNIP9 {}),
-            Box::new(HookNIP16 {}),
-        ];
-        Hooks { hooks }
+            Box::new(HookReplaceable {}),
+            Box::new(HookNIP32 {}),
+            Box::new(HookNIP33 {}),
+            Box::new(HookNIP40 {}),
+            Box::new(HookNIP56 {}),
+            Box::new(HookContentFilter {}),
+            #[cfg(feature = "invoke")]
+            Box::new(HookInvoke {}),
+            #[cfg(feature = "lang")]
+            Box::new(HookLanguage {}),
+            #[cfg(feature = "eventbridge")]
+            Box::new(HookEventBridge {}),
+            #[cfg(feature = "firehose")]
+            Box::new(HookSns {}),
+            #[cfg(feature = "firehose")]
+            Box::new(HookSqs {}),
+            #[cfg(feature = "webhook")]
+            Box::new(HookWebhook {}),
+        ]
+    }
+
+    /// Fails on the first of `names` that is not one of the relay's own
+    /// hooks.
+    pub fn check_names(names: &HashSet<String>) -> Result<(), String> {
+        let hooks = Hooks::all();
+        match names
+            .iter()
+            .find(|n| !hooks.iter().any(|h| h.name() == n.as_str()))
+        {
+            Some(unknown) => Err(format!("unknown hook: {unknown}")),
+            None => Ok(()),
+        }
+    }
+
+    /// NIPs the relay no longer supports once the hooks in `disabled` are
+    /// left out.
+    pub fn disabled_nips(disabled: &HashSet<String>) -> HashSet<u64> {
+        Hooks::all()
+            .iter()
+            .filter(|h| disabled.contains(h.name()))
+            .flat_map(|h| h.nips().iter().copied())
+            .collect()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.hooks.iter().map(|h| h.name()).collect()
+    }
+
+    fn is_dry_run(&self, hook: &dyn Hook) -> bool {
+        self.dry_run.contains("all") || self.dry_run.contains(hook.name())
     }
 
-    pub async fn pre_event_write_hook(&self, ev: &Event) {
+    /// Runs the pre-write hooks in order and stops at the first veto. A
+    /// hook in dry-run writes nothing and only logs its veto.
+    pub async fn pre_event_write_hook(
+        &self,
+        ctx: &HookContext<'_>,
+        ev: &Event,
+    ) -> Result<(), RejectReason> {
         for hook in self.hooks.iter() {
-            hook.pre_event_write_hook(ev).await;
+            if !self.is_dry_run(hook.as_ref()) {
+                hook.pre_event_write_hook(ctx, ev).await?;
+                continue;
+            }
+            let dry = DryRunStore::new(ctx.store, hook.name());
+            let ctx = ctx.with_store(&dry);
+            if let Err(reason) = hook.pre_event_write_hook(&ctx, ev).await {
+                println!("dry-run {}: would refuse {}: {reason}", hook.name(), ev.id);
+            }
         }
+        Ok(())
     }
 
-    pub async fn post_event_write_hook(&self, ev: &Event) {
+    pub async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
         for hook in self.hooks.iter() {
-            hook.post_event_write_hook(ev).await;
+            if self.is_dry_run(hook.as_ref()) {
+                let dry = DryRunStore::new(ctx.store, hook.name());
+                hook.post_event_write_hook(&ctx.with_store(&dry), ev).await;
+            } else {
+                hook.post_event_write_hook(ctx, ev).await;
+            }
         }
     }
 }
 
-struct HookNIP2 {}
+/// Assembles a hook pipeline for `Hooks::install`; hooks run in the order
+/// they were added.
+#[derive(Default)]
+pub struct HooksBuilder {
+    hooks: Vec<Box<dyn Hook + Sync + Send>>,
+    disabled: HashSet<String>,
+    dry_run: HashSet<String>,
+}
 
-#[async_trait]
-impl Hook for HookNIP2 {
-    async fn pre_event_write_hook(&self, ev: &Event) {
-        let target_kinds = [3];
+impl HooksBuilder {
+    pub fn with(mut self, hook: impl Hook + Send + 'static) -> HooksBuilder {
+        self.hooks.push(Box::new(hook));
+        self
+    }
 
-        if !target_kinds.contains(&ev.kind) {
-            return;
-        }
-        println!("nip2 pre_event_write_hook");
-        let ddb = Ddb::new().await;
-        let pubkey = &ev.pubkey;
+    /// Adds the relay's own hooks.
+    pub fn with_defaults(mut self) -> HooksBuilder {
+        self.hooks.extend(Hooks::all());
+        self
+    }
 
-        if let Ok(evs) = ddb
-            .get_event_by_pubkeys(
-                [pubkey.to_string()].as_ref(),
-                Some([3].to_vec()),
-                None,
-                None,
-                None,
-            )
-            .await
-        {
-            let ids: Vec<String> = evs.iter().map(|ev| ev.id.to_string()).collect();
-            if ids.is_empty() {
-                return;
-            }
-            match ddb.delete_event_by_ids(ids).await {
-                Ok(_) => (),
-                Err(e) => println!("Hook_nip3 err:{e:?}"),
-            }
-        };
+    /// Leaves out the hooks in `names`, e.g. NIP-9 deletions on an archival
+    /// relay.
+    pub fn disable(mut self, names: HashSet<String>) -> HooksBuilder {
+        self.disabled = names;
+        self
+    }
+
+    /// Hooks in `names` (or all with "all") only log their changes.
+    pub fn dry_run(mut self, names: HashSet<String>) -> HooksBuilder {
+        self.dry_run = names;
+        self
+    }
+
+    /// Leaves out the disabled hooks and those for kinds the relay refuses,
+    /// which would never run.
+    pub fn build(self) -> Hooks {
+        let kinds = &RelayPolicy::current().kinds;
+        let hooks = self
+            .hooks
+            .into_iter()
+            .filter(|h| {
+                if self.disabled.contains(h.name()) {
+                    println!("hook {}: disabled", h.name());
+                    return false;
+                }
+                let used = h
+                    .kinds()
+                    .is_none_or(|ks| ks.iter().any(|k| kinds.accepts(*k)));
+                if !used {
+                    println!("hook {}: disabled, its kinds are not accepted", h.name());
+                }
+                used
+            })
+            .collect();
+        Hooks {
+            hooks,
+            dry_run: self.dry_run,
+        }
     }
 }
 
-struct HookNIP9 {}
+pub struct HookNIP9 {}
 #[async_trait]
 impl Hook for HookNIP9 {
-    async fn post_event_write_hook(&self, ev: &Event) {
-        let target_kinds = [5];
+    fn name(&self) -> &'static str {
+        "nip9"
+    }
+
+    fn kinds(&self) -> Option<&'static [u64]> {
+        Some(&[KIND_DELETION])
+    }
+
+    fn nips(&self) -> &'static [u64] {
+        &[9]
+    }
+
+    async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
+        let target_kinds = [KIND_DELETION];
 
         if !target_kinds.contains(&ev.kind) {
             return;
         }
         println!("nip9 post_event_write_hook");
-        let ddb = Ddb::new().await;
         let pubkey = &ev.pubkey;
         let mut ids = vec![];
 
         for tag in ev.tags.iter() {
             if tag.len() >= 2 && tag[0] == "e" {
-                ids.push(tag[1].clone())
+                if let Ok(id) = tag[1].parse::<EventId>() {
+                    ids.push(id)
+                }
             }
         }
 
-        if let Ok(evs) = ddb.get_event_by_ids(&ids).await {
-            let ids: Vec<String> = evs
+        if let Ok(evs) = ctx.store.get_event_by_ids(&ids).await {
+            let ids: Vec<EventId> = evs
                 .iter()
                 .filter_map(|ev| {
                     if ev.pubkey == *pubkey {
-                        Some(ev.id.to_string())
+                        Some(ev.id.clone())
                     } else {
                         None
                     }
@@ -108,7 +341,7 @@ impl Hook for HookNIP9 {
             if ids.is_empty() {
                 return;
             }
-            match ddb.delete_event_by_ids(ids).await {
+            match ctx.store.delete_event_by_ids(ids).await {
                 Ok(_) => (),
                 Err(e) => println!("Hook_nip9 err:{e:?}"),
             }
@@ -116,34 +349,464 @@ impl Hook for HookNIP9 {
     }
 }
 
-struct HookNIP16 {}
+pub struct HookReplaceable {}
 #[async_trait]
-impl Hook for HookNIP16 {
-    /// NIP-16 Replaceable Events
-    async fn post_event_write_hook(&self, ev: &Event) {
-        if !(10000 <= ev.kind && ev.kind < 20000) {
+impl Hook for HookReplaceable {
+    fn name(&self) -> &'static str {
+        "replaceable"
+    }
+
+    fn nips(&self) -> &'static [u64] {
+        &[16]
+    }
+
+    /// Replaceable events (kinds 0, 3 and 10000-19999): keeps only the
+    /// newest event of the pubkey and kind, which may be an already stored
+    /// one, on stores that do not replace them as they write.
+    async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
+        if !ev.is_replaceable() || ctx.store.replaces_on_write(ev) {
             return;
         }
-        println!("nip16 post_event_write_hook");
-        let ddb = Ddb::new().await;
-        let pubkey = &ev.pubkey;
+        println!("replaceable post_event_write_hook");
+        let Ok(evs) = ctx
+            .store
+            .get_event_by_pubkeys(
+                [ev.pubkey.clone()].as_ref(),
+                Some(vec![ev.kind]),
+                None,
+                None,
+                None,
+            )
+            .await
+        else {
+            return;
+        };
+        if let Err(e) = keep_newest(ctx.store, ev, &evs).await {
+            println!("Hook_replaceable err:{e:?}");
+        }
+    }
+}
+
+pub struct HookNIP32 {}
+#[async_trait]
+impl Hook for HookNIP32 {
+    fn name(&self) -> &'static str {
+        "nip32"
+    }
+
+    fn nips(&self) -> &'static [u64] {
+        &[32]
+    }
+
+    /// NIP-32 Labeling
+    async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
+        let entries = label::label_entries(ev);
+        if entries.is_empty() {
+            return;
+        }
+        println!("nip32 post_event_write_hook");
+        if let Err(e) = ctx.store.write_labels(ev, &entries).await {
+            println!("Hook_nip32 err:{e:?}");
+        }
+    }
+}
+
+pub struct HookNIP33 {}
+#[async_trait]
+impl Hook for HookNIP33 {
+    fn name(&self) -> &'static str {
+        "nip33"
+    }
+
+    fn nips(&self) -> &'static [u64] {
+        &[33]
+    }
 
-        if let Ok(evs) = ddb
-            .get_event_by_pubkeys([pubkey.to_string()].as_ref(), None, None, None, None)
+    /// NIP-33 Parameterized Replaceable Events: keeps only the newest
+    /// version of the address, which may be an already stored one.
+    async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
+        if !ev.is_parameterized_replaceable() {
+            return;
+        }
+        println!("nip33 post_event_write_hook");
+        let Ok(evs) = ctx
+            .store
+            .get_event_by_address(&ev.pubkey, ev.kind, ev.d_tag())
             .await
-        {
-            let evs: Vec<&Event> = evs
-                .iter()
-                .filter(|evx| ev.kind == evx.kind && ev.created_at > evx.created_at)
-                .collect();
-            if evs.is_empty() {
-                return;
-            }
-            let ids = evs.iter().map(|e| e.id.to_string()).collect();
-            match ddb.delete_event_by_ids(ids).await {
-                Ok(_) => (),
-                Err(e) => println!("Hook_nip16 err:{e:?}"),
+        else {
+            return;
+        };
+        if let Err(e) = keep_newest(ctx.store, ev, &evs).await {
+            println!("Hook_nip33 err:{e:?}");
+        }
+    }
+}
+
+/// Deletes every version among `stored` and `ev` but the newest, so writers
+/// racing on the same slot all leave the same event behind.
+async fn keep_newest(store: &dyn EventStore, ev: &Event, stored: &[Event]) -> Result<(), String> {
+    // The read may not see the event just written yet.
+    let Some(newest) = newest_version(stored.iter().chain([ev]).cloned().collect()) else {
+        return Ok(());
+    };
+    let ids: Vec<EventId> = stored
+        .iter()
+        .chain([ev])
+        .filter(|e| e.id != newest.id)
+        .map(|e| e.id.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    store.delete_event_by_ids(ids).await
+}
+
+pub struct HookNIP40 {}
+#[async_trait]
+impl Hook for HookNIP40 {
+    fn name(&self) -> &'static str {
+        "nip40"
+    }
+
+    /// NIP-40 Expiration Timestamp: deletes events already past their
+    /// expiration, e.g. when replayed over a store without a TTL.
+    async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if !ev.is_expired(now) {
+            return;
+        }
+        println!("nip40 post_event_write_hook");
+        if let Err(e) = ctx.store.delete_event_by_ids(vec![ev.id.clone()]).await {
+            println!("Hook_nip40 err:{e:?}");
+        }
+    }
+}
+
+pub struct HookNIP56 {}
+#[async_trait]
+impl Hook for HookNIP56 {
+    fn name(&self) -> &'static str {
+        "nip56"
+    }
+
+    fn kinds(&self) -> Option<&'static [u64]> {
+        Some(&[KIND_REPORT])
+    }
+
+    fn nips(&self) -> &'static [u64] {
+        &[56]
+    }
+
+    /// NIP-56 Reporting: queues reports for the operators.
+    async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
+        let Some(report) = Report::from_event(ev) else {
+            return;
+        };
+        println!("nip56 post_event_write_hook");
+        if let Err(e) = ctx.store.write_report(&report).await {
+            println!("Hook_nip56 err:{e:?}");
+        }
+    }
+}
+
+pub struct HookContentFilter {}
+#[async_trait]
+impl Hook for HookContentFilter {
+    fn name(&self) -> &'static str {
+        "content"
+    }
+
+    /// Refuses events breaking the rules of `ContentFilter`.
+    async fn pre_event_write_hook(
+        &self,
+        ctx: &HookContext<'_>,
+        ev: &Event,
+    ) -> Result<(), RejectReason> {
+        ContentFilter::load(ctx.store).await.check_event(ev)
+    }
+}
+
+#[cfg(feature = "invoke")]
+pub struct HookInvoke {}
+#[cfg(feature = "invoke")]
+#[async_trait]
+impl Hook for HookInvoke {
+    fn name(&self) -> &'static str {
+        "invoke"
+    }
+
+    /// Refuses the events `NOSTR_POLICY_FUNCTION` does not accept.
+    async fn pre_event_write_hook(
+        &self,
+        ctx: &HookContext<'_>,
+        ev: &Event,
+    ) -> Result<(), RejectReason> {
+        let Some(function) = crate::invoke::PolicyFunction::shared().await else {
+            return Ok(());
+        };
+        function.check(ev, ctx.msg).await
+    }
+}
+
+#[cfg(feature = "lang")]
+pub struct HookLanguage {}
+#[cfg(feature = "lang")]
+#[async_trait]
+impl Hook for HookLanguage {
+    fn name(&self) -> &'static str {
+        "lang"
+    }
+
+    /// Counts the language of notes for NIP-11 `language_tags`.
+    async fn post_event_write_hook(&self, ctx: &HookContext<'_>, ev: &Event) {
+        let Some(language) = crate::lang::detect(ev) else {
+            return;
+        };
+        if let Err(e) = ctx.store.add_language(language).await {
+            println!("Hook_lang err:{e:?}");
+        }
+    }
+}
+
+#[cfg(feature = "eventbridge")]
+pub struct HookEventBridge {}
+#[cfg(feature = "eventbridge")]
+#[async_trait]
+impl Hook for HookEventBridge {
+    fn name(&self) -> &'static str {
+        "eventbridge"
+    }
+
+    /// Publishes accepted events to `NOSTR_EVENTBRIDGE_BUS`.
+    async fn post_event_write_hook(&self, _ctx: &HookContext<'_>, ev: &Event) {
+        let Some(bus) = crate::eventbridge::EventBus::shared().await else {
+            return;
+        };
+        if let Err(e) = bus.publish(ev).await {
+            println!("Hook_eventbridge err:{e:?}");
+        }
+    }
+}
+
+#[cfg(feature = "firehose")]
+pub struct HookSns {}
+#[cfg(feature = "firehose")]
+#[async_trait]
+impl Hook for HookSns {
+    fn name(&self) -> &'static str {
+        "sns"
+    }
+
+    /// Publishes accepted events to `NOSTR_FIREHOSE_TOPIC_ARN`.
+    async fn post_event_write_hook(&self, _ctx: &HookContext<'_>, ev: &Event) {
+        let Some(topic) = crate::firehose::Topic::shared().await else {
+            return;
+        };
+        if let Err(e) = topic.publish(ev).await {
+            println!("Hook_sns err:{e:?}");
+        }
+    }
+}
+
+#[cfg(feature = "firehose")]
+pub struct HookSqs {}
+#[cfg(feature = "firehose")]
+#[async_trait]
+impl Hook for HookSqs {
+    fn name(&self) -> &'static str {
+        "sqs"
+    }
+
+    /// Sends accepted events to `NOSTR_FIREHOSE_QUEUE_URL`.
+    async fn post_event_write_hook(&self, _ctx: &HookContext<'_>, ev: &Event) {
+        let Some(queue) = crate::firehose::Queue::shared().await else {
+            return;
+        };
+        if let Err(e) = queue.publish(ev).await {
+            println!("Hook_sqs err:{e:?}");
+        }
+    }
+}
+
+#[cfg(feature = "webhook")]
+pub struct HookWebhook {}
+#[cfg(feature = "webhook")]
+#[async_trait]
+impl Hook for HookWebhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    /// POSTs accepted events to `NOSTR_WEBHOOK_URLS`.
+    async fn post_event_write_hook(&self, _ctx: &HookContext<'_>, ev: &Event) {
+        // Built once so that warm invocations reuse the connections.
+        static WEBHOOKS: once_cell::sync::Lazy<Option<crate::webhook::Webhooks>> =
+            once_cell::sync::Lazy::new(crate::webhook::Webhooks::from_env);
+        let Some(webhooks) = &*WEBHOOKS else {
+            return;
+        };
+        for e in webhooks.deliver(ev).await {
+            println!("Hook_webhook err:{e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hook, HookContext, HookReplaceable, Hooks};
+    use crate::memory::MemoryStore;
+    use crate::message::{test_event, Event, Filter, MessageContext};
+    use crate::reject::RejectReason;
+    use crate::store::EventStore;
+    use crate::transport::MemoryTransport;
+    use crate::types::{EventId, Pubkey};
+    use async_trait::async_trait;
+    use std::collections::HashSet;
+
+    struct HookNoEmpty {}
+    #[async_trait]
+    impl Hook for HookNoEmpty {
+        fn name(&self) -> &'static str {
+            "noempty"
+        }
+
+        async fn pre_event_write_hook(
+            &self,
+            _ctx: &HookContext<'_>,
+            ev: &Event,
+        ) -> Result<(), RejectReason> {
+            if ev.content.is_empty() {
+                return Err(RejectReason::Invalid("empty note".to_string()));
             }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn builder01() {
+        let store = MemoryStore::new();
+        let ev = test_event();
+
+        let hooks = Hooks::builder()
+            .with(super::HookNIP9 {})
+            .with(HookNoEmpty {})
+            .build();
+        assert_eq!(vec!["nip9", "noempty"], hooks.names());
+        assert_eq!(
+            Err(RejectReason::Invalid("empty note".to_string())),
+            hooks
+                .pre_event_write_hook(&HookContext::new(&store), &ev)
+                .await
+        );
+
+        let hooks = Hooks::builder()
+            .with(HookNoEmpty {})
+            .dry_run(HashSet::from(["noempty".to_string()]))
+            .build();
+        let ctx = HookContext::new(&store);
+        assert_eq!(Ok(()), hooks.pre_event_write_hook(&ctx, &ev).await);
+        assert!(Hooks::builder().with_defaults().build().names().len() > 2);
+
+        let disabled = HashSet::from(["nip9".to_string(), "replaceable".to_string()]);
+        let hooks = Hooks::builder()
+            .with_defaults()
+            .disable(disabled.clone())
+            .build();
+        assert!(!hooks.names().contains(&"nip9") && hooks.names().contains(&"nip33"));
+        assert_eq!(HashSet::from([9, 16]), Hooks::disabled_nips(&disabled));
+        assert!(Hooks::check_names(&HashSet::from(["nip99".to_string()])).is_err());
+    }
+
+    /// Store that replaces events as it writes them, over `MemoryStore`.
+    struct ReplacingStore(MemoryStore);
+
+    #[async_trait]
+    impl EventStore for ReplacingStore {
+        fn replaces_on_write(&self, ev: &Event) -> bool {
+            ev.is_replaceable()
+        }
+        async fn write_event(&self, ev: &Event) -> Result<(), String> {
+            self.0.write_event(ev).await
+        }
+        async fn write_subscription(&self, _: &str, _: &str, _: &[Filter]) -> Result<bool, String> {
+            Err("unavailable".into())
+        }
+        async fn delete_subscriptions(&self, _: &str, _: Vec<String>) -> Result<(), String> {
+            Err("unavailable".into())
+        }
+        async fn close_connection(&self, _conn_id: &str) -> Result<usize, String> {
+            Err("unavailable".into())
+        }
+        async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)> {
+            vec![]
+        }
+        async fn get_event_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, String> {
+            self.0.get_event_by_ids(ids).await
+        }
+        async fn get_event_by_pubkeys(
+            &self,
+            pubkeys: &[Pubkey],
+            kinds: Option<Vec<u64>>,
+            since: Option<u64>,
+            until: Option<u64>,
+            limit: Option<i32>,
+        ) -> Result<Vec<Event>, String> {
+            self.0
+                .get_event_by_pubkeys(pubkeys, kinds, since, until, limit)
+                .await
+        }
+        async fn delete_event_by_ids(&self, ids: Vec<EventId>) -> Result<(), String> {
+            self.0.delete_event_by_ids(ids).await
+        }
+    }
+
+    #[tokio::test]
+    async fn replaceable01() {
+        let old = Event {
+            kind: 0,
+            ..test_event()
+        };
+        let new = Event {
+            id: EventId::padded("1d02"),
+            created_at: old.created_at + 1,
+            ..old.clone()
         };
+        let ids = [old.id.clone(), new.id.clone()];
+        let hook = HookReplaceable {};
+
+        let store = MemoryStore::new();
+        store.write_event(&old).await.unwrap();
+        store.write_event(&new).await.unwrap();
+        hook.post_event_write_hook(&HookContext::new(&store), &new)
+            .await;
+        assert_eq!(
+            vec![new.clone()],
+            store.get_event_by_ids(&ids).await.unwrap()
+        );
+
+        // The store's own replacement is not done a second time.
+        let store = ReplacingStore(MemoryStore::new());
+        store.write_event(&old).await.unwrap();
+        store.write_event(&new).await.unwrap();
+        hook.post_event_write_hook(&HookContext::new(&store), &new)
+            .await;
+        assert_eq!(2, store.get_event_by_ids(&ids).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn context01() {
+        let store = MemoryStore::new();
+        let api = MemoryTransport::new();
+        let msg = MessageContext::new("conn01", "https://example.com/stage", "EVENT", 0);
+
+        assert!(!HookContext::new(&store).notice("hello").await);
+        let ctx = HookContext::new(&store).with_message(&msg, &api);
+        assert!(ctx.notice("hello").await);
+        assert_eq!(vec![r#"["NOTICE","hello"]"#], api.frames("conn01"));
     }
 }
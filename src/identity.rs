@@ -0,0 +1,181 @@
+use bech32::{FromBase32, ToBase32, Variant};
+use secp256k1::{rand, KeyPair, Secp256k1, SecretKey};
+
+/// The relay's own keypair.
+///
+/// It is stored as the 64 character hex secret key, which is also what
+/// `Identity::parse` expects besides an `nsec`.
+pub struct Identity {
+    keys: KeyPair,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        let keys = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        Identity { keys }
+    }
+
+    /// Reads a hex or `nsec` encoded secret key.
+    pub fn parse(secret: &str) -> Result<Identity, String> {
+        let secret = secret.trim();
+        let bytes = if secret.starts_with("nsec1") {
+            decode_bech32("nsec", secret)?
+        } else {
+            hex::decode(secret).map_err(|e| format!("invalid secret key: {e}"))?
+        };
+        let sk = SecretKey::from_slice(&bytes).map_err(|e| format!("invalid secret key: {e}"))?;
+        Ok(Identity {
+            keys: KeyPair::from_secret_key(&Secp256k1::new(), &sk),
+        })
+    }
+
+    pub fn keys(&self) -> &KeyPair {
+        &self.keys
+    }
+
+    pub fn pubkey_hex(&self) -> String {
+        self.keys.x_only_public_key().0.to_string()
+    }
+
+    pub fn secret_hex(&self) -> String {
+        hex::encode(self.keys.secret_bytes())
+    }
+
+    pub fn npub(&self) -> String {
+        encode_bech32("npub", &self.keys.x_only_public_key().0.serialize())
+    }
+
+    pub fn nsec(&self) -> String {
+        encode_bech32("nsec", &self.keys.secret_bytes())
+    }
+}
+
+/// Where the relay keeps its secret key.
+#[cfg(feature = "aws")]
+pub enum SecretLocation {
+    /// SecureString parameter in SSM Parameter Store.
+    Ssm(String),
+    /// SecretString of a Secrets Manager secret.
+    SecretsManager(String),
+}
+
+#[cfg(feature = "aws")]
+impl SecretLocation {
+    /// `NOSTR_RELAY_SECRET_PARAMETER` or else `NOSTR_RELAY_SECRET_ID`.
+    pub fn from_env() -> Option<SecretLocation> {
+        if let Ok(name) = std::env::var("NOSTR_RELAY_SECRET_PARAMETER") {
+            Some(SecretLocation::Ssm(name))
+        } else if let Ok(id) = std::env::var("NOSTR_RELAY_SECRET_ID") {
+            Some(SecretLocation::SecretsManager(id))
+        } else {
+            None
+        }
+    }
+
+    pub async fn load(&self) -> Result<Identity, String> {
+        let config = crate::config::sdk_config().await;
+        let secret = match self {
+            SecretLocation::Ssm(name) => aws_sdk_ssm::Client::new(config)
+                .get_parameter()
+                .name(name)
+                .with_decryption(true)
+                .send()
+                .await
+                .map_err(|e| format!("get_parameter: {e}"))?
+                .parameter()
+                .and_then(|p| p.value())
+                .map(|v| v.to_string()),
+            SecretLocation::SecretsManager(id) => aws_sdk_secretsmanager::Client::new(config)
+                .get_secret_value()
+                .secret_id(id)
+                .send()
+                .await
+                .map_err(|e| format!("get_secret_value: {e}"))?
+                .secret_string()
+                .map(|v| v.to_string()),
+        };
+        Identity::parse(&secret.ok_or("secret is empty")?)
+    }
+
+    /// Writes the hex secret key, replacing any previous value.
+    pub async fn store(&self, identity: &Identity) -> Result<(), String> {
+        let config = crate::config::sdk_config().await;
+        let secret = identity.secret_hex();
+        match self {
+            SecretLocation::Ssm(name) => {
+                aws_sdk_ssm::Client::new(config)
+                    .put_parameter()
+                    .name(name)
+                    .value(secret)
+                    .r#type(aws_sdk_ssm::model::ParameterType::SecureString)
+                    .overwrite(true)
+                    .send()
+                    .await
+                    .map_err(|e| format!("put_parameter: {e}"))?;
+            }
+            SecretLocation::SecretsManager(id) => {
+                let client = aws_sdk_secretsmanager::Client::new(config);
+                let put = client
+                    .put_secret_value()
+                    .secret_id(id)
+                    .secret_string(&secret)
+                    .send()
+                    .await;
+                if put.is_err() {
+                    client
+                        .create_secret()
+                        .name(id)
+                        .secret_string(secret)
+                        .send()
+                        .await
+                        .map_err(|e| format!("create_secret: {e}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode_bech32(hrp: &str, data: &[u8]) -> String {
+    bech32::encode(hrp, data.to_base32(), Variant::Bech32).unwrap()
+}
+
+fn decode_bech32(hrp: &str, s: &str) -> Result<Vec<u8>, String> {
+    let (got, data, _) = bech32::decode(s).map_err(|e| format!("invalid {hrp}: {e}"))?;
+    if got != hrp {
+        return Err(format!("expected {hrp}, got {got}"));
+    }
+    Vec::<u8>::from_base32(&data).map_err(|e| format!("invalid {hrp}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Identity;
+
+    #[test]
+    fn identity01() {
+        // NIP-19 test vector
+        let id =
+            Identity::parse("67dea2ed018072d675f5415ecfaed7d2597555e202d85b3d65ea4e58d2d92ffa")
+                .unwrap();
+        assert_eq!(
+            "nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5",
+            id.nsec()
+        );
+        assert_eq!(
+            "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e",
+            id.pubkey_hex()
+        );
+        assert_eq!(
+            "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg",
+            id.npub()
+        );
+
+        let again = Identity::parse(&id.nsec()).unwrap();
+        assert_eq!(id.secret_hex(), again.secret_hex());
+        assert!(
+            Identity::parse("npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg")
+                .is_err()
+        );
+    }
+}
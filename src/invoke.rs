@@ -0,0 +1,153 @@
+//! Custom write policies in a Lambda function: the relay invokes it with each
+//! event and it answers whether to accept it, so that operators can write
+//! moderation logic in any language the runtime supports.
+use crate::config::sdk_config;
+use crate::message::{Event, MessageContext};
+use crate::reject::RejectReason;
+use aws_sdk_lambda::types::Blob;
+use aws_sdk_lambda::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+/// The function of the process environment, built on first use so that
+/// warm invocations reuse the client.
+static SHARED: tokio::sync::OnceCell<Option<PolicyFunction>> = tokio::sync::OnceCell::const_new();
+
+pub struct PolicyFunction {
+    client: Client,
+    function: String,
+    fail_open: bool,
+}
+
+/// What the function answers, e.g. `{"accept": false, "message": "spam"}`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub accept: bool,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl PolicyFunction {
+    /// The function `from_env` reads, built once per process.
+    pub async fn shared() -> Option<&'static PolicyFunction> {
+        SHARED.get_or_init(PolicyFunction::from_env).await.as_ref()
+    }
+
+    /// Reads `NOSTR_POLICY_FUNCTION` (a function name or ARN) and
+    /// `NOSTR_POLICY_FAIL_OPEN`; None when no function is set.
+    async fn from_env() -> Option<PolicyFunction> {
+        let function = std::env::var("NOSTR_POLICY_FUNCTION")
+            .ok()
+            .filter(|f| !f.is_empty())?;
+        Some(PolicyFunction {
+            client: Client::new(sdk_config().await),
+            function,
+            fail_open: std::env::var("NOSTR_POLICY_FAIL_OPEN").is_ok_and(|v| v == "1"),
+        })
+    }
+
+    /// Invokes the function and waits for its verdict. When it fails, the
+    /// event is refused unless `NOSTR_POLICY_FAIL_OPEN` is set.
+    pub async fn check(
+        &self,
+        ev: &Event,
+        msg: Option<&MessageContext>,
+    ) -> Result<(), RejectReason> {
+        match self.invoke(ev, msg).await {
+            Ok(verdict) => verdict.into_result(),
+            Err(e) => {
+                println!("policy function {} err: {e}", self.function);
+                if self.fail_open {
+                    Ok(())
+                } else {
+                    Err(RejectReason::Error("could not check event".to_string()))
+                }
+            }
+        }
+    }
+
+    async fn invoke(&self, ev: &Event, msg: Option<&MessageContext>) -> Result<Verdict, String> {
+        let ret = self
+            .client
+            .invoke()
+            .function_name(&self.function)
+            .payload(Blob::new(payload(ev, msg).to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        if let Some(e) = ret.function_error() {
+            return Err(format!("function error: {e}"));
+        }
+        let body = ret.payload().map(|p| p.as_ref()).unwrap_or_default();
+        serde_json::from_slice(body).map_err(|e| format!("bad verdict: {e}"))
+    }
+}
+
+impl Verdict {
+    /// A refusal is `blocked` unless the message starts with another NIP-01
+    /// prefix, e.g. `rate-limited: slow down`.
+    pub fn into_result(self) -> Result<(), RejectReason> {
+        if self.accept {
+            return Ok(());
+        }
+        if self.message.is_empty() {
+            return Err(RejectReason::Blocked("refused by policy".to_string()));
+        }
+        let Some((prefix, message)) = self.message.split_once(": ") else {
+            return Err(RejectReason::Blocked(self.message));
+        };
+        let message = message.to_string();
+        Err(match prefix {
+            "pow" => RejectReason::Pow(message),
+            "rate-limited" => RejectReason::RateLimited(message),
+            "invalid" => RejectReason::Invalid(message),
+            "restricted" => RejectReason::Restricted(message),
+            "blocked" => RejectReason::Blocked(message),
+            _ => RejectReason::Blocked(self.message),
+        })
+    }
+}
+
+/// The event and, when a client sent it, who sent it from where.
+fn payload(ev: &Event, msg: Option<&MessageContext>) -> serde_json::Value {
+    json!({
+        "event": ev,
+        "auth_pubkey": msg.and_then(|m| m.auth_pubkey.as_deref()),
+        "source_ip": msg.and_then(|m| m.source_ip.as_deref()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{payload, Verdict};
+    use crate::message::test_event;
+
+    fn verdict(body: &str) -> String {
+        serde_json::from_str::<Verdict>(body)
+            .unwrap()
+            .into_result()
+            .map_or_else(|r| r.to_string(), |_| "ok".to_string())
+    }
+
+    #[test]
+    fn verdict01() {
+        assert_eq!("ok", verdict(r#"{"accept": true}"#));
+        assert_eq!(
+            "blocked: refused by policy",
+            verdict(r#"{"accept": false}"#)
+        );
+        assert_eq!(
+            "blocked: no spam: please",
+            verdict(r#"{"accept": false, "message": "no spam: please"}"#)
+        );
+        assert_eq!(
+            "rate-limited: slow down",
+            verdict(r#"{"accept": false, "message": "rate-limited: slow down"}"#)
+        );
+
+        let ev = test_event();
+        let body = payload(&ev, None);
+        assert_eq!(ev, serde_json::from_value(body["event"].clone()).unwrap());
+        assert!(body["auth_pubkey"].is_null());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::message::Event;
+use crate::policy::env_list;
+use crate::store::EventStore;
+use std::collections::HashSet;
+
+/// NIP-32 label event kind.
+pub const KIND_LABEL: u64 = 1985;
+
+const TARGET_TAGS: [&str; 5] = ["e", "p", "a", "r", "t"];
+
+/// One NIP-32 label applied by `labeler` to a single target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelEntry {
+    pub namespace: String,
+    pub value: String,
+    pub target_tag: String,
+    pub target: String,
+    pub labeler: String,
+    pub label_event: String,
+}
+
+/// Label entries carried by `ev`.
+///
+/// Kind 1985 events label whatever they tag; any other event carrying `l`
+/// tags labels itself.
+pub fn label_entries(ev: &Event) -> Vec<LabelEntry> {
+    let labels: Vec<(String, String)> = ev
+        .tags
+        .iter()
+        .filter(|t| t.len() >= 2 && t[0] == "l")
+        .map(|t| {
+            let namespace = t.get(2).cloned().unwrap_or_else(|| "ugc".to_string());
+            (namespace, t[1].clone())
+        })
+        .collect();
+
+    let targets: Vec<(String, String)> = if ev.kind == KIND_LABEL {
+        ev.tags
+            .iter()
+            .filter(|t| t.len() >= 2 && TARGET_TAGS.contains(&t[0].as_str()))
+            .map(|t| (t[0].clone(), t[1].clone()))
+            .collect()
+    } else {
+        vec![("e".to_string(), ev.id.to_string())]
+    };
+
+    let mut entries = vec![];
+    for (namespace, value) in labels.iter() {
+        for (target_tag, target) in targets.iter() {
+            entries.push(LabelEntry {
+                namespace: namespace.clone(),
+                value: value.clone(),
+                target_tag: target_tag.clone(),
+                target: target.clone(),
+                labeler: ev.pubkey.to_string(),
+                label_event: ev.id.to_string(),
+            });
+        }
+    }
+    entries
+}
+
+/// Labels listed in `NOSTR_HIDDEN_LABELS` as comma separated `namespace:value`.
+pub fn hidden_labels() -> Vec<(String, String)> {
+    std::env::var("NOSTR_HIDDEN_LABELS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|l| l.trim().split_once(':'))
+        .map(|(ns, v)| (ns.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Pubkeys listed in `NOSTR_TRUSTED_LABELERS`; only their labels hide events.
+pub fn trusted_labelers() -> HashSet<String> {
+    env_list("NOSTR_TRUSTED_LABELERS")
+}
+
+/// Event ids and pubkeys hidden by trusted labelers.
+#[derive(Debug, Default)]
+pub struct HiddenTargets {
+    pub ids: HashSet<String>,
+    pub pubkeys: HashSet<String>,
+}
+
+impl HiddenTargets {
+    /// Targets of `labels` applied by one of `labelers`, such as
+    /// `hidden_labels()` and `trusted_labelers()`.
+    pub async fn load(
+        store: &dyn EventStore,
+        labels: &[(String, String)],
+        labelers: &HashSet<String>,
+    ) -> HiddenTargets {
+        let mut hidden = HiddenTargets::default();
+        for (namespace, value) in labels {
+            match store.get_label_targets(namespace, value).await {
+                Ok(entries) => {
+                    for e in entries.into_iter() {
+                        if !labelers.contains(&e.labeler) {
+                            continue;
+                        }
+                        match &*e.target_tag {
+                            "e" => hidden.ids.insert(e.target),
+                            "p" => hidden.pubkeys.insert(e.target),
+                            _ => false,
+                        };
+                    }
+                }
+                Err(e) => println!("label err: {e}"),
+            }
+        }
+        hidden
+    }
+
+    pub fn is_hidden(&self, ev: &Event) -> bool {
+        self.ids.contains(ev.id.as_str()) || self.pubkeys.contains(ev.pubkey.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{label_entries, HiddenTargets, LabelEntry};
+    use crate::message::Event;
+    use crate::types::{EventId, Pubkey, Signature};
+
+    fn build_label_event() -> Event {
+        Event {
+            id: EventId::padded("1abe101"),
+            pubkey: Pubkey::padded("1abe1e01"),
+            created_at: 1676118868,
+            kind: 1985,
+            tags: vec![
+                vec!["L".into(), "com.example".into()],
+                vec!["l".into(), "spam".into(), "com.example".into()],
+                vec!["e".into(), "ev01".into(), "wss://relay".into()],
+                vec!["p".into(), "pub01".into()],
+            ],
+            content: "".into(),
+            sig: Signature::padded(""),
+        }
+    }
+
+    #[test]
+    fn label_entries01() {
+        let entries = label_entries(&build_label_event());
+        assert_eq!(2, entries.len());
+        assert_eq!(
+            LabelEntry {
+                namespace: "com.example".into(),
+                value: "spam".into(),
+                target_tag: "e".into(),
+                target: "ev01".into(),
+                labeler: Pubkey::padded("1abe1e01").to_string(),
+                label_event: EventId::padded("1abe101").to_string(),
+            },
+            entries[0]
+        );
+        assert_eq!("p", entries[1].target_tag);
+        assert_eq!("pub01", entries[1].target);
+    }
+
+    #[test]
+    fn label_entries_self() {
+        let ev = Event {
+            id: EventId::padded("0e01"),
+            kind: 1,
+            tags: vec![vec!["l".into(), "en".into()]],
+            ..build_label_event()
+        };
+        let entries = label_entries(&ev);
+        assert_eq!(1, entries.len());
+        assert_eq!("ugc", entries[0].namespace);
+        assert_eq!(EventId::padded("0e01"), entries[0].target);
+    }
+
+    #[test]
+    fn hidden_targets01() {
+        let mut hidden = HiddenTargets::default();
+        hidden.pubkeys.insert(Pubkey::padded("b01").to_string());
+        let ev = Event {
+            pubkey: Pubkey::padded("b01"),
+            ..build_label_event()
+        };
+        assert!(hidden.is_hidden(&ev));
+        assert!(!hidden.is_hidden(&build_label_event()));
+    }
+}
@@ -0,0 +1,83 @@
+use crate::message::Event;
+
+/// Kinds whose content is prose worth detecting: text notes and long-form.
+pub const DETECTED_KINDS: [u64; 2] = [1, 30023];
+
+/// ISO 639-3 codes reported by whatlang and the ISO 639-1 tag NIP-11 uses.
+const TAGS: [(&str, &str); 34] = [
+    ("ara", "ar"),
+    ("ces", "cs"),
+    ("cmn", "zh"),
+    ("dan", "da"),
+    ("deu", "de"),
+    ("ell", "el"),
+    ("eng", "en"),
+    ("epo", "eo"),
+    ("fin", "fi"),
+    ("fra", "fr"),
+    ("heb", "he"),
+    ("hin", "hi"),
+    ("hun", "hu"),
+    ("ind", "id"),
+    ("ita", "it"),
+    ("jpn", "ja"),
+    ("kor", "ko"),
+    ("nld", "nl"),
+    ("nob", "nb"),
+    ("pes", "fa"),
+    ("pol", "pl"),
+    ("por", "pt"),
+    ("ron", "ro"),
+    ("rus", "ru"),
+    ("slk", "sk"),
+    ("spa", "es"),
+    ("swe", "sv"),
+    ("tgl", "tl"),
+    ("tha", "th"),
+    ("tur", "tr"),
+    ("ukr", "uk"),
+    ("urd", "ur"),
+    ("vie", "vi"),
+    ("zul", "zu"),
+];
+
+/// Language tag of `ev`'s content, if it is a detected kind and whatlang is
+/// confident about it.
+pub fn detect(ev: &Event) -> Option<&'static str> {
+    if !DETECTED_KINDS.contains(&ev.kind) {
+        return None;
+    }
+    let info = whatlang::detect(&ev.content).filter(|i| i.is_reliable())?;
+    let code = info.lang().code();
+    Some(
+        TAGS.iter()
+            .find(|(iso3, _)| *iso3 == code)
+            .map_or(code, |(_, tag)| tag),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect;
+    use crate::identity::Identity;
+    use crate::message::Event;
+
+    #[test]
+    fn detect01() {
+        let id = Identity::generate();
+        let note = |kind, content| Event::sign(id.keys(), 1676118868, kind, vec![], content);
+
+        assert_eq!(
+            Some("ja"),
+            detect(&note(1, "今日はとても良い天気なので、散歩に出かけました。"))
+        );
+        assert_eq!(
+            Some("en"),
+            detect(&note(
+                30023,
+                "The weather was lovely today, so I went for a long walk along the river."
+            ))
+        );
+        assert_eq!(None, detect(&note(7, "The weather was lovely today.")));
+    }
+}
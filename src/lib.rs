@@ -1,6 +1,59 @@
-mod apigwmgmt;
-mod ddb;
-mod hook;
+pub mod allowlist;
+#[cfg(feature = "aws")]
+pub mod apigwmgmt;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod auth;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod config;
+pub mod content;
+#[cfg(feature = "aws")]
+pub mod ddb;
+pub mod denylist;
+#[cfg(feature = "eventbridge")]
+pub mod eventbridge;
+#[cfg(feature = "firehose")]
+pub mod firehose;
+pub mod hook;
+pub mod identity;
+#[cfg(feature = "invoke")]
+pub mod invoke;
+pub mod label;
+#[cfg(feature = "lang")]
+pub mod lang;
+#[cfg(feature = "local")]
+pub mod local;
+#[cfg(feature = "media")]
+pub mod media;
+pub mod memory;
 pub mod message;
+pub mod metrics;
 pub mod nip11;
+pub mod nip65;
+pub mod nip86;
+pub mod nip96;
+pub mod nip98;
+#[cfg(feature = "payments")]
+pub mod payments;
+pub mod policy;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+#[cfg(feature = "queue")]
+pub mod queue;
+pub mod recent;
+pub mod reject;
 pub mod relay;
+pub mod report;
+pub mod restore;
+pub mod seed;
+pub mod selftest;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod store;
+#[cfg(feature = "stream")]
+pub mod stream;
+pub mod transport;
+pub mod types;
+#[cfg(feature = "webhook")]
+pub mod webhook;
@@ -0,0 +1,160 @@
+use crate::message::MessageContext;
+use crate::relay;
+use crate::store::EventStore;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Transport posting frames straight to the websocket connections accepted
+/// by [`serve`], in place of the API Gateway Management API.
+#[derive(Default)]
+pub struct LocalTransport {
+    conns: Mutex<HashMap<String, UnboundedSender<String>>>,
+}
+
+#[async_trait]
+impl Transport for LocalTransport {
+    async fn post_connection(&self, conn_id: &str, data: &str) -> bool {
+        match self.conns.lock().unwrap().get(conn_id) {
+            Some(tx) => tx.send(data.to_string()).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Accepts websocket connections on `listener` and runs them through the
+/// same handlers as the Lambda, so the relay can be used without AWS.
+pub async fn serve(
+    listener: TcpListener,
+    store: Arc<dyn EventStore + Send + Sync>,
+) -> Result<(), String> {
+    let endpoint = format!("ws://{}", listener.local_addr().map_err(|e| e.to_string())?);
+    let api = Arc::new(LocalTransport::default());
+    let next_id = AtomicU64::new(1);
+    loop {
+        let (stream, addr) = listener.accept().await.map_err(|e| e.to_string())?;
+        let conn_id = format!("local{}", next_id.fetch_add(1, Ordering::Relaxed));
+        println!("accept: {addr}: {conn_id}");
+        let (store, api, endpoint) = (store.clone(), api.clone(), endpoint.clone());
+        tokio::spawn(async move {
+            let ip = addr.ip().to_string();
+            if let Err(e) = handle_connection(stream, &conn_id, &ip, &endpoint, &*store, &api).await
+            {
+                println!("{conn_id}: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    conn_id: &str,
+    source_ip: &str,
+    endpoint: &str,
+    store: &(dyn EventStore + Send + Sync),
+    api: &LocalTransport,
+) -> Result<(), String> {
+    let ws = accept_async(stream).await.map_err(|e| e.to_string())?;
+    let (mut sink, mut source) = ws.split();
+    let (tx, mut rx) = unbounded_channel::<String>();
+    api.conns.lock().unwrap().insert(conn_id.to_string(), tx);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if sink.send(Message::Text(frame)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let ctx = |command: &str| {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let mut ctx = MessageContext::new(conn_id, endpoint, command, now);
+        ctx.source_ip = Some(source_ip.to_string());
+        ctx
+    };
+    relay::process_conn(&ctx("$connect"), store).await;
+    while let Some(msg) = source.next().await {
+        match msg {
+            Ok(Message::Text(msg)) => {
+                let outcome = relay::process_message(&mut ctx("$default"), store, api, &msg).await;
+                println!("outcome: {outcome:?}");
+            }
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+    relay::process_disconn(&ctx("$disconnect"), store).await;
+    api.conns.lock().unwrap().remove(conn_id);
+    writer.await.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve;
+    use crate::message::Event;
+    use crate::sqlite::SqliteStore;
+    use crate::store::EventStore;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    /// The next frame that is not a greeting.
+    async fn recv(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> Value {
+        loop {
+            if let Some(Ok(Message::Text(text))) = ws.next().await {
+                let v: Value = serde_json::from_str(&text).unwrap();
+                if v[0] != "NOTICE" && v[0] != "AUTH" {
+                    return v;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serve01() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let store = Arc::new(SqliteStore::open_in_memory().unwrap());
+        store
+            .add_allowed_pubkey("98f4285bcb2cc65c3a66bd77ccffd2563ed3303e7e02a489c63a887fcd06bbe5")
+            .await
+            .unwrap();
+        tokio::spawn(serve(listener, store));
+
+        let (mut reader, _) = connect_async(&url).await.unwrap();
+        let (mut writer, _) = connect_async(&url).await.unwrap();
+        let req = json!(["REQ", "sub01", {"kinds": [1]}]).to_string();
+        reader.send(Message::Text(req)).await.unwrap();
+        assert_eq!(json!(["EOSE", "sub01"]), recv(&mut reader).await);
+
+        // Signed by the allowed user.
+        let ev: Event = serde_json::from_value(json!({
+            "id": "87ae4ae2974e96e857856fe5f677d412df40cb331378fd1b20e0ed78910629a2",
+            "pubkey": "98f4285bcb2cc65c3a66bd77ccffd2563ed3303e7e02a489c63a887fcd06bbe5",
+            "created_at": 1676118868,
+            "kind": 1,
+            "tags": [],
+            "content": "hello!",
+            "sig": "e9bfd020031ae702d5af21f029613d8a7957bfc269d5a8da36a79c2ff696f54db68e3ccd4111171f61335fa89369cbe96fa45b2a032061726a04afa157df32eb",
+        }))
+        .unwrap();
+        let msg = json!(["EVENT", ev]).to_string();
+        writer.send(Message::Text(msg)).await.unwrap();
+        assert_eq!(json!(["OK", ev.id, true, ""]), recv(&mut writer).await);
+        assert_eq!(json!(["EVENT", "sub01", ev]), recv(&mut reader).await);
+    }
+}
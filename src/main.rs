@@ -1,5 +1,11 @@
 use lambda_http::request::RequestContext;
 use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response};
+use nostr_relay_apigw::apigwmgmt::ApiGwMgmt;
+use nostr_relay_apigw::config::Config;
+use nostr_relay_apigw::ddb::Ddb;
+use nostr_relay_apigw::store::EventStore;
+use nostr_relay_apigw::transport::RouteReply;
+use nostr_relay_apigw::types::Pubkey;
 use nostr_relay_apigw::{message, relay};
 
 fn build_messagectx(request: &Request) -> message::MessageContext {
@@ -8,7 +14,7 @@ fn build_messagectx(request: &Request) -> message::MessageContext {
     } else {
         panic!("expect websocket");
     };
-    message::MessageContext::new(
+    let mut msgctx = message::MessageContext::new(
         &ctx.connection_id.unwrap(),
         &format!(
             "https://{}/{}",
@@ -17,68 +23,466 @@ fn build_messagectx(request: &Request) -> message::MessageContext {
         ),
         &ctx.route_key.unwrap(),
         ctx.request_time_epoch.try_into().unwrap(),
-    )
+    );
+    msgctx.source_ip = ctx.identity.source_ip;
+    msgctx
+}
+
+fn status_code(outcome: &relay::Outcome) -> u16 {
+    match outcome {
+        relay::Outcome::Accepted { .. }
+        | relay::Outcome::Duplicate
+        | relay::Outcome::Delivered(_)
+        | relay::Outcome::Counted(_)
+        | relay::Outcome::Connected
+        | relay::Outcome::Authenticated(_)
+        | relay::Outcome::Closed => 200,
+        relay::Outcome::Rejected(_) => 403,
+        relay::Outcome::Malformed => 400,
+        relay::Outcome::Error(_) => 500,
+    }
 }
 
-fn parse_eventmsg(message: &str) -> Option<message::EventCmd> {
-    let ret = serde_json::from_str(message);
-    if let Err(err) = ret {
-        println!("err: {err}");
-        return None;
+async fn function_handler_http(event: Request) -> Result<Response<Body>, Error> {
+    #[cfg(feature = "media")]
+    if let Some(resp) = function_handler_media(&event).await? {
+        return Ok(resp);
+    }
+    #[cfg(feature = "payments")]
+    if let Some(resp) = function_handler_payments(&event).await? {
+        return Ok(resp);
+    }
+    if event.uri().path().ends_with("/stats") {
+        return function_handler_stats().await;
+    }
+    if event.uri().path().ends_with("/selftest") {
+        return function_handler_selftest(&event).await;
+    }
+    if event
+        .headers()
+        .get("content-type")
+        .is_some_and(|v| v == nostr_relay_apigw::nip86::CONTENT_TYPE)
+    {
+        return function_handler_management(&event).await;
     }
-    let arr: Vec<message::EventMsg> = ret.unwrap();
-    if let (message::EventMsg::String(cmd), message::EventMsg::Event(ev)) = (&arr[0], &arr[1]) {
-        Some(message::EventCmd::new(cmd, ev))
+    if event.uri().path().contains("/reports") {
+        return function_handler_reports(&event).await;
+    }
+    if let Some(pubkey) = nostr_relay_apigw::nip65::pubkey_from_path(event.uri().path()) {
+        return function_handler_relay_list(&pubkey).await;
+    }
+
+    function_handler_nip11(&event).await
+}
+
+/// Serves the NIP-11 document with an ETag, answering 304 to clients that
+/// already have it.
+async fn function_handler_nip11(event: &Request) -> Result<Response<Body>, Error> {
+    use nostr_relay_apigw::nip11;
+
+    let detected;
+    let doc = if nip11::detects_languages() {
+        detected = nip11::Document::new(nip11_json().await);
+        &detected
     } else {
-        None
+        &*nip11::DOCUMENT
+    };
+    let not_modified = event
+        .headers()
+        .get("if-none-match")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| doc.matches(v));
+
+    let resp = Response::builder()
+        .status(if not_modified { 304 } else { 200 })
+        .header("content-type", "application/nostr+json")
+        .header("etag", &doc.etag)
+        .header(
+            "cache-control",
+            format!("public, max-age={}", nip11::max_age()),
+        )
+        .body(if not_modified {
+            Body::Empty
+        } else {
+            doc.body.clone().into()
+        })
+        .map_err(Box::new)?;
+    Ok(resp)
+}
+
+/// The NIP-11 document with the detected languages, kept in the Redis
+/// cache when there is one.
+async fn nip11_json() -> String {
+    #[cfg(feature = "cache")]
+    if let Some(cache) = nostr_relay_apigw::cache::Cache::from_env().await {
+        if let Some(json) = cache.get("nip11").await {
+            return json;
+        }
+        let json = nip11_json_uncached().await;
+        cache.put("nip11", &json).await;
+        return json;
     }
+    nip11_json_uncached().await
 }
 
-fn parse_reqmsg(message: &str) -> Option<message::ReqCmd> {
-    let ret = serde_json::from_str(message);
-    if let Err(err) = ret {
-        println!("err: {err}");
-        return None;
+async fn nip11_json_uncached() -> String {
+    use nostr_relay_apigw::{metrics, nip11};
+
+    let min_share = std::env::var("NOSTR_LANGUAGE_MIN_SHARE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5);
+    let stats = match Ddb::new().await {
+        Ok(ddb) => ddb.get_stats().await,
+        Err(e) => Err(e),
+    };
+    match stats {
+        Ok(stats) => {
+            nip11::json_with_languages(&metrics::language_tags(&stats.languages, min_share))
+        }
+        Err(e) => {
+            println!("stats err: {e}");
+            nip11::json()
+        }
     }
-    let arr: Vec<message::ReqMsg> = ret.unwrap();
-    let cmd = if let message::ReqMsg::String(cmd) = &arr[0] {
-        cmd
-    } else {
-        return None;
+}
+
+#[cfg(any(feature = "media", feature = "payments"))]
+fn json_response(status: u16, body: String) -> Result<Response<Body>, Error> {
+    let resp = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(body.into())
+        .map_err(Box::new)?;
+    Ok(resp)
+}
+
+/// NIP-96 upload, delete and discovery routes, None for other requests.
+#[cfg(feature = "media")]
+async fn function_handler_media(event: &Request) -> Result<Option<Response<Body>>, Error> {
+    use nostr_relay_apigw::media::MediaStore;
+    use nostr_relay_apigw::{nip96, nip98};
+
+    let Some(config) = nip96::MediaConfig::from_env() else {
+        return Ok(None);
+    };
+    let path = event.uri().path();
+    let method = event.method().as_str();
+    let header = |name: &str| {
+        event
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
     };
-    let sub_id = if let message::ReqMsg::String(sub_id) = &arr[1] {
-        sub_id
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    if method == "GET" && path.ends_with("/.well-known/nostr/nip96.json") {
+        return json_response(200, config.json()).map(Some);
+    }
+
+    if method == "POST" && path == config.api_path() {
+        let body: &[u8] = event.body().as_ref();
+        let pubkey = match nip98::verify(
+            header("authorization"),
+            &config.api_url,
+            method,
+            Some(body),
+            now,
+        ) {
+            Ok(pubkey) => pubkey,
+            Err(e) => return json_response(401, nip96::status_response(false, &e)).map(Some),
+        };
+        if body.len() > config.max_size {
+            let msg = nip96::status_response(false, "file too large");
+            return json_response(413, msg).map(Some);
+        }
+        let upload = match nip96::parse_multipart(header("content-type"), body) {
+            Ok(upload) => upload,
+            Err(e) => return json_response(400, nip96::status_response(false, &e)).map(Some),
+        };
+        if !config.accepts(&upload.content_type) {
+            let msg = nip96::status_response(false, "unsupported content type");
+            return json_response(415, msg).map(Some);
+        }
+        let Some(media) = MediaStore::from_config(Config::global()?).await else {
+            let msg = nip96::status_response(false, "error: media storage is not configured");
+            return json_response(500, msg).map(Some);
+        };
+        return match media.put(&pubkey, &upload).await {
+            Ok(key) => {
+                let url = format!("{}/{key}", config.download_url);
+                json_response(201, nip96::upload_response(&url, &upload)).map(Some)
+            }
+            Err(e) => {
+                println!("media err: {e}");
+                let msg = nip96::status_response(false, "error: failed to store the file");
+                json_response(500, msg).map(Some)
+            }
+        };
+    }
+
+    let name = path
+        .strip_prefix(config.api_path())
+        .and_then(|p| p.strip_prefix('/'));
+    if let (Some(name), "DELETE") = (name, method) {
+        let url = format!("{}/{name}", config.api_url);
+        let pubkey = match nip98::verify(header("authorization"), &url, method, None, now) {
+            Ok(pubkey) => pubkey,
+            Err(e) => return json_response(401, nip96::status_response(false, &e)).map(Some),
+        };
+        let key = name.split('.').next().unwrap_or_default();
+        let Some(media) = MediaStore::from_config(Config::global()?).await else {
+            let msg = nip96::status_response(false, "error: media storage is not configured");
+            return json_response(500, msg).map(Some);
+        };
+        return match media.delete(&pubkey, key).await {
+            Ok(()) => json_response(200, nip96::status_response(true, "File deleted.")).map(Some),
+            Err(e) => json_response(403, nip96::status_response(false, &e)).map(Some),
+        };
+    }
+
+    Ok(None)
+}
+
+/// Paid admission routes, None for other requests or when payments are not
+/// configured: `POST /invoices` with `{"pubkey": ...}` issues an invoice,
+/// `GET /invoices/<payment_hash>` polls it, and LNbits calls
+/// `POST /payments/webhook` when one is paid.
+#[cfg(feature = "payments")]
+async fn function_handler_payments(event: &Request) -> Result<Option<Response<Body>>, Error> {
+    use nostr_relay_apigw::payments::Payments;
+    use serde_json::json;
+
+    let path = event.uri().path().trim_end_matches('/');
+    let method = event.method().as_str();
+    let route = if path.ends_with("/payments/webhook") {
+        None
+    } else if let Some((_, rest)) = path.split_once("/invoices") {
+        Some(rest.trim_start_matches('/'))
     } else {
-        return None;
+        return Ok(None);
+    };
+    let Some(payments) = Payments::from_env() else {
+        return Ok(None);
+    };
+    let body: &[u8] = event.body().as_ref();
+    let param = |name: &str| {
+        serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .and_then(|v| v[name].as_str().map(str::to_string))
+    };
+    let store = Ddb::new().await?;
+
+    let hash = match (method, route) {
+        ("POST", Some("")) => {
+            let Some(pubkey) = param("pubkey").and_then(|p| p.parse::<Pubkey>().ok()) else {
+                return json_response(400, r#"{"error":"bad pubkey"}"#.to_string()).map(Some);
+            };
+            return match payments.issue(&store, &pubkey).await {
+                Ok(invoice) => json_response(201, serde_json::to_string(&invoice).unwrap()),
+                Err(e) => {
+                    println!("payments err: {e}");
+                    json_response(502, r#"{"error":"failed to issue an invoice"}"#.to_string())
+                }
+            }
+            .map(Some);
+        }
+        ("POST", None) => param("payment_hash"),
+        ("GET", Some(hash)) if !hash.is_empty() => Some(hash.to_string()),
+        _ => return json_response(405, r#"{"error":"method not allowed"}"#.to_string()).map(Some),
     };
-    let mut fs = vec![];
-    for v in arr[2..].iter() {
-        if let message::ReqMsg::Filter(fl) = v {
-            fs.push(fl.clone())
+    let Some(hash) = hash else {
+        return json_response(400, r#"{"error":"no payment_hash"}"#.to_string()).map(Some);
+    };
+    match payments.settle(&store, &hash).await {
+        Ok(paid) => json_response(200, json!({ "paid": paid }).to_string()),
+        Err(e) => {
+            println!("payments err: {e}");
+            json_response(404, json!({ "error": e }).to_string())
         }
     }
+    .map(Some)
+}
 
-    Some(message::ReqCmd::new(cmd, sub_id, fs))
+/// Gauges and rejection counts as JSON.
+async fn function_handler_stats() -> Result<Response<Body>, Error> {
+    let (status, body) = match Ddb::new().await?.get_stats().await {
+        Ok(stats) => (200, serde_json::to_string_pretty(&stats).unwrap()),
+        Err(e) => {
+            println!("stats err: {e}");
+            (500, r#"{"error":"stats are unavailable"}"#.to_string())
+        }
+    };
+    let resp = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(body.into())
+        .map_err(Box::new)?;
+    Ok(resp)
 }
 
-fn parse_closemsg(message: &str) -> Option<message::CloseCmd> {
-    let ret = serde_json::from_str(message);
-    if let Err(err) = ret {
-        println!("err: {err}");
-        return None;
+/// The newest NIP-65 relay list of `pubkey`, 404 if it has none.
+async fn function_handler_relay_list(pubkey: &Pubkey) -> Result<Response<Body>, Error> {
+    let (status, body) = match Ddb::new().await?.get_relay_list(pubkey).await {
+        Ok(Some(ev)) => (200, serde_json::to_string(&ev).unwrap()),
+        Ok(None) => (404, r#"{"error":"no relay list"}"#.to_string()),
+        Err(e) => {
+            println!("relay list err: {e}");
+            (500, r#"{"error":"relay list is unavailable"}"#.to_string())
+        }
+    };
+    let resp = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("access-control-allow-origin", "*")
+        .body(body.into())
+        .map_err(Box::new)?;
+    Ok(resp)
+}
+
+/// Checks that `event` carries a NIP-98 authorization by one of
+/// `NOSTR_ADMIN_PUBKEYS`, or returns the 401 response to send.
+fn authorize_admin(event: &Request) -> Result<Result<String, Response<Body>>, Error> {
+    use nostr_relay_apigw::{nip98, policy};
+    use std::time::SystemTime;
+
+    let header = |name: &str| {
+        event
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+    };
+    let url = format!("https://{}{}", header("host"), event.uri().path());
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let body: &[u8] = event.body().as_ref();
+    let authorized = nip98::verify(
+        header("authorization"),
+        &url,
+        event.method().as_str(),
+        Some(body),
+        now,
+    )
+    .and_then(|pubkey| {
+        if policy::admin_pubkeys().contains(&pubkey) {
+            Ok(pubkey)
+        } else {
+            Err("restricted: not an admin".to_string())
+        }
+    });
+    match authorized {
+        Ok(pubkey) => Ok(Ok(pubkey)),
+        Err(e) => {
+            let resp = Response::builder()
+                .status(401)
+                .header("content-type", "application/json")
+                .body(serde_json::json!({ "error": e }).to_string().into())
+                .map_err(Box::new)?;
+            Ok(Err(resp))
+        }
     }
-    let arr: Vec<message::CloseMsg> = ret.unwrap();
-    let message::CloseMsg::String(cmd) = &arr[0];
-    let message::CloseMsg::String(sub_id) = &arr[1];
+}
+
+/// The NIP-56 moderation queue for NIP-98 authenticated admins:
+/// `GET /reports` lists open reports and `POST /reports/<id>` with
+/// `{"action": "delete" | "ban" | "dismiss"}` resolves one.
+async fn function_handler_reports(event: &Request) -> Result<Response<Body>, Error> {
+    use nostr_relay_apigw::report::{self, Action};
 
-    Some(message::CloseCmd::new(cmd, sub_id))
+    if let Err(resp) = authorize_admin(event)? {
+        return Ok(resp);
+    }
+    let ddb = Ddb::new().await?;
+    let path = event.uri().path().trim_end_matches('/');
+    let (status, body) = if event.method() == "GET" && path.ends_with("/reports") {
+        match ddb.get_open_reports().await {
+            Ok(reports) => (200, serde_json::to_string_pretty(&reports).unwrap()),
+            Err(e) => (500, serde_json::json!({ "error": e }).to_string()),
+        }
+    } else if let (true, Some((_, id))) = (event.method() == "POST", path.rsplit_once('/')) {
+        let body: &[u8] = event.body().as_ref();
+        let action = serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .and_then(|v| v["action"].as_str().and_then(Action::parse));
+        match action {
+            Some(action) => match report::resolve(&ddb, id, action).await {
+                Ok(()) => (
+                    200,
+                    serde_json::json!({ "action": action.as_str() }).to_string(),
+                ),
+                Err(e) => (404, serde_json::json!({ "error": e }).to_string()),
+            },
+            None => (400, r#"{"error":"unknown action"}"#.to_string()),
+        }
+    } else {
+        (405, r#"{"error":"method not allowed"}"#.to_string())
+    };
+    let resp = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(body.into())
+        .map_err(Box::new)?;
+    Ok(resp)
 }
 
-async fn function_handler_http(_event: Request) -> Result<Response<Body>, Error> {
+/// NIP-86 relay management for NIP-98 authenticated admins: a JSON-RPC
+/// style `{"method": ..., "params": [...]}` POSTed to the relay URL.
+async fn function_handler_management(event: &Request) -> Result<Response<Body>, Error> {
+    use nostr_relay_apigw::nip86;
+
+    if let Err(resp) = authorize_admin(event)? {
+        return Ok(resp);
+    }
+    let body: &[u8] = event.body().as_ref();
+    let (status, result) = match serde_json::from_slice::<nip86::Request>(body) {
+        Ok(req) => {
+            println!("management: {} {:?}", req.method, req.params);
+            let result = nip86::handle(&Ddb::new().await?, &req).await;
+            (if result.is_ok() { 200 } else { 400 }, result)
+        }
+        Err(e) => (400, Err(format!("malformed request: {e}"))),
+    };
     let resp = Response::builder()
-        .status(200)
-        .header("content-type", "application/nostr+json")
-        .body(nostr_relay_apigw::nip11::json().into())
+        .status(status)
+        .header("content-type", "application/json")
+        .body(nip86::response(&result).to_string().into())
+        .map_err(Box::new)?;
+    Ok(resp)
+}
+
+/// End-to-end check of the store and the management API for a NIP-98
+/// authenticated admin.
+async fn function_handler_selftest(event: &Request) -> Result<Response<Body>, Error> {
+    use nostr_relay_apigw::selftest;
+    use std::time::Instant;
+
+    if let Err(resp) = authorize_admin(event)? {
+        return Ok(resp);
+    }
+
+    let mut checks = selftest::run(&Ddb::new().await?).await;
+    if let Some(endpoint) = &Config::global()?.websocket_endpoint {
+        let started = Instant::now();
+        let pinged = ApiGwMgmt::new(endpoint)
+            .await
+            .ping()
+            .await
+            .map(|_| endpoint.clone());
+        checks.push(selftest::check("management_api", started, pinged));
+    }
+    let report = selftest::Report::new(checks);
+
+    let resp = Response::builder()
+        .status(if report.ok { 200 } else { 503 })
+        .header("content-type", "application/json")
+        .body(serde_json::to_string_pretty(&report).unwrap().into())
         .map_err(Box::new)?;
     Ok(resp)
 }
@@ -97,29 +501,39 @@ async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
         return function_handler_http(event).await;
     }
 
-    let ctx = build_messagectx(&event);
-    if !event.body().is_empty() {
+    let mut ctx = build_messagectx(&event);
+    let ddb = Ddb::new().await?;
+    let mgmt = ApiGwMgmt::new(&ctx.endpoint).await;
+    let route_response = RouteReply::enabled();
+    let api = RouteReply::new(&mgmt, &ctx.connection_id, route_response);
+    let outcome = if !event.body().is_empty() {
         if let Body::Text(msg) = event.body() {
-            match &*ctx.command {
-                "EVENT" => relay::process_event(&ctx, &parse_eventmsg(msg)).await,
-                "REQ" => relay::process_req(&ctx, &parse_reqmsg(msg)).await,
-                "CLOSE" => relay::process_close(&ctx, &parse_closemsg(msg)).await,
-                c => println!("default: command: {c}"),
-            }
+            relay::process_message(&mut ctx, &ddb, &api, msg).await
+        } else {
+            relay::Outcome::Malformed
         }
     } else {
         match &*ctx.command {
-            "$disconnect" => relay::process_disconn(&ctx).await,
-            c => println!("default: command: {c}"),
+            "$connect" => relay::process_conn(&ctx, &ddb).await,
+            "$disconnect" => relay::process_disconn(&ctx, &ddb).await,
+            c => {
+                println!("default: command: {c}");
+                relay::Outcome::Malformed
+            }
         }
-    }
+    };
+    println!("outcome: {outcome:?}");
 
-    // Return something that implements IntoResponse.
-    // It will be serialized to the right response event automatically by the runtime
+    // A lone reply to the sender goes back as the route response body.
+    let body = match api.into_body() {
+        Some(frame) => frame,
+        None if route_response => String::new(),
+        None => "Hello AWS Lambda HTTP request".to_string(),
+    };
     let resp = Response::builder()
-        .status(200)
+        .status(status_code(&outcome))
         .header("content-type", "text/html")
-        .body("Hello AWS Lambda HTTP request".into())
+        .body(body.into())
         .map_err(Box::new)?;
     Ok(resp)
 }
@@ -134,46 +548,28 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
+    // A missing or malformed setting stops the function at startup.
+    Config::init().await?;
     run(service_fn(function_handler)).await
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_closemsg;
-    use super::parse_eventmsg;
-    use super::parse_reqmsg;
-    use serde_json;
-
-    #[test]
-    fn parse_reqmsg01() {
-        let msg = r#"["REQ", "sub_id01", {"authors": ["npub1xxx"]}]"#;
-        let ret = parse_reqmsg(msg).expect("REQ");
-        assert_eq!(
-            r#"{"cmd":"REQ","subscription_id":"sub_id01","filters":[{"authors":["npub1xxx"]}]}"#,
-            serde_json::to_string(&ret).unwrap()
-        );
-    }
-
-    #[test]
-    fn parse_eventmsg01() {
-        let msg = r#"["EVENT", {"id": "id01", "pubkey": "npub1yyy", "created_at": 1675949672, "kind": 0,
-                            "tags":[["e", "0000"], ["p", "1111"]],
-                            "content": "content",
-                            "sig": "sig01"}]"#;
-        let ret = parse_eventmsg(msg).expect("EVENT");
-        assert_eq!(
-            r#"{"cmd":"EVENT","event":{"id":"id01","pubkey":"npub1yyy","created_at":1675949672,"kind":0,"tags":[["e","0000"],["p","1111"]],"content":"content","sig":"sig01"}}"#,
-            serde_json::to_string(&ret).unwrap()
-        );
-    }
+    use super::status_code;
+    use nostr_relay_apigw::reject::RejectReason;
+    use nostr_relay_apigw::relay::Outcome;
 
     #[test]
-    fn parse_closemsg01() {
-        let msg = r#"["CLOSE", "sub_id01"]"#;
-        let ret = parse_closemsg(msg).expect("CLOSE");
+    fn status_code01() {
+        assert_eq!(200, status_code(&Outcome::Accepted { delivered: 2 }));
+        assert_eq!(200, status_code(&Outcome::Delivered(0)));
+        assert_eq!(200, status_code(&Outcome::Connected));
+        assert_eq!(200, status_code(&Outcome::Closed));
         assert_eq!(
-            r#"{"cmd":"CLOSE","subscription_id":"sub_id01"}"#,
-            serde_json::to_string(&ret).unwrap()
+            403,
+            status_code(&Outcome::Rejected(RejectReason::Blocked("x".into())))
         );
+        assert_eq!(400, status_code(&Outcome::Malformed));
+        assert_eq!(500, status_code(&Outcome::Error("error: x".into())));
     }
 }
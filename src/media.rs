@@ -0,0 +1,62 @@
+use crate::config::Config;
+use crate::nip96::Upload;
+use aws_sdk_s3::types::ByteStream;
+use aws_sdk_s3::Client;
+
+/// Uploaded files kept in the S3 bucket named by `NOSTR_MEDIA_BUCKET`,
+/// keyed by their sha256.
+pub struct MediaStore {
+    client: Client,
+    bucket: String,
+}
+
+impl MediaStore {
+    /// The store in `config.media_bucket`; None when it is not set.
+    pub async fn from_config(config: &Config) -> Option<MediaStore> {
+        let bucket = config.media_bucket.clone()?;
+        Some(MediaStore {
+            client: Client::new(crate::config::sdk_config().await),
+            bucket,
+        })
+    }
+
+    /// Stores `upload` on behalf of `pubkey` and returns its key.
+    pub async fn put(&self, pubkey: &str, upload: &Upload) -> Result<String, String> {
+        let key = upload.sha256();
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .content_type(&upload.content_type)
+            .metadata("uploader", pubkey)
+            .body(ByteStream::from(upload.data.clone()))
+            .send()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(key)
+    }
+
+    /// Deletes `key` if it was uploaded by `pubkey`.
+    pub async fn delete(&self, pubkey: &str, key: &str) -> Result<(), String> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|_| "not found".to_string())?;
+        let uploader = head.metadata().and_then(|m| m.get("uploader"));
+        if uploader.map(|u| u.as_str()) != Some(pubkey) {
+            return Err("restricted: not the uploader".to_string());
+        }
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+}
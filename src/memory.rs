@@ -0,0 +1,449 @@
+use crate::auth::Connection;
+use crate::denylist::Denylist;
+use crate::label::LabelEntry;
+use crate::message::{Event, Filter, KIND_GIFT_WRAP};
+use crate::metrics::{Gauges, Stats};
+use crate::store::EventStore;
+use crate::types::{EventId, Pubkey};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// EventStore kept in process memory, for tests that need a working store
+/// without DynamoDB or SQLite.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    events: HashMap<EventId, Event>,
+    /// Filters by connection and `sub_id`.
+    subscriptions: HashMap<(String, String), Vec<Filter>>,
+    connections: HashMap<String, Connection>,
+    greeted: HashSet<String>,
+    counters: HashMap<String, u64>,
+    bans: HashSet<String>,
+    banned_events: HashSet<String>,
+    allowed: HashSet<String>,
+    content_rules: HashSet<String>,
+    members: HashSet<String>,
+    /// Pubkeys by payment hash.
+    invoices: HashMap<String, String>,
+    labels: Vec<LabelEntry>,
+    stats: Stats,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Up to `limit` events between `since` and `until` (both inclusive)
+    /// matching `pred`, newest first; ties are ordered by id.
+    fn select(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+        pred: impl Fn(&Event) -> bool,
+    ) -> Vec<Event> {
+        let state = self.state.lock().unwrap();
+        let mut evs: Vec<Event> = state
+            .events
+            .values()
+            .filter(|ev| in_range(ev, since, until))
+            .filter(|ev| pred(ev))
+            .cloned()
+            .collect();
+        evs.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+        evs.truncate(limit.unwrap_or(100).max(1) as usize);
+        evs
+    }
+}
+
+fn in_range(ev: &Event, since: Option<u64>, until: Option<u64>) -> bool {
+    since.is_none_or(|s| ev.created_at >= s) && until.is_none_or(|u| ev.created_at <= u)
+}
+
+fn kind_in(kinds: &Option<Vec<u64>>, ev: &Event) -> bool {
+    kinds.as_ref().is_none_or(|ks| ks.contains(&ev.kind))
+}
+
+fn has_tag(ev: &Event, name: &str, values: &[&str]) -> bool {
+    ev.tags
+        .iter()
+        .any(|t| t.len() >= 2 && t[0] == name && values.contains(&t[1].as_str()))
+}
+
+#[async_trait]
+impl EventStore for MemoryStore {
+    async fn write_event(&self, ev: &Event) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.events.insert(ev.id.clone(), ev.clone());
+        Ok(())
+    }
+
+    async fn write_subscription(
+        &self,
+        conn_id: &str,
+        sub_id: &str,
+        filters: &[Filter],
+    ) -> Result<bool, String> {
+        let mut state = self.state.lock().unwrap();
+        let previous = state
+            .subscriptions
+            .insert((conn_id.to_string(), sub_id.to_string()), filters.to_vec());
+        Ok(previous.is_some())
+    }
+
+    async fn delete_subscriptions(
+        &self,
+        conn_id: &str,
+        sub_ids: Vec<String>,
+    ) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        for sub_id in sub_ids {
+            state.subscriptions.remove(&(conn_id.to_string(), sub_id));
+        }
+        Ok(())
+    }
+
+    async fn close_connection(&self, conn_id: &str) -> Result<usize, String> {
+        let mut state = self.state.lock().unwrap();
+        state.greeted.remove(conn_id);
+        state.connections.remove(conn_id);
+        let before = state.subscriptions.len();
+        state.subscriptions.retain(|(c, _), _| c != conn_id);
+        Ok(before - state.subscriptions.len())
+    }
+
+    async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)> {
+        let state = self.state.lock().unwrap();
+        let mut subs: Vec<_> = state
+            .subscriptions
+            .iter()
+            .map(|((conn_id, sub_id), filters)| (sub_id.clone(), conn_id.clone(), filters.clone()))
+            .collect();
+        subs.sort_by(|a, b| (&a.1, &a.0).cmp(&(&b.1, &b.0)));
+        subs
+    }
+
+    async fn get_event_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, String> {
+        let state = self.state.lock().unwrap();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.events.get(id).cloned())
+            .collect())
+    }
+
+    async fn get_event_by_id_prefixes(
+        &self,
+        prefixes: &[String],
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Ok(self.select(None, None, limit, |ev| {
+            prefixes.iter().any(|p| ev.id.starts_with(p.as_str()))
+        }))
+    }
+
+    async fn get_event_by_pubkey_prefixes(
+        &self,
+        prefixes: &[String],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Ok(self.select(since, until, limit, |ev| {
+            prefixes.iter().any(|p| ev.pubkey.starts_with(p.as_str())) && kind_in(&kinds, ev)
+        }))
+    }
+
+    async fn get_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Ok(self.select(since, until, limit, |ev| {
+            pubkeys.contains(&ev.pubkey) && kind_in(&kinds, ev)
+        }))
+    }
+
+    async fn delete_event_by_ids(&self, ids: Vec<EventId>) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        for id in ids {
+            state.events.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn get_event_by_channels(
+        &self,
+        channels: &[EventId],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let channels: Vec<&str> = channels.iter().map(|c| c.as_str()).collect();
+        Ok(self.select(since, until, limit, |ev| {
+            has_tag(ev, "e", &channels) && kind_in(&kinds, ev)
+        }))
+    }
+
+    async fn get_event_by_recipients(
+        &self,
+        recipients: &[Pubkey],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let recipients: Vec<&str> = recipients.iter().map(|r| r.as_str()).collect();
+        Ok(self.select(since, until, limit, |ev| {
+            ev.kind == KIND_GIFT_WRAP && has_tag(ev, "p", &recipients)
+        }))
+    }
+
+    async fn get_event_by_kinds(
+        &self,
+        kinds: &[u64],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Ok(self.select(since, until, limit, |ev| kinds.contains(&ev.kind)))
+    }
+
+    async fn get_event_by_time(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Ok(self.select(since, until, limit, |_| true))
+    }
+
+    async fn scan_events(&self, max_items: usize) -> Result<Vec<Event>, String> {
+        Ok(self.select(None, None, Some(max_items as i32), |_| true))
+    }
+
+    async fn count_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<u64, String> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .events
+            .values()
+            .filter(|ev| pubkeys.contains(&ev.pubkey) && kind_in(&kinds, ev))
+            .filter(|ev| in_range(ev, since, until))
+            .count() as u64)
+    }
+
+    async fn adjust_gauges(&self, connections: i64, subscriptions: i64) -> Result<Gauges, String> {
+        let mut state = self.state.lock().unwrap();
+        state.stats.connections += connections;
+        state.stats.subscriptions += subscriptions;
+        Ok(Gauges {
+            connections: state.stats.connections,
+            subscriptions: state.stats.subscriptions,
+        })
+    }
+
+    async fn add_ban(&self, pubkey: &str) -> Result<(), String> {
+        self.state.lock().unwrap().bans.insert(pubkey.to_string());
+        Ok(())
+    }
+
+    async fn remove_ban(&self, pubkey: &str) -> Result<(), String> {
+        self.state.lock().unwrap().bans.remove(pubkey);
+        Ok(())
+    }
+
+    async fn is_banned(&self, pubkey: &str) -> Result<bool, String> {
+        Ok(self.state.lock().unwrap().bans.contains(pubkey))
+    }
+
+    async fn add_banned_event(&self, id: &str) -> Result<(), String> {
+        self.state
+            .lock()
+            .unwrap()
+            .banned_events
+            .insert(id.to_string());
+        Ok(())
+    }
+
+    async fn remove_banned_event(&self, id: &str) -> Result<(), String> {
+        self.state.lock().unwrap().banned_events.remove(id);
+        Ok(())
+    }
+
+    async fn get_denylist(&self) -> Result<Denylist, String> {
+        let state = self.state.lock().unwrap();
+        Ok(Denylist::new(
+            state.bans.iter().cloned(),
+            state.banned_events.iter().cloned(),
+        ))
+    }
+
+    async fn add_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        self.state
+            .lock()
+            .unwrap()
+            .allowed
+            .insert(pubkey.to_string());
+        Ok(())
+    }
+
+    async fn remove_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        self.state.lock().unwrap().allowed.remove(pubkey);
+        Ok(())
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>, String> {
+        Ok(self.state.lock().unwrap().allowed.iter().cloned().collect())
+    }
+
+    async fn add_content_rule(&self, rule: &str) -> Result<(), String> {
+        self.state
+            .lock()
+            .unwrap()
+            .content_rules
+            .insert(rule.to_string());
+        Ok(())
+    }
+
+    async fn get_content_rules(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .content_rules
+            .iter()
+            .cloned()
+            .collect())
+    }
+
+    async fn add_member(&self, pubkey: &str) -> Result<(), String> {
+        self.state
+            .lock()
+            .unwrap()
+            .members
+            .insert(pubkey.to_string());
+        Ok(())
+    }
+
+    async fn is_member(&self, pubkey: &str) -> Result<bool, String> {
+        Ok(self.state.lock().unwrap().members.contains(pubkey))
+    }
+
+    async fn write_invoice(&self, payment_hash: &str, pubkey: &str) -> Result<(), String> {
+        self.state
+            .lock()
+            .unwrap()
+            .invoices
+            .insert(payment_hash.to_string(), pubkey.to_string());
+        Ok(())
+    }
+
+    async fn get_invoice_pubkey(&self, payment_hash: &str) -> Result<Option<String>, String> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .invoices
+            .get(payment_hash)
+            .cloned())
+    }
+
+    async fn mark_greeted(&self, conn_id: &str) -> Result<bool, String> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .greeted
+            .insert(conn_id.to_string()))
+    }
+
+    async fn increment_counter(&self, key: &str, _ttl: u64) -> Result<u64, String> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.counters.entry(key.to_string()).or_default();
+        *count += 1;
+        Ok(*count)
+    }
+
+    async fn write_connection(
+        &self,
+        conn_id: &str,
+        challenge: &str,
+        source_ip: Option<&str>,
+    ) -> Result<(), String> {
+        let record = Connection {
+            challenge: challenge.to_string(),
+            auth_pubkey: None,
+            source_ip: source_ip.map(str::to_string),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.connections.insert(conn_id.to_string(), record);
+        Ok(())
+    }
+
+    async fn get_connection(&self, conn_id: &str) -> Result<Option<Connection>, String> {
+        Ok(self.state.lock().unwrap().connections.get(conn_id).cloned())
+    }
+
+    async fn set_auth_pubkey(&self, conn_id: &str, pubkey: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        match state.connections.get_mut(conn_id) {
+            Some(record) => {
+                record.auth_pubkey = Some(pubkey.to_string());
+                Ok(())
+            }
+            None => Err(format!("no connection {conn_id}")),
+        }
+    }
+
+    async fn add_rejection(&self, kind: &str, reason: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        *state
+            .stats
+            .rejections
+            .entry(kind.to_string())
+            .or_default()
+            .entry(reason.to_string())
+            .or_default() += 1;
+        Ok(())
+    }
+
+    async fn get_stats(&self) -> Result<Stats, String> {
+        Ok(self.state.lock().unwrap().stats.clone())
+    }
+
+    async fn write_labels(&self, _ev: &Event, entries: &[LabelEntry]) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.labels.extend_from_slice(entries);
+        Ok(())
+    }
+
+    async fn get_label_targets(
+        &self,
+        namespace: &str,
+        value: &str,
+    ) -> Result<Vec<LabelEntry>, String> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .labels
+            .iter()
+            .filter(|e| e.namespace == namespace && e.value == value)
+            .cloned()
+            .collect())
+    }
+}
@@ -29,10 +29,16 @@ THE SOFTWARE.
 
 */
 
-use crate::ddb::{QueryByIds, QueryByPubkeys, QueryPlan};
+use crate::policy::RelayPolicy;
+use crate::reject::RejectReason;
+use crate::store::{
+    CountByPubkeys, QueryByAddress, QueryByChannels, QueryByIds, QueryByKinds, QueryByPubkeys,
+    QueryByRecipients, QueryByScan, QueryByTime, QueryPlan,
+};
+use crate::types::{is_lower_hex, EventId, Pubkey, Signature};
 use once_cell::sync::Lazy;
 use secp256k1::hashes::{sha256, Hash};
-use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
+use secp256k1::{schnorr, KeyPair, Secp256k1, SignOnly, VerifyOnly, XOnlyPublicKey};
 use serde::de::Unexpected;
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Serialize};
@@ -41,17 +47,48 @@ use serde_json::Number;
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 
+/// Kinds are 16 bit unsigned integers per NIP-01.
+const MAX_KIND: u64 = 65535;
+
+/// Most NIP-33 addresses a single filter is looked up by.
+const MAX_ADDRESSES: usize = 100;
+
+/// NIP-09 event deletion kind.
+pub const KIND_DELETION: u64 = 5;
+
+/// NIP-28 public chat kinds.
+pub const KIND_CHANNEL_CREATE: u64 = 40;
+pub const KIND_CHANNEL_METADATA: u64 = 41;
+pub const KIND_CHANNEL_MESSAGE: u64 = 42;
+
+/// Most channels a filter is looked up by.
+const MAX_CHANNELS: usize = 20;
+
+/// NIP-59 gift wrap kind, used for NIP-17 direct messages.
+pub const KIND_GIFT_WRAP: u64 = 1059;
+
+/// Most recipients a filter is looked up by.
+const MAX_RECIPIENTS: usize = 20;
+
+/// Most kinds a filter without authors is looked up by.
+const MAX_KINDS: usize = 10;
+
+/// Width of the id and pubkey prefixes the store indexes, and so the
+/// shortest prefix that can be looked up.
+pub const MIN_PREFIX_LENGTH: usize = 4;
+
 static SECP: Lazy<Secp256k1<VerifyOnly>> = Lazy::new(Secp256k1::verification_only);
+static SECP_SIGN: Lazy<Secp256k1<SignOnly>> = Lazy::new(Secp256k1::signing_only);
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Event {
-    pub id: String,
-    pub pubkey: String,
+    pub id: EventId,
+    pub pubkey: Pubkey,
     pub created_at: u64,
     pub kind: u64,
     pub tags: Vec<Vec<String>>,
     pub content: String,
-    pub sig: String,
+    pub sig: Signature,
 }
 
 impl Event {
@@ -60,7 +97,7 @@ impl Event {
 
         let id = Number::from(0);
         v.push(serde_json::Value::Number(id));
-        v.push(serde_json::Value::String(self.pubkey.clone()));
+        v.push(serde_json::Value::String(self.pubkey.to_string()));
         let created_at = Number::from(self.created_at);
         v.push(serde_json::Value::Number(created_at));
         let kind = Number::from(self.kind);
@@ -82,6 +119,36 @@ impl Event {
         serde_json::Value::Array(v)
     }
 
+    /// The JSON of the event compressed with zstd, as the stores keep it
+    /// when `NOSTR_COMPRESS_EVENTS` is set.
+    pub fn to_zstd(&self) -> Vec<u8> {
+        let json = serde_json::to_vec(self).unwrap();
+        zstd::encode_all(json.as_slice(), zstd::DEFAULT_COMPRESSION_LEVEL).unwrap()
+    }
+
+    /// Keys under which the subscriptions that may match this event are
+    /// indexed, see [`Filter::match_keys`].
+    pub fn match_keys(&self) -> Vec<String> {
+        let mut keys = vec![
+            "*".to_string(),
+            format!("id:{}", self.id),
+            format!("author:{}", self.pubkey),
+            format!("kind:{}", self.kind),
+        ];
+        for tag in self.tags.iter().filter(|t| t.len() >= 2) {
+            let name = tag[0].chars().next().unwrap_or_default();
+            keys.extend(tag[1..].iter().map(|v| format!("tag:{name}:{v}")));
+        }
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    pub fn from_zstd(bytes: &[u8]) -> Result<Event, String> {
+        let json = zstd::decode_all(bytes).map_err(|e| format!("zstd: {e}"))?;
+        serde_json::from_slice(&json).map_err(|e| format!("{e:?}"))
+    }
+
     pub fn digest(&self) -> sha256::Hash {
         sha256::Hash::hash(self.to_canonical().unwrap_or("".into()).as_bytes())
     }
@@ -93,7 +160,9 @@ impl Event {
 
     pub fn validate(&self) -> Result<(), &str> {
         let digest = self.digest();
-        let sig = schnorr::Signature::from_str(&self.sig).unwrap();
+        let Ok(sig) = schnorr::Signature::from_str(&self.sig) else {
+            return Err("EventInvalidSignature");
+        };
         if let Ok(msg) = secp256k1::Message::from_slice(digest.as_ref()) {
             if let Ok(pubkey) = XOnlyPublicKey::from_str(&self.pubkey) {
                 SECP.verify_schnorr(&sig, &msg, &pubkey)
@@ -108,9 +177,108 @@ impl Event {
         }
     }
 
+    /// Builds an event signed by `keys`.
+    pub fn sign(
+        keys: &KeyPair,
+        created_at: u64,
+        kind: u64,
+        tags: Vec<Vec<String>>,
+        content: &str,
+    ) -> Event {
+        let (pubkey, _) = keys.x_only_public_key();
+        let mut ev = Event {
+            id: "0".repeat(64).parse().unwrap(),
+            pubkey: pubkey.to_string().parse().unwrap(),
+            created_at,
+            kind,
+            tags,
+            content: content.to_string(),
+            sig: "0".repeat(128).parse().unwrap(),
+        };
+        let digest = ev.digest();
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        ev.id = format!("{digest:x}").parse().unwrap();
+        ev.sig = SECP_SIGN
+            .sign_schnorr_no_aux_rand(&msg, keys)
+            .to_string()
+            .parse()
+            .unwrap();
+        ev
+    }
+
     pub fn is_nip16_ephemeral(&self) -> bool {
         20000 <= self.kind && self.kind < 30000
     }
+
+    /// Metadata, contact lists and NIP-16 replaceable events.
+    pub fn is_replaceable(&self) -> bool {
+        self.kind == 0 || self.kind == 3 || (10000 <= self.kind && self.kind < 20000)
+    }
+
+    /// NIP-33 parameterized replaceable events.
+    pub fn is_parameterized_replaceable(&self) -> bool {
+        30000 <= self.kind && self.kind < 40000
+    }
+
+    /// The NIP-33 `d` tag value, empty when there is none.
+    pub fn d_tag(&self) -> &str {
+        self.tags
+            .iter()
+            .find(|t| !t.is_empty() && t[0] == "d")
+            .and_then(|t| t.get(1))
+            .map_or("", |d| d.as_str())
+    }
+
+    /// The NIP-28 channel a channel creation, metadata or message event
+    /// belongs to: the id of the creation event.
+    pub fn channel_id(&self) -> Option<&str> {
+        match self.kind {
+            KIND_CHANNEL_CREATE => Some(self.id.as_str()),
+            KIND_CHANNEL_METADATA | KIND_CHANNEL_MESSAGE => {
+                let es: Vec<&Vec<String>> = self
+                    .tags
+                    .iter()
+                    .filter(|t| t.len() >= 2 && t[0] == "e")
+                    .collect();
+                es.iter()
+                    .find(|t| t.get(3).is_some_and(|m| m == "root"))
+                    .or(es.first())
+                    .map(|t| t[1].as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// The recipient of a NIP-59 gift wrap, from its `p` tag.
+    pub fn recipient(&self) -> Option<&str> {
+        if self.kind != KIND_GIFT_WRAP {
+            return None;
+        }
+        self.tags
+            .iter()
+            .find(|t| t.len() >= 2 && t[0] == "p")
+            .map(|t| t[1].as_str())
+    }
+
+    /// The NIP-40 `expiration` timestamp, if the event has a valid one.
+    pub fn expiration(&self) -> Option<u64> {
+        self.tags
+            .iter()
+            .find(|t| t.len() >= 2 && t[0] == "expiration")
+            .and_then(|t| t[1].parse().ok())
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expiration().is_some_and(|exp| exp <= now)
+    }
+
+    /// The NIP-36 `content-warning` reason, empty if none was given.
+    pub fn content_warning(&self) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|t| !t.is_empty() && t[0] == "content-warning")
+            .map(|t| t.get(1).map_or("", |r| r.as_str()))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -119,6 +287,12 @@ pub struct MessageContext {
     pub endpoint: String,
     pub command: String,
     pub create_at: u64,
+    /// Pubkey the connection has authenticated as, if any.
+    pub auth_pubkey: Option<String>,
+    /// NIP-42 challenge issued to the connection, once its record is loaded.
+    pub challenge: Option<String>,
+    /// Address the connection was opened from, if known.
+    pub source_ip: Option<String>,
 }
 
 impl MessageContext {
@@ -133,6 +307,9 @@ impl MessageContext {
             endpoint: endpoint.into(),
             command: command.into(),
             create_at,
+            auth_pubkey: None,
+            challenge: None,
+            source_ip: None,
         }
     }
 }
@@ -218,7 +395,14 @@ impl<'de> Deserialize<'de> for Filter {
                 }
                 f.ids = raw_ids;
             } else if key == "kinds" {
-                f.kinds = Deserialize::deserialize(val).ok();
+                let raw_kinds: Option<Vec<u64>> = Deserialize::deserialize(val).ok();
+                if raw_kinds.is_none() && !val.is_null() {
+                    return Err(serde::de::Error::invalid_type(
+                        Unexpected::Other("kinds must be non-negative integers"),
+                        &"a json object",
+                    ));
+                }
+                f.kinds = raw_kinds;
             } else if key == "since" {
                 f.since = Deserialize::deserialize(val).ok();
             } else if key == "until" {
@@ -258,6 +442,20 @@ impl<'de> Deserialize<'de> for Filter {
     }
 }
 
+/// Splits ids or authors into complete values and the prefixes at least
+/// `min_length` long.
+fn split_prefixes<T: FromStr>(values: &[String], min_length: usize) -> (Vec<T>, Vec<String>) {
+    let (full, prefixes): (Vec<&String>, Vec<&String>) = values.iter().partition(|v| v.len() == 64);
+    (
+        full.iter().filter_map(|v| v.parse().ok()).collect(),
+        prefixes
+            .into_iter()
+            .filter(|p| p.len() >= min_length)
+            .cloned()
+            .collect(),
+    )
+}
+
 fn tag_search_char_from_filter(tagname: &str) -> Option<char> {
     let tagname_nohash = &tagname[1..];
     let mut tagnamechars = tagname_nohash.chars();
@@ -288,13 +486,13 @@ impl Filter {
     fn ids_match(&self, event: &Event) -> bool {
         self.ids
             .as_ref()
-            .map_or(true, |vs| prefix_match(vs, &event.id))
+            .is_none_or(|vs| prefix_match(vs, &event.id))
     }
 
     fn authors_match(&self, event: &Event) -> bool {
         self.authors
             .as_ref()
-            .map_or(true, |vs| prefix_match(vs, &event.pubkey))
+            .is_none_or(|vs| prefix_match(vs, &event.pubkey))
     }
 
     fn tag_match(&self, event: &Event) -> bool {
@@ -317,34 +515,299 @@ impl Filter {
     }
 
     fn kind_match(&self, kind: u64) -> bool {
-        self.kinds.as_ref().map_or(true, |ks| ks.contains(&kind))
+        self.kinds.as_ref().is_none_or(|ks| ks.contains(&kind))
+    }
+
+    /// Keys of which a matching event carries at least one, taken from the
+    /// most selective condition of the filter; `*` when any event may match.
+    ///
+    /// Prefixes of ids and authors are not indexed and fall through to the
+    /// next condition.
+    pub fn match_keys(&self) -> Vec<String> {
+        let exact = |vs: &Vec<String>| vs.iter().all(|v| v.len() == 64);
+        if let Some(ids) = self.ids.as_ref().filter(|vs| exact(vs)) {
+            return ids.iter().map(|id| format!("id:{id}")).collect();
+        }
+        if let Some(authors) = self.authors.as_ref().filter(|vs| exact(vs)) {
+            return authors.iter().map(|a| format!("author:{a}")).collect();
+        }
+        let tag = self
+            .tags
+            .as_ref()
+            .and_then(|tags| tags.iter().min_by_key(|(_, vs)| vs.len()));
+        if let Some((name, values)) = tag {
+            return values.iter().map(|v| format!("tag:{name}:{v}")).collect();
+        }
+        if let Some(kinds) = &self.kinds {
+            return kinds.iter().map(|k| format!("kind:{k}")).collect();
+        }
+        vec!["*".to_string()]
     }
 
     pub fn event_match(&self, event: &Event) -> bool {
         self.ids_match(event)
-            && self.since.map_or(true, |t| event.created_at > t)
-            && self.until.map_or(true, |t| event.created_at < t)
+            && self.since.is_none_or(|t| event.created_at > t)
+            && self.until.is_none_or(|t| event.created_at < t)
             && self.kind_match(event.kind)
             && self.authors_match(event)
             && self.tag_match(event)
     }
 
-    pub fn query_plan(&self) -> QueryPlan {
+    /// Rejects filters that can never match anything sensible.
+    pub fn validate(&self) -> Result<(), RejectReason> {
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if until < since {
+                return Err(RejectReason::Invalid(
+                    "until is earlier than since".to_string(),
+                ));
+            }
+        }
+        if self.limit.is_some_and(|l| l < 0) {
+            return Err(RejectReason::Invalid(
+                "limit must not be negative".to_string(),
+            ));
+        }
+        if let Some(kinds) = &self.kinds {
+            if kinds.iter().any(|k| *k > MAX_KIND) {
+                return Err(RejectReason::Invalid(format!(
+                    "kinds must be between 0 and {MAX_KIND}"
+                )));
+            }
+        }
+        if let Some(tags) = &self.tags {
+            if let Some((k, _)) = tags.iter().find(|(_, vs)| vs.is_empty()) {
+                return Err(RejectReason::Invalid(format!("#{k} must not be empty")));
+            }
+        }
+
+        let max_values = RelayPolicy::current().limits.max_filter_values;
+        let unsupported = |msg: String| Err(RejectReason::Unsupported(msg));
+        // Ids and authors are matched as prefixes of lowercase hex values.
+        for (name, values) in [("ids", &self.ids), ("authors", &self.authors)] {
+            let Some(values) = values else {
+                continue;
+            };
+            if values.len() > max_values {
+                return unsupported(format!("too many {name}"));
+            }
+            if !values.iter().all(|v| v.len() <= 64 && is_lower_hex(v)) {
+                return unsupported(format!("{name} must be lowercase hex"));
+            }
+        }
+        if self.kinds.as_ref().is_some_and(|ks| ks.len() > max_values) {
+            return unsupported("too many kinds".to_string());
+        }
+        if let Some((k, _)) = self
+            .tags
+            .iter()
+            .flatten()
+            .find(|(_, vs)| vs.len() > max_values)
+        {
+            return unsupported(format!("too many #{k} values"));
+        }
+        Ok(())
+    }
+
+    /// Whether the filter only matches events `#p` tagged with `pubkey`.
+    pub fn is_addressed_to(&self, pubkey: &str) -> bool {
+        let Some(tags) = &self.tags else {
+            return false;
+        };
+        tags.get(&'p')
+            .is_some_and(|ps| !ps.is_empty() && ps.iter().all(|p| p == pubkey))
+    }
+
+    /// `until` for stores, which read inclusive ranges, while `event_match`
+    /// keeps only events created before it.
+    fn store_until(&self) -> Option<u64> {
+        self.until.map(|t| t.saturating_sub(1))
+    }
+
+    pub fn limit(&self) -> Option<i32> {
+        self.limit
+    }
+
+    /// The filter narrowed to `limit` events created before `until`, for
+    /// reading stored events a page at a time.
+    pub fn page(&self, until: Option<u64>, limit: i32) -> Filter {
+        Filter {
+            until: match (self.until, until) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            limit: Some(limit),
+            ..self.clone()
+        }
+    }
+
+    /// `"limit": 0` asks for future events only, without stored ones.
+    pub fn is_live_only(&self) -> bool {
+        self.limit == Some(0)
+    }
+
+    pub fn query_plan(&self) -> QueryPlan<'_> {
+        // Prefixes shorter than `Limits::min_prefix_length` are not looked
+        // up and find nothing.
+        let limits = &RelayPolicy::current().limits;
         if let Some(ids) = &self.ids {
-            return QueryPlan::ByIds(QueryByIds::new(self, ids.to_vec()));
+            let (ids, prefixes) = split_prefixes(ids, limits.min_prefix_length);
+            return QueryPlan::ByIds(QueryByIds::new(
+                self,
+                ids,
+                prefixes,
+                Some(limits.clamp(self.limit)),
+            ));
+        }
+        if let Some(plan) = self.address_plan() {
+            return QueryPlan::ByAddress(plan);
+        }
+        if let Some(plan) = self.channel_plan() {
+            return QueryPlan::ByChannels(plan);
+        }
+        if let Some(plan) = self.recipient_plan() {
+            return QueryPlan::ByRecipients(plan);
         }
         if let Some(authors) = &self.authors {
+            let (authors, prefixes) = split_prefixes(authors, limits.min_prefix_length);
             return QueryPlan::ByPubkeys(QueryByPubkeys::new(
                 self,
-                authors.to_vec(),
+                authors,
+                prefixes,
                 self.kinds.clone(),
                 self.since,
-                self.until,
-                self.limit,
+                self.store_until(),
+                Some(limits.clamp(self.limit)),
             ));
         }
+        if let Some(plan) = self.kind_plan() {
+            return QueryPlan::ByKinds(plan);
+        }
+        // "Everything since T" subscriptions.
+        if self.tags.is_none() && self.kinds.is_none() {
+            return QueryPlan::ByTime(QueryByTime::new(
+                self,
+                self.since,
+                self.store_until(),
+                Some(RelayPolicy::current().limits.clamp(self.limit)),
+            ));
+        }
+        if let Some(max_items) = limits.max_scan_items {
+            return QueryPlan::BoundedScan(QueryByScan::new(
+                self,
+                max_items,
+                limits.clamp(self.limit),
+            ));
+        }
+
+        QueryPlan::NoPlan(RejectReason::Unsupported(
+            "filter needs ids, authors, kinds or a time range".to_string(),
+        ))
+    }
+
+    /// Lookup of NIP-33 addresses for filters on authors, parameterized
+    /// replaceable kinds and `#d` only, as clients fetching addressable
+    /// content send them.
+    fn address_plan(&self) -> Option<QueryByAddress<'_>> {
+        let (Some(authors), Some(kinds), Some(tags)) = (&self.authors, &self.kinds, &self.tags)
+        else {
+            return None;
+        };
+        let ds = tags.get(&'d').filter(|_| tags.len() == 1)?;
+        if !kinds.iter().all(|k| (30000..40000).contains(k))
+            || authors.len() * kinds.len() * ds.len() > MAX_ADDRESSES
+        {
+            return None;
+        }
+        let mut addresses = vec![];
+        for author in authors.iter().filter_map(|a| a.parse::<Pubkey>().ok()) {
+            for kind in kinds {
+                for d in ds {
+                    addresses.push((author.clone(), *kind, d.clone()));
+                }
+            }
+        }
+        Some(QueryByAddress::new(self, addresses))
+    }
+
+    /// Lookup of NIP-28 channel timelines for filters on channel metadata
+    /// or message kinds and the channels' `#e` alone.
+    fn channel_plan(&self) -> Option<QueryByChannels<'_>> {
+        let (Some(kinds), Some(tags), None) = (&self.kinds, &self.tags, &self.authors) else {
+            return None;
+        };
+        let es = tags.get(&'e').filter(|_| tags.len() == 1)?;
+        if kinds.is_empty()
+            || !kinds
+                .iter()
+                .all(|k| [KIND_CHANNEL_METADATA, KIND_CHANNEL_MESSAGE].contains(k))
+            || es.len() > MAX_CHANNELS
+        {
+            return None;
+        }
+        Some(QueryByChannels::new(
+            self,
+            es.iter().filter_map(|e| e.parse().ok()).collect(),
+            self.kinds.clone(),
+            self.since,
+            self.store_until(),
+            Some(RelayPolicy::current().limits.clamp(self.limit)),
+        ))
+    }
+
+    /// Lookup of gift wraps for filters on kind 1059 and `#p` alone, as
+    /// NIP-17 clients fetch their direct messages.
+    fn recipient_plan(&self) -> Option<QueryByRecipients<'_>> {
+        let (Some(kinds), Some(tags), None) = (&self.kinds, &self.tags, &self.authors) else {
+            return None;
+        };
+        let ps = tags.get(&'p').filter(|_| tags.len() == 1)?;
+        if kinds != &[KIND_GIFT_WRAP] || ps.len() > MAX_RECIPIENTS {
+            return None;
+        }
+        Some(QueryByRecipients::new(
+            self,
+            ps.iter().filter_map(|p| p.parse().ok()).collect(),
+            self.since,
+            self.store_until(),
+            Some(RelayPolicy::current().limits.clamp(self.limit)),
+        ))
+    }
 
-        QueryPlan::NoPlan("invalid: we do not support this filter".to_string())
+    /// Global timelines of a few kinds, such as `{"kinds": [1]}`. Tags are
+    /// left to the filter match, so a REQ keeps reading pages of the kinds
+    /// until the limit is met or the store runs out.
+    fn kind_plan(&self) -> Option<QueryByKinds<'_>> {
+        let kinds = self.kinds.as_ref()?;
+        if kinds.is_empty() || kinds.len() > MAX_KINDS {
+            return None;
+        }
+        Some(QueryByKinds::new(
+            self,
+            kinds.clone(),
+            self.since,
+            self.store_until(),
+            Some(RelayPolicy::current().limits.clamp(self.limit)),
+        ))
+    }
+
+    /// Plan for a NIP-45 COUNT: filters on authors, kinds and time alone are
+    /// counted by the store, others are queried and their results counted.
+    pub fn count_plan(&self) -> QueryPlan<'_> {
+        match &self.authors {
+            Some(authors)
+                if self.ids.is_none()
+                    && self.tags.is_none()
+                    && authors.iter().all(|a| a.len() == 64) =>
+            {
+                QueryPlan::Count(CountByPubkeys::new(
+                    authors.iter().filter_map(|a| a.parse().ok()).collect(),
+                    self.kinds.clone(),
+                    self.since,
+                    self.store_until(),
+                ))
+            }
+            _ => self.query_plan(),
+        }
     }
 }
 
@@ -415,6 +878,70 @@ impl CloseCmd {
     }
 }
 
+/// Parses `["EVENT", event]` and `["AUTH", event]`.
+pub fn parse_eventmsg(message: &str) -> Option<EventCmd> {
+    let ret = serde_json::from_str(message);
+    if let Err(err) = ret {
+        println!("err: {err}");
+        return None;
+    }
+    let arr: Vec<EventMsg> = ret.unwrap();
+    if let (EventMsg::String(cmd), EventMsg::Event(ev)) = (&arr[0], &arr[1]) {
+        Some(EventCmd::new(cmd, ev))
+    } else {
+        None
+    }
+}
+
+/// Parses `["REQ", sub_id, filters...]` and COUNT.
+pub fn parse_reqmsg(message: &str) -> Option<ReqCmd> {
+    let ret = serde_json::from_str(message);
+    if let Err(err) = ret {
+        println!("err: {err}");
+        return None;
+    }
+    let arr: Vec<ReqMsg> = ret.unwrap();
+    let cmd = if let ReqMsg::String(cmd) = &arr[0] {
+        cmd
+    } else {
+        return None;
+    };
+    let sub_id = if let ReqMsg::String(sub_id) = &arr[1] {
+        sub_id
+    } else {
+        return None;
+    };
+    let mut fs = vec![];
+    for v in arr[2..].iter() {
+        if let ReqMsg::Filter(fl) = v {
+            fs.push(fl.clone())
+        }
+    }
+
+    Some(ReqCmd::new(cmd, sub_id, fs))
+}
+
+/// Parses `["CLOSE", sub_id]`.
+pub fn parse_closemsg(message: &str) -> Option<CloseCmd> {
+    let ret = serde_json::from_str(message);
+    if let Err(err) = ret {
+        println!("err: {err}");
+        return None;
+    }
+    let arr: Vec<CloseMsg> = ret.unwrap();
+    let CloseMsg::String(cmd) = &arr[0];
+    let CloseMsg::String(sub_id) = &arr[1];
+
+    Some(CloseCmd::new(cmd, sub_id))
+}
+
+/// The nostr verb of a message, for the `$default` route where API Gateway
+/// did not select the route by it.
+pub fn body_verb(message: &str) -> Option<String> {
+    let arr: Vec<serde_json::Value> = serde_json::from_str(message).ok()?;
+    arr.first()?.as_str().map(|s| s.to_string())
+}
+
 /// https://github.com/nostr-protocol/nips/blob/master/20.md
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(untagged)]
@@ -423,28 +950,43 @@ pub enum CommandResult {
     Bool(bool),
 }
 
+/// A kind 1 event with padded id, pubkey and sig, for test fixtures.
+#[cfg(test)]
+pub(crate) fn test_event() -> Event {
+    Event {
+        id: EventId::padded("1d01"),
+        pubkey: Pubkey::padded("b01"),
+        created_at: 1676118868,
+        kind: 1,
+        tags: vec![],
+        content: "".into(),
+        sig: Signature::padded(""),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::{HashMap, HashSet};
 
     use super::Event;
     use super::Filter;
+    use super::{body_verb, parse_closemsg, parse_eventmsg, parse_reqmsg};
 
     fn build_event01() -> Event {
         Event {
-            id: "87ae4ae2974e96e857856fe5f677d412df40cb331378fd1b20e0ed78910629a2".into(),
-            pubkey: "98f4285bcb2cc65c3a66bd77ccffd2563ed3303e7e02a489c63a887fcd06bbe5".into(),
+            id: "87ae4ae2974e96e857856fe5f677d412df40cb331378fd1b20e0ed78910629a2".parse().unwrap(),
+            pubkey: "98f4285bcb2cc65c3a66bd77ccffd2563ed3303e7e02a489c63a887fcd06bbe5".parse().unwrap(),
             created_at: 1676118868,
             kind: 1,
             tags: [].to_vec(),
             content: "hello!".into(),
-            sig: "e9bfd020031ae702d5af21f029613d8a7957bfc269d5a8da36a79c2ff696f54db68e3ccd4111171f61335fa89369cbe96fa45b2a032061726a04afa157df32eb".into()
+            sig: "e9bfd020031ae702d5af21f029613d8a7957bfc269d5a8da36a79c2ff696f54db68e3ccd4111171f61335fa89369cbe96fa45b2a032061726a04afa157df32eb".parse().unwrap()
         }
     }
 
     fn build_event01_but_broken_sig() -> Event {
         Event {
-            sig: "000fd020031ae702d5af21f029613d8a7957bfc269d5a8da36a79c2ff696f54db68e3ccd4111171f61335fa89369cbe96fa45b2a032061726a04afa157df32eb".into(),
+            sig: "000fd020031ae702d5af21f029613d8a7957bfc269d5a8da36a79c2ff696f54db68e3ccd4111171f61335fa89369cbe96fa45b2a032061726a04afa157df32eb".parse().unwrap(),
             ..build_event01()
         }
     }
@@ -492,8 +1034,8 @@ mod tests {
         tags.insert('p', tag_p);
 
         Filter {
-            ids: Some(vec!["id1".into(), "id2".into()]),
-            authors: Some(vec!["pub1".into(), "pub2".into()]),
+            ids: Some(vec!["1d1".into(), "1d2".into()]),
+            authors: Some(vec!["b1".into(), "b2".into()]),
             kinds: Some(vec![0]),
             tags: Some(tags),
             since: Some(1),
@@ -593,4 +1135,141 @@ mod tests {
         };
         assert!(fl.event_match(&ev));
     }
+
+    #[test]
+    fn filter_live_only() {
+        let fl: Filter = serde_json::from_str(r#"{"kinds": [1], "limit": 0}"#).unwrap();
+        assert!(fl.is_live_only());
+        let fl: Filter = serde_json::from_str(r#"{"kinds": [1], "limit": 10}"#).unwrap();
+        assert!(!fl.is_live_only());
+        let fl: Filter = serde_json::from_str(r#"{"kinds": [1]}"#).unwrap();
+        assert!(!fl.is_live_only());
+    }
+
+    #[test]
+    fn filter_count_plan() {
+        use crate::store::QueryPlan;
+
+        let author = "a".repeat(64);
+        let fl: Filter =
+            serde_json::from_str(&format!(r#"{{"authors": ["{author}"], "kinds": [3]}}"#)).unwrap();
+        assert!(matches!(fl.count_plan(), QueryPlan::Count(_)));
+        let fl: Filter =
+            serde_json::from_str(&format!(r##"{{"authors": ["{author}"], "#p": ["b"]}}"##))
+                .unwrap();
+        assert!(matches!(fl.count_plan(), QueryPlan::ByPubkeys(_)));
+        let fl: Filter = serde_json::from_str(r#"{"kinds": [3]}"#).unwrap();
+        assert!(matches!(fl.count_plan(), QueryPlan::ByKinds(_)));
+        let fl: Filter = serde_json::from_str(r#"{"since": 1676118868}"#).unwrap();
+        assert!(matches!(fl.count_plan(), QueryPlan::ByTime(_)));
+        let fl: Filter = serde_json::from_str(r##"{"#t": ["nostr"]}"##).unwrap();
+        assert!(matches!(fl.count_plan(), QueryPlan::NoPlan(_)));
+    }
+
+    #[test]
+    fn filter_validate() {
+        let fl: Filter =
+            serde_json::from_str(r#"{"kinds": [1], "since": 10, "until": 20}"#).unwrap();
+        assert!(fl.validate().is_ok());
+
+        for (json, reason) in [
+            (
+                r#"{"since": 20, "until": 10}"#,
+                "invalid: until is earlier than since",
+            ),
+            (r#"{"limit": -1}"#, "invalid: limit must not be negative"),
+            (
+                r#"{"kinds": [65536]}"#,
+                "invalid: kinds must be between 0 and 65535",
+            ),
+            (r##"{"#e": []}"##, "invalid: #e must not be empty"),
+            (
+                r#"{"authors": ["npub1xxx"]}"#,
+                "unsupported: authors must be lowercase hex",
+            ),
+            (
+                r#"{"ids": ["ABCD"]}"#,
+                "unsupported: ids must be lowercase hex",
+            ),
+        ] {
+            let fl: Filter = serde_json::from_str(json).unwrap();
+            assert_eq!(Err(reason.to_string()), fl.validate().map_err(String::from));
+        }
+        assert!(serde_json::from_str::<Filter>(r#"{"kinds": [-1]}"#).is_err());
+    }
+
+    #[test]
+    fn match_keys01() {
+        let ev = Event {
+            tags: vec![vec!["e".into(), "0000".into()]],
+            ..build_event01()
+        };
+        let keys = ev.match_keys();
+        let shares = |f: &str| {
+            let f: Filter = serde_json::from_str(f).unwrap();
+            assert!(f.event_match(&ev), "{f:?}");
+            f.match_keys().iter().any(|k| keys.contains(k))
+        };
+
+        assert!(shares("{}"));
+        assert!(shares(&format!(r#"{{"ids":["{}"],"kinds":[0,1]}}"#, ev.id)));
+        assert!(shares(&format!(r#"{{"authors":["{}"]}}"#, ev.pubkey)));
+        assert!(shares(r#"{"authors":["98f4"],"kinds":[1]}"#));
+        assert!(shares(r##"{"#e":["0000","1111"],"kinds":[1]}"##));
+
+        let f: Filter = serde_json::from_str(r#"{"authors":["98f4"],"kinds":[7]}"#).unwrap();
+        assert_eq!(vec!["kind:7".to_string()], f.match_keys());
+    }
+
+    #[test]
+    fn parse_reqmsg01() {
+        let msg = r#"["REQ", "sub_id01", {"authors": ["98f4"]}]"#;
+        let ret = parse_reqmsg(msg).expect("REQ");
+        assert_eq!(
+            r#"{"cmd":"REQ","subscription_id":"sub_id01","filters":[{"authors":["98f4"]}]}"#,
+            serde_json::to_string(&ret).unwrap()
+        );
+        // Malformed authors are refused with a reason by Filter::validate.
+        let ret = parse_reqmsg(r#"["REQ", "sub_id01", {"authors": ["npub1xxx"]}]"#).expect("REQ");
+        assert!(ret.filters[0].validate().is_err());
+    }
+
+    #[test]
+    fn parse_eventmsg01() {
+        let (id, pubkey, sig) = ("1".repeat(64), "2".repeat(64), "3".repeat(128));
+        let msg = format!(
+            r#"["EVENT", {{"id": "{id}", "pubkey": "{pubkey}", "created_at": 1675949672, "kind": 0,
+                            "tags":[["e", "0000"], ["p", "1111"]],
+                            "content": "content",
+                            "sig": "{sig}"}}]"#
+        );
+        let ret = parse_eventmsg(&msg).expect("EVENT");
+        assert_eq!(
+            format!(
+                r#"{{"cmd":"EVENT","event":{{"id":"{id}","pubkey":"{pubkey}","created_at":1675949672,"kind":0,"tags":[["e","0000"],["p","1111"]],"content":"content","sig":"{sig}"}}}}"#
+            ),
+            serde_json::to_string(&ret).unwrap()
+        );
+        assert!(parse_eventmsg(&msg.replace(&pubkey, "npub1yyy")).is_none());
+    }
+
+    #[test]
+    fn parse_closemsg01() {
+        let msg = r#"["CLOSE", "sub_id01"]"#;
+        let ret = parse_closemsg(msg).expect("CLOSE");
+        assert_eq!(
+            r#"{"cmd":"CLOSE","subscription_id":"sub_id01"}"#,
+            serde_json::to_string(&ret).unwrap()
+        );
+    }
+
+    #[test]
+    fn body_verb01() {
+        assert_eq!(
+            Some("REQ".to_string()),
+            body_verb(r#"["REQ", "sub_id01", {}]"#)
+        );
+        assert_eq!(None, body_verb(r#"{"REQ": 1}"#));
+        assert_eq!(None, body_verb("[]"));
+    }
 }
@@ -0,0 +1,153 @@
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Relay-wide counters of live connections and subscriptions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Gauges {
+    pub connections: i64,
+    pub subscriptions: i64,
+}
+
+/// Gauges and rejection counts served by the stats endpoint.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub connections: i64,
+    pub subscriptions: i64,
+    /// Expired events copied to the archive.
+    pub archived: i64,
+    /// Counts by command (`event`, `req`) and reason prefix.
+    pub rejections: BTreeMap<String, BTreeMap<String, i64>>,
+    /// Stored notes by detected language tag.
+    pub languages: BTreeMap<String, i64>,
+}
+
+/// Languages making up at least `min_share` percent of the detected notes,
+/// most common first.
+pub fn language_tags(languages: &BTreeMap<String, i64>, min_share: i64) -> Vec<String> {
+    let total: i64 = languages.values().sum();
+    let mut tags: Vec<(&String, &i64)> = languages
+        .iter()
+        .filter(|(_, n)| total > 0 && **n * 100 >= total * min_share)
+        .collect();
+    tags.sort_by_key(|(_, n)| std::cmp::Reverse(**n));
+    tags.into_iter().map(|(t, _)| t.clone()).collect()
+}
+
+fn namespace() -> String {
+    std::env::var("NOSTR_METRICS_NAMESPACE").unwrap_or_else(|_| "NostrRelay".to_string())
+}
+
+/// Builds a CloudWatch Embedded Metric Format record for `metrics`.
+pub fn emf_record(metrics: &[(&str, &str, i64)]) -> String {
+    emf_record_with(&[], metrics)
+}
+
+/// Like `emf_record`, with every metric broken down by `dimensions`.
+pub fn emf_record_with(dimensions: &[(&str, &str)], metrics: &[(&str, &str, i64)]) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let definitions: Vec<_> = metrics
+        .iter()
+        .map(|(name, unit, _)| json!({"Name": name, "Unit": unit}))
+        .collect();
+    let mut record = json!({
+        "_aws": {
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": namespace(),
+                "Dimensions": [dimensions.iter().map(|(k, _)| *k).collect::<Vec<_>>()],
+                "Metrics": definitions,
+            }],
+        },
+    });
+    for (name, _, value) in metrics {
+        record[*name] = json!(value);
+    }
+    for (key, value) in dimensions {
+        record[*key] = json!(value);
+    }
+    record.to_string()
+}
+
+/// Publishes one rejection of `kind` (`event` or `req`) for `reason`.
+pub fn publish_rejection(kind: &str, reason: &str) {
+    println!(
+        "{}",
+        emf_record_with(
+            &[("Command", kind), ("Reason", reason)],
+            &[("Rejections", "Count", 1)]
+        )
+    );
+}
+
+/// Publishes the capacity units one `kind` of message (`event`, `req` or
+/// `count`) consumed, rounded up to whole units.
+pub fn publish_capacity(kind: &str, units: f64) {
+    if units <= 0.0 {
+        return;
+    }
+    println!(
+        "{}",
+        emf_record_with(
+            &[("Command", kind)],
+            &[("ConsumedCapacity", "Count", units.ceil() as i64)]
+        )
+    );
+}
+
+/// Publishes the gauges by logging them in Embedded Metric Format, which
+/// CloudWatch Logs turns into metrics.
+pub fn publish_gauges(gauges: &Gauges) {
+    println!(
+        "{}",
+        emf_record(&[
+            ("ActiveConnections", "Count", gauges.connections),
+            ("ActiveSubscriptions", "Count", gauges.subscriptions),
+        ])
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emf_record, emf_record_with, language_tags};
+
+    #[test]
+    fn emf_record01() {
+        let record: serde_json::Value =
+            serde_json::from_str(&emf_record(&[("ActiveConnections", "Count", 3)])).unwrap();
+        assert_eq!(3, record["ActiveConnections"]);
+        assert_eq!(
+            "ActiveConnections",
+            record["_aws"]["CloudWatchMetrics"][0]["Metrics"][0]["Name"]
+        );
+    }
+
+    #[test]
+    fn rejection01() {
+        let record: serde_json::Value = serde_json::from_str(&emf_record_with(
+            &[("Reason", "pow")],
+            &[("Rejections", "Count", 1)],
+        ))
+        .unwrap();
+        assert_eq!("pow", record["Reason"]);
+        assert_eq!(
+            serde_json::json!([["Reason"]]),
+            record["_aws"]["CloudWatchMetrics"][0]["Dimensions"]
+        );
+    }
+
+    #[test]
+    fn language_tags01() {
+        let languages = [("en", 30), ("ja", 66), ("de", 4)]
+            .into_iter()
+            .map(|(t, n)| (t.to_string(), n))
+            .collect();
+        assert_eq!(vec!["ja", "en"], language_tags(&languages, 5));
+        assert_eq!(vec!["ja", "en", "de"], language_tags(&languages, 1));
+        assert!(language_tags(&Default::default(), 5).is_empty());
+    }
+}
@@ -1,14 +1,312 @@
+use crate::hook::Hooks;
+use crate::message::{
+    KIND_CHANNEL_CREATE, KIND_CHANNEL_MESSAGE, KIND_CHANNEL_METADATA, KIND_DELETION, KIND_GIFT_WRAP,
+};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::policy::{
+    env_list, AuthBinding, ContentWarningPolicy, CreatedAtBounds, DmRelay, RelayPolicy, Retention,
+};
+use crate::report::KIND_REPORT;
+use once_cell::sync::Lazy;
+use secp256k1::hashes::{sha256, Hash};
+use serde_json::{json, Value};
+
+/// The document for the configuration this process was started with.
+pub static DOCUMENT: Lazy<Document> = Lazy::new(|| Document::new(json()));
+
+/// A serialized NIP-11 document and its ETag.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub body: String,
+    pub etag: String,
+}
+
+impl Document {
+    pub fn new(body: String) -> Document {
+        let digest = sha256::Hash::hash(body.as_bytes());
+        let etag = format!("\"{}\"", &digest.to_string()[..16]);
+        Document { body, etag }
+    }
+
+    /// Whether an `If-None-Match` header value names this document.
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == self.etag)
+    }
+}
+
+/// `Cache-Control` max-age in seconds, `NOSTR_RELAY_INFO_MAX_AGE` or 300.
+pub fn max_age() -> u64 {
+    std::env::var("NOSTR_RELAY_INFO_MAX_AGE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300)
+}
+
+/// Optional string fields and the variables they are read from.
+const STRING_FIELDS: [(&str, &str); 4] = [
+    ("icon", "NOSTR_RELAY_ICON"),
+    ("banner", "NOSTR_RELAY_BANNER"),
+    ("payments_url", "NOSTR_RELAY_PAYMENTS_URL"),
+    ("posting_policy", "NOSTR_RELAY_POSTING_POLICY"),
+];
+
+/// Optional comma separated list fields and the variables they are read from.
+const LIST_FIELDS: [(&str, &str); 3] = [
+    ("relay_countries", "NOSTR_RELAY_COUNTRIES"),
+    ("language_tags", "NOSTR_RELAY_LANGUAGE_TAGS"),
+    ("tags", "NOSTR_RELAY_TAGS"),
+];
+
+/// NOTICE sent once per connection: `NOSTR_GREETING` followed by the
+/// requirements a client has to meet to publish, or None if unset.
+pub fn greeting() -> Option<String> {
+    let mut lines = vec![std::env::var("NOSTR_GREETING")
+        .ok()
+        .filter(|g| !g.is_empty())?];
+    if let Some(url) = std::env::var("NOSTR_RELAY_POSTING_POLICY")
+        .ok()
+        .filter(|v| !v.is_empty())
+    {
+        lines.push(format!("terms: {url}"));
+    }
+    let binding = AuthBinding::from_env();
+    if binding.required {
+        lines.push("auth-required: authentication is required to publish".to_string());
+    }
+    if binding.read_required {
+        lines.push("auth-required: authentication is required to read".to_string());
+    }
+    if let Some(url) = std::env::var("NOSTR_RELAY_PAYMENTS_URL")
+        .ok()
+        .filter(|v| !v.is_empty())
+    {
+        lines.push(format!("payment-required: {url}"));
+    }
+    Some(lines.join("\n"))
+}
+
+/// NIPs that are only about events of some kinds, left out of
+/// `supported_nips` when none of them are accepted.
+const NIP_KINDS: [(u64, &[u64]); 6] = [
+    (9, &[KIND_DELETION]),
+    (17, &[KIND_GIFT_WRAP]),
+    (
+        28,
+        &[
+            KIND_CHANNEL_CREATE,
+            KIND_CHANNEL_METADATA,
+            KIND_CHANNEL_MESSAGE,
+        ],
+    ),
+    (56, &[KIND_REPORT]),
+    (59, &[KIND_GIFT_WRAP]),
+    (65, &[KIND_RELAY_LIST]),
+];
+
 pub fn json() -> String {
+    json_with_languages(&[])
+}
+
+/// True when `NOSTR_RELAY_LANGUAGE_TAGS` is `auto`, i.e. `language_tags`
+/// follows the languages detected in stored notes.
+pub fn detects_languages() -> bool {
+    std::env::var("NOSTR_RELAY_LANGUAGE_TAGS").is_ok_and(|v| v.trim() == "auto")
+}
+
+/// The document with `languages` as `language_tags` when it is `auto`.
+pub fn json_with_languages(languages: &[String]) -> String {
     let ver = env!("CARGO_PKG_VERSION");
-    format!(
-        r#"{{
-  "name": "relay",
-  "description": "no description",
-  "pubkey": "no pubkey",
-  "contact": "no contact",
-  "supported_nips": [1, 2, 9, 11, 15, 16, 20],
-  "software": "private relay",
-  "version": "{ver}"
-}}"#
-    )
+    let mut doc = json!({
+        "name": "relay",
+        "description": "no description",
+        "pubkey": "no pubkey",
+        "contact": "no contact",
+        "supported_nips": [1, 2, 9, 11, 15, 16, 20, 22, 28, 32, 33, 36, 40, 42, 45, 56, 65, 86],
+        "software": "private relay",
+        "version": ver,
+        "content_warning_policy": ContentWarningPolicy::from_env().as_str(),
+    });
+
+    let obj = doc.as_object_mut().unwrap();
+    for (field, var) in STRING_FIELDS {
+        if let Some(v) = std::env::var(var).ok().filter(|v| !v.is_empty()) {
+            obj.insert(field.to_string(), Value::String(v));
+        }
+    }
+    for (field, var) in LIST_FIELDS {
+        if field == "language_tags" && detects_languages() {
+            if !languages.is_empty() {
+                obj.insert(field.to_string(), json!(languages));
+            }
+            continue;
+        }
+        let items: Vec<Value> = std::env::var(var)
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| Value::String(s.to_string()))
+            .collect();
+        if !items.is_empty() {
+            obj.insert(field.to_string(), Value::Array(items));
+        }
+    }
+
+    if let Some(Value::Array(nips)) = obj.get_mut("supported_nips") {
+        if DmRelay::from_env().enabled {
+            nips.extend([json!(17), json!(59)]);
+            nips.sort_by_key(|n| n.as_u64());
+        }
+        let kinds = &RelayPolicy::current().kinds;
+        let disabled = Hooks::disabled_nips(&env_list("NOSTR_DISABLED_HOOKS"));
+        nips.retain(|n| {
+            !n.as_u64().is_some_and(|n| disabled.contains(&n))
+                && NIP_KINDS
+                    .iter()
+                    .find(|(nip, _)| n.as_u64() == Some(*nip))
+                    .is_none_or(|(_, ks)| ks.iter().any(|k| kinds.accepts(*k)))
+        });
+    }
+    #[cfg(feature = "payments")]
+    if let Some(fee) = crate::payments::admission_fee() {
+        obj.insert("fees".to_string(), crate::payments::fees(fee));
+    }
+    obj.insert("limitation".to_string(), limitation(RelayPolicy::current()));
+    obj.insert(
+        "retention".to_string(),
+        retention(Retention::from_env().ok().as_ref()),
+    );
+    serde_json::to_string_pretty(&doc).unwrap()
+}
+
+/// The limits the relay enforces. Only local users (and mentions of them in
+/// inbox mode) may publish, hence `restricted_writes`. `payment_required`
+/// is true only when the admission fee is charged (the `payments` feature
+/// and `NOSTR_ADMISSION_FEE`), not merely with a `payments_url`.
+fn limitation(policy: &RelayPolicy) -> Value {
+    let limits = &policy.limits;
+    let binding = AuthBinding::from_env();
+    let mut limitation = json!({
+        "max_message_length": limits.max_message_length,
+        "max_limit": limits.max_limit,
+        "max_subscriptions": limits.max_subscriptions,
+        "max_filters": limits.max_filters,
+        "default_limit": limits.default_limit,
+        "auth_required": binding.required || binding.read_required || DmRelay::from_env().enabled,
+        "payment_required": payment_required(),
+        "restricted_writes": true,
+    });
+    let obj = limitation.as_object_mut().unwrap();
+    let bounds = CreatedAtBounds::from_env();
+    if let Some(l) = bounds.lower_limit {
+        obj.insert("created_at_lower_limit".to_string(), json!(l));
+    }
+    if let Some(l) = bounds.upper_limit {
+        obj.insert("created_at_upper_limit".to_string(), json!(l));
+    }
+    // Not part of NIP-11; messages allowed per minute.
+    let rate = &policy.rate_limit;
+    for (field, limit) in [
+        ("max_events_per_minute", rate.events),
+        ("max_reqs_per_minute", rate.reqs),
+        ("max_messages_per_minute_per_ip", rate.per_ip),
+    ] {
+        if let Some(l) = limit {
+            obj.insert(field.to_string(), json!(l));
+        }
+    }
+    limitation
+}
+
+fn payment_required() -> bool {
+    #[cfg(feature = "payments")]
+    if crate::payments::admission_fee().is_some() {
+        return true;
+    }
+    false
+}
+
+/// Ephemeral events are never stored; the rest are kept as the retention
+/// policy says.
+fn retention(policy: Option<&Retention>) -> Value {
+    let mut retention = vec![json!({"kinds": [[20000, 29999]], "time": 0})];
+    if let Some(policy) = policy {
+        retention.extend(policy.nip11());
+    }
+    Value::Array(retention)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{greeting, json, limitation, retention, Document};
+    use crate::policy::{Limits, RateLimit, RelayPolicy, Retention};
+
+    #[test]
+    fn json_extended_fields() {
+        std::env::set_var("NOSTR_RELAY_ICON", "https://example.com/icon.png");
+        std::env::set_var("NOSTR_RELAY_LANGUAGE_TAGS", "ja, en");
+
+        let doc: serde_json::Value = serde_json::from_str(&json()).unwrap();
+        assert_eq!("https://example.com/icon.png", doc["icon"]);
+        assert_eq!(serde_json::json!(["ja", "en"]), doc["language_tags"]);
+        assert!(doc.get("banner").is_none());
+    }
+
+    #[test]
+    fn limitation_and_retention() {
+        let relay_policy = RelayPolicy {
+            limits: Limits {
+                max_limit: 200,
+                ..Limits::default()
+            },
+            rate_limit: RateLimit {
+                events: Some(30),
+                ..RateLimit::default()
+            },
+            ..RelayPolicy::default()
+        };
+        let policy = Retention::parse("0,3=forever", 86400).unwrap();
+
+        let limitation = limitation(&relay_policy);
+        assert_eq!(200, limitation["max_limit"]);
+        assert_eq!(100, limitation["default_limit"]);
+        assert_eq!(131072, limitation["max_message_length"]);
+        assert_eq!(false, limitation["payment_required"]);
+        assert_eq!(30, limitation["max_events_per_minute"]);
+        assert!(limitation.get("max_reqs_per_minute").is_none());
+        assert_eq!(
+            serde_json::json!([
+                {"kinds": [[20000, 29999]], "time": 0},
+                {"kinds": [0, 3], "time": null},
+                {"time": 86400}
+            ]),
+            retention(Some(&policy))
+        );
+    }
+
+    #[test]
+    fn document_etag() {
+        let doc = Document::new("{}".to_string());
+        assert_eq!(doc.etag, Document::new("{}".to_string()).etag);
+        assert_ne!(doc.etag, Document::new("{ }".to_string()).etag);
+
+        assert!(doc.matches(&doc.etag));
+        assert!(doc.matches(&format!("\"other\", W/{}", doc.etag)));
+        assert!(doc.matches("*"));
+        assert!(!doc.matches("\"other\""));
+    }
+
+    #[test]
+    fn greeting01() {
+        std::env::set_var("NOSTR_GREETING", "welcome");
+        std::env::set_var("NOSTR_RELAY_POSTING_POLICY", "https://example.com/terms");
+
+        assert_eq!(
+            "welcome\nterms: https://example.com/terms",
+            greeting().unwrap()
+        );
+    }
 }
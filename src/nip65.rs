@@ -0,0 +1,35 @@
+use crate::types::Pubkey;
+
+/// NIP-65 relay list metadata kind.
+pub const KIND_RELAY_LIST: u64 = 10002;
+
+/// The pubkey of a `GET .../relays/<pubkey>` request, None for other paths.
+pub fn pubkey_from_path(path: &str) -> Option<Pubkey> {
+    let (rest, pubkey) = path.trim_end_matches('/').rsplit_once('/')?;
+    if !rest.ends_with("/relays") && rest != "relays" {
+        return None;
+    }
+    pubkey.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pubkey_from_path;
+    use crate::types::Pubkey;
+
+    #[test]
+    fn pubkey_from_path01() {
+        let pubkey = Pubkey::padded("a1");
+        assert_eq!(
+            Some(pubkey.clone()),
+            pubkey_from_path(&format!("/prod/relays/{pubkey}"))
+        );
+        assert_eq!(
+            Some(pubkey.clone()),
+            pubkey_from_path(&format!("/relays/{pubkey}/"))
+        );
+        assert_eq!(None, pubkey_from_path("/prod/relays/abc"));
+        assert_eq!(None, pubkey_from_path(&format!("/prod/users/{pubkey}")));
+        assert_eq!(None, pubkey_from_path("/prod/stats"));
+    }
+}
@@ -0,0 +1,231 @@
+//! NIP-86 relay management: admins change the allowlist and the denylist at
+//! runtime and look up labelled events, over HTTP with NIP-98 or with
+//! signed requests on the websocket.
+use crate::message::Event;
+use crate::policy::admin_pubkeys;
+use crate::reject::RejectReason;
+use crate::store::EventStore;
+use crate::types::{EventId, Pubkey};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Content type of management requests over HTTP.
+pub const CONTENT_TYPE: &str = "application/nostr+json+rpc";
+
+/// Kind of a management request sent as an event over the websocket, with
+/// the request JSON as content. Ephemeral, so it is never stored.
+pub const KIND_MANAGEMENT_REQUEST: u64 = 28086;
+
+/// How far created_at of a request event may be from now, in seconds.
+const MAX_SKEW: u64 = 60;
+
+const METHODS: [&str; 11] = [
+    "supportedmethods",
+    "banpubkey",
+    "unbanpubkey",
+    "listbannedpubkeys",
+    "allowpubkey",
+    "unallowpubkey",
+    "listallowedpubkeys",
+    "banevent",
+    "allowevent",
+    "listbannedevents",
+    "listeventsbylabel",
+];
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+/// The request carried by a signed event from one of
+/// `NOSTR_ADMIN_PUBKEYS`.
+pub fn parse_event(ev: &Event, now: u64) -> Result<Request, RejectReason> {
+    if !admin_pubkeys().contains(ev.pubkey.as_str()) {
+        return Err(RejectReason::Restricted("not an admin".to_string()));
+    }
+    if ev.created_at.abs_diff(now) > MAX_SKEW {
+        return Err(RejectReason::Invalid("request expired".to_string()));
+    }
+    if ev.id != ev.hex_digest() || ev.validate().is_err() {
+        return Err(RejectReason::Invalid("signature is wrong".to_string()));
+    }
+    serde_json::from_str(&ev.content)
+        .map_err(|_| RejectReason::Invalid("malformed request".to_string()))
+}
+
+/// Carries out `req` on the store's allowlist and denylist.
+pub async fn handle(store: &dyn EventStore, req: &Request) -> Result<Value, String> {
+    let param = |i: usize| {
+        req.params
+            .get(i)
+            .and_then(Value::as_str)
+            .ok_or(format!("{}: missing parameter", req.method))
+    };
+    let pubkey = || param(0)?.parse::<Pubkey>();
+    let event_id = || param(0)?.parse::<EventId>();
+    match req.method.as_str() {
+        "supportedmethods" => Ok(json!(METHODS)),
+        "banpubkey" => store.add_ban(&pubkey()?).await.map(|_| json!(true)),
+        "unbanpubkey" => store.remove_ban(&pubkey()?).await.map(|_| json!(true)),
+        "listbannedpubkeys" => {
+            let denylist = store.get_denylist().await?;
+            Ok(denylist
+                .pubkeys()
+                .map(|pubkey| json!({ "pubkey": pubkey }))
+                .collect())
+        }
+        "allowpubkey" => store
+            .add_allowed_pubkey(&pubkey()?)
+            .await
+            .map(|_| json!(true)),
+        "unallowpubkey" => store
+            .remove_allowed_pubkey(&pubkey()?)
+            .await
+            .map(|_| json!(true)),
+        "listallowedpubkeys" => Ok(store
+            .get_allowed_pubkeys()
+            .await?
+            .into_iter()
+            .map(|pubkey| json!({ "pubkey": pubkey }))
+            .collect()),
+        "banevent" => store
+            .add_banned_event(&event_id()?)
+            .await
+            .map(|_| json!(true)),
+        "allowevent" => store
+            .remove_banned_event(&event_id()?)
+            .await
+            .map(|_| json!(true)),
+        "listbannedevents" => {
+            let denylist = store.get_denylist().await?;
+            Ok(denylist.event_ids().map(|id| json!({ "id": id })).collect())
+        }
+        // NIP-32: events labelled `value` in `namespace`, with who said so.
+        "listeventsbylabel" => Ok(store
+            .get_label_targets(param(0)?, param(1)?)
+            .await?
+            .into_iter()
+            .filter(|e| e.target_tag == "e")
+            .map(|e| json!({ "id": e.target, "labeler": e.labeler, "label": e.label_event }))
+            .collect()),
+        method => Err(format!("unsupported method: {method}")),
+    }
+}
+
+/// The response body for the result of a request.
+pub fn response(result: &Result<Value, String>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result }),
+        Err(e) => json!({ "result": null, "error": e }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, parse_event, Request, KIND_MANAGEMENT_REQUEST};
+    use crate::identity::Identity;
+    use crate::memory::MemoryStore;
+    use crate::message::Event;
+    use crate::store::EventStore;
+    use serde_json::json;
+
+    fn request(method: &str, params: &[&str]) -> Request {
+        serde_json::from_value(json!({ "method": method, "params": params })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn handle01() {
+        let store = MemoryStore::new();
+        let pubkey = Identity::generate().pubkey_hex();
+
+        let ret = handle(&store, &request("allowpubkey", &[&pubkey])).await;
+        assert_eq!(Ok(json!(true)), ret);
+        assert_eq!(
+            vec![pubkey.clone()],
+            store.get_allowed_pubkeys().await.unwrap()
+        );
+        handle(&store, &request("unallowpubkey", &[&pubkey]))
+            .await
+            .unwrap();
+        assert!(store.get_allowed_pubkeys().await.unwrap().is_empty());
+
+        handle(&store, &request("banpubkey", &[&pubkey, "spam"]))
+            .await
+            .unwrap();
+        let ret = handle(&store, &request("listbannedpubkeys", &[])).await;
+        assert_eq!(Ok(json!([{ "pubkey": pubkey }])), ret);
+        handle(&store, &request("unbanpubkey", &[&pubkey]))
+            .await
+            .unwrap();
+        assert!(!store.is_banned(&pubkey).await.unwrap());
+
+        let label = crate::label::LabelEntry {
+            namespace: "com.example".into(),
+            value: "spam".into(),
+            target_tag: "e".into(),
+            target: "ev01".into(),
+            labeler: pubkey.clone(),
+            label_event: "1abe101".into(),
+        };
+        let profile = crate::label::LabelEntry {
+            target_tag: "p".into(),
+            target: "pub01".into(),
+            ..label.clone()
+        };
+        let ev = Event::sign(Identity::generate().keys(), 1000, 1985, vec![], "");
+        store.write_labels(&ev, &[label, profile]).await.unwrap();
+        let ret = handle(
+            &store,
+            &request("listeventsbylabel", &["com.example", "spam"]),
+        )
+        .await;
+        assert_eq!(
+            Ok(json!([{ "id": "ev01", "labeler": pubkey, "label": "1abe101" }])),
+            ret
+        );
+        assert_eq!(
+            Ok(json!([])),
+            handle(
+                &store,
+                &request("listeventsbylabel", &["com.example", "nsfw"])
+            )
+            .await
+        );
+        assert!(
+            handle(&store, &request("listeventsbylabel", &["com.example"]))
+                .await
+                .is_err()
+        );
+
+        assert!(handle(&store, &request("banpubkey", &["b01"]))
+            .await
+            .is_err());
+        assert!(handle(&store, &request("changerelayname", &["x"]))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn parse_event01() {
+        let admin = Identity::generate();
+        std::env::set_var("NOSTR_ADMIN_PUBKEYS", admin.pubkey_hex());
+        let content = r#"{"method": "supportedmethods", "params": []}"#;
+
+        let ev = Event::sign(admin.keys(), 1000, KIND_MANAGEMENT_REQUEST, vec![], content);
+        assert_eq!(Ok(request("supportedmethods", &[])), parse_event(&ev, 1030));
+        assert!(parse_event(&ev, 1100).is_err_and(|r| r.prefix() == "invalid"));
+
+        let ev = Event::sign(
+            Identity::generate().keys(),
+            1000,
+            KIND_MANAGEMENT_REQUEST,
+            vec![],
+            content,
+        );
+        assert!(parse_event(&ev, 1000).is_err_and(|r| r.prefix() == "restricted"));
+        std::env::remove_var("NOSTR_ADMIN_PUBKEYS");
+    }
+}
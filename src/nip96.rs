@@ -0,0 +1,205 @@
+use secp256k1::hashes::{sha256, Hash};
+use serde_json::json;
+
+/// Where uploads are accepted and served from, read from the environment.
+#[derive(Debug, Clone)]
+pub struct MediaConfig {
+    /// Absolute URL of the upload endpoint, as signed in NIP-98 `u` tags.
+    pub api_url: String,
+    /// Base URL the stored files are served from, e.g. a CloudFront domain.
+    pub download_url: String,
+    pub max_size: usize,
+    /// Accepted MIME types; `image/*` style prefixes are allowed.
+    pub content_types: Vec<String>,
+}
+
+impl MediaConfig {
+    /// Reads `NOSTR_MEDIA_API_URL`, `NOSTR_MEDIA_PUBLIC_URL`,
+    /// `NOSTR_MEDIA_MAX_SIZE` and `NOSTR_MEDIA_CONTENT_TYPES`.
+    pub fn from_env() -> Option<MediaConfig> {
+        let api_url = std::env::var("NOSTR_MEDIA_API_URL").ok()?;
+        let download_url = std::env::var("NOSTR_MEDIA_PUBLIC_URL").ok()?;
+        let max_size = std::env::var("NOSTR_MEDIA_MAX_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10 * 1024 * 1024);
+        let content_types = std::env::var("NOSTR_MEDIA_CONTENT_TYPES")
+            .unwrap_or_else(|_| "image/*,video/*,audio/*".to_string())
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        Some(MediaConfig {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            download_url: download_url.trim_end_matches('/').to_string(),
+            max_size,
+            content_types,
+        })
+    }
+
+    /// Path part of `api_url`, which the upload and delete routes live under.
+    pub fn api_path(&self) -> &str {
+        let rest = self.api_url.split_once("://").map_or("", |(_, r)| r);
+        rest.find('/').map_or("", |i| &rest[i..])
+    }
+
+    pub fn accepts(&self, content_type: &str) -> bool {
+        self.content_types
+            .iter()
+            .any(|t| match t.strip_suffix('*') {
+                Some(prefix) => content_type.starts_with(prefix),
+                None => content_type == t,
+            })
+    }
+
+    /// The `/.well-known/nostr/nip96.json` document.
+    pub fn json(&self) -> String {
+        let doc = json!({
+            "api_url": self.api_url,
+            "download_url": self.download_url,
+            "supported_nips": [96, 98],
+            "content_types": self.content_types,
+            "plans": {
+                "free": {
+                    "name": "free",
+                    "is_nip98_required": true,
+                    "max_byte_size": self.max_size,
+                }
+            }
+        });
+        serde_json::to_string_pretty(&doc).unwrap()
+    }
+}
+
+/// A file taken from a `multipart/form-data` upload.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Upload {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Upload {
+    pub fn sha256(&self) -> String {
+        format!("{:x}", sha256::Hash::hash(&self.data))
+    }
+}
+
+/// Extracts the `file` field of a `multipart/form-data` body.
+pub fn parse_multipart(content_type: &str, body: &[u8]) -> Result<Upload, String> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|p| p.trim().strip_prefix("boundary="))
+        .map(|b| b.trim_matches('"'))
+        .ok_or("expected multipart/form-data")?;
+    let delimiter = format!("--{boundary}");
+
+    for part in split(body, delimiter.as_bytes()).into_iter().skip(1) {
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let Some(end) = find(part, b"\r\n\r\n") else {
+            continue;
+        };
+        let headers = String::from_utf8_lossy(&part[..end]).to_string();
+        let data = &part[end + 4..];
+        let data = data.strip_suffix(b"\r\n").unwrap_or(data);
+
+        let is_file = headers.lines().any(|h| {
+            h.to_ascii_lowercase().starts_with("content-disposition:")
+                && h.contains("name=\"file\"")
+        });
+        if !is_file {
+            continue;
+        }
+        let content_type = headers
+            .lines()
+            .find_map(|h| {
+                let (k, v) = h.split_once(':')?;
+                k.eq_ignore_ascii_case("content-type")
+                    .then(|| v.trim().to_string())
+            })
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        return Ok(Upload {
+            content_type,
+            data: data.to_vec(),
+        });
+    }
+    Err("no file field in the upload".to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn split<'a>(mut body: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = vec![];
+    while let Some(i) = find(body, delimiter) {
+        parts.push(&body[..i]);
+        body = &body[i + delimiter.len()..];
+    }
+    parts.push(body);
+    parts
+}
+
+/// Body answering a successful upload, with the NIP-94 tags of the file.
+pub fn upload_response(url: &str, upload: &Upload) -> String {
+    let sha256 = upload.sha256();
+    let doc = json!({
+        "status": "success",
+        "message": "Upload successful.",
+        "nip94_event": {
+            "tags": [
+                ["url", url],
+                ["ox", sha256],
+                ["x", sha256],
+                ["m", upload.content_type],
+                ["size", upload.data.len().to_string()],
+            ],
+            "content": "",
+        }
+    });
+    doc.to_string()
+}
+
+pub fn status_response(success: bool, message: &str) -> String {
+    let status = if success { "success" } else { "error" };
+    json!({ "status": status, "message": message }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_multipart, MediaConfig, Upload};
+
+    #[test]
+    fn parse_multipart01() {
+        let body = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"caption\"\r\n\r\n\
+hi\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+Content-Type: image/png\r\n\r\n\
+\x89PNG\r\n\
+--XyZ--\r\n";
+        let upload = parse_multipart("multipart/form-data; boundary=XyZ", body).unwrap();
+        assert_eq!(
+            Upload {
+                content_type: "image/png".into(),
+                data: b"\x89PNG".to_vec(),
+            },
+            upload
+        );
+        assert!(parse_multipart("application/json", body).is_err());
+    }
+
+    #[test]
+    fn media_config01() {
+        let config = MediaConfig {
+            api_url: "https://example.com/media".into(),
+            download_url: "https://cdn.example.com".into(),
+            max_size: 100,
+            content_types: vec!["image/*".into(), "video/mp4".into()],
+        };
+        assert_eq!("/media", config.api_path());
+        assert!(config.accepts("image/png"));
+        assert!(config.accepts("video/mp4"));
+        assert!(!config.accepts("video/webm"));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::message::Event;
+use base64::Engine;
+use secp256k1::hashes::{sha256, Hash};
+
+/// NIP-98 HTTP auth event kind.
+pub const KIND_HTTP_AUTH: u64 = 27235;
+
+/// How far created_at may be from now, in seconds.
+const MAX_SKEW: u64 = 60;
+
+/// Checks a NIP-98 `Authorization` header and returns the pubkey it was
+/// signed by.
+///
+/// `url` and `method` are the request being authorized; `body`, if given, is
+/// checked against the `payload` tag when the event has one.
+pub fn verify(
+    header: &str,
+    url: &str,
+    method: &str,
+    body: Option<&[u8]>,
+    now: u64,
+) -> Result<String, String> {
+    let encoded = header
+        .strip_prefix("Nostr ")
+        .ok_or("auth-required: expected a Nostr authorization")?;
+    let json = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|_| "invalid: authorization is not base64")?;
+    let ev: Event =
+        serde_json::from_slice(&json).map_err(|_| "invalid: authorization is not an event")?;
+
+    if ev.kind != KIND_HTTP_AUTH {
+        return Err("invalid: wrong authorization kind".to_string());
+    }
+    if ev.created_at.abs_diff(now) > MAX_SKEW {
+        return Err("invalid: authorization expired".to_string());
+    }
+    if tag_value(&ev, "u") != Some(url) {
+        return Err("invalid: authorization url mismatch".to_string());
+    }
+    if !tag_value(&ev, "method").is_some_and(|m| m.eq_ignore_ascii_case(method)) {
+        return Err("invalid: authorization method mismatch".to_string());
+    }
+    if let (Some(payload), Some(body)) = (tag_value(&ev, "payload"), body) {
+        if payload != format!("{:x}", sha256::Hash::hash(body)) {
+            return Err("invalid: authorization payload mismatch".to_string());
+        }
+    }
+    if ev.id != ev.hex_digest() || ev.validate().is_err() {
+        return Err("invalid: authorization signature is wrong".to_string());
+    }
+    Ok(ev.pubkey.to_string())
+}
+
+fn tag_value<'a>(ev: &'a Event, name: &str) -> Option<&'a str> {
+    ev.tags
+        .iter()
+        .find(|t| t.len() >= 2 && t[0] == name)
+        .map(|t| t[1].as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, KIND_HTTP_AUTH};
+    use crate::identity::Identity;
+    use crate::message::Event;
+    use base64::Engine;
+
+    fn header(tags: Vec<Vec<String>>, created_at: u64) -> (String, String) {
+        let id = Identity::generate();
+        let ev = Event::sign(id.keys(), created_at, KIND_HTTP_AUTH, tags, "");
+        let json = serde_json::to_string(&ev).unwrap();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(json);
+        (format!("Nostr {encoded}"), id.pubkey_hex())
+    }
+
+    #[test]
+    fn verify01() {
+        let url = "https://example.com/media";
+        let tags = vec![
+            vec!["u".to_string(), url.to_string()],
+            vec!["method".to_string(), "POST".to_string()],
+        ];
+        let (auth, pubkey) = header(tags, 1000);
+
+        assert_eq!(Ok(pubkey), verify(&auth, url, "POST", None, 1030));
+        assert!(verify(&auth, url, "DELETE", None, 1030).is_err());
+        assert!(verify(&auth, "https://example.com/other", "POST", None, 1030).is_err());
+        assert!(verify(&auth, url, "POST", None, 2000).is_err());
+        assert!(verify("Basic Zm9vOmJhcg==", url, "POST", None, 1030).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Paid admissions: a pubkey pays a Lightning invoice issued through LNbits
+//! and becomes a member, who may publish like the relay's own users.
+use crate::message::Event;
+use crate::reject::RejectReason;
+use crate::store::EventStore;
+use crate::types::Pubkey;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The admission fee in sats, `NOSTR_ADMISSION_FEE`; None when admission is
+/// free.
+pub fn admission_fee() -> Option<u64> {
+    std::env::var("NOSTR_ADMISSION_FEE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&fee| fee > 0)
+}
+
+/// Lets members publish the events the admission policy `refused`;
+/// everyone else is asked to pay.
+pub async fn check_member(
+    store: &dyn EventStore,
+    ev: &Event,
+    refused: RejectReason,
+) -> Result<(), RejectReason> {
+    match store.is_member(ev.pubkey.as_str()).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(RejectReason::Restricted("payment required".to_string())),
+        Err(e) => {
+            println!("member check err: {e}");
+            Err(refused)
+        }
+    }
+}
+
+/// An invoice for the admission of a pubkey.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invoice {
+    pub payment_hash: String,
+    /// The BOLT11 invoice to pay.
+    pub payment_request: String,
+    /// In sats.
+    pub amount: u64,
+}
+
+/// Client of an LNbits wallet.
+pub struct Payments {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+    fee: u64,
+    webhook: Option<String>,
+}
+
+impl Payments {
+    /// Reads `NOSTR_ADMISSION_FEE`, `NOSTR_LNBITS_URL`,
+    /// `NOSTR_LNBITS_API_KEY` (the wallet's invoice key) and
+    /// `NOSTR_PAYMENTS_WEBHOOK_URL`; None unless the first three are set.
+    pub fn from_env() -> Option<Payments> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Some(Payments {
+            client: reqwest::Client::new(),
+            url: var("NOSTR_LNBITS_URL")?.trim_end_matches('/').to_string(),
+            api_key: var("NOSTR_LNBITS_API_KEY")?,
+            fee: admission_fee()?,
+            webhook: var("NOSTR_PAYMENTS_WEBHOOK_URL"),
+        })
+    }
+
+    /// Issues an invoice for the admission of `pubkey` and remembers whom it
+    /// was for.
+    pub async fn issue(&self, store: &dyn EventStore, pubkey: &Pubkey) -> Result<Invoice, String> {
+        #[derive(Deserialize)]
+        struct Created {
+            payment_hash: String,
+            #[serde(alias = "bolt11")]
+            payment_request: String,
+        }
+
+        let mut body = json!({
+            "out": false,
+            "amount": self.fee,
+            "memo": format!("relay admission for {pubkey}"),
+        });
+        if let Some(webhook) = &self.webhook {
+            body["webhook"] = json!(webhook);
+        }
+        let created: Created = self
+            .client
+            .post(format!("{}/api/v1/payments", self.url))
+            .header("X-Api-Key", &self.api_key)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        store
+            .write_invoice(&created.payment_hash, pubkey.as_str())
+            .await?;
+        Ok(Invoice {
+            payment_hash: created.payment_hash,
+            payment_request: created.payment_request,
+            amount: self.fee,
+        })
+    }
+
+    /// Asks LNbits whether the invoice `payment_hash` is paid and, once it
+    /// is, makes its pubkey a member. Webhook bodies are never trusted, so
+    /// this is what both the webhook and the poller call.
+    pub async fn settle(&self, store: &dyn EventStore, payment_hash: &str) -> Result<bool, String> {
+        #[derive(Deserialize)]
+        struct Status {
+            paid: bool,
+        }
+
+        let pubkey = store
+            .get_invoice_pubkey(payment_hash)
+            .await?
+            .ok_or("unknown invoice")?;
+        if store.is_member(&pubkey).await? {
+            return Ok(true);
+        }
+        let status: Status = self
+            .client
+            .get(format!("{}/api/v1/payments/{payment_hash}", self.url))
+            .header("X-Api-Key", &self.api_key)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        if status.paid {
+            println!("payments: {pubkey} paid {payment_hash}");
+            store.add_member(&pubkey).await?;
+        }
+        Ok(status.paid)
+    }
+}
+
+/// NIP-11 `fees` for the admission fee.
+pub fn fees(fee: u64) -> serde_json::Value {
+    json!({ "admission": [{ "amount": fee * 1000, "unit": "msats" }] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_member, fees};
+    use crate::memory::MemoryStore;
+    use crate::message::test_event;
+    use crate::reject::RejectReason;
+    use crate::store::EventStore;
+
+    #[tokio::test]
+    async fn check_member01() {
+        let store = MemoryStore::new();
+        let ev = test_event();
+        let refused = || RejectReason::Blocked("not allowed".to_string());
+
+        assert!(check_member(&store, &ev, refused())
+            .await
+            .is_err_and(|r| r.to_string() == "restricted: payment required"));
+
+        store
+            .write_invoice("hash01", ev.pubkey.as_str())
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(ev.pubkey.to_string()),
+            store.get_invoice_pubkey("hash01").await.unwrap()
+        );
+        store.add_member(ev.pubkey.as_str()).await.unwrap();
+        assert_eq!(Ok(()), check_member(&store, &ev, refused()).await);
+
+        assert_eq!(21000, fees(21)["admission"][0]["amount"]);
+    }
+}
@@ -0,0 +1,1075 @@
+use crate::allowlist::Allowlist;
+use crate::config::Config;
+use crate::message::{Event, Filter, KIND_GIFT_WRAP, MIN_PREFIX_LENGTH};
+use crate::reject::RejectReason;
+use crate::report::KIND_REPORT;
+use once_cell::sync::{Lazy, OnceCell};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Comma separated values of the environment variable `name`.
+pub(crate) fn env_list(name: &str) -> HashSet<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+pub(crate) fn env_flag(name: &str) -> bool {
+    matches!(
+        std::env::var(name).as_deref(),
+        Ok("1") | Ok("true") | Ok("yes")
+    )
+}
+
+/// Pubkeys listed in `NOSTR_ADMIN_PUBKEYS`, allowed to use the
+/// operational HTTP routes.
+pub fn admin_pubkeys() -> HashSet<String> {
+    env_list("NOSTR_ADMIN_PUBKEYS")
+}
+
+/// Who may publish: local users, and in inbox mode anyone mentioning one.
+#[derive(Debug, Default)]
+pub struct Admission {
+    /// NIP-65 read relay role, so replies and mentions from strangers reach
+    /// the local users.
+    pub inbox: bool,
+    /// NIP-56 reports are accepted from anyone for the moderation queue.
+    pub reports: bool,
+    /// The local users.
+    pub allowlist: Allowlist,
+}
+
+impl Admission {
+    /// Reads `NOSTR_INBOX_MODE`, `NOSTR_ACCEPT_REPORTS` and
+    /// `NOSTR_ALLOWED_PUBKEYS`.
+    pub fn from_env() -> Admission {
+        Admission {
+            inbox: env_flag("NOSTR_INBOX_MODE"),
+            reports: env_flag("NOSTR_ACCEPT_REPORTS"),
+            allowlist: Allowlist::from_env(),
+        }
+    }
+
+    pub fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        if self.allowlist.contains(ev.pubkey.as_str()) {
+            return Ok(());
+        }
+        if self.reports && ev.kind == KIND_REPORT {
+            return Ok(());
+        }
+        let mentions_local = ev
+            .tags
+            .iter()
+            .any(|t| t.len() >= 2 && t[0] == "p" && self.allowlist.contains(&t[1]));
+        if self.inbox && mentions_local {
+            return Ok(());
+        }
+        Err(RejectReason::Blocked("not allowed".to_string()))
+    }
+}
+
+/// NIP-17 DM relay mode: only gift wraps are stored, and only their
+/// authenticated recipients may read them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DmRelay {
+    pub enabled: bool,
+}
+
+impl DmRelay {
+    /// Reads `NOSTR_DM_RELAY`.
+    pub fn from_env() -> DmRelay {
+        DmRelay {
+            enabled: env_flag("NOSTR_DM_RELAY"),
+        }
+    }
+
+    /// In DM relay mode, replaces `Admission`: gift wraps are accepted from
+    /// anyone, as they are signed by throwaway keys.
+    pub fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        if ev.kind != KIND_GIFT_WRAP {
+            return Err(RejectReason::Blocked(
+                "only gift wraps are accepted".to_string(),
+            ));
+        }
+        if ev.recipient().is_none() {
+            return Err(RejectReason::Invalid(
+                "gift wrap has no recipient".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that every filter only asks for gift wraps addressed to the
+    /// authenticated pubkey, so neither stored nor live events leak.
+    pub fn check_read(
+        &self,
+        filters: &[Filter],
+        auth_pubkey: Option<&str>,
+    ) -> Result<(), RejectReason> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let Some(authed) = auth_pubkey else {
+            return Err(RejectReason::AuthRequired(
+                "gift wraps are only served to their recipient".to_string(),
+            ));
+        };
+        if !filters.iter().all(|f| f.is_addressed_to(authed)) {
+            return Err(RejectReason::Restricted(
+                "filters must be limited to #p of the authenticated pubkey".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How events carrying a NIP-36 `content-warning` tag are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentWarningPolicy {
+    Accept,
+    Reject,
+    /// Store the event but only serve it to authenticated connections.
+    ExcludeUnauthenticated,
+}
+
+impl ContentWarningPolicy {
+    pub fn parse(s: &str) -> Option<ContentWarningPolicy> {
+        match s {
+            "accept" => Some(ContentWarningPolicy::Accept),
+            "reject" => Some(ContentWarningPolicy::Reject),
+            "exclude-unauthenticated" => Some(ContentWarningPolicy::ExcludeUnauthenticated),
+            _ => None,
+        }
+    }
+
+    /// Reads `NOSTR_CONTENT_WARNING_POLICY`, defaulting to `accept`.
+    pub fn from_env() -> ContentWarningPolicy {
+        std::env::var("NOSTR_CONTENT_WARNING_POLICY")
+            .ok()
+            .and_then(|s| ContentWarningPolicy::parse(&s))
+            .unwrap_or(ContentWarningPolicy::Accept)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentWarningPolicy::Accept => "accept",
+            ContentWarningPolicy::Reject => "reject",
+            ContentWarningPolicy::ExcludeUnauthenticated => "exclude-unauthenticated",
+        }
+    }
+
+    pub fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        if *self == ContentWarningPolicy::Reject && ev.content_warning().is_some() {
+            return Err(RejectReason::Blocked(
+                "content-warning events are not accepted".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether `ev` may be served to a connection.
+    pub fn visible(&self, ev: &Event, authenticated: bool) -> bool {
+        *self != ContentWarningPolicy::ExcludeUnauthenticated
+            || authenticated
+            || ev.content_warning().is_none()
+    }
+}
+
+/// Shadow mode: events are validated and stored but not dispatched to
+/// subscribers, either for every author or only for listed ones.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShadowMode {
+    pub all: bool,
+    pub pubkeys: HashSet<String>,
+}
+
+impl ShadowMode {
+    /// Reads `NOSTR_SHADOW_MODE` and `NOSTR_SHADOW_PUBKEYS`.
+    pub fn from_env() -> ShadowMode {
+        ShadowMode {
+            all: env_flag("NOSTR_SHADOW_MODE"),
+            pubkeys: env_list("NOSTR_SHADOW_PUBKEYS"),
+        }
+    }
+
+    pub fn applies(&self, ev: &Event) -> bool {
+        self.all || self.pubkeys.contains(ev.pubkey.as_str())
+    }
+}
+
+/// Rejects events older than `max_age` seconds so old data cannot be
+/// back-filled, except replaceable events and allowlisted importers.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplayWindow {
+    pub max_age: Option<u64>,
+    pub import_pubkeys: HashSet<String>,
+}
+
+impl ReplayWindow {
+    /// Reads `NOSTR_MAX_EVENT_AGE` and `NOSTR_IMPORT_PUBKEYS`.
+    pub fn from_env() -> ReplayWindow {
+        ReplayWindow {
+            max_age: std::env::var("NOSTR_MAX_EVENT_AGE")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+            import_pubkeys: env_list("NOSTR_IMPORT_PUBKEYS"),
+        }
+    }
+
+    pub fn check_event(&self, ev: &Event, now: u64) -> Result<(), RejectReason> {
+        let Some(max_age) = self.max_age else {
+            return Ok(());
+        };
+        if ev.is_replaceable()
+            || ev.is_parameterized_replaceable()
+            || self.import_pubkeys.contains(ev.pubkey.as_str())
+        {
+            return Ok(());
+        }
+        if ev.created_at.saturating_add(max_age) < now {
+            return Err(RejectReason::Invalid("event too old".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Limits on what clients may send or request, enforced by the relay and
+/// advertised in the NIP-11 `limitation` object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest message accepted, in bytes.
+    pub max_message_length: usize,
+    /// `limit` used for filters without one.
+    pub default_limit: i32,
+    /// Largest `limit` a filter is served with.
+    pub max_limit: i32,
+    /// Most subscriptions a connection may hold open.
+    pub max_subscriptions: usize,
+    /// Most filters one REQ or COUNT may carry.
+    pub max_filters: usize,
+    /// Shortest id or author prefix that is looked up.
+    pub min_prefix_length: usize,
+    /// Stored events a REQ reads and sends at a time.
+    pub req_chunk_size: i32,
+    /// Most ids, authors, kinds or values of one tag in a filter.
+    pub max_filter_values: usize,
+    /// Most items a filter without an index may scan; such filters are
+    /// refused when unset.
+    pub max_scan_items: Option<usize>,
+    /// Read capacity units the stored events of one REQ may use before the
+    /// rest are skipped.
+    pub max_req_capacity: Option<u64>,
+    /// Deliveries of one event to its subscriptions in flight at a time.
+    pub dispatch_concurrency: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        // API Gateway refuses WebSocket messages over 128KB anyway.
+        Limits {
+            max_message_length: 128 * 1024,
+            default_limit: 100,
+            max_limit: 500,
+            max_subscriptions: 20,
+            max_filters: 10,
+            min_prefix_length: MIN_PREFIX_LENGTH,
+            req_chunk_size: 100,
+            max_filter_values: 1000,
+            max_scan_items: None,
+            max_req_capacity: None,
+            dispatch_concurrency: 32,
+        }
+    }
+}
+
+impl Limits {
+    /// Reads `NOSTR_MAX_MESSAGE_LENGTH`, `NOSTR_MAX_LIMIT`,
+    /// `NOSTR_MAX_SUBSCRIPTIONS`, `NOSTR_MAX_FILTERS`, `NOSTR_REQ_CHUNK_SIZE`,
+    /// `NOSTR_MAX_FILTER_VALUES`, `NOSTR_MAX_SCAN_ITEMS`,
+    /// `NOSTR_MAX_REQ_CAPACITY` and
+    /// `NOSTR_MIN_PREFIX_LENGTH`, which cannot go below what the store
+    /// indexes, through `get`.
+    pub fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Limits {
+        let default = Limits::default();
+        let var = |name| get(name).and_then(|v| v.parse().ok());
+        let max_limit = var("NOSTR_MAX_LIMIT").unwrap_or(default.max_limit as usize) as i32;
+        Limits {
+            max_message_length: var("NOSTR_MAX_MESSAGE_LENGTH")
+                .unwrap_or(default.max_message_length),
+            default_limit: default.default_limit.min(max_limit),
+            max_limit,
+            max_subscriptions: var("NOSTR_MAX_SUBSCRIPTIONS").unwrap_or(default.max_subscriptions),
+            max_filters: var("NOSTR_MAX_FILTERS").unwrap_or(default.max_filters),
+            min_prefix_length: var("NOSTR_MIN_PREFIX_LENGTH")
+                .unwrap_or(default.min_prefix_length)
+                .max(MIN_PREFIX_LENGTH),
+            req_chunk_size: var("NOSTR_REQ_CHUNK_SIZE")
+                .unwrap_or(default.req_chunk_size as usize)
+                .max(1) as i32,
+            max_filter_values: var("NOSTR_MAX_FILTER_VALUES").unwrap_or(default.max_filter_values),
+            max_scan_items: var("NOSTR_MAX_SCAN_ITEMS").filter(|n| *n > 0),
+            max_req_capacity: var("NOSTR_MAX_REQ_CAPACITY")
+                .filter(|n| *n > 0)
+                .map(|n| n as u64),
+            dispatch_concurrency: var("NOSTR_DISPATCH_CONCURRENCY")
+                .unwrap_or(default.dispatch_concurrency)
+                .max(1),
+        }
+    }
+
+    /// The `limit` a filter asking for `limit` is served with.
+    pub fn clamp(&self, limit: Option<i32>) -> i32 {
+        limit.unwrap_or(self.default_limit).min(self.max_limit)
+    }
+
+    /// Rejects client messages longer than `max_message_length`.
+    pub fn check_message(&self, msg: &str) -> Result<(), RejectReason> {
+        if msg.len() > self.max_message_length {
+            return Err(RejectReason::Invalid("message too large".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Rejects requests with more filters than `max_filters`, as each
+    /// filter costs its own store queries.
+    pub fn check_filters(&self, filters: &[Filter]) -> Result<(), RejectReason> {
+        if filters.len() > self.max_filters {
+            return Err(RejectReason::Invalid("too many filters".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Refuses a new subscription `sub_id` on a connection already holding
+    /// `open`. Replacing one of them is always allowed.
+    pub fn check_subscriptions(&self, open: &[String], sub_id: &str) -> Result<(), RejectReason> {
+        if open.len() >= self.max_subscriptions && !open.iter().any(|s| s == sub_id) {
+            return Err(RejectReason::Error("too many subscriptions".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// NIP-22: rejects events whose `created_at` is further in the past or the
+/// future than the limits, in seconds from now.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CreatedAtBounds {
+    pub lower_limit: Option<u64>,
+    pub upper_limit: Option<u64>,
+}
+
+impl CreatedAtBounds {
+    /// Reads `NOSTR_CREATED_AT_LOWER_LIMIT` and `NOSTR_CREATED_AT_UPPER_LIMIT`.
+    pub fn from_env() -> CreatedAtBounds {
+        let limit = |name| std::env::var(name).ok().and_then(|v| v.parse().ok());
+        CreatedAtBounds {
+            lower_limit: limit("NOSTR_CREATED_AT_LOWER_LIMIT"),
+            upper_limit: limit("NOSTR_CREATED_AT_UPPER_LIMIT"),
+        }
+    }
+
+    pub fn check_event(&self, ev: &Event, now: u64) -> Result<(), RejectReason> {
+        let too_old = self
+            .lower_limit
+            .is_some_and(|l| ev.created_at.saturating_add(l) < now);
+        let too_new = self
+            .upper_limit
+            .is_some_and(|l| ev.created_at > now.saturating_add(l));
+        if too_old || too_new {
+            return Err(RejectReason::Invalid("created_at too far off".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A kind (`7`) or an inclusive kind range (`30000-39999`).
+fn parse_kind_range(item: &str) -> Result<(u64, u64), String> {
+    let kind = |k: &str| k.trim().parse().map_err(|_| "bad kind".to_string());
+    match item.split_once('-') {
+        Some((from, to)) => Ok((kind(from)?, kind(to)?)),
+        None => Ok((kind(item)?, kind(item)?)),
+    }
+}
+
+/// Kinds the relay accepts: those in `accepted` (any kind when empty) that
+/// are not in `rejected`, as inclusive ranges.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KindPolicy {
+    pub accepted: Vec<(u64, u64)>,
+    pub rejected: Vec<(u64, u64)>,
+}
+
+impl KindPolicy {
+    /// Parses comma separated kinds and kind ranges, e.g. `0,1,3,7,10002`.
+    pub fn parse(accepted: &str, rejected: &str) -> Result<KindPolicy, String> {
+        let ranges = |list: &str| -> Result<Vec<(u64, u64)>, String> {
+            list.split(',')
+                .map(str::trim)
+                .filter(|k| !k.is_empty())
+                .map(|k| parse_kind_range(k).map_err(|e| format!("{e}: {k}")))
+                .collect()
+        };
+        Ok(KindPolicy {
+            accepted: ranges(accepted).map_err(|e| format!("NOSTR_ACCEPTED_KINDS: {e}"))?,
+            rejected: ranges(rejected).map_err(|e| format!("NOSTR_REJECTED_KINDS: {e}"))?,
+        })
+    }
+
+    pub fn accepts(&self, kind: u64) -> bool {
+        let within = |ranges: &[(u64, u64)]| ranges.iter().any(|(a, b)| (*a..=*b).contains(&kind));
+        (self.accepted.is_empty() || within(&self.accepted)) && !within(&self.rejected)
+    }
+
+    pub fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        if !self.accepts(ev.kind) {
+            return Err(RejectReason::Blocked("kind not accepted".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Length of a rate limiting window, in seconds.
+pub const RATE_WINDOW: u64 = 60;
+
+/// Messages a connection or an address may send per window; unset limits
+/// are not counted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    /// EVENT messages per connection.
+    pub events: Option<u64>,
+    /// REQ and COUNT messages per connection.
+    pub reqs: Option<u64>,
+    /// Messages of any kind per source IP, across its connections.
+    pub per_ip: Option<u64>,
+}
+
+impl RateLimit {
+    /// Reads `NOSTR_RATE_LIMIT_EVENTS`, `NOSTR_RATE_LIMIT_REQS` and
+    /// `NOSTR_RATE_LIMIT_IP` through `get`.
+    pub fn from_lookup(get: impl Fn(&str) -> Option<String>) -> RateLimit {
+        let limit = |name| get(name).and_then(|v| v.parse().ok()).filter(|n| *n > 0);
+        RateLimit {
+            events: limit("NOSTR_RATE_LIMIT_EVENTS"),
+            reqs: limit("NOSTR_RATE_LIMIT_REQS"),
+            per_ip: limit("NOSTR_RATE_LIMIT_IP"),
+        }
+    }
+
+    /// The counters a `command` message adds to in the window of `now`,
+    /// with their limits.
+    pub fn counters(
+        &self,
+        command: &str,
+        conn_id: &str,
+        source_ip: Option<&str>,
+        now: u64,
+    ) -> Vec<(String, u64)> {
+        let window = now / RATE_WINDOW;
+        let per_connection = match command {
+            "EVENT" => self.events.map(|l| ("events", l)),
+            "REQ" | "COUNT" => self.reqs.map(|l| ("reqs", l)),
+            _ => None,
+        };
+        let mut counters = vec![];
+        if let Some((name, limit)) = per_connection {
+            counters.push((format!("rate#{conn_id}#{name}#{window}"), limit));
+        }
+        if let (Some(ip), Some(limit)) = (source_ip, self.per_ip) {
+            counters.push((format!("rate#ip#{ip}#{window}"), limit));
+        }
+        counters
+    }
+}
+
+static RELAY_POLICY: OnceCell<RelayPolicy> = OnceCell::new();
+
+/// What the relay enforces on every message, read once with `Config`, or by
+/// `RelayPolicy::init` in processes that run without one.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RelayPolicy {
+    pub limits: Limits,
+    pub rate_limit: RateLimit,
+    /// `NOSTR_ACCEPTED_KINDS` and `NOSTR_REJECTED_KINDS`.
+    pub kinds: KindPolicy,
+    /// `NOSTR_STREAM_DISPATCH`: stored events are fanned out by the
+    /// dispatcher from the table's stream instead of by the handler.
+    pub stream_dispatch: bool,
+}
+
+impl RelayPolicy {
+    /// Reads the settings through `get`, failing on a malformed kind list.
+    pub fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<RelayPolicy, String> {
+        Ok(RelayPolicy {
+            limits: Limits::from_lookup(&get),
+            rate_limit: RateLimit::from_lookup(&get),
+            kinds: KindPolicy::parse(
+                &get("NOSTR_ACCEPTED_KINDS").unwrap_or_default(),
+                &get("NOSTR_REJECTED_KINDS").unwrap_or_default(),
+            )?,
+            stream_dispatch: matches!(
+                get("NOSTR_STREAM_DISPATCH").as_deref(),
+                Some("1") | Some("true") | Some("yes")
+            ),
+        })
+    }
+
+    /// Reads the environment for processes without a `Config`, such as the
+    /// local server.
+    pub fn init() -> Result<&'static RelayPolicy, String> {
+        if let Some(policy) = RELAY_POLICY.get() {
+            return Ok(policy);
+        }
+        let policy = RelayPolicy::from_lookup(|name| std::env::var(name).ok())?;
+        Ok(RELAY_POLICY.get_or_init(|| policy))
+    }
+
+    /// The policy of the loaded `Config`, else that of `init`, else the
+    /// defaults (e.g. in tests).
+    pub fn current() -> &'static RelayPolicy {
+        static DEFAULT: Lazy<RelayPolicy> = Lazy::new(RelayPolicy::default);
+        Config::loaded()
+            .map(|c| &c.policy)
+            .or_else(|| RELAY_POLICY.get())
+            .unwrap_or(&DEFAULT)
+    }
+}
+
+/// In auth-required mode, events must be authored by the authenticated
+/// pubkey or by a pubkey it has been delegated to publish for.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuthBinding {
+    pub required: bool,
+    /// Only local users and their delegates may read, as a private inbox.
+    pub read_required: bool,
+    pub delegations: HashMap<String, HashSet<String>>,
+    /// The local users.
+    pub allowlist: Allowlist,
+}
+
+impl AuthBinding {
+    /// Reads `NOSTR_AUTH_REQUIRED`, `NOSTR_AUTH_REQUIRED_FOR_READS` and
+    /// `NOSTR_AUTH_DELEGATIONS` (`authenticated:author|author,...`).
+    pub fn from_env() -> AuthBinding {
+        let mut delegations: HashMap<String, HashSet<String>> = HashMap::new();
+        for d in env_list("NOSTR_AUTH_DELEGATIONS") {
+            if let Some((authed, authors)) = d.split_once(':') {
+                delegations
+                    .entry(authed.to_string())
+                    .or_default()
+                    .extend(authors.split('|').map(|a| a.to_string()));
+            }
+        }
+        AuthBinding {
+            required: env_flag("NOSTR_AUTH_REQUIRED"),
+            read_required: env_flag("NOSTR_AUTH_REQUIRED_FOR_READS"),
+            delegations,
+            allowlist: Allowlist::from_env(),
+        }
+    }
+
+    pub fn check_event(&self, ev: &Event, auth_pubkey: Option<&str>) -> Result<(), RejectReason> {
+        if !self.required {
+            return Ok(());
+        }
+        let Some(authed) = auth_pubkey else {
+            return Err(RejectReason::AuthRequired(
+                "authentication is required to publish".to_string(),
+            ));
+        };
+        if ev.pubkey == authed
+            || self
+                .delegations
+                .get(authed)
+                .is_some_and(|authors| authors.contains(ev.pubkey.as_str()))
+        {
+            return Ok(());
+        }
+        Err(RejectReason::Restricted(
+            "event pubkey does not match the authenticated pubkey".to_string(),
+        ))
+    }
+
+    /// Checks a REQ or COUNT of a connection authenticated as `auth_pubkey`.
+    pub fn check_read(&self, auth_pubkey: Option<&str>) -> Result<(), RejectReason> {
+        if !self.read_required {
+            return Ok(());
+        }
+        let Some(authed) = auth_pubkey else {
+            return Err(RejectReason::AuthRequired(
+                "authentication is required to read".to_string(),
+            ));
+        };
+        if self.allowlist.contains(authed) || self.delegations.contains_key(authed) {
+            return Ok(());
+        }
+        Err(RejectReason::Restricted("not allowed to read".to_string()))
+    }
+}
+
+/// How long stored events are kept: the first matching rule of
+/// `NOSTR_EVENT_RETENTION`, else `NOSTR_EVENT_TTL`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retention {
+    pub rules: Vec<RetentionRule>,
+    /// Seconds events no rule matches are kept.
+    pub default_ttl: u64,
+    /// Pubkeys `remote` rules leave out; only `NOSTR_ALLOWED_PUBKEYS`, as
+    /// the retention is fixed when the configuration is loaded.
+    pub local: Allowlist,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionRule {
+    /// Inclusive kind ranges; any kind when empty.
+    pub kinds: Vec<(u64, u64)>,
+    /// Only events of pubkeys other than the local users.
+    pub remote: bool,
+    /// Seconds kept after created_at; None keeps them forever.
+    pub time: Option<u64>,
+}
+
+impl Retention {
+    /// Parses rules like `0,3,10002=forever;1=7776000;remote=604800`: kinds,
+    /// kind ranges (`30000-39999`) or `remote`, and seconds or `forever`.
+    pub fn parse(rules: &str, default_ttl: u64) -> Result<Retention, String> {
+        let rules = rules
+            .split(';')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(|rule| {
+                let (selector, time) = rule
+                    .split_once('=')
+                    .ok_or(format!("rule without a time: {rule}"))?;
+                let time = match time.trim() {
+                    "forever" => None,
+                    t => Some(t.parse().map_err(|_| format!("bad time: {rule}"))?),
+                };
+                let mut parsed = RetentionRule {
+                    kinds: vec![],
+                    remote: false,
+                    time,
+                };
+                for item in selector.split(',').map(str::trim) {
+                    match item {
+                        "remote" => parsed.remote = true,
+                        _ => parsed
+                            .kinds
+                            .push(parse_kind_range(item).map_err(|e| format!("{e}: {rule}"))?),
+                    }
+                }
+                Ok(parsed)
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Retention {
+            rules,
+            default_ttl,
+            local: Allowlist::default(),
+        })
+    }
+
+    /// Reads `NOSTR_EVENT_RETENTION` and `NOSTR_EVENT_TTL`.
+    pub fn from_env() -> Result<Retention, String> {
+        let ttl = std::env::var("NOSTR_EVENT_TTL").unwrap_or_default();
+        let ttl = ttl
+            .parse()
+            .map_err(|_| format!("NOSTR_EVENT_TTL must be a number of seconds: {ttl}"))?;
+        let rules = std::env::var("NOSTR_EVENT_RETENTION").unwrap_or_default();
+        Retention::parse(&rules, ttl).map_err(|e| format!("NOSTR_EVENT_RETENTION: {e}"))
+    }
+
+    /// Seconds `ev` is kept after its created_at, None for forever.
+    pub fn ttl(&self, ev: &Event) -> Option<u64> {
+        let local = self.local.contains(ev.pubkey.as_str());
+        self.rules
+            .iter()
+            .find(|r| {
+                (!r.remote || !local)
+                    && (r.kinds.is_empty()
+                        || r.kinds.iter().any(|(a, b)| (*a..=*b).contains(&ev.kind)))
+            })
+            .map_or(Some(self.default_ttl), |r| r.time)
+    }
+
+    /// NIP-11 `retention` entries. Rules for remote pubkeys cannot be
+    /// expressed there and are left out.
+    pub fn nip11(&self) -> Vec<Value> {
+        let mut entries: Vec<Value> = self
+            .rules
+            .iter()
+            .filter(|r| !r.remote)
+            .map(|r| {
+                let kinds: Vec<Value> = r
+                    .kinds
+                    .iter()
+                    .map(|(a, b)| if a == b { json!(a) } else { json!([a, b]) })
+                    .collect();
+                match kinds.is_empty() {
+                    true => json!({ "time": r.time }),
+                    false => json!({ "kinds": kinds, "time": r.time }),
+                }
+            })
+            .collect();
+        entries.push(json!({ "time": self.default_ttl }));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Admission, AuthBinding, ContentWarningPolicy, CreatedAtBounds, DmRelay, KindPolicy, Limits,
+        RateLimit, ReplayWindow, Retention, ShadowMode,
+    };
+    use crate::allowlist::Allowlist;
+    use crate::message::{test_event, Event, Filter, KIND_GIFT_WRAP};
+    use crate::reject::RejectReason;
+    use crate::report::KIND_REPORT;
+    use std::collections::HashSet;
+
+    fn build_event(tags: Vec<Vec<String>>) -> Event {
+        Event {
+            tags,
+            content: "content".into(),
+            ..test_event()
+        }
+    }
+
+    #[test]
+    fn content_warning_policy01() {
+        let plain = build_event(vec![]);
+        let cw = build_event(vec![vec!["content-warning".into(), "spoiler".into()]]);
+
+        let policy = ContentWarningPolicy::Accept;
+        assert!(policy.check_event(&cw).is_ok());
+        assert!(policy.visible(&cw, false));
+
+        let policy = ContentWarningPolicy::Reject;
+        assert!(policy.check_event(&plain).is_ok());
+        assert!(policy.check_event(&cw).is_err());
+
+        let policy = ContentWarningPolicy::ExcludeUnauthenticated;
+        assert!(policy.check_event(&cw).is_ok());
+        assert!(policy.visible(&plain, false));
+        assert!(!policy.visible(&cw, false));
+        assert!(policy.visible(&cw, true));
+    }
+
+    #[test]
+    fn content_warning_policy_parse() {
+        assert_eq!(
+            Some(ContentWarningPolicy::ExcludeUnauthenticated),
+            ContentWarningPolicy::parse("exclude-unauthenticated")
+        );
+        assert_eq!(None, ContentWarningPolicy::parse("maybe"));
+    }
+
+    #[test]
+    fn shadow_mode01() {
+        let ev = build_event(vec![]);
+        assert!(!ShadowMode::default().applies(&ev));
+
+        let shadow = ShadowMode {
+            all: true,
+            ..ShadowMode::default()
+        };
+        assert!(shadow.applies(&ev));
+
+        let shadow = ShadowMode {
+            all: false,
+            pubkeys: [ev.pubkey.to_string()].into_iter().collect(),
+        };
+        assert!(shadow.applies(&ev));
+    }
+
+    /// Pubkeys of the local users in the tests.
+    const LOCAL_USERS: [&str; 2] = [
+        "14e83f2cffa739fa7d88de86acfe8edf0750841c9460ebf7e1c56ff381d89666",
+        "98f4285bcb2cc65c3a66bd77ccffd2563ed3303e7e02a489c63a887fcd06bbe5",
+    ];
+
+    fn local_users() -> Allowlist {
+        Allowlist::new(LOCAL_USERS.map(String::from))
+    }
+
+    #[test]
+    fn limits01() {
+        let limits = Limits {
+            max_limit: 50,
+            ..Limits::default()
+        };
+        assert_eq!(50, limits.clamp(None));
+        assert_eq!(10, limits.clamp(Some(10)));
+        assert_eq!(50, limits.clamp(Some(1000)));
+        assert_eq!(100, Limits::default().clamp(None));
+
+        let limits = Limits {
+            max_subscriptions: 2,
+            ..Limits::default()
+        };
+        let filters: Vec<Filter> =
+            serde_json::from_str(r#"[{"kinds":[1]},{"kinds":[7]}]"#).unwrap();
+        assert!(Limits::default().check_filters(&filters).is_ok());
+        let limits = Limits {
+            max_filters: 1,
+            ..limits
+        };
+        assert!(limits
+            .check_filters(&filters)
+            .is_err_and(|r| r.prefix() == "invalid"));
+        let open = vec!["s1".to_string(), "s2".to_string()];
+        assert!(limits.check_subscriptions(&open[..1], "s3").is_ok());
+        assert!(limits.check_subscriptions(&open, "s2").is_ok());
+        assert_eq!(
+            Err(RejectReason::Error("too many subscriptions".to_string())),
+            limits.check_subscriptions(&open, "s3")
+        );
+    }
+
+    #[test]
+    fn created_at_bounds01() {
+        let ev = build_event(vec![]);
+        assert!(CreatedAtBounds::default()
+            .check_event(&ev, ev.created_at + 100_000)
+            .is_ok());
+
+        let bounds = CreatedAtBounds {
+            lower_limit: Some(3600),
+            upper_limit: Some(900),
+        };
+        let off = Err(RejectReason::Invalid("created_at too far off".to_string()));
+        assert!(bounds.check_event(&ev, ev.created_at + 3600).is_ok());
+        assert_eq!(off, bounds.check_event(&ev, ev.created_at + 3601));
+        assert!(bounds.check_event(&ev, ev.created_at - 900).is_ok());
+        assert_eq!(off, bounds.check_event(&ev, ev.created_at - 901));
+    }
+
+    #[test]
+    fn kind_policy01() {
+        assert!(KindPolicy::default().accepts(30023));
+
+        let policy = KindPolicy::parse("0,1,3,7,30000-39999", "30023").unwrap();
+        assert!(policy.accepts(7));
+        assert!(policy.accepts(30000));
+        assert!(!policy.accepts(30023));
+        assert!(!policy.accepts(4));
+        assert_eq!(
+            Err(RejectReason::Blocked("kind not accepted".to_string())),
+            policy.check_event(&Event {
+                kind: 4,
+                ..build_event(vec![])
+            })
+        );
+        assert!(KindPolicy::parse("", "1,x").is_err());
+    }
+
+    #[test]
+    fn rate_limit01() {
+        assert!(RateLimit::default()
+            .counters("EVENT", "conn01", Some("192.0.2.1"), 120)
+            .is_empty());
+
+        let rate = RateLimit {
+            events: Some(10),
+            reqs: None,
+            per_ip: Some(100),
+        };
+        assert_eq!(
+            vec![
+                ("rate#conn01#events#2".to_string(), 10),
+                ("rate#ip#192.0.2.1#2".to_string(), 100)
+            ],
+            rate.counters("EVENT", "conn01", Some("192.0.2.1"), 179)
+        );
+        assert_eq!(
+            vec![("rate#ip#192.0.2.1#3".to_string(), 100)],
+            rate.counters("REQ", "conn01", Some("192.0.2.1"), 180)
+        );
+        assert!(rate.counters("CLOSE", "conn01", None, 180).is_empty());
+    }
+
+    #[test]
+    fn replay_window01() {
+        let ev = build_event(vec![]);
+        let now = ev.created_at + 1000;
+        assert!(ReplayWindow::default().check_event(&ev, now).is_ok());
+
+        let window = ReplayWindow {
+            max_age: Some(100),
+            ..ReplayWindow::default()
+        };
+        assert_eq!(
+            Err(RejectReason::Invalid("event too old".to_string())),
+            window.check_event(&ev, now)
+        );
+        assert!(window.check_event(&ev, ev.created_at + 100).is_ok());
+
+        let metadata = Event {
+            kind: 0,
+            ..build_event(vec![])
+        };
+        assert!(window.check_event(&metadata, now).is_ok());
+
+        let window = ReplayWindow {
+            max_age: Some(100),
+            import_pubkeys: [ev.pubkey.to_string()].into_iter().collect(),
+        };
+        assert!(window.check_event(&ev, now).is_ok());
+    }
+
+    #[test]
+    fn auth_binding01() {
+        let ev = build_event(vec![]);
+        assert!(AuthBinding::default().check_event(&ev, None).is_ok());
+
+        let binding = AuthBinding {
+            required: true,
+            read_required: false,
+            delegations: [(
+                "pub02".to_string(),
+                [ev.pubkey.to_string()].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        assert!(binding
+            .check_event(&ev, None)
+            .is_err_and(|r| r.prefix() == "auth-required"));
+        assert!(binding.check_event(&ev, Some(ev.pubkey.as_str())).is_ok());
+        assert!(binding.check_event(&ev, Some("pub02")).is_ok());
+        assert!(binding
+            .check_event(&ev, Some("pub03"))
+            .is_err_and(|r| r.prefix() == "restricted"));
+    }
+
+    #[test]
+    fn auth_binding_reads() {
+        assert!(AuthBinding::default().check_read(None).is_ok());
+
+        let binding = AuthBinding {
+            read_required: true,
+            delegations: [("pub02".to_string(), HashSet::new())]
+                .into_iter()
+                .collect(),
+            allowlist: local_users(),
+            ..AuthBinding::default()
+        };
+        assert!(binding
+            .check_read(None)
+            .is_err_and(|r| r.prefix() == "auth-required"));
+        assert!(binding.check_read(Some(LOCAL_USERS[0])).is_ok());
+        assert!(binding.check_read(Some("pub02")).is_ok());
+        assert!(binding
+            .check_read(Some("pub03"))
+            .is_err_and(|r| r.prefix() == "restricted"));
+    }
+
+    #[test]
+    fn dm_relay01() {
+        let dm = DmRelay { enabled: true };
+        let wrap = Event {
+            kind: KIND_GIFT_WRAP,
+            ..build_event(vec![vec!["p".into(), "pub02".into()]])
+        };
+        assert!(dm.check_event(&wrap).is_ok());
+        assert!(dm.check_event(&build_event(vec![])).is_err());
+        let unaddressed = Event {
+            tags: vec![],
+            ..wrap.clone()
+        };
+        assert!(dm
+            .check_event(&unaddressed)
+            .is_err_and(|r| r.prefix() == "invalid"));
+
+        let filter = |json: &str| -> Filter { serde_json::from_str(json).unwrap() };
+        let mine = [filter(r##"{"kinds": [1059], "#p": ["pub02"]}"##)];
+        assert!(dm.check_read(&mine, Some("pub02")).is_ok());
+        assert!(DmRelay::default().check_read(&mine, None).is_ok());
+        assert!(dm
+            .check_read(&mine, None)
+            .is_err_and(|r| r.prefix() == "auth-required"));
+        assert!(dm
+            .check_read(&mine, Some("pub03"))
+            .is_err_and(|r| r.prefix() == "restricted"));
+        let all = [filter(r#"{"kinds": [1059]}"#)];
+        assert!(dm.check_read(&all, Some("pub02")).is_err());
+    }
+
+    #[test]
+    fn admission_inbox() {
+        let mention = build_event(vec![vec!["p".into(), LOCAL_USERS[0].into()]]);
+        let stranger = build_event(vec![vec!["p".into(), "pub02".into()]]);
+        let local = Event {
+            pubkey: LOCAL_USERS[1].parse().unwrap(),
+            ..build_event(vec![])
+        };
+
+        let closed = Admission {
+            allowlist: local_users(),
+            ..Admission::default()
+        };
+        assert!(closed.check_event(&local).is_ok());
+        assert!(closed.check_event(&mention).is_err());
+
+        let inbox = Admission {
+            inbox: true,
+            ..closed
+        };
+        assert!(inbox.check_event(&local).is_ok());
+        assert!(inbox.check_event(&mention).is_ok());
+        assert_eq!(
+            Err(RejectReason::Blocked("not allowed".to_string())),
+            inbox.check_event(&stranger)
+        );
+
+        let report = Event {
+            kind: KIND_REPORT,
+            ..stranger.clone()
+        };
+        let reports = Admission {
+            reports: true,
+            ..Admission::default()
+        };
+        assert!(reports.check_event(&report).is_ok());
+        assert!(reports.check_event(&stranger).is_err());
+    }
+
+    #[test]
+    fn retention01() {
+        let retention = Retention {
+            local: local_users(),
+            ..Retention::parse(
+                "0, 3,10002=forever; remote,1=604800;1=7776000;30000-39999=2592000",
+                86400,
+            )
+            .unwrap()
+        };
+        let local = |kind| Event {
+            kind,
+            pubkey: LOCAL_USERS[1].parse().unwrap(),
+            ..build_event(vec![])
+        };
+        let remote = |kind| Event {
+            kind,
+            ..build_event(vec![])
+        };
+        assert_eq!(None, retention.ttl(&local(3)));
+        assert_eq!(Some(7776000), retention.ttl(&local(1)));
+        assert_eq!(Some(604800), retention.ttl(&remote(1)));
+        assert_eq!(Some(2592000), retention.ttl(&remote(30023)));
+        assert_eq!(Some(86400), retention.ttl(&local(7)));
+        assert_eq!(
+            vec![
+                serde_json::json!({"kinds": [0, 3, 10002], "time": null}),
+                serde_json::json!({"kinds": [1], "time": 7776000}),
+                serde_json::json!({"kinds": [[30000, 39999]], "time": 2592000}),
+                serde_json::json!({"time": 86400}),
+            ],
+            retention.nip11()
+        );
+        assert!(Retention::parse("1=1d", 86400).is_err());
+        assert!(Retention::parse("x=forever", 86400).is_err());
+    }
+}
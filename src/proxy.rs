@@ -0,0 +1,153 @@
+use crate::message::{Event, Filter};
+use crate::policy::{env_flag, env_list};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+const SUB_ID: &str = "proxy";
+
+/// Relays REQs are also forwarded to, read from the environment.
+#[derive(Debug, Clone)]
+pub struct Upstreams {
+    pub urls: Vec<String>,
+    /// How long to wait for an upstream's EOSE.
+    pub timeout: Duration,
+    /// Whether fetched events are stored locally.
+    pub cache: bool,
+}
+
+impl Upstreams {
+    /// Reads `NOSTR_PROXY_UPSTREAMS`, `NOSTR_PROXY_TIMEOUT_MS` and
+    /// `NOSTR_PROXY_CACHE`; None when no upstream is configured.
+    pub fn from_env() -> Option<Upstreams> {
+        let mut urls: Vec<String> = env_list("NOSTR_PROXY_UPSTREAMS").into_iter().collect();
+        if urls.is_empty() {
+            return None;
+        }
+        urls.sort();
+        let timeout = std::env::var("NOSTR_PROXY_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(3000);
+        let cache = env_flag("NOSTR_PROXY_CACHE");
+        Some(Upstreams {
+            urls,
+            timeout: Duration::from_millis(timeout),
+            cache,
+        })
+    }
+
+    /// Events every upstream has for `filters`, queried concurrently.
+    ///
+    /// Upstreams that fail or time out contribute what they sent so far;
+    /// events with a wrong id or signature or not matching the filters are
+    /// dropped.
+    pub async fn fetch(&self, filters: &[Filter]) -> Vec<Event> {
+        let fetches = self.urls.iter().map(|url| async move {
+            let mut evs = vec![];
+            let fetched = tokio::time::timeout(self.timeout, fetch(url, filters, &mut evs)).await;
+            match fetched {
+                Err(_) => println!("proxy {url}: timed out"),
+                Ok(Err(e)) => println!("proxy {url}: {e}"),
+                Ok(Ok(())) => {}
+            }
+            evs
+        });
+        futures_util::future::join_all(fetches)
+            .await
+            .into_iter()
+            .flatten()
+            .filter(|ev| acceptable(ev, filters))
+            .collect()
+    }
+}
+
+/// Whether an upstream event is genuine and asked for. The id is checked
+/// as well as the signature, since the events may be cached under it.
+fn acceptable(ev: &Event, filters: &[Filter]) -> bool {
+    ev.id == ev.hex_digest() && ev.validate().is_ok() && filters.iter().any(|f| f.event_match(ev))
+}
+
+/// Sends a REQ to `url` and collects the stored events until EOSE.
+async fn fetch(url: &str, filters: &[Filter], evs: &mut Vec<Event>) -> Result<(), String> {
+    let (mut ws, _) = connect_async(url)
+        .await
+        .map_err(|e| format!("connect: {e}"))?;
+    let mut req = vec![json!("REQ"), json!(SUB_ID)];
+    req.extend(filters.iter().map(|f| json!(f)));
+    ws.send(Message::Text(Value::Array(req).to_string()))
+        .await
+        .map_err(|e| format!("send: {e}"))?;
+
+    while let Some(frame) = ws.next().await {
+        let Message::Text(text) = frame.map_err(|e| format!("recv: {e}"))? else {
+            continue;
+        };
+        match parse_reply(&text) {
+            Some(Reply::Event(ev)) => evs.push(ev),
+            Some(Reply::Eose) => break,
+            None => {}
+        }
+    }
+    let close = json!(["CLOSE", SUB_ID]).to_string();
+    let _ = ws.send(Message::Text(close)).await;
+    let _ = ws.close(None).await;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Event(Event),
+    Eose,
+}
+
+/// An EVENT or EOSE for our subscription; other messages are ignored.
+fn parse_reply(text: &str) -> Option<Reply> {
+    let msg: Vec<Value> = serde_json::from_str(text).ok()?;
+    match &msg[..] {
+        [cmd, sub, ev] if cmd == "EVENT" && sub == SUB_ID => {
+            serde_json::from_value(ev.clone()).ok().map(Reply::Event)
+        }
+        [cmd, sub] if cmd == "EOSE" && sub == SUB_ID => Some(Reply::Eose),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{acceptable, parse_reply, Reply};
+    use crate::identity::Identity;
+    use crate::message::{Event, Filter};
+    use serde_json::json;
+
+    #[test]
+    fn parse_reply01() {
+        let ev = Event::sign(Identity::generate().keys(), 1676118868, 1, vec![], "hi");
+        assert_eq!(
+            Some(Reply::Event(ev.clone())),
+            parse_reply(&json!(["EVENT", "proxy", ev]).to_string())
+        );
+        assert_eq!(Some(Reply::Eose), parse_reply(r#"["EOSE","proxy"]"#));
+        assert_eq!(None, parse_reply(r#"["EOSE","other"]"#));
+        assert_eq!(None, parse_reply(r#"["NOTICE","hello"]"#));
+    }
+
+    #[test]
+    fn acceptable01() {
+        let filters: Vec<Filter> = vec![serde_json::from_str(r#"{"kinds": [1]}"#).unwrap()];
+        let ev = Event::sign(Identity::generate().keys(), 1676118868, 1, vec![], "hi");
+        let other = Event::sign(Identity::generate().keys(), 1676118868, 1, vec![], "bye");
+        assert!(acceptable(&ev, &filters));
+        assert!(!acceptable(
+            &Event {
+                kind: 7,
+                ..ev.clone()
+            },
+            &filters
+        ));
+        // A genuine event claiming another event's id.
+        assert!(!acceptable(&Event { id: other.id, ..ev }, &filters));
+    }
+}
@@ -0,0 +1,310 @@
+//! Fan-out through an SQS queue: the relay enqueues accepted events and a
+//! worker Lambda delivers them, so that a burst of events or thousands of
+//! subscribers never run into the 29 second API Gateway integration timeout.
+use crate::config::sdk_config;
+use crate::message::Event;
+use crate::relay;
+use crate::store::EventStore;
+use crate::transport::{event_frame, Transport};
+use aws_lambda_events::sqs::{BatchItemFailure, SqsEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Deliveries of a frame before it is dropped.
+const FANOUT_ATTEMPTS: u32 = 3;
+/// Connections delivered to at a time.
+const FANOUT_CONCURRENCY: usize = 16;
+
+/// Body of a fan-out queue message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FanOutMessage {
+    /// An accepted event still to be matched against the subscriptions.
+    Event(Event),
+    /// Frames a connection failed to take, queued again.
+    Retry {
+        conn_id: String,
+        frames: Vec<String>,
+        attempt: u32,
+    },
+}
+
+/// A frame for one connection and the queue message it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    message_id: String,
+    conn_id: String,
+    data: String,
+    attempt: u32,
+}
+
+/// What the worker did with a batch.
+#[derive(Debug, Default)]
+pub struct FanOutResult {
+    pub delivered: usize,
+    pub requeued: usize,
+    pub dropped: usize,
+    /// Messages to receive again because their frames could not be queued
+    /// for a retry.
+    pub failures: Vec<BatchItemFailure>,
+}
+
+pub struct FanOutQueue {
+    client: aws_sdk_sqs::Client,
+    url: String,
+}
+
+impl FanOutQueue {
+    /// Reads `NOSTR_FANOUT_QUEUE_URL`; None when it is not set.
+    pub async fn from_env() -> Option<FanOutQueue> {
+        let url = std::env::var("NOSTR_FANOUT_QUEUE_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let client = aws_sdk_sqs::Client::new(sdk_config().await);
+        Some(FanOutQueue { client, url })
+    }
+
+    pub async fn enqueue(&self, ev: &Event) -> Result<(), String> {
+        self.send(&FanOutMessage::Event(ev.clone()), 0).await
+    }
+
+    async fn send(&self, msg: &FanOutMessage, delay: i32) -> Result<(), String> {
+        self.client
+            .send_message()
+            .queue_url(&self.url)
+            .message_body(serde_json::to_string(msg).unwrap())
+            .delay_seconds(delay)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    /// Delivers a batch received from the queue.
+    ///
+    /// Frames are grouped by connection and sent in order, one connection
+    /// at a time per delivery slot. Frames a connection fails to take are
+    /// queued again with a delay, so that retries go only to that
+    /// connection.
+    pub async fn process(
+        &self,
+        store: &dyn EventStore,
+        api: &dyn Transport,
+        batch: &SqsEvent,
+    ) -> FanOutResult {
+        let mut frames = vec![];
+        // The queue delivers at least once, so an event may come twice in a
+        // batch; each subscription gets it once.
+        let mut seen = HashSet::new();
+        for (message_id, msg) in queued_messages(batch) {
+            match msg {
+                FanOutMessage::Event(ev) => {
+                    for (sub, conn_id) in relay::matching_subscriptions(store, &ev).await {
+                        if !seen.insert((conn_id.clone(), sub.clone(), ev.id.clone())) {
+                            continue;
+                        }
+                        frames.push(Frame {
+                            message_id: message_id.clone(),
+                            conn_id,
+                            data: event_frame(&sub, &ev),
+                            attempt: 0,
+                        });
+                    }
+                }
+                FanOutMessage::Retry {
+                    conn_id,
+                    frames: data,
+                    attempt,
+                } => {
+                    frames.extend(data.into_iter().map(|data| Frame {
+                        message_id: message_id.clone(),
+                        conn_id: conn_id.clone(),
+                        data,
+                        attempt,
+                    }));
+                }
+            }
+        }
+
+        let deliveries: Vec<_> = by_connection(frames)
+            .into_iter()
+            .map(|(conn_id, frames)| deliver(api, conn_id, frames))
+            .collect();
+        let results: Vec<_> =
+            futures_util::StreamExt::collect(futures_util::StreamExt::buffer_unordered(
+                futures_util::stream::iter(deliveries),
+                FANOUT_CONCURRENCY,
+            ))
+            .await;
+
+        let mut result = FanOutResult::default();
+        let mut failed = HashSet::new();
+        for (conn_id, delivered, undelivered) in results {
+            result.delivered += delivered;
+            if undelivered.is_empty() {
+                continue;
+            }
+            let attempt = undelivered.iter().map(|f| f.attempt).max().unwrap_or(0) + 1;
+            if attempt >= FANOUT_ATTEMPTS {
+                println!(
+                    "fan-out {conn_id}: dropping {} frames after {attempt} attempts",
+                    undelivered.len()
+                );
+                result.dropped += undelivered.len();
+                continue;
+            }
+            let retry = FanOutMessage::Retry {
+                conn_id: conn_id.clone(),
+                frames: undelivered.iter().map(|f| f.data.clone()).collect(),
+                attempt,
+            };
+            match self.send(&retry, retry_delay(attempt)).await {
+                Ok(()) => result.requeued += undelivered.len(),
+                Err(e) => {
+                    println!("fan-out {conn_id}: requeue err: {e}");
+                    failed.extend(undelivered.into_iter().map(|f| f.message_id));
+                }
+            }
+        }
+        result.failures = failed
+            .into_iter()
+            .map(|item_identifier| BatchItemFailure { item_identifier })
+            .collect();
+        result
+    }
+}
+
+/// Messages of the batch by message id; bodies that do not parse are
+/// logged and left out, as receiving them again would not help.
+fn queued_messages(batch: &SqsEvent) -> Vec<(String, FanOutMessage)> {
+    batch
+        .records
+        .iter()
+        .filter_map(|r| {
+            let id = r.message_id.clone().unwrap_or_default();
+            match serde_json::from_str(r.body.as_deref().unwrap_or_default()) {
+                Ok(msg) => Some((id, msg)),
+                Err(e) => {
+                    println!("fan-out {id}: malformed message: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Frames grouped by connection, in the order connections first appear and
+/// frames were queued.
+fn by_connection(frames: Vec<Frame>) -> Vec<(String, Vec<Frame>)> {
+    let mut index = HashMap::new();
+    let mut groups: Vec<(String, Vec<Frame>)> = vec![];
+    for frame in frames {
+        let i = *index.entry(frame.conn_id.clone()).or_insert_with(|| {
+            groups.push((frame.conn_id.clone(), vec![]));
+            groups.len() - 1
+        });
+        groups[i].1.push(frame);
+    }
+    groups
+}
+
+/// Sends the frames of a connection in order, stopping at the first failure
+/// worth retrying. Returns the connection, how many frames were delivered
+/// and the frames still to send.
+async fn deliver(
+    api: &dyn Transport,
+    conn_id: String,
+    frames: Vec<Frame>,
+) -> (String, usize, Vec<Frame>) {
+    let mut delivered = 0;
+    for (i, frame) in frames.iter().enumerate() {
+        match api.try_post_connection(&conn_id, &frame.data).await {
+            Ok(true) => delivered += 1,
+            // Gone connections and oversized frames are not retried.
+            Ok(false) => {}
+            Err(e) => {
+                println!("fan-out {conn_id}: {e}");
+                return (conn_id, delivered, frames[i..].to_vec());
+            }
+        }
+    }
+    (conn_id, delivered, vec![])
+}
+
+/// Seconds a retry waits in the queue, doubling per attempt.
+fn retry_delay(attempt: u32) -> i32 {
+    (5 << attempt.min(7)).min(900)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{by_connection, deliver, queued_messages, retry_delay, FanOutMessage, Frame};
+    use crate::message::test_event;
+    use crate::transport::MemoryTransport;
+
+    fn frame(message_id: &str, conn_id: &str, data: &str) -> Frame {
+        Frame {
+            message_id: message_id.into(),
+            conn_id: conn_id.into(),
+            data: data.into(),
+            attempt: 0,
+        }
+    }
+
+    #[test]
+    fn queued_messages01() {
+        let ev = test_event();
+        let retry = FanOutMessage::Retry {
+            conn_id: "conn01".into(),
+            frames: vec!["[]".into()],
+            attempt: 1,
+        };
+        let record = |id: &str, body: String| serde_json::json!({"messageId": id, "body": body, "attributes": {}, "messageAttributes": {}});
+        let batch = serde_json::from_value(serde_json::json!({
+            "Records": [
+                record("m1", serde_json::to_string(&FanOutMessage::Event(ev.clone())).unwrap()),
+                record("m2", serde_json::to_string(&retry).unwrap()),
+                record("m3", "{}".into()),
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                ("m1".to_string(), FanOutMessage::Event(ev)),
+                ("m2".to_string(), retry)
+            ],
+            queued_messages(&batch)
+        );
+    }
+
+    #[tokio::test]
+    async fn by_connection01() {
+        let groups = by_connection(vec![
+            frame("m1", "conn01", "a"),
+            frame("m1", "conn02", "b"),
+            frame("m2", "conn01", "c"),
+        ]);
+        assert_eq!(
+            vec!["conn01", "conn02"],
+            groups.iter().map(|(c, _)| c.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["a", "c"],
+            groups[0]
+                .1
+                .iter()
+                .map(|f| f.data.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let api = MemoryTransport::new();
+        let (conn_id, frames) = groups.into_iter().next().unwrap();
+        let (_, delivered, undelivered) = deliver(&api, conn_id, frames).await;
+        assert_eq!((2, 0), (delivered, undelivered.len()));
+        assert_eq!(vec!["a", "c"], api.frames("conn01"));
+
+        assert_eq!(10, retry_delay(1));
+        assert_eq!(640, retry_delay(7));
+    }
+}
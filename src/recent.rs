@@ -0,0 +1,61 @@
+use crate::types::EventId;
+use std::collections::{HashSet, VecDeque};
+
+/// The last `capacity` event ids remembered, oldest forgotten first.
+#[derive(Debug, Clone, Default)]
+pub struct RecentIds {
+    capacity: usize,
+    order: VecDeque<EventId>,
+    ids: HashSet<EventId>,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> RecentIds {
+        RecentIds {
+            capacity,
+            ..RecentIds::default()
+        }
+    }
+
+    pub fn insert(&mut self, id: &EventId) {
+        if self.capacity == 0 || !self.ids.insert(id.clone()) {
+            return;
+        }
+        self.order.push_back(id.clone());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn contains(&self, id: &EventId) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecentIds;
+    use crate::types::EventId;
+
+    #[test]
+    fn insert01() {
+        let ids: Vec<EventId> = ["1d01", "1d02", "1d03"]
+            .iter()
+            .map(|id| EventId::padded(id))
+            .collect();
+        let mut recent = RecentIds::new(2);
+        recent.insert(&ids[0]);
+        recent.insert(&ids[1]);
+        recent.insert(&ids[0]);
+        assert!(recent.contains(&ids[0]));
+        recent.insert(&ids[2]);
+        assert!(!recent.contains(&ids[0]));
+        assert!(recent.contains(&ids[1]) && recent.contains(&ids[2]));
+
+        let mut disabled = RecentIds::new(0);
+        disabled.insert(&ids[0]);
+        assert!(!disabled.contains(&ids[0]));
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+
+/// Why a message was refused. It is sent to the client as `prefix: message`
+/// with the machine-readable prefixes of NIP-01 OK and CLOSED messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    Duplicate(String),
+    Pow(String),
+    Blocked(String),
+    RateLimited(String),
+    Invalid(String),
+    Restricted(String),
+    /// NIP-42: the client has to authenticate first.
+    AuthRequired(String),
+    /// A filter the relay cannot serve; the client may narrow it.
+    Unsupported(String),
+    /// The relay failed; the client did nothing wrong.
+    Error(String),
+}
+
+impl RejectReason {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            RejectReason::Duplicate(_) => "duplicate",
+            RejectReason::Pow(_) => "pow",
+            RejectReason::Blocked(_) => "blocked",
+            RejectReason::RateLimited(_) => "rate-limited",
+            RejectReason::Invalid(_) => "invalid",
+            RejectReason::Restricted(_) => "restricted",
+            RejectReason::AuthRequired(_) => "auth-required",
+            RejectReason::Unsupported(_) => "unsupported",
+            RejectReason::Error(_) => "error",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            RejectReason::Duplicate(m)
+            | RejectReason::Pow(m)
+            | RejectReason::Blocked(m)
+            | RejectReason::RateLimited(m)
+            | RejectReason::Invalid(m)
+            | RejectReason::Restricted(m)
+            | RejectReason::AuthRequired(m)
+            | RejectReason::Unsupported(m)
+            | RejectReason::Error(m) => m,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.prefix(), self.message())
+    }
+}
+
+impl From<RejectReason> for String {
+    fn from(reason: RejectReason) -> String {
+        reason.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RejectReason;
+
+    #[test]
+    fn reject_reason01() {
+        let reason = RejectReason::RateLimited("slow down".into());
+        assert_eq!("rate-limited", reason.prefix());
+        assert_eq!("rate-limited: slow down", reason.to_string());
+        assert_eq!(
+            "auth-required: sign in",
+            String::from(RejectReason::AuthRequired("sign in".into()))
+        );
+    }
+}
@@ -1,156 +1,1804 @@
-use crate::apigwmgmt::ApiGwMgmt;
-use crate::ddb::Ddb;
-use crate::ddb::QueryPlan;
-use crate::hook::HOOKS;
-use crate::message::{CloseCmd, Event, EventCmd, MessageContext, ReqCmd};
-use std::collections::HashSet;
-
-pub async fn process_event(ctx: &MessageContext, cmd: &Option<EventCmd>) {
-    if let Some(cmd) = cmd {
-        println!(
-            "cmd: {}, conn: {}, event: {:?}",
-            cmd.cmd, ctx.connection_id, cmd.event
-        );
-        let api = ApiGwMgmt::new(&ctx.endpoint).await;
-        if cmd.event.pubkey != "14e83f2cffa739fa7d88de86acfe8edf0750841c9460ebf7e1c56ff381d89666"
-            && cmd.event.pubkey
-                != "98f4285bcb2cc65c3a66bd77ccffd2563ed3303e7e02a489c63a887fcd06bbe5"
-        {
-            api.send_nip20msg(
-                &ctx.connection_id,
-                &cmd.event.id,
-                false,
-                "blocked: not allowed",
-            )
+use crate::allowlist::Allowlist;
+use crate::auth;
+use crate::denylist::Denylist;
+use crate::hook::{HookContext, Hooks};
+use crate::label::{hidden_labels, trusted_labelers, HiddenTargets};
+use crate::message::{
+    body_verb, parse_closemsg, parse_eventmsg, parse_reqmsg, CloseCmd, Event, EventCmd, Filter,
+    MessageContext, ReqCmd,
+};
+use crate::metrics;
+use crate::nip11;
+use crate::nip86;
+use crate::policy::{
+    Admission, AuthBinding, ContentWarningPolicy, CreatedAtBounds, DmRelay, Limits, RateLimit,
+    RelayPolicy, ReplayWindow, ShadowMode, RATE_WINDOW,
+};
+use crate::reject::RejectReason;
+use crate::store::{filter_match, EventStore, QueryPlan};
+use crate::transport::Transport;
+use crate::types::EventId;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashSet, VecDeque};
+use std::time::SystemTime;
+
+/// The result of processing a single client message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The event was accepted and dispatched to `delivered` subscriptions.
+    Accepted { delivered: usize },
+    /// The event was already stored and was acknowledged without being
+    /// checked or written again.
+    Duplicate,
+    /// The message was refused; the reason is the one sent to the client.
+    Rejected(RejectReason),
+    /// A REQ was answered with `delivered` stored events before EOSE.
+    Delivered(usize),
+    /// A COUNT was answered with the count.
+    Counted(u64),
+    /// A connection was opened.
+    Connected,
+    /// The connection authenticated as the pubkey.
+    Authenticated(String),
+    /// A subscription or connection was closed.
+    Closed,
+    /// The message could not be parsed.
+    Malformed,
+    /// Storage or delivery failed.
+    Error(String),
+}
+
+pub async fn process_event(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    cmd: &Option<EventCmd>,
+) -> Outcome {
+    let Some(cmd) = cmd else {
+        return Outcome::Malformed;
+    };
+    println!(
+        "cmd: {}, conn: {}, event: {:?}",
+        cmd.cmd, ctx.connection_id, cmd.event
+    );
+    if cmd.event.kind == nip86::KIND_MANAGEMENT_REQUEST {
+        return process_management(ctx, store, api, &cmd.event).await;
+    }
+    let dm_relay = DmRelay::from_env();
+    let admission = if dm_relay.enabled {
+        dm_relay.check_event(&cmd.event)
+    } else {
+        Admission {
+            allowlist: Allowlist::load(store).await,
+            ..Admission::from_env()
+        }
+        .check_event(&cmd.event)
+    };
+    #[cfg(feature = "payments")]
+    let admission = match admission {
+        Err(refused) if !dm_relay.enabled && crate::payments::admission_fee().is_some() => {
+            crate::payments::check_member(store, &cmd.event, refused).await
+        }
+        admission => admission,
+    };
+    let admitted =
+        match admission.and_then(|_| RelayPolicy::current().kinds.check_event(&cmd.event)) {
+            Ok(()) => check_ban(store, &cmd.event).await,
+            Err(reason) => Err(reason),
+        };
+    if let Err(reason) = admitted {
+        api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
             .await;
-            return;
-        }
-        if let Err(reason) = cmd.event.validate() {
-            println!("sig:{reason}");
-            api.send_nip20msg(
-                &ctx.connection_id,
-                &cmd.event.id,
-                false,
-                "invalid: signature is wrong",
-            )
+        return Outcome::Rejected(reason);
+    }
+    // The id is what the duplicate check trusts, so it must be the event's.
+    if cmd.event.id != cmd.event.hex_digest() {
+        let reason = RejectReason::Invalid("event id is wrong".to_string());
+        api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
+            .await;
+        return Outcome::Rejected(reason);
+    }
+    // Clients re-broadcast what they publish to every relay; a stored event
+    // is acknowledged before the signature check and the hooks.
+    if !cmd.event.is_nip16_ephemeral() {
+        match store.has_event(&cmd.event.id).await {
+            Ok(true) => {
+                api.send_nip20msg(
+                    &ctx.connection_id,
+                    &cmd.event.id,
+                    true,
+                    "duplicate: already have this event",
+                )
+                .await;
+                return Outcome::Duplicate;
+            }
+            Ok(false) => {}
+            Err(e) => println!("duplicate check err: {e}"),
+        }
+    }
+    if let Err(e) = cmd.event.validate() {
+        println!("sig:{e}");
+        let reason = RejectReason::Invalid("signature is wrong".to_string());
+        api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
+            .await;
+        return Outcome::Rejected(reason);
+    }
+    println!("sig:ok");
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let cw_policy = ContentWarningPolicy::from_env();
+    if let Err(reason) = AuthBinding::from_env()
+        .check_event(&cmd.event, ctx.auth_pubkey.as_deref())
+        .and_then(|_| cw_policy.check_event(&cmd.event))
+        .and_then(|_| CreatedAtBounds::from_env().check_event(&cmd.event, now))
+        .and_then(|_| ReplayWindow::from_env().check_event(&cmd.event, now))
+        .and_then(|_| check_expiration(&cmd.event, now))
+        .and_then(|_| store.check_event(&cmd.event))
+    {
+        api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
+            .await;
+        return Outcome::Rejected(reason);
+    }
+
+    // A hook may veto the write, e.g. the content filter.
+    let hooks = Hooks::global();
+    let hook_ctx = HookContext::new(store).with_message(ctx, api);
+    if let Err(reason) = hooks.pre_event_write_hook(&hook_ctx, &cmd.event).await {
+        api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
             .await;
-        } else {
-            println!("sig:ok");
-            let ddb = Ddb::new().await;
-            HOOKS.pre_event_write_hook(&cmd.event).await;
-            write_event(&ddb, ctx, &cmd.event).await;
-            HOOKS.post_event_write_hook(&cmd.event).await;
-            dispatch_event(&ddb, ctx, &cmd.event).await;
+        return Outcome::Rejected(reason);
+    }
+
+    // Commit the write and acknowledge it before the post-write hooks and
+    // the fan-out, which may take a while; a held OK would only go out in
+    // the route response after them.
+    if let Err(reason) = write_event(store, &cmd.event).await {
+        api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
+            .await;
+        return Outcome::Error(reason.to_string());
+    }
+    api.send_nip20msg(&ctx.connection_id, &cmd.event.id, true, "")
+        .await;
+    api.flush().await;
+
+    hooks.post_event_write_hook(&hook_ctx, &cmd.event).await;
+    #[cfg(feature = "queue")]
+    if let Some(queue) = crate::queue::FanOutQueue::from_env().await {
+        match queue.enqueue(&cmd.event).await {
+            Ok(()) => return Outcome::Accepted { delivered: 0 },
+            Err(e) => println!("fan-out queue err: {e}"),
+        }
+    }
+    // The dispatcher Lambda fans stored events out from the table's stream.
+    let delivered = if RelayPolicy::current().stream_dispatch && !cmd.event.is_nip16_ephemeral() {
+        0
+    } else {
+        fan_out(store, api, &cmd.event).await
+    };
+    Outcome::Accepted { delivered }
+}
+
+/// NIP-86 request signed by an admin, answered in the OK message and
+/// neither stored nor dispatched.
+async fn process_management(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    event: &Event,
+) -> Outcome {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let req = match nip86::parse_event(event, now) {
+        Ok(req) => req,
+        Err(reason) => {
+            api.send_rejection(&ctx.connection_id, &event.id, &reason)
+                .await;
+            return Outcome::Rejected(reason);
+        }
+    };
+    println!("management: {} {:?}", req.method, req.params);
+    match nip86::handle(store, &req).await {
+        Ok(result) => {
+            api.send_nip20msg(&ctx.connection_id, &event.id, true, &result.to_string())
+                .await;
+            Outcome::Accepted { delivered: 0 }
+        }
+        Err(e) => {
+            let reason = RejectReason::Error(e.clone());
+            api.send_rejection(&ctx.connection_id, &event.id, &reason)
+                .await;
+            Outcome::Error(e)
         }
     }
 }
 
-async fn write_event(ddb: &Ddb, ctx: &MessageContext, event: &Event) {
-    let api = ApiGwMgmt::new(&ctx.endpoint).await;
+/// Sends a stored event to the matching subscriptions, returning how many
+/// frames were delivered.
+pub async fn fan_out(store: &dyn EventStore, api: &dyn Transport, event: &Event) -> usize {
+    if dispatchable(event) {
+        dispatch_event(store, api, event, &hidden_targets(store).await).await
+    } else {
+        0
+    }
+}
+
+/// The subscriptions a stored event is sent to, as `(sub_id, conn_id)`.
+pub async fn matching_subscriptions(
+    store: &dyn EventStore,
+    event: &Event,
+) -> Vec<(String, String)> {
+    if dispatchable(event) {
+        dispatch_targets(store, event, &hidden_targets(store).await).await
+    } else {
+        vec![]
+    }
+}
+
+fn dispatchable(event: &Event) -> bool {
+    // Subscriptions carry no authentication state, so events that must not
+    // reach unauthenticated readers are not dispatched live.
+    if ShadowMode::from_env().applies(event) {
+        println!("shadow: not dispatching {}", event.id);
+        false
+    } else {
+        ContentWarningPolicy::from_env().visible(event, false)
+    }
+}
 
+/// The binding for REQ and COUNT, with the store's allowlist when reads
+/// are limited to the local users.
+async fn read_binding(store: &dyn EventStore) -> AuthBinding {
+    let binding = AuthBinding::from_env();
+    if !binding.read_required {
+        return binding;
+    }
+    AuthBinding {
+        allowlist: Allowlist::load(store).await,
+        ..binding
+    }
+}
+
+/// NIP-40: events published after their expiration are dropped.
+fn check_expiration(event: &Event, now: u64) -> Result<(), RejectReason> {
+    if event.is_expired(now) {
+        return Err(RejectReason::Invalid("event has expired".to_string()));
+    }
+    Ok(())
+}
+
+/// Enforces the per-connection subscription limit. The limit is skipped
+/// when the store cannot list the connection's subscriptions.
+async fn check_subscriptions(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    sub_id: &str,
+) -> Result<(), RejectReason> {
+    match store.get_subscription_ids(&ctx.connection_id).await {
+        Ok(open) => RelayPolicy::current()
+            .limits
+            .check_subscriptions(&open, sub_id),
+        Err(e) => {
+            println!("store err: {e}");
+            Ok(())
+        }
+    }
+}
+
+/// Refuses events by banned pubkeys and banned event ids.
+async fn check_ban(store: &dyn EventStore, event: &Event) -> Result<(), RejectReason> {
+    Denylist::load(store).await.check_event(event)?;
+    match store.is_banned(&event.pubkey).await {
+        Ok(true) => Err(RejectReason::Blocked("pubkey is banned".to_string())),
+        Ok(false) => Ok(()),
+        Err(e) => {
+            println!("ban check err: {e}");
+            Ok(())
+        }
+    }
+}
+
+async fn write_event(store: &dyn EventStore, event: &Event) -> Result<(), RejectReason> {
     if event.is_nip16_ephemeral() {
-        api.send_nip20msg(&ctx.connection_id, &event.id, true, "")
+        return Ok(());
+    }
+
+    store.write_event(event).await.map_err(|r| {
+        println!("store err: {r}");
+        RejectReason::Error("failed to save the event".to_string())
+    })
+}
+
+/// Stores the events fetched from upstream relays that are not stored yet,
+/// after the checks and hooks a published event goes through. Deletions go
+/// last so that they also apply to the events fetched with them.
+#[cfg(feature = "proxy")]
+async fn cache_fetched(store: &dyn EventStore, denylist: &Denylist, fetched: &[Event]) -> usize {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let kind_policy = &RelayPolicy::current().kinds;
+    let hooks = Hooks::global();
+    let hook_ctx = HookContext::new(store);
+    let mut evs: Vec<&Event> = fetched
+        .iter()
+        .filter(|ev| denylist.allows(ev) && !ev.is_nip16_ephemeral())
+        .collect();
+    evs.sort_by_key(|ev| ev.kind == crate::message::KIND_DELETION);
+
+    let mut cached = 0;
+    for ev in evs {
+        match store.has_event(&ev.id).await {
+            Ok(false) => {}
+            Ok(true) => continue,
+            Err(e) => {
+                println!("duplicate check err: {e}");
+                continue;
+            }
+        }
+        let checked = kind_policy
+            .check_event(ev)
+            .and_then(|_| check_expiration(ev, now))
+            .and_then(|_| store.check_event(ev));
+        let checked = match checked {
+            Ok(()) => hooks.pre_event_write_hook(&hook_ctx, ev).await,
+            Err(reason) => Err(reason),
+        };
+        if let Err(reason) = checked {
+            println!("proxy: not caching {}: {reason}", ev.id);
+            continue;
+        }
+        if write_event(store, ev).await.is_ok() {
+            hooks.post_event_write_hook(&hook_ctx, ev).await;
+            cached += 1;
+        }
+    }
+    cached
+}
+
+/// Events hidden by the labels in `NOSTR_HIDDEN_LABELS` of the labelers in
+/// `NOSTR_TRUSTED_LABELERS`.
+async fn hidden_targets(store: &dyn EventStore) -> HiddenTargets {
+    let labels = hidden_labels();
+    if labels.is_empty() {
+        return HiddenTargets::default();
+    }
+    HiddenTargets::load(store, &labels, &trusted_labelers()).await
+}
+
+async fn dispatch_event(
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    event: &Event,
+    hidden: &HiddenTargets,
+) -> usize {
+    let concurrency = RelayPolicy::current().limits.dispatch_concurrency;
+    let targets = dispatch_targets(store, event, hidden).await;
+    let mut targets = targets.iter();
+    let mut pending = FuturesUnordered::new();
+    let mut delivered = 0;
+
+    // A delivery starts whenever one finishes, so that a slow connection
+    // holds up one slot rather than every subscription after it.
+    loop {
+        while pending.len() < concurrency {
+            let Some((sub, conn)) = targets.next() else {
+                break;
+            };
+            pending.push(api.reply_event(sub, conn, event));
+        }
+        match pending.next().await {
+            Some(true) => delivered += 1,
+            Some(false) => {}
+            None => break,
+        }
+    }
+    delivered
+}
+
+/// Each subscription at most once, however many of its filters match.
+/// Events hidden by trusted labelers go to none, as in REQ results.
+async fn dispatch_targets(
+    store: &dyn EventStore,
+    event: &Event,
+    hidden: &HiddenTargets,
+) -> Vec<(String, String)> {
+    if hidden.is_hidden(event) {
+        println!("label: not dispatching {}", event.id);
+        return vec![];
+    }
+    let v = store.get_subscriptions_for(event).await;
+    let mut seen = HashSet::new();
+    let mut targets = vec![];
+    for (sub, conn, fs) in v {
+        if fs.iter().any(|f| f.event_match(event)) && seen.insert((conn.clone(), sub.clone())) {
+            targets.push((sub, conn));
+        }
+    }
+    targets
+}
+
+pub async fn process_req(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    cmd: &Option<ReqCmd>,
+) -> Outcome {
+    let Some(cmd) = cmd else {
+        return Outcome::Malformed;
+    };
+    println!(
+        "cmd: {}, conn: {}, arg: {:?}",
+        cmd.cmd, ctx.connection_id, cmd
+    );
+
+    if let Some(reason) = read_binding(store)
+        .await
+        .check_read(ctx.auth_pubkey.as_deref())
+        .and_then(|_| DmRelay::from_env().check_read(&cmd.filters, ctx.auth_pubkey.as_deref()))
+        .and_then(|_| RelayPolicy::current().limits.check_filters(&cmd.filters))
+        .err()
+        .or_else(|| cmd.filters.iter().find_map(|f| f.validate().err()))
+        .or_else(|| unplannable(&cmd.filters))
+    {
+        api.send_closed(&ctx.connection_id, &cmd.subscription_id, &reason)
             .await;
-        return;
+        return Outcome::Rejected(reason);
+    }
+    if let Err(reason) = check_subscriptions(ctx, store, &cmd.subscription_id).await {
+        api.send_closed(&ctx.connection_id, &cmd.subscription_id, &reason)
+            .await;
+        return Outcome::Rejected(reason);
     }
 
-    let ret = ddb.write_event(event).await;
+    let ret = store
+        .write_subscription(&ctx.connection_id, &cmd.subscription_id, &cmd.filters)
+        .await;
     match ret {
-        Ok(r) => {
-            println!("ddb ok: {r:?}");
-            api.send_nip20msg(&ctx.connection_id, &event.id, true, "")
+        Ok(true) => println!("replaced subscription {}", cmd.subscription_id),
+        Ok(false) => update_gauges(store, 0, 1).await,
+        Err(r) => {
+            println!("store err: {r}");
+            let reason = RejectReason::Error("failed to save the subscription".to_string());
+            api.send_closed(&ctx.connection_id, &cmd.subscription_id, &reason)
                 .await;
+            return Outcome::Error(r);
         }
-        Err(r) => {
-            println!("ddb err: {r:?}");
-            api.send_nip20msg(
-                &ctx.connection_id,
-                &event.id,
-                false,
-                "error: failed to save the event",
-            )
-            .await;
+    }
+
+    let limits = &RelayPolicy::current().limits;
+    let denylist = Denylist::load(store).await;
+    let mut sources: Vec<Source> = cmd
+        .filters
+        .iter()
+        .filter(|f| !f.is_live_only())
+        .map(|f| Source::stored(Pager::new(f, limits)))
+        .collect();
+    #[cfg(feature = "proxy")]
+    if let Some(upstreams) = crate::proxy::Upstreams::from_env() {
+        let fetched = upstreams.fetch(&cmd.filters).await;
+        println!("proxy: fetched {} events", fetched.len());
+        if upstreams.cache {
+            let cached = cache_fetched(store, &denylist, &fetched).await;
+            println!("proxy: cached {cached} events");
+        }
+        sources.push(Source::fetched(fetched));
+    }
+
+    let hidden = hidden_targets(store).await;
+    let cw_policy = ContentWarningPolicy::from_env();
+    let authenticated = ctx.auth_pubkey.is_some();
+    // Events left out do not count toward the limit, only those sent.
+    let mut seen = HashSet::new();
+    let mut sent = 0;
+    let mut delivered = 0;
+    let consumed = store.consumed_capacity();
+    while sent < limits.max_limit as usize {
+        if limits
+            .max_req_capacity
+            .is_some_and(|budget| store.consumed_capacity() - consumed > budget as f64)
+        {
+            let notice = format!(
+                "{}: read capacity budget exceeded, results may be incomplete",
+                cmd.subscription_id
+            );
+            api.send_notice(&ctx.connection_id, &notice).await;
+            break;
+        }
+        let Some(ev) = next_newest(&mut sources, store).await else {
+            break;
+        };
+        if !seen.insert(ev.id.clone())
+            || hidden.is_hidden(&ev)
+            || !denylist.allows(&ev)
+            || !cw_policy.visible(&ev, authenticated)
+        {
+            continue;
+        }
+        sent += 1;
+        if api
+            .reply_event(&cmd.subscription_id, &ctx.connection_id, &ev)
+            .await
+        {
+            delivered += 1;
         }
     }
+    api.send_nip15eose(&ctx.connection_id, &cmd.subscription_id)
+        .await;
+    Outcome::Delivered(delivered)
 }
 
-async fn dispatch_event(ddb: &Ddb, ctx: &MessageContext, event: &Event) {
-    let api = ApiGwMgmt::new(&ctx.endpoint).await;
-    let v = ddb.get_all_subscriptions().await;
-    for (sub, conn, fs) in v {
-        for f in fs {
-            if f.event_match(event) {
-                api.reply_event(&sub, &conn, event).await;
+/// Events of one filter, or of the upstreams, waiting to be merged into a
+/// REQ's reply.
+struct Source<'a> {
+    pager: Option<Pager<'a>>,
+    buffer: VecDeque<Event>,
+}
+
+impl<'a> Source<'a> {
+    fn stored(pager: Pager<'a>) -> Source<'a> {
+        Source {
+            pager: Some(pager),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    #[cfg(feature = "proxy")]
+    fn fetched(mut evs: Vec<Event>) -> Source<'a> {
+        evs.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        Source {
+            pager: None,
+            buffer: evs.into(),
+        }
+    }
+}
+
+/// The newest event at the head of any source, so that the events of all
+/// filters go out newest first while only a page of each is held.
+async fn next_newest(sources: &mut [Source<'_>], store: &dyn EventStore) -> Option<Event> {
+    for source in sources.iter_mut() {
+        if source.buffer.is_empty() {
+            if let Some(pager) = &mut source.pager {
+                source.buffer.extend(pager.next_page(store).await);
             }
         }
     }
+    let newest = sources
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| Some((i, s.buffer.front()?.created_at)))
+        .max_by_key(|(_, created_at)| *created_at)?
+        .0;
+    sources[newest].buffer.pop_front()
 }
 
-pub async fn process_req(ctx: &MessageContext, cmd: &Option<ReqCmd>) {
-    if let Some(cmd) = cmd {
-        println!(
-            "cmd: {}, conn: {}, arg: {:?}",
-            cmd.cmd, ctx.connection_id, cmd
-        );
+/// Reads the stored events of a filter newest first, `NOSTR_REQ_CHUNK_SIZE`
+/// at a time, moving `until` back past each page.
+struct Pager<'a> {
+    filter: &'a Filter,
+    until: Option<u64>,
+    remaining: i32,
+    chunk: i32,
+    /// Ids already read at the oldest created_at, which the next page
+    /// starts again with.
+    previous: HashSet<EventId>,
+    /// Set once the store returned a short page.
+    exhausted: bool,
+}
+
+impl<'a> Pager<'a> {
+    fn new(filter: &'a Filter, limits: &Limits) -> Pager<'a> {
+        let remaining = limits.clamp(filter.limit());
+        // Every page of a scan would read the same items again.
+        let scans = matches!(filter.query_plan(), QueryPlan::BoundedScan(_));
+        Pager {
+            filter,
+            until: None,
+            remaining,
+            chunk: if scans {
+                remaining
+            } else {
+                limits.req_chunk_size
+            },
+            previous: HashSet::new(),
+            exhausted: scans,
+        }
+    }
+
+    /// The next page of matching events, empty once the filter's limit is
+    /// reached or the store has no more events.
+    ///
+    /// Conditions the index does not cover, such as tags, are checked after
+    /// the store applied the limit, so pages are read until one has a match.
+    async fn next_page(&mut self, store: &dyn EventStore) -> Vec<Event> {
+        while self.remaining > 0 {
+            let read = self.read_page(store).await;
+            let evs: Vec<Event> = filter_match(self.filter, &Ok(read), true)
+                .unwrap_or_default()
+                .into_iter()
+                .take(self.remaining as usize)
+                .collect();
+            self.remaining = if self.exhausted {
+                0
+            } else {
+                self.remaining - evs.len() as i32
+            };
+            if !evs.is_empty() || self.exhausted {
+                return evs;
+            }
+        }
+        vec![]
+    }
 
-        let ddb = crate::ddb::Ddb::new().await;
-        let ret = ddb
-            .write_subscription(&ctx.connection_id, &cmd.subscription_id, &cmd.filters)
+    /// The next page of what the store returns, before the filter is
+    /// checked, moving the cursor past it.
+    async fn read_page(&mut self, store: &dyn EventStore) -> Vec<Event> {
+        let size = self.chunk.min(self.remaining);
+        let overlap = self.previous.len();
+        let page = self.filter.page(self.until, size + overlap as i32);
+        let mut evs = match read_stored(store, &page).await {
+            Ok(evs) => evs,
+            Err(e) => {
+                println!("store err: {e}");
+                vec![]
+            }
+        };
+        evs.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        // Events sharing the oldest created_at may continue on the next
+        // page, so it starts there and skips those already read. A short
+        // page, or one with nothing new, is the last one.
+        let full = evs.len() >= size as usize + overlap;
+        let evs: Vec<Event> = evs
+            .into_iter()
+            .filter(|e| !self.previous.contains(&e.id))
+            .take(size as usize)
+            .collect();
+        if !full || evs.is_empty() {
+            self.exhausted = true;
+        }
+        if let Some(oldest) = evs.iter().map(|e| e.created_at).min() {
+            if self.until != Some(oldest + 1) {
+                self.previous.clear();
+                self.until = Some(oldest + 1);
+            }
+            let at_oldest = evs.iter().filter(|e| e.created_at == oldest);
+            self.previous.extend(at_oldest.map(|e| e.id.clone()));
+        }
+        evs
+    }
+}
+
+/// What the store returns for `filter`'s plan, up to its limit and before
+/// the rest of the filter is checked; none for filters without a plan.
+async fn read_stored(store: &dyn EventStore, filter: &Filter) -> Result<Vec<Event>, String> {
+    match filter.query_plan() {
+        QueryPlan::ByIds(plan) => plan.read(store).await,
+        QueryPlan::ByPubkeys(plan) => plan.read(store).await,
+        QueryPlan::ByAddress(plan) => plan.read(store).await,
+        QueryPlan::ByChannels(plan) => plan.read(store).await,
+        QueryPlan::ByRecipients(plan) => plan.read(store).await,
+        QueryPlan::ByKinds(plan) => plan.read(store).await,
+        QueryPlan::ByTime(plan) => plan.read(store).await,
+        // A scan is read once, already matched and cut to the limit.
+        QueryPlan::BoundedScan(plan) => plan.exec(store).await,
+        // Only reached when upstreams answer what we cannot plan.
+        QueryPlan::Count(_) | QueryPlan::NoPlan(_) => Ok(vec![]),
+    }
+}
+
+/// Why a REQ cannot be served: the reason of the first filter with stored
+/// events to look up but no query plan, unless upstreams can answer it.
+fn unplannable(filters: &[Filter]) -> Option<RejectReason> {
+    #[cfg(feature = "proxy")]
+    if crate::proxy::Upstreams::from_env().is_some() {
+        return None;
+    }
+    filters
+        .iter()
+        .filter(|f| !f.is_live_only())
+        .find_map(|f| match f.query_plan() {
+            QueryPlan::NoPlan(reason) => Some(reason),
+            _ => None,
+        })
+}
+
+/// Answers a NIP-45 COUNT with the number of stored events matching any of
+/// the filters. Counts of different filters are added up, so an event
+/// matching two of them is counted twice.
+pub async fn process_count(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    cmd: &Option<ReqCmd>,
+) -> Outcome {
+    let Some(cmd) = cmd else {
+        return Outcome::Malformed;
+    };
+    println!(
+        "cmd: {}, conn: {}, arg: {:?}",
+        cmd.cmd, ctx.connection_id, cmd
+    );
+
+    if let Some(reason) = read_binding(store)
+        .await
+        .check_read(ctx.auth_pubkey.as_deref())
+        .and_then(|_| DmRelay::from_env().check_read(&cmd.filters, ctx.auth_pubkey.as_deref()))
+        .and_then(|_| RelayPolicy::current().limits.check_filters(&cmd.filters))
+        .err()
+        .or_else(|| cmd.filters.iter().find_map(|f| f.validate().err()))
+    {
+        api.send_closed(&ctx.connection_id, &cmd.subscription_id, &reason)
             .await;
-        match ret {
-            Ok(r) => {
-                println!("ddb ok: {r:?}");
-                let api = ApiGwMgmt::new(&ctx.endpoint).await;
-                let mut evs: Vec<Event> = vec![];
-                for f in &cmd.filters {
-                    let r = match f.query_plan() {
-                        QueryPlan::ByIds(plan) => plan.exec().await,
-                        QueryPlan::ByPubkeys(plan) => plan.exec().await,
-                        _ => {
-                            api.send_nip15eose(&ctx.connection_id, &cmd.subscription_id)
-                                .await;
-                            return;
-                        }
-                    };
-                    if let Ok(r) = r {
-                        evs.extend(r);
-                    }
-                }
-                let evsh: HashSet<&Event> = evs.iter().collect();
+        return Outcome::Rejected(reason);
+    }
 
-                for ev in evsh {
-                    api.reply_event(&cmd.subscription_id, &ctx.connection_id, ev)
-                        .await;
-                }
-                api.send_nip15eose(&ctx.connection_id, &cmd.subscription_id)
+    let mut count = 0;
+    for f in &cmd.filters {
+        let r = match f.count_plan() {
+            QueryPlan::Count(plan) => plan.exec(store).await,
+            QueryPlan::ByIds(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::ByPubkeys(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::ByAddress(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::ByChannels(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::ByRecipients(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::ByKinds(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::ByTime(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::BoundedScan(plan) => plan.exec(store).await.map(|evs| evs.len() as u64),
+            QueryPlan::NoPlan(reason) => {
+                api.send_closed(&ctx.connection_id, &cmd.subscription_id, &reason)
+                    .await;
+                return Outcome::Rejected(reason);
+            }
+        };
+        match r {
+            Ok(n) => count += n,
+            Err(r) => {
+                println!("store err: {r}");
+                let reason = RejectReason::Error("failed to count events".to_string());
+                api.send_closed(&ctx.connection_id, &cmd.subscription_id, &reason)
                     .await;
+                return Outcome::Error(reason.to_string());
             }
-            Err(r) => println!("ddb err: {r:?}"),
         }
     }
+    api.send_count(&ctx.connection_id, &cmd.subscription_id, count)
+        .await;
+    Outcome::Counted(count)
 }
 
-pub async fn process_close(ctx: &MessageContext, cmd: &Option<CloseCmd>) {
-    if let Some(cmd) = cmd {
-        println!(
-            "cmd: {}, conn: {}, sub_id: {}",
-            cmd.cmd, ctx.connection_id, cmd.subscription_id
-        );
+pub async fn process_close(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    cmd: &Option<CloseCmd>,
+) -> Outcome {
+    let Some(cmd) = cmd else {
+        return Outcome::Malformed;
+    };
+    println!(
+        "cmd: {}, conn: {}, sub_id: {}",
+        cmd.cmd, ctx.connection_id, cmd.subscription_id
+    );
+
+    let ret = store
+        .delete_subscriptions(&ctx.connection_id, vec![cmd.subscription_id.to_string()])
+        .await;
+    match ret {
+        Ok(()) => {
+            update_gauges(store, 0, -1).await;
+            Outcome::Closed
+        }
+        Err(r) => {
+            println!("store err: {r}");
+            Outcome::Error(r)
+        }
+    }
+}
+
+/// Counts a refused EVENT or REQ by the prefix of its reason.
+pub async fn record_rejection(store: &dyn EventStore, command: &str, outcome: &Outcome) {
+    let kind = match command {
+        "EVENT" => "event",
+        "REQ" => "req",
+        "COUNT" => "count",
+        "AUTH" => "auth",
+        _ => return,
+    };
+    let reason = match outcome {
+        Outcome::Rejected(reason) => reason.prefix(),
+        Outcome::Error(_) => "error",
+        _ => return,
+    };
+    metrics::publish_rejection(kind, reason);
+    if let Err(e) = store.add_rejection(kind, reason).await {
+        println!("stats err: {e}");
+    }
+}
 
-        let ddb = crate::ddb::Ddb::new().await;
-        let ret = ddb
-            .delete_subscriptions(vec![cmd.subscription_id.to_string()])
+/// Sends the greeting NOTICE and the NIP-42 challenge if
+/// `ctx.connection_id` has not been greeted yet.
+///
+/// API Gateway cannot post to a connection before `$connect` returns, so this
+/// runs on the messages that follow it.
+pub async fn greet(ctx: &MessageContext, store: &dyn EventStore, api: &dyn Transport) {
+    let greeting = nip11::greeting();
+    if greeting.is_none() && ctx.challenge.is_none() {
+        return;
+    }
+    match store.mark_greeted(&ctx.connection_id).await {
+        Ok(true) => {
+            if let Some(greeting) = greeting {
+                api.send_notice(&ctx.connection_id, &greeting).await;
+            }
+            if let Some(challenge) = &ctx.challenge {
+                api.send_auth(&ctx.connection_id, challenge).await;
+            }
+        }
+        Ok(false) => {}
+        Err(e) => println!("greeting err: {e}"),
+    }
+}
+
+/// Refuses messages over the advertised `max_message_length` with a NOTICE
+/// before they are parsed.
+pub async fn check_message(
+    ctx: &MessageContext,
+    api: &dyn Transport,
+    msg: &str,
+) -> Result<(), Outcome> {
+    if let Err(reason) = RelayPolicy::current().limits.check_message(msg) {
+        api.send_notice(&ctx.connection_id, &reason.to_string())
             .await;
-        match ret {
-            Ok(r) => println!("ddb ok: {r:?}"),
-            Err(r) => println!("ddb err: {r:?}"),
+        return Err(Outcome::Rejected(reason));
+    }
+    Ok(())
+}
+
+/// Counts the message against the rate limits and refuses it once one is
+/// exceeded: an EVENT with OK, a REQ or COUNT with CLOSED and anything else
+/// with a NOTICE. Counters the store cannot keep are skipped.
+pub async fn check_rate(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    rate: &RateLimit,
+    command: &str,
+    msg: &str,
+) -> Result<(), Outcome> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut exceeded = false;
+    for (key, limit) in rate.counters(command, &ctx.connection_id, ctx.source_ip.as_deref(), now) {
+        match store.increment_counter(&key, RATE_WINDOW).await {
+            Ok(count) => exceeded |= count > limit,
+            Err(e) => println!("rate limit err: {e}"),
         }
     }
+    if !exceeded {
+        return Ok(());
+    }
+
+    let reason = RejectReason::RateLimited("slow down".to_string());
+    match command {
+        "EVENT" => match parse_eventmsg(msg) {
+            Some(cmd) => {
+                api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
+                    .await
+            }
+            None => {
+                api.send_notice(&ctx.connection_id, &reason.to_string())
+                    .await
+            }
+        },
+        "REQ" | "COUNT" => match parse_reqmsg(msg) {
+            Some(cmd) => {
+                api.send_closed(&ctx.connection_id, &cmd.subscription_id, &reason)
+                    .await
+            }
+            None => {
+                api.send_notice(&ctx.connection_id, &reason.to_string())
+                    .await
+            }
+        },
+        _ => {
+            api.send_notice(&ctx.connection_id, &reason.to_string())
+                .await
+        }
+    };
+    Err(Outcome::Rejected(reason))
 }
 
-pub async fn process_disconn(ctx: &MessageContext) {
+/// Handles one client message: greets the connection, routes the message by
+/// `ctx.command` (or its verb on `$default`) and records rejections.
+pub async fn process_message(
+    ctx: &mut MessageContext,
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    msg: &str,
+) -> Outcome {
+    load_connection(ctx, store).await;
+    greet(ctx, store, api).await;
+    let command = if ctx.command == "$default" {
+        body_verb(msg).unwrap_or_default()
+    } else {
+        ctx.command.clone()
+    };
+    let consumed = store.consumed_capacity();
+    let checked = match check_message(ctx, api, msg).await {
+        Ok(()) => {
+            check_rate(
+                ctx,
+                store,
+                api,
+                &RelayPolicy::current().rate_limit,
+                &command,
+                msg,
+            )
+            .await
+        }
+        Err(outcome) => Err(outcome),
+    };
+    let outcome = match checked {
+        Err(outcome) => outcome,
+        Ok(()) => match &*command {
+            "EVENT" => process_event(ctx, store, api, &parse_eventmsg(msg)).await,
+            "REQ" => process_req(ctx, store, api, &parse_reqmsg(msg)).await,
+            "COUNT" => process_count(ctx, store, api, &parse_reqmsg(msg)).await,
+            "CLOSE" => process_close(ctx, store, &parse_closemsg(msg)).await,
+            "AUTH" => process_auth(ctx, store, api, &parse_eventmsg(msg)).await,
+            c => {
+                println!("default: command: {c}");
+                Outcome::Malformed
+            }
+        },
+    };
+    metrics::publish_capacity(
+        &command.to_lowercase(),
+        store.consumed_capacity() - consumed,
+    );
+    record_rejection(store, &command, &outcome).await;
+    outcome
+}
+
+/// Fills in the challenge and authenticated pubkey recorded for the
+/// connection.
+pub async fn load_connection(ctx: &mut MessageContext, store: &dyn EventStore) {
+    match store.get_connection(&ctx.connection_id).await {
+        Ok(Some(record)) => {
+            ctx.challenge = Some(record.challenge);
+            ctx.auth_pubkey = record.auth_pubkey;
+            ctx.source_ip = ctx.source_ip.take().or(record.source_ip);
+        }
+        Ok(None) => {}
+        Err(e) => println!("connection record err: {e}"),
+    }
+}
+
+pub async fn process_conn(ctx: &MessageContext, store: &dyn EventStore) -> Outcome {
+    println!("cmd: {}, conn: {}", ctx.command, ctx.connection_id);
+
+    if let Err(e) = store
+        .write_connection(
+            &ctx.connection_id,
+            &auth::challenge(),
+            ctx.source_ip.as_deref(),
+        )
+        .await
+    {
+        println!("connection record err: {e}");
+    }
+    update_gauges(store, 1, 0).await;
+    Outcome::Connected
+}
+
+/// Handles a NIP-42 `["AUTH", event]` answering the connection's challenge.
+pub async fn process_auth(
+    ctx: &MessageContext,
+    store: &dyn EventStore,
+    api: &dyn Transport,
+    cmd: &Option<EventCmd>,
+) -> Outcome {
+    let Some(cmd) = cmd else {
+        return Outcome::Malformed;
+    };
+    println!(
+        "cmd: {}, conn: {}, pubkey: {}",
+        cmd.cmd, ctx.connection_id, cmd.event.pubkey
+    );
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let verified = match &ctx.challenge {
+        Some(challenge) => auth::verify(&cmd.event, challenge, auth::relay_url().as_deref(), now),
+        None => Err(RejectReason::AuthRequired(
+            "no challenge was issued to this connection".to_string(),
+        )),
+    };
+    let pubkey = match verified {
+        Ok(pubkey) => pubkey,
+        Err(reason) => {
+            api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
+                .await;
+            return Outcome::Rejected(reason);
+        }
+    };
+    if let Err(r) = store.set_auth_pubkey(&ctx.connection_id, &pubkey).await {
+        println!("store err: {r}");
+        let reason = RejectReason::Error("failed to record the authentication".to_string());
+        api.send_rejection(&ctx.connection_id, &cmd.event.id, &reason)
+            .await;
+        return Outcome::Error(reason.to_string());
+    }
+    api.send_nip20msg(&ctx.connection_id, &cmd.event.id, true, "")
+        .await;
+    Outcome::Authenticated(pubkey)
+}
+
+pub async fn process_disconn(ctx: &MessageContext, store: &dyn EventStore) -> Outcome {
     println!("cmd: {}, conn: {}", ctx.command, ctx.connection_id);
 
-    let ddb = crate::ddb::Ddb::new().await;
-    let _ret = ddb.close_connection(&ctx.connection_id).await;
+    match store.close_connection(&ctx.connection_id).await {
+        Ok(closed) => {
+            update_gauges(store, -1, -(closed as i64)).await;
+            Outcome::Closed
+        }
+        Err(r) => {
+            println!("store err: {r}");
+            Outcome::Error(r)
+        }
+    }
+}
+
+async fn update_gauges(store: &dyn EventStore, connections: i64, subscriptions: i64) {
+    match store.adjust_gauges(connections, subscriptions).await {
+        Ok(gauges) => metrics::publish_gauges(&gauges),
+        Err(e) => println!("gauges err: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pager;
+    use super::{
+        check_rate, dispatch_event, process_auth, process_close, process_event, process_req,
+        Outcome,
+    };
+    use crate::auth::KIND_CLIENT_AUTH;
+    use crate::identity::Identity;
+    use crate::label::HiddenTargets;
+    use crate::message::{Event, EventCmd, Filter, MessageContext, ReqCmd};
+    use crate::nip86;
+    use crate::policy::RateLimit;
+    use crate::reject::RejectReason;
+    use crate::store::EventStore;
+    use crate::transport::MemoryTransport;
+    use crate::types::{EventId, Pubkey, Signature};
+    use async_trait::async_trait;
+    use std::collections::HashSet;
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn pager01() {
+        use crate::policy::Limits;
+        use crate::sqlite::SqliteStore;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        let id = Identity::generate();
+        for (i, created_at) in [1, 2, 2, 3, 4].into_iter().enumerate() {
+            let ev = Event::sign(id.keys(), created_at, 1, vec![], &format!("{i}"));
+            store.write_event(&ev).await.unwrap();
+        }
+        let filter: Filter = serde_json::from_str(&format!(
+            r#"{{"authors": ["{}"], "limit": 4}}"#,
+            id.pubkey_hex()
+        ))
+        .unwrap();
+        let limits = Limits {
+            req_chunk_size: 2,
+            ..Limits::default()
+        };
+
+        let mut pager = Pager::new(&filter, &limits);
+        let mut pages = vec![];
+        loop {
+            let page: Vec<u64> = pager
+                .next_page(&store)
+                .await
+                .iter()
+                .map(|e| e.created_at)
+                .collect();
+            if page.is_empty() {
+                break;
+            }
+            pages.push(page);
+        }
+        // The second page starts again at the oldest created_at of the first
+        // and leaves out what it already returned.
+        assert_eq!(vec![vec![4, 3], vec![2, 2]], pages);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn process_req_merges_filters() {
+        use crate::sqlite::SqliteStore;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        for (id, created_at, kind) in [(&alice, 1, 1), (&bob, 2, 1), (&alice, 3, 7), (&bob, 4, 7)] {
+            let ev = Event::sign(id.keys(), created_at, kind, vec![], "");
+            store.write_event(&ev).await.unwrap();
+        }
+        let filters = vec![
+            serde_json::from_str(&format!(r#"{{"authors": ["{}"]}}"#, alice.pubkey_hex())).unwrap(),
+            serde_json::from_str(r#"{"kinds": [1]}"#).unwrap(),
+        ];
+        let api = MemoryTransport::new();
+        let cmd = ReqCmd::new("REQ", "sub01", filters);
+
+        let outcome = process_req(&build_ctx("REQ"), &store, &api, &Some(cmd)).await;
+        assert_eq!(Outcome::Delivered(3), outcome);
+        let created_at: Vec<u64> = api
+            .frames("conn01")
+            .iter()
+            .filter_map(|f| {
+                let v: serde_json::Value = serde_json::from_str(f).unwrap();
+                v[2]["created_at"].as_u64()
+            })
+            .collect();
+        // The event both filters match is sent once, and all go out newest
+        // first.
+        assert_eq!(vec![3, 2, 1], created_at);
+    }
+
+    #[tokio::test]
+    async fn process_event_dispatches_and_stores() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        store
+            .add_allowed_pubkey(build_event01().pubkey.as_str())
+            .await
+            .unwrap();
+        let api = MemoryTransport::new();
+        let filters = vec![serde_json::from_str(r#"{"kinds": [1]}"#).unwrap()];
+        let reader = MessageContext::new("conn02", "https://example.com/stage", "REQ", 0);
+        let cmd = ReqCmd::new("REQ", "sub01", filters.clone());
+        let outcome = process_req(&reader, &store, &api, &Some(cmd)).await;
+        assert_eq!(Outcome::Delivered(0), outcome);
+
+        let ev = build_event01();
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+        let outcome = process_event(&build_ctx("EVENT"), &store, &api, &cmd).await;
+        assert_eq!(Outcome::Accepted { delivered: 1 }, outcome);
+        let event_frame = format!(
+            r#"["EVENT","sub01",{}]"#,
+            serde_json::to_string(&ev).unwrap()
+        );
+        assert_eq!(Some(&event_frame), api.frames("conn02").last());
+
+        let cmd = ReqCmd::new("REQ", "sub02", filters);
+        let outcome = process_req(&build_ctx("REQ"), &store, &api, &Some(cmd)).await;
+        assert_eq!(Outcome::Delivered(1), outcome);
+        assert_eq!(
+            vec![
+                event_frame.replace("sub01", "sub02"),
+                r#"["EOSE", "sub02"]"#.to_string()
+            ],
+            api.frames("conn01")[1..]
+        );
+
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+        let outcome = process_event(&build_ctx("EVENT"), &store, &api, &cmd).await;
+        assert_eq!(Outcome::Duplicate, outcome);
+        assert_eq!(
+            Some(&format!(
+                r#"["OK","{}",true,"duplicate: already have this event"]"#,
+                ev.id
+            )),
+            api.frames("conn01").last()
+        );
+
+        // Another event claiming the stored id is not taken for it.
+        let forged = Event {
+            content: "forged".into(),
+            ..ev.clone()
+        };
+        let cmd = Some(EventCmd::new("EVENT", &forged));
+        let outcome = process_event(&build_ctx("EVENT"), &store, &api, &cmd).await;
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Invalid("event id is wrong".to_string())),
+            outcome
+        );
+    }
+
+    #[tokio::test]
+    async fn process_event_acknowledges_before_fan_out() {
+        use crate::memory::MemoryStore;
+        use crate::transport::RouteReply;
+
+        let store = MemoryStore::new();
+        let ev = build_event01();
+        store.add_allowed_pubkey(ev.pubkey.as_str()).await.unwrap();
+        let api = MemoryTransport::new();
+        let filters = vec![serde_json::from_str(r#"{"kinds": [1]}"#).unwrap()];
+        let reader = MessageContext::new("conn02", "https://example.com/stage", "REQ", 0);
+        let cmd = ReqCmd::new("REQ", "sub01", filters);
+        process_req(&reader, &store, &api, &Some(cmd)).await;
+
+        let ctx = build_ctx("EVENT");
+        let reply = RouteReply::new(&api, &ctx.connection_id, true);
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+        let outcome = process_event(&ctx, &store, &reply, &cmd).await;
+        assert_eq!(Outcome::Accepted { delivered: 1 }, outcome);
+        assert_eq!(None, reply.into_body());
+        let sent: Vec<String> = api
+            .all_frames()
+            .into_iter()
+            .filter(|(_, frame)| !frame.starts_with(r#"["EOSE""#))
+            .map(|(conn, _)| conn)
+            .collect();
+        assert_eq!(vec![ctx.connection_id.clone(), "conn02".to_string()], sent);
+    }
+
+    #[tokio::test]
+    async fn process_req_leaves_out_banned_events() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        let ev = build_event01();
+        store.write_event(&ev).await.unwrap();
+        store.add_banned_event(ev.id.as_str()).await.unwrap();
+        let api = MemoryTransport::new();
+
+        let filters = vec![serde_json::from_str(r#"{"kinds": [1]}"#).unwrap()];
+        let cmd = ReqCmd::new("REQ", "sub01", filters);
+        let outcome = process_req(&build_ctx("REQ"), &store, &api, &Some(cmd)).await;
+        assert_eq!(Outcome::Delivered(0), outcome);
+
+        store.add_allowed_pubkey(ev.pubkey.as_str()).await.unwrap();
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+        let outcome = process_event(&build_ctx("EVENT"), &store, &api, &cmd).await;
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Blocked("event is banned".into())),
+            outcome
+        );
+    }
+
+    #[tokio::test]
+    async fn process_req_limit_counts_sent_events() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        let id = Identity::generate();
+        for created_at in 1..=600 {
+            let kind = if created_at > 100 { 1 } else { 7 };
+            let ev = Event::sign(id.keys(), created_at, kind, vec![], "");
+            store.write_event(&ev).await.unwrap();
+            if created_at > 580 {
+                store.add_banned_event(ev.id.as_str()).await.unwrap();
+            }
+        }
+        // The filters together read more than the largest limit, banned
+        // events first.
+        let filters = vec![
+            serde_json::from_str(r#"{"kinds": [1], "limit": 500}"#).unwrap(),
+            serde_json::from_str(r#"{"kinds": [7], "limit": 500}"#).unwrap(),
+        ];
+        let api = MemoryTransport::new();
+        let cmd = ReqCmd::new("REQ", "sub01", filters);
+
+        let outcome = process_req(&build_ctx("REQ"), &store, &api, &Some(cmd)).await;
+        assert_eq!(Outcome::Delivered(500), outcome);
+    }
+
+    #[tokio::test]
+    async fn pager_reads_past_tag_misses() {
+        use crate::memory::MemoryStore;
+        use crate::policy::Limits;
+
+        let store = MemoryStore::new();
+        let id = Identity::generate();
+        for created_at in 1..=30 {
+            let tags = if created_at % 10 == 0 {
+                vec![vec!["t".to_string(), "x".to_string()]]
+            } else {
+                vec![]
+            };
+            let ev = Event::sign(id.keys(), created_at, 1, tags, "");
+            store.write_event(&ev).await.unwrap();
+        }
+        let limits = Limits {
+            req_chunk_size: 5,
+            ..Limits::default()
+        };
+
+        // The matches are further apart than a page of the kind's index.
+        for (limit, expected) in [(100, vec![30, 20, 10]), (2, vec![30, 20])] {
+            let filter: Filter = serde_json::from_str(&format!(
+                r##"{{"kinds": [1], "#t": ["x"], "limit": {limit}}}"##
+            ))
+            .unwrap();
+            let mut pager = Pager::new(&filter, &limits);
+            let mut created_at = vec![];
+            loop {
+                let page = pager.next_page(&store).await;
+                if page.is_empty() {
+                    break;
+                }
+                created_at.extend(page.iter().map(|e| e.created_at));
+            }
+            assert_eq!(expected, created_at);
+        }
+    }
+
+    #[tokio::test]
+    async fn process_req_kinds_and_tags() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        let id = Identity::generate();
+        for created_at in 1..=250 {
+            let tags = if created_at % 100 == 0 {
+                vec![vec!["t".to_string(), "nostr".to_string()]]
+            } else {
+                vec![]
+            };
+            let ev = Event::sign(id.keys(), created_at, 1, tags, "");
+            store.write_event(&ev).await.unwrap();
+        }
+        let filters =
+            vec![
+                serde_json::from_str(r##"{"kinds": [1], "#t": ["nostr"], "limit": 10}"##).unwrap(),
+            ];
+        let api = MemoryTransport::new();
+        let cmd = ReqCmd::new("REQ", "sub01", filters);
+
+        // The older match is beyond the first page of kind 1.
+        let outcome = process_req(&build_ctx("REQ"), &store, &api, &Some(cmd)).await;
+        assert_eq!(Outcome::Delivered(2), outcome);
+    }
+
+    #[tokio::test]
+    async fn dispatch_event_concurrently() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        store
+            .add_allowed_pubkey(build_event01().pubkey.as_str())
+            .await
+            .unwrap();
+        let api = MemoryTransport::new();
+        let filters: Vec<Filter> = vec![serde_json::from_str(r#"{"kinds": [1]}"#).unwrap()];
+        // More subscriptions than deliveries in flight at a time.
+        for i in 0..50 {
+            store
+                .write_subscription(&format!("conn{i:02}"), "sub01", &filters)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            50,
+            dispatch_event(&store, &api, &build_event01(), &HiddenTargets::default()).await
+        );
+        assert_eq!(50, api.all_frames().len());
+        assert_eq!(1, api.frames("conn49").len());
+    }
+
+    #[tokio::test]
+    async fn process_req_replaces_subscription() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        store
+            .add_allowed_pubkey(build_event01().pubkey.as_str())
+            .await
+            .unwrap();
+        let api = MemoryTransport::new();
+        let reader = MessageContext::new("conn02", "https://example.com/stage", "REQ", 0);
+        for kinds in ["[7]", "[1]"] {
+            let filters = vec![serde_json::from_str(&format!(r#"{{"kinds": {kinds}}}"#)).unwrap()];
+            let cmd = ReqCmd::new("REQ", "sub01", filters);
+            process_req(&reader, &store, &api, &Some(cmd)).await;
+        }
+        let subs = store.get_all_subscriptions().await;
+        assert_eq!(1, subs.len());
+        assert_eq!(1, store.get_stats().await.unwrap().subscriptions);
+
+        // Only the filters of the latest REQ match.
+        let ev = build_event01();
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+        let outcome = process_event(&build_ctx("EVENT"), &store, &api, &cmd).await;
+        assert_eq!(Outcome::Accepted { delivered: 1 }, outcome);
+    }
+
+    #[tokio::test]
+    async fn dispatch_event_once_per_subscription() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        store
+            .add_allowed_pubkey(build_event01().pubkey.as_str())
+            .await
+            .unwrap();
+        let api = MemoryTransport::new();
+        let ev = build_event01();
+        let filters: Vec<Filter> = serde_json::from_str(&format!(
+            r#"[{{"kinds": [1]}}, {{"authors": ["{}"]}}, {{}}]"#,
+            ev.pubkey
+        ))
+        .unwrap();
+        store
+            .write_subscription("conn02", "sub01", &filters)
+            .await
+            .unwrap();
+        store
+            .write_subscription("conn02", "sub02", &filters[..1])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            2,
+            dispatch_event(&store, &api, &ev, &HiddenTargets::default()).await
+        );
+        assert_eq!(2, api.frames("conn02").len());
+    }
+
+    #[tokio::test]
+    async fn dispatch_event_skips_hidden() {
+        use crate::label::label_entries;
+        use crate::memory::MemoryStore;
+
+        let labeler = Identity::generate();
+        let store = MemoryStore::new();
+        let api = MemoryTransport::new();
+        let filters: Vec<Filter> = vec![serde_json::from_str(r#"{"kinds": [1]}"#).unwrap()];
+        store
+            .write_subscription("conn02", "sub01", &filters)
+            .await
+            .unwrap();
+        let ev = build_event01();
+        let other = Event {
+            id: EventId::padded("1d02"),
+            ..ev.clone()
+        };
+        let label = Event::sign(
+            labeler.keys(),
+            1676118868,
+            1985,
+            vec![
+                vec!["l".into(), "spam".into(), "com.example.dispatch".into()],
+                vec!["e".into(), ev.id.to_string()],
+            ],
+            "",
+        );
+        store
+            .write_labels(&label, &label_entries(&label))
+            .await
+            .unwrap();
+
+        let labels = [("com.example.dispatch".to_string(), "spam".to_string())];
+        let hidden = HiddenTargets::load(&store, &labels, &HashSet::new()).await;
+        assert_eq!(1, dispatch_event(&store, &api, &ev, &hidden).await);
+
+        let labelers = HashSet::from([labeler.pubkey_hex()]);
+        let hidden = HiddenTargets::load(&store, &labels, &labelers).await;
+        assert_eq!(0, dispatch_event(&store, &api, &ev, &hidden).await);
+        assert_eq!(1, dispatch_event(&store, &api, &other, &hidden).await);
+    }
+
+    #[cfg(feature = "proxy")]
+    #[tokio::test]
+    async fn cache_fetched_checks_events() {
+        use super::cache_fetched;
+        use crate::denylist::Denylist;
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        let alice = Identity::generate();
+        let stored = Event::sign(alice.keys(), 1676118868, 1, vec![], "stored");
+        store.write_event(&stored).await.unwrap();
+        let target = Event::sign(alice.keys(), 1676118868, 1, vec![], "target");
+        let deletion = Event::sign(
+            alice.keys(),
+            1676118869,
+            5,
+            vec![vec!["e".into(), target.id.to_string()]],
+            "",
+        );
+        let expired = Event::sign(
+            alice.keys(),
+            1676118868,
+            1,
+            vec![vec!["expiration".into(), "1676118900".into()]],
+            "",
+        );
+        let fetched = vec![deletion.clone(), target.clone(), expired.clone(), stored];
+
+        // The deletion fetched before its target still removes it.
+        assert_eq!(
+            2,
+            cache_fetched(&store, &Denylist::default(), &fetched).await
+        );
+        assert!(store.has_event(&deletion.id).await.unwrap());
+        assert!(!store.has_event(&target.id).await.unwrap());
+        assert!(!store.has_event(&expired.id).await.unwrap());
+    }
+
+    /// Store for paths that must not touch storage.
+    struct NullStore;
+
+    #[async_trait]
+    impl EventStore for NullStore {
+        async fn write_event(&self, _ev: &Event) -> Result<(), String> {
+            Err("unavailable".into())
+        }
+        async fn write_subscription(&self, _: &str, _: &str, _: &[Filter]) -> Result<bool, String> {
+            Err("unavailable".into())
+        }
+        async fn delete_subscriptions(
+            &self,
+            _conn_id: &str,
+            _sub_ids: Vec<String>,
+        ) -> Result<(), String> {
+            Err("unavailable".into())
+        }
+        async fn close_connection(&self, _conn_id: &str) -> Result<usize, String> {
+            Err("unavailable".into())
+        }
+        async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)> {
+            vec![]
+        }
+        async fn get_event_by_ids(&self, _ids: &[EventId]) -> Result<Vec<Event>, String> {
+            Err("unavailable".into())
+        }
+        async fn get_event_by_pubkeys(
+            &self,
+            _: &[Pubkey],
+            _: Option<Vec<u64>>,
+            _: Option<u64>,
+            _: Option<u64>,
+            _: Option<i32>,
+        ) -> Result<Vec<Event>, String> {
+            Err("unavailable".into())
+        }
+        async fn delete_event_by_ids(&self, _ids: Vec<EventId>) -> Result<(), String> {
+            Err("unavailable".into())
+        }
+        async fn get_allowed_pubkeys(&self) -> Result<Vec<String>, String> {
+            Ok(vec![build_event01().pubkey.to_string()])
+        }
+    }
+
+    fn build_ctx(command: &str) -> MessageContext {
+        MessageContext::new("conn01", "https://example.com/stage", command, 1676118868)
+    }
+
+    fn build_event01() -> Event {
+        Event {
+            id: "87ae4ae2974e96e857856fe5f677d412df40cb331378fd1b20e0ed78910629a2".parse().unwrap(),
+            pubkey: "98f4285bcb2cc65c3a66bd77ccffd2563ed3303e7e02a489c63a887fcd06bbe5".parse().unwrap(),
+            created_at: 1676118868,
+            kind: 1,
+            tags: [].to_vec(),
+            content: "hello!".into(),
+            sig: "e9bfd020031ae702d5af21f029613d8a7957bfc269d5a8da36a79c2ff696f54db68e3ccd4111171f61335fa89369cbe96fa45b2a032061726a04afa157df32eb".parse().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn process_event_blocked() {
+        let api = MemoryTransport::new();
+        let ev = Event {
+            pubkey: Pubkey::padded(""),
+            ..build_event01()
+        };
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+
+        let ret = process_event(&build_ctx("EVENT"), &NullStore, &api, &cmd).await;
+
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Blocked("not allowed".into())),
+            ret
+        );
+        assert_eq!(
+            vec![format!(
+                r#"["OK","{}",false,"blocked: not allowed"]"#,
+                ev.id
+            )],
+            api.frames("conn01")
+        );
+    }
+
+    #[tokio::test]
+    async fn process_event_invalid_sig() {
+        let api = MemoryTransport::new();
+        let ev = Event {
+            sig: Signature::padded("5"),
+            ..build_event01()
+        };
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+
+        let ret = process_event(&build_ctx("EVENT"), &NullStore, &api, &cmd).await;
+
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Invalid("signature is wrong".into())),
+            ret
+        );
+        assert_eq!(
+            vec![format!(
+                r#"["OK","{}",false,"invalid: signature is wrong"]"#,
+                ev.id
+            )],
+            api.frames("conn01")
+        );
+    }
+
+    #[tokio::test]
+    async fn process_event_management_by_stranger() {
+        let api = MemoryTransport::new();
+        let content = r#"{"method": "allowpubkey", "params": []}"#;
+        let ev = Event::sign(
+            Identity::generate().keys(),
+            0,
+            nip86::KIND_MANAGEMENT_REQUEST,
+            vec![],
+            content,
+        );
+        let cmd = Some(EventCmd::new("EVENT", &ev));
+
+        // Refused before the allowlist or the store is consulted.
+        let ret = process_event(&build_ctx("EVENT"), &NullStore, &api, &cmd).await;
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Restricted("not an admin".into())),
+            ret
+        );
+    }
+
+    #[tokio::test]
+    async fn check_rate_refuses_floods() {
+        use crate::memory::MemoryStore;
+
+        let store = MemoryStore::new();
+        let api = MemoryTransport::new();
+        let rate = RateLimit {
+            events: Some(1),
+            ..Default::default()
+        };
+        let ev = build_event01();
+        let msg = serde_json::json!(["EVENT", ev]).to_string();
+        let ctx = build_ctx("EVENT");
+
+        assert!(check_rate(&ctx, &store, &api, &rate, "EVENT", &msg)
+            .await
+            .is_ok());
+        assert_eq!(
+            Err(Outcome::Rejected(RejectReason::RateLimited(
+                "slow down".into()
+            ))),
+            check_rate(&ctx, &store, &api, &rate, "EVENT", &msg).await
+        );
+        assert_eq!(
+            vec![format!(
+                r#"["OK","{}",false,"rate-limited: slow down"]"#,
+                ev.id
+            )],
+            api.frames("conn01")
+        );
+        // REQs are not limited here.
+        assert!(check_rate(&ctx, &store, &api, &rate, "REQ", "[]")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn process_malformed() {
+        let api = MemoryTransport::new();
+
+        assert_eq!(
+            Outcome::Malformed,
+            process_event(&build_ctx("EVENT"), &NullStore, &api, &None).await
+        );
+        assert_eq!(
+            Outcome::Malformed,
+            process_req(&build_ctx("REQ"), &NullStore, &api, &None).await
+        );
+        assert_eq!(
+            Outcome::Malformed,
+            process_close(&build_ctx("CLOSE"), &NullStore, &None).await
+        );
+        assert!(api.all_frames().is_empty());
+    }
+
+    #[tokio::test]
+    async fn process_auth_rejected() {
+        let api = MemoryTransport::new();
+        let tags = vec![
+            vec!["relay".to_string(), "wss://relay.example.com".to_string()],
+            vec!["challenge".to_string(), "chal01".to_string()],
+        ];
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let ev = Event::sign(Identity::generate().keys(), now, KIND_CLIENT_AUTH, tags, "");
+        let cmd = Some(EventCmd::new("AUTH", &ev));
+
+        let outcome = process_auth(&build_ctx("AUTH"), &NullStore, &api, &cmd).await;
+        assert_eq!(
+            Outcome::Rejected(RejectReason::AuthRequired(
+                "no challenge was issued to this connection".into()
+            )),
+            outcome
+        );
+
+        let mut ctx = build_ctx("AUTH");
+        ctx.challenge = Some("chal02".into());
+        let outcome = process_auth(&ctx, &NullStore, &api, &cmd).await;
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Invalid("challenge mismatch".into())),
+            outcome
+        );
+        assert_eq!(
+            format!(r#"["OK","{}",false,"invalid: challenge mismatch"]"#, ev.id),
+            api.frames("conn01")[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn process_req_invalid_filter() {
+        let api = MemoryTransport::new();
+        let filter: Filter = serde_json::from_str(r#"{"limit": -1}"#).unwrap();
+        let cmd = ReqCmd::new("REQ", "sub01", vec![filter]);
+
+        let outcome = process_req(&build_ctx("REQ"), &NullStore, &api, &Some(cmd)).await;
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Invalid("limit must not be negative".into())),
+            outcome
+        );
+        assert_eq!(
+            vec![r#"["CLOSED","sub01","invalid: limit must not be negative"]"#],
+            api.frames("conn01")
+        );
+    }
+
+    #[tokio::test]
+    async fn process_req_unsupported_filter() {
+        let api = MemoryTransport::new();
+        let live: Filter = serde_json::from_str(r#"{"kinds": [1], "limit": 0}"#).unwrap();
+        let stored: Filter = serde_json::from_str(r##"{"#t": ["nostr"]}"##).unwrap();
+        let cmd = ReqCmd::new("REQ", "sub01", vec![live.clone(), stored]);
+
+        let outcome = process_req(&build_ctx("REQ"), &NullStore, &api, &Some(cmd)).await;
+        assert_eq!(
+            Outcome::Rejected(RejectReason::Unsupported(
+                "filter needs ids, authors, kinds or a time range".into()
+            )),
+            outcome
+        );
+        assert_eq!(
+            vec![
+                r#"["CLOSED","sub01","unsupported: filter needs ids, authors, kinds or a time range"]"#
+            ],
+            api.frames("conn01")
+        );
+
+        let cmd = ReqCmd::new("REQ", "sub02", vec![live]);
+        // Plannable, so it only fails on the store that cannot save it.
+        let outcome = process_req(&build_ctx("REQ"), &NullStore, &api, &Some(cmd)).await;
+        assert_eq!(Outcome::Error("unavailable".into()), outcome);
+        assert_eq!(
+            r#"["CLOSED","sub02","error: failed to save the subscription"]"#,
+            api.frames("conn01")[1]
+        );
+    }
 }
@@ -0,0 +1,129 @@
+use crate::message::Event;
+use crate::store::EventStore;
+use crate::types::EventId;
+use serde::{Deserialize, Serialize};
+
+/// NIP-56 report event kind.
+pub const KIND_REPORT: u64 = 1984;
+
+/// A NIP-56 report waiting in the moderation queue.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Id of the report event.
+    pub id: String,
+    pub reporter: String,
+    /// Reported pubkey.
+    pub pubkey: String,
+    /// Reported event, if the report is about one.
+    pub event_id: Option<String>,
+    /// `nudity`, `spam`, `illegal` and so on, empty if not given.
+    pub report_type: String,
+    pub content: String,
+    pub created_at: u64,
+}
+
+impl Report {
+    /// The report carried by a kind 1984 event with a `p` tag.
+    pub fn from_event(ev: &Event) -> Option<Report> {
+        if ev.kind != KIND_REPORT {
+            return None;
+        }
+        let tag = |name: &str| ev.tags.iter().find(|t| t.len() >= 2 && t[0] == name);
+        let p = tag("p")?;
+        let e = tag("e");
+        let report_type = e
+            .and_then(|e| e.get(2))
+            .or(p.get(2))
+            .cloned()
+            .unwrap_or_default();
+        Some(Report {
+            id: ev.id.to_string(),
+            reporter: ev.pubkey.to_string(),
+            pubkey: p[1].clone(),
+            event_id: e.map(|e| e[1].clone()),
+            report_type,
+            content: ev.content.clone(),
+            created_at: ev.created_at,
+        })
+    }
+}
+
+/// What an operator decided about a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Delete the reported event.
+    Delete,
+    /// Delete the reported event and refuse further events by the pubkey.
+    Ban,
+    /// Close the report without changes.
+    Dismiss,
+}
+
+impl Action {
+    pub fn parse(s: &str) -> Option<Action> {
+        match s {
+            "delete" => Some(Action::Delete),
+            "ban" => Some(Action::Ban),
+            "dismiss" => Some(Action::Dismiss),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Delete => "delete",
+            Action::Ban => "ban",
+            Action::Dismiss => "dismiss",
+        }
+    }
+}
+
+/// Carries out `action` on the open report `id` and closes it.
+pub async fn resolve(store: &dyn EventStore, id: &str, action: Action) -> Result<(), String> {
+    let report = store
+        .get_open_reports()
+        .await?
+        .into_iter()
+        .find(|r| r.id == id)
+        .ok_or(format!("no open report {id}"))?;
+    if action == Action::Ban {
+        store.add_ban(&report.pubkey).await?;
+    }
+    if action != Action::Dismiss {
+        if let Some(event_id) = report.event_id.and_then(|e| e.parse::<EventId>().ok()) {
+            store.delete_event_by_ids(vec![event_id]).await?;
+        }
+    }
+    store.resolve_report(id, action.as_str()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Report, KIND_REPORT};
+    use crate::identity::Identity;
+    use crate::message::Event;
+
+    #[test]
+    fn from_event01() {
+        let tags = vec![
+            vec!["p".to_string(), "pub02".to_string()],
+            vec!["e".to_string(), "ev02".to_string(), "spam".to_string()],
+        ];
+        let ev = Event::sign(
+            Identity::generate().keys(),
+            1676118868,
+            KIND_REPORT,
+            tags,
+            "ad",
+        );
+        let report = Report::from_event(&ev).unwrap();
+        assert_eq!("pub02", report.pubkey);
+        assert_eq!(Some("ev02".to_string()), report.event_id);
+        assert_eq!("spam", report.report_type);
+
+        let ev = Event::sign(Identity::generate().keys(), 1676118868, 1, vec![], "");
+        assert_eq!(None, Report::from_event(&ev));
+        assert_eq!(Some(Action::Ban), Action::parse("ban"));
+        assert_eq!(None, Action::parse("purge"));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::message::Event;
+use base64::Engine;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// An item of the export that the restore needs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExportItem {
+    /// An event, its signature checked.
+    Event(Event),
+    /// An event whose tags are in that many `tags#<n>` items; its signature
+    /// is checked by `join_tags`.
+    Untagged(Event, usize),
+    /// The tags in item `tags#<n>` of event `id`.
+    Tags(String, usize, Vec<Vec<String>>),
+}
+
+/// Tags read from `tags#<n>` items, by event id and `n`.
+pub type ChunkedTags = HashMap<String, BTreeMap<usize, Vec<Vec<String>>>>;
+
+/// Reads one line of a DynamoDB S3 export in the `DYNAMODB_JSON` format.
+///
+/// Returns `None` for other items (gauges, labels and so on), which are
+/// rebuilt by `EventStore::write_event` anyway.
+pub fn parse_export_line(line: &str) -> Result<Option<ExportItem>, String> {
+    let v: Value = serde_json::from_str(line).map_err(|e| format!("invalid line: {e}"))?;
+    let item = v.get("Item").ok_or("invalid line: no Item")?;
+    let string = |name: &str| {
+        item.get(name)
+            .and_then(|a| a.get("S"))
+            .and_then(|s| s.as_str())
+    };
+
+    let id = string("id").unwrap_or_default();
+    if let Some(n) = string("type").and_then(|t| t.strip_prefix("tags#")) {
+        let n = n.parse().map_err(|_| format!("{id}: invalid tags item"))?;
+        let tags = item
+            .get("tags")
+            .and_then(|a| a.get("L"))
+            .and_then(|l| l.as_array())
+            .and_then(|tags| {
+                tags.iter()
+                    .map(|tag| {
+                        tag.get("L")?
+                            .as_array()?
+                            .iter()
+                            .map(|v| Some(v.get("S")?.as_str()?.to_string()))
+                            .collect()
+                    })
+                    .collect()
+            })
+            .ok_or(format!("{id}: invalid tags item"))?;
+        return Ok(Some(ExportItem::Tags(id.to_string(), n, tags)));
+    }
+    if string("type") != Some("event") {
+        return Ok(None);
+    }
+    // Compressed events are exported as base64 binaries.
+    let compressed = item
+        .get("json")
+        .and_then(|a| a.get("B"))
+        .and_then(|b| b.as_str());
+    let ev: Event = match (string("json"), compressed) {
+        (Some(json), _) => serde_json::from_str(json).map_err(|e| format!("{id}: {e}"))?,
+        (None, Some(b64)) => base64::engine::general_purpose::STANDARD
+            .decode(b64)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Event::from_zstd(&bytes))
+            .map_err(|e| format!("{id}: {e}"))?,
+        (None, None) => return Err(format!("{id}: no json attribute")),
+    };
+    let chunks = item
+        .get("tag_chunks")
+        .and_then(|a| a.get("N"))
+        .and_then(|n| n.as_str())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    if chunks > 0 {
+        return Ok(Some(ExportItem::Untagged(ev, chunks)));
+    }
+    ev.validate().map_err(|e| format!("{id}: {e}"))?;
+    Ok(Some(ExportItem::Event(ev)))
+}
+
+/// Puts back the tags of an `Untagged` event from the `chunks` items read
+/// into `tags` and checks its signature.
+pub fn join_tags(mut ev: Event, chunks: usize, tags: &mut ChunkedTags) -> Result<Event, String> {
+    let id = ev.id.to_string();
+    let parts = tags.remove(&id).unwrap_or_default();
+    if parts.keys().copied().ne(0..chunks) {
+        return Err(format!("{id}: tags are missing"));
+    }
+    ev.tags = parts.into_values().flatten().collect();
+    ev.validate().map_err(|e| format!("{id}: {e}"))?;
+    Ok(ev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{join_tags, parse_export_line, ChunkedTags, ExportItem};
+    use crate::identity::Identity;
+    use crate::message::Event;
+    use base64::Engine;
+    use serde_json::json;
+
+    #[test]
+    fn parse_export_line01() {
+        let id = Identity::generate();
+        let ev = Event::sign(id.keys(), 1676118868, 1, vec![], "hello");
+        let line = |item_type: &str, ev: &Event| {
+            json!({"Item": {
+                "id": {"S": ev.id},
+                "type": {"S": item_type},
+                "json": {"S": serde_json::to_string(ev).unwrap()},
+            }})
+            .to_string()
+        };
+
+        assert_eq!(
+            Some(ExportItem::Event(ev.clone())),
+            parse_export_line(&line("event", &ev)).unwrap()
+        );
+        assert_eq!(None, parse_export_line(&line("gauge", &ev)).unwrap());
+        let compressed = json!({"Item": {
+            "id": {"S": ev.id},
+            "type": {"S": "event"},
+            "json": {"B": base64::engine::general_purpose::STANDARD.encode(ev.to_zstd())},
+        }});
+        assert_eq!(
+            Some(ExportItem::Event(ev.clone())),
+            parse_export_line(&compressed.to_string()).unwrap()
+        );
+
+        let mut forged = ev.clone();
+        forged.content = "bye".into();
+        assert!(parse_export_line(&line("event", &forged)).is_err());
+        assert!(parse_export_line("{}").is_err());
+    }
+    #[test]
+    fn join_tags01() {
+        let id = Identity::generate();
+        let tags = vec![
+            vec!["p".to_string(), "a".to_string()],
+            vec!["p".to_string(), "b".to_string()],
+        ];
+        let ev = Event::sign(id.keys(), 1676118868, 3, tags, "");
+        let untagged = Event {
+            tags: vec![],
+            ..ev.clone()
+        };
+        let event = json!({"Item": {
+            "id": {"S": ev.id},
+            "type": {"S": "event"},
+            "tag_chunks": {"N": "2"},
+            "json": {"S": serde_json::to_string(&untagged).unwrap()},
+        }});
+        let chunk = |n: usize| {
+            json!({"Item": {
+                "id": {"S": ev.id},
+                "type": {"S": format!("tags#{n}")},
+                "tags": {"L": [{"L": [{"S": "p"}, {"S": ev.tags[n][1]}]}]},
+            }})
+            .to_string()
+        };
+
+        assert_eq!(
+            Some(ExportItem::Untagged(untagged.clone(), 2)),
+            parse_export_line(&event.to_string()).unwrap()
+        );
+        let mut tags = ChunkedTags::new();
+        for n in [1, 0] {
+            let Some(ExportItem::Tags(id, n, part)) = parse_export_line(&chunk(n)).unwrap() else {
+                panic!("not a tags item");
+            };
+            tags.entry(id).or_default().insert(n, part);
+        }
+        assert_eq!(ev, join_tags(untagged.clone(), 2, &mut tags).unwrap());
+        assert!(join_tags(untagged, 2, &mut tags).is_err());
+    }
+}
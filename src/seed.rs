@@ -0,0 +1,171 @@
+use crate::message::Event;
+use secp256k1::hashes::{sha256, Hash};
+use secp256k1::{KeyPair, Secp256k1};
+
+const TOPICS: [&str; 6] = ["nostr", "aws", "rust", "coffee", "photography", "music"];
+const WORDS: [&str; 12] = [
+    "hello", "relay", "today", "lambda", "cold", "start", "great", "new", "note", "from", "the",
+    "weekend",
+];
+
+/// Shape of a generated corpus.
+#[derive(Debug, Clone)]
+pub struct SeedConfig {
+    pub authors: usize,
+    pub notes_per_author: usize,
+    /// Percentage of notes that are replies / preceded by a reaction.
+    pub reply_ratio: usize,
+    pub reaction_ratio: usize,
+    /// Changes every key and therefore every event id.
+    pub seed: u64,
+    /// created_at of the oldest event; later events are a minute apart.
+    pub start: u64,
+}
+
+impl Default for SeedConfig {
+    fn default() -> Self {
+        SeedConfig {
+            authors: 10,
+            notes_per_author: 20,
+            reply_ratio: 30,
+            reaction_ratio: 50,
+            seed: 0,
+            start: 1676000000,
+        }
+    }
+}
+
+/// Deterministic pseudo random numbers so a corpus can be regenerated.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn author_keys(seed: u64, i: usize) -> KeyPair {
+    let secp = Secp256k1::signing_only();
+    let secret = sha256::Hash::hash(format!("seed:{seed}:{i}").as_bytes());
+    KeyPair::from_seckey_slice(&secp, secret.as_ref()).unwrap()
+}
+
+/// Generates profiles, contact lists, notes, replies and reactions.
+///
+/// Events are ordered oldest first and every referenced event precedes the
+/// events referring to it.
+pub fn generate(config: &SeedConfig) -> Vec<Event> {
+    let mut rng = Rng(config.seed.wrapping_mul(0x9e3779b97f4a7c15) | 1);
+    let keys: Vec<KeyPair> = (0..config.authors)
+        .map(|i| author_keys(config.seed, i))
+        .collect();
+    let pubkeys: Vec<String> = keys
+        .iter()
+        .map(|k| k.x_only_public_key().0.to_string())
+        .collect();
+
+    let mut now = config.start;
+    let mut tick = || {
+        now += 60;
+        now
+    };
+    let mut evs = vec![];
+
+    for (i, k) in keys.iter().enumerate() {
+        let profile = serde_json::json!({
+            "name": format!("seed{i}"),
+            "about": format!("generated author #{i}"),
+        });
+        evs.push(Event::sign(k, tick(), 0, vec![], &profile.to_string()));
+    }
+
+    for k in keys.iter() {
+        let follows = (0..rng.below(config.authors.max(1)))
+            .map(|_| vec!["p".to_string(), pubkeys[rng.below(pubkeys.len())].clone()])
+            .collect();
+        evs.push(Event::sign(k, tick(), 3, follows, ""));
+    }
+
+    let mut notes: Vec<Event> = vec![];
+    for _ in 0..config.notes_per_author * config.authors {
+        let a = rng.below(config.authors);
+        let content: Vec<&str> = (0..3 + rng.below(8))
+            .map(|_| WORDS[rng.below(WORDS.len())])
+            .collect();
+        let topic = TOPICS[rng.below(TOPICS.len())];
+        let mut tags = vec![vec!["t".to_string(), topic.to_string()]];
+
+        if !notes.is_empty() && rng.below(100) < config.reaction_ratio {
+            let target = &notes[rng.below(notes.len())];
+            let reaction = Event::sign(
+                &keys[a],
+                tick(),
+                7,
+                vec![
+                    vec!["e".to_string(), target.id.to_string()],
+                    vec!["p".to_string(), target.pubkey.to_string()],
+                ],
+                "+",
+            );
+            evs.push(reaction);
+        }
+
+        if !notes.is_empty() && rng.below(100) < config.reply_ratio {
+            let parent = &notes[rng.below(notes.len())];
+            let root = parent
+                .tags
+                .iter()
+                .find(|t| t.len() >= 4 && t[0] == "e" && t[3] == "root")
+                .map(|t| t[1].clone())
+                .unwrap_or_else(|| parent.id.to_string());
+            let is_nested = parent.id != root;
+            tags.push(vec!["e".into(), root, "".into(), "root".into()]);
+            if is_nested {
+                tags.push(vec![
+                    "e".into(),
+                    parent.id.to_string(),
+                    "".into(),
+                    "reply".into(),
+                ]);
+            }
+            tags.push(vec!["p".into(), parent.pubkey.to_string()]);
+        }
+
+        let note = Event::sign(&keys[a], tick(), 1, tags, &content.join(" "));
+        notes.push(note.clone());
+        evs.push(note);
+    }
+
+    evs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, SeedConfig};
+
+    #[test]
+    fn generate01() {
+        let config = SeedConfig {
+            authors: 3,
+            notes_per_author: 4,
+            ..SeedConfig::default()
+        };
+        let evs = generate(&config);
+
+        assert_eq!(3, evs.iter().filter(|e| e.kind == 0).count());
+        assert_eq!(3, evs.iter().filter(|e| e.kind == 3).count());
+        assert_eq!(12, evs.iter().filter(|e| e.kind == 1).count());
+        assert!(evs.iter().all(|e| e.validate().is_ok()));
+        assert!(evs.windows(2).all(|w| w[0].created_at < w[1].created_at));
+
+        let again = generate(&config);
+        assert_eq!(evs, again);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::identity::Identity;
+use crate::message::{Event, Filter};
+use crate::store::{EventStore, QueryPlan};
+use serde::Serialize;
+use std::time::{Instant, SystemTime};
+
+/// Outcome of one self-test step.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+    pub millis: u128,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn new(checks: Vec<Check>) -> Report {
+        Report {
+            ok: checks.iter().all(|c| c.ok),
+            checks,
+        }
+    }
+}
+
+/// Times `result` as the check `name`.
+pub fn check(name: &str, started: Instant, result: Result<String, String>) -> Check {
+    let (ok, detail) = match result {
+        Ok(detail) => (true, detail),
+        Err(detail) => (false, detail),
+    };
+    Check {
+        name: name.to_string(),
+        ok,
+        detail,
+        millis: started.elapsed().as_millis(),
+    }
+}
+
+fn expect_one(evs: Result<Vec<Event>, String>, ev: &Event) -> Result<String, String> {
+    match evs {
+        Ok(evs) if evs.iter().any(|e| e.id == ev.id) => Ok(format!("{} events", evs.len())),
+        Ok(_) => Err("synthetic event not found".to_string()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a synthetic event under a throwaway key, reads it back through
+/// every query plan and deletes it again.
+pub async fn run(store: &dyn EventStore) -> Vec<Check> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let ev = Event::sign(Identity::generate().keys(), now, 20999, vec![], "selftest");
+    let mut checks = vec![];
+
+    let started = Instant::now();
+    let written = store.write_event(&ev).await.map(|_| ev.id.to_string());
+    let ok = written.is_ok();
+    checks.push(check("write_event", started, written));
+    if !ok {
+        return checks;
+    }
+
+    let filters: Vec<Filter> = [
+        serde_json::json!({"ids": [ev.id]}),
+        serde_json::json!({"authors": [ev.pubkey], "kinds": [ev.kind]}),
+    ]
+    .into_iter()
+    .map(|f| serde_json::from_value(f).unwrap())
+    .collect();
+    for f in filters.iter() {
+        let started = Instant::now();
+        let (name, result) = match f.query_plan() {
+            QueryPlan::ByIds(plan) => ("query_by_ids", plan.exec(store).await),
+            QueryPlan::ByPubkeys(plan) => ("query_by_pubkeys", plan.exec(store).await),
+            QueryPlan::ByAddress(plan) => ("query_by_address", plan.exec(store).await),
+            QueryPlan::ByChannels(plan) => ("query_by_channels", plan.exec(store).await),
+            QueryPlan::ByRecipients(plan) => ("query_by_recipients", plan.exec(store).await),
+            QueryPlan::ByKinds(plan) => ("query_by_kinds", plan.exec(store).await),
+            QueryPlan::ByTime(plan) => ("query_by_time", plan.exec(store).await),
+            QueryPlan::BoundedScan(plan) => ("scan", plan.exec(store).await),
+            QueryPlan::Count(_) => unreachable!("query_plan does not count"),
+            QueryPlan::NoPlan(reason) => ("query", Err(reason.into())),
+        };
+        checks.push(check(name, started, expect_one(result, &ev)));
+    }
+
+    let started = Instant::now();
+    let deleted = store
+        .delete_event_by_ids(vec![ev.id.clone()])
+        .await
+        .map(|_| ev.id.to_string());
+    checks.push(check("delete_event", started, deleted));
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn run01() {
+        let store = crate::sqlite::SqliteStore::open_in_memory().unwrap();
+        let report = super::Report::new(super::run(&store).await);
+        assert!(report.ok, "{report:?}");
+        assert_eq!(4, report.checks.len());
+    }
+}
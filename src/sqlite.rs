@@ -0,0 +1,1490 @@
+use crate::auth;
+use crate::denylist::Denylist;
+use crate::label::LabelEntry;
+use crate::message::{Event, Filter, KIND_GIFT_WRAP};
+use crate::metrics::{Gauges, Stats};
+use crate::report::Report;
+use crate::store::EventStore;
+use crate::types::{EventId, Pubkey};
+use async_trait::async_trait;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS events (
+    id TEXT PRIMARY KEY,
+    pubkey TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    kind INTEGER NOT NULL,
+    json TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_pubkey_created_at ON events (pubkey, created_at);
+CREATE INDEX IF NOT EXISTS events_kind_created_at ON events (kind, created_at);
+CREATE INDEX IF NOT EXISTS events_created_at ON events (created_at);
+
+CREATE TABLE IF NOT EXISTS tags (
+    event_id TEXT NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tags_name_value ON tags (name, value);
+CREATE INDEX IF NOT EXISTS tags_event_id ON tags (event_id);
+
+CREATE TABLE IF NOT EXISTS subscriptions (
+    conn_id TEXT NOT NULL,
+    sub_id TEXT NOT NULL,
+    filters TEXT NOT NULL,
+    PRIMARY KEY (conn_id, sub_id)
+);
+
+CREATE TABLE IF NOT EXISTS labels (
+    namespace TEXT NOT NULL,
+    value TEXT NOT NULL,
+    target_tag TEXT NOT NULL,
+    target TEXT NOT NULL,
+    labeler TEXT NOT NULL,
+    label_event TEXT NOT NULL,
+    PRIMARY KEY (namespace, value, target_tag, target, label_event)
+);
+
+CREATE TABLE IF NOT EXISTS gauges (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    connections INTEGER NOT NULL,
+    subscriptions INTEGER NOT NULL
+);
+INSERT OR IGNORE INTO gauges VALUES (0, 0, 0);
+
+CREATE TABLE IF NOT EXISTS rejections (
+    kind TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (kind, reason)
+);
+
+CREATE TABLE IF NOT EXISTS languages (
+    language TEXT PRIMARY KEY,
+    count INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS greeted (
+    conn_id TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS reports (
+    id TEXT PRIMARY KEY,
+    json TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS bans (
+    pubkey TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS banned_events (
+    id TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS allowlist (
+    pubkey TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS content_rules (
+    rule TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS members (
+    pubkey TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS invoices (
+    payment_hash TEXT PRIMARY KEY,
+    pubkey TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS connections (
+    conn_id TEXT PRIMARY KEY,
+    challenge TEXT NOT NULL,
+    auth_pubkey TEXT,
+    source_ip TEXT
+);
+
+CREATE TABLE IF NOT EXISTS counters (
+    key TEXT PRIMARY KEY,
+    count INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+"#;
+
+/// EventStore kept in a single SQLite database, for running the relay
+/// without AWS.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens (and if needed creates) the database at `path`.
+    pub fn open(path: &str) -> Result<SqliteStore, String> {
+        SqliteStore::init(Connection::open(path).map_err(|e| e.to_string())?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore, String> {
+        SqliteStore::init(Connection::open_in_memory().map_err(|e| e.to_string())?)
+    }
+
+    fn init(conn: Connection) -> Result<SqliteStore, String> {
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")
+            .map_err(|e| e.to_string())?;
+        // Subscriptions and connections belong to an earlier run, and older
+        // files keyed subscriptions by sub_id alone.
+        conn.execute_batch("DROP TABLE IF EXISTS subscriptions; DROP TABLE IF EXISTS connections;")
+            .map_err(|e| e.to_string())?;
+        conn.execute_batch(SCHEMA).map_err(|e| e.to_string())?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Ids of the events carrying the tag `name` with `value`.
+    pub fn get_event_ids_by_tag(&self, name: &str, value: &str) -> Result<Vec<String>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT event_id FROM tags WHERE name = ? AND value = ?")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![name, value], |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    /// The stored event with `id`, if any.
+    pub fn get_event(&self, id: &str) -> Result<Option<Event>, String> {
+        let json: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT json FROM events WHERE id = ?", params![id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(|e| e.to_string())?;
+        Ok(json.and_then(|j| serde_json::from_str(&j).ok()))
+    }
+
+    /// The newest events tagged `name` with one of `values`.
+    fn query_events_by_tag(
+        &self,
+        name: &str,
+        values: &[&str],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let mut sql = format!(
+            "SELECT DISTINCT json, created_at FROM events JOIN tags ON tags.event_id = events.id \
+             WHERE tags.name = ? AND tags.value IN ({}) AND created_at BETWEEN ? AND ?",
+            placeholders(values.len())
+        );
+        let mut args = vec![Value::Text(name.to_string())];
+        args.extend(values.iter().map(|v| Value::Text(v.to_string())));
+        args.push(Value::Integer(since.unwrap_or(0) as i64));
+        args.push(Value::Integer(until.unwrap_or(i64::MAX as u64) as i64));
+        if let Some(kinds) = kinds {
+            sql += &format!(" AND kind IN ({})", placeholders(kinds.len()));
+            args.extend(kinds.iter().map(|k| Value::Integer(*k as i64)));
+        }
+        sql += " ORDER BY created_at DESC LIMIT ?";
+        args.push(Value::Integer(limit.unwrap_or(100).max(1) as i64));
+        self.query_events(&sql, args)
+    }
+
+    fn query_events(&self, sql: &str, args: Vec<Value>) -> Result<Vec<Event>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(sql).map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params_from_iter(args), |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+        let mut evs = vec![];
+        for json in rows {
+            let json = json.map_err(|e| e.to_string())?;
+            evs.push(serde_json::from_str(&json).map_err(|e| e.to_string())?);
+        }
+        Ok(evs)
+    }
+}
+
+/// `?, ?, ?` for `n` parameters.
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+#[async_trait]
+impl EventStore for SqliteStore {
+    async fn write_event(&self, ev: &Event) -> Result<(), String> {
+        let json = serde_json::to_string(ev).unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute(
+            "INSERT OR REPLACE INTO events (id, pubkey, created_at, kind, json) VALUES (?, ?, ?, ?, ?)",
+            params![ev.id.as_str(), ev.pubkey.as_str(), ev.created_at as i64, ev.kind as i64, json],
+        )
+        .map_err(|e| e.to_string())?;
+        tx.execute(
+            "DELETE FROM tags WHERE event_id = ?",
+            params![ev.id.as_str()],
+        )
+        .map_err(|e| e.to_string())?;
+        for tag in ev.tags.iter().filter(|t| t.len() >= 2 && t[0].len() == 1) {
+            tx.execute(
+                "INSERT INTO tags (event_id, name, value) VALUES (?, ?, ?)",
+                params![ev.id.as_str(), tag[0], tag[1]],
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    async fn write_subscription(
+        &self,
+        conn_id: &str,
+        sub_id: &str,
+        filters: &[Filter],
+    ) -> Result<bool, String> {
+        let filters = serde_json::to_string(filters).unwrap();
+        let conn = self.conn.lock().unwrap();
+        let replaced = conn
+            .query_row(
+                "SELECT 1 FROM subscriptions WHERE conn_id = ? AND sub_id = ?",
+                params![conn_id, sub_id],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?
+            .is_some();
+        conn.execute(
+            "INSERT OR REPLACE INTO subscriptions (sub_id, conn_id, filters) VALUES (?, ?, ?)",
+            params![sub_id, conn_id, filters],
+        )
+        .map(|_| replaced)
+        .map_err(|e| e.to_string())
+    }
+
+    async fn delete_subscriptions(
+        &self,
+        conn_id: &str,
+        sub_ids: Vec<String>,
+    ) -> Result<(), String> {
+        let sql = format!(
+            "DELETE FROM subscriptions WHERE conn_id = ? AND sub_id IN ({})",
+            placeholders(sub_ids.len())
+        );
+        let params = std::iter::once(conn_id.to_string()).chain(sub_ids);
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(&sql, params_from_iter(params))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn close_connection(&self, conn_id: &str) -> Result<usize, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM greeted WHERE conn_id = ?", params![conn_id])
+            .map_err(|e| e.to_string())?;
+        conn.execute(
+            "DELETE FROM connections WHERE conn_id = ?",
+            params![conn_id],
+        )
+        .map_err(|e| e.to_string())?;
+        conn.execute(
+            "DELETE FROM subscriptions WHERE conn_id = ?",
+            params![conn_id],
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    async fn get_subscription_ids(&self, conn_id: &str) -> Result<Vec<String>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT sub_id FROM subscriptions WHERE conn_id = ?")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![conn_id], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = match conn.prepare("SELECT sub_id, conn_id, filters FROM subscriptions") {
+            Ok(stmt) => stmt,
+            Err(e) => {
+                println!("sqlite err: {e}");
+                return vec![];
+            }
+        };
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        });
+        match rows {
+            Ok(rows) => rows
+                .filter_map(|r| r.ok())
+                .filter_map(|(sub_id, conn_id, filters)| {
+                    let filters = serde_json::from_str(&filters).ok()?;
+                    Some((sub_id, conn_id, filters))
+                })
+                .collect(),
+            Err(e) => {
+                println!("sqlite err: {e}");
+                vec![]
+            }
+        }
+    }
+
+    async fn get_event_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, String> {
+        let sql = format!(
+            "SELECT json FROM events WHERE id IN ({})",
+            placeholders(ids.len())
+        );
+        let args = ids.iter().map(|id| Value::Text(id.to_string())).collect();
+        self.query_events(&sql, args)
+    }
+
+    async fn get_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let mut sql = format!(
+            "SELECT json FROM events WHERE pubkey IN ({}) AND created_at BETWEEN ? AND ?",
+            placeholders(pubkeys.len())
+        );
+        let mut args: Vec<Value> = pubkeys.iter().map(|p| Value::Text(p.to_string())).collect();
+        args.push(Value::Integer(since.unwrap_or(0) as i64));
+        args.push(Value::Integer(until.unwrap_or(i64::MAX as u64) as i64));
+        if let Some(kinds) = kinds {
+            sql += &format!(" AND kind IN ({})", placeholders(kinds.len()));
+            args.extend(kinds.iter().map(|k| Value::Integer(*k as i64)));
+        }
+        sql += " ORDER BY created_at DESC LIMIT ?";
+        args.push(Value::Integer(limit.unwrap_or(100).max(1) as i64));
+        self.query_events(&sql, args)
+    }
+
+    /// Channel messages are found by their `e` tags, which may also match
+    /// replies to events of the channel; the filter match drops those.
+    async fn get_event_by_channels(
+        &self,
+        channels: &[EventId],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let channels: Vec<&str> = channels.iter().map(|c| c.as_str()).collect();
+        self.query_events_by_tag("e", &channels, kinds, since, until, limit)
+    }
+
+    async fn get_event_by_pubkey_prefixes(
+        &self,
+        prefixes: &[String],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let likes = vec!["pubkey LIKE ?"; prefixes.len()].join(" OR ");
+        let mut sql =
+            format!("SELECT json FROM events WHERE ({likes}) AND created_at BETWEEN ? AND ?");
+        let mut args: Vec<Value> = prefixes
+            .iter()
+            .map(|p| Value::Text(format!("{p}%")))
+            .collect();
+        args.push(Value::Integer(since.unwrap_or(0) as i64));
+        args.push(Value::Integer(until.unwrap_or(i64::MAX as u64) as i64));
+        if let Some(kinds) = kinds {
+            sql += &format!(" AND kind IN ({})", placeholders(kinds.len()));
+            args.extend(kinds.iter().map(|k| Value::Integer(*k as i64)));
+        }
+        sql += " ORDER BY created_at DESC LIMIT ?";
+        args.push(Value::Integer(limit.unwrap_or(100).max(1) as i64));
+        self.query_events(&sql, args)
+    }
+
+    async fn get_event_by_id_prefixes(
+        &self,
+        prefixes: &[String],
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        // Prefixes are lowercase hex, so they hold no LIKE wildcards.
+        let likes = vec!["id LIKE ?"; prefixes.len()].join(" OR ");
+        let sql = format!("SELECT json FROM events WHERE {likes} ORDER BY created_at DESC LIMIT ?");
+        let mut args: Vec<Value> = prefixes
+            .iter()
+            .map(|p| Value::Text(format!("{p}%")))
+            .collect();
+        args.push(Value::Integer(limit.unwrap_or(100).max(1) as i64));
+        self.query_events(&sql, args)
+    }
+
+    async fn get_event_by_kinds(
+        &self,
+        kinds: &[u64],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let sql = format!(
+            "SELECT json FROM events WHERE kind IN ({}) AND created_at BETWEEN ? AND ? \
+             ORDER BY created_at DESC LIMIT ?",
+            placeholders(kinds.len())
+        );
+        let mut args: Vec<Value> = kinds.iter().map(|k| Value::Integer(*k as i64)).collect();
+        args.push(Value::Integer(since.unwrap_or(0) as i64));
+        args.push(Value::Integer(until.unwrap_or(i64::MAX as u64) as i64));
+        args.push(Value::Integer(limit.unwrap_or(100).max(1) as i64));
+        self.query_events(&sql, args)
+    }
+
+    async fn get_event_by_time(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let args = vec![
+            Value::Integer(since.unwrap_or(0) as i64),
+            Value::Integer(until.unwrap_or(i64::MAX as u64) as i64),
+            Value::Integer(limit.unwrap_or(100).max(1) as i64),
+        ];
+        self.query_events(
+            "SELECT json FROM events WHERE created_at BETWEEN ? AND ? \
+             ORDER BY created_at DESC LIMIT ?",
+            args,
+        )
+    }
+
+    async fn scan_events(&self, max_items: usize) -> Result<Vec<Event>, String> {
+        self.query_events(
+            "SELECT json FROM events ORDER BY created_at DESC LIMIT ?",
+            vec![Value::Integer(max_items as i64)],
+        )
+    }
+
+    async fn get_event_by_recipients(
+        &self,
+        recipients: &[Pubkey],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        let recipients: Vec<&str> = recipients.iter().map(|r| r.as_str()).collect();
+        let kinds = Some(vec![KIND_GIFT_WRAP]);
+        self.query_events_by_tag("p", &recipients, kinds, since, until, limit)
+    }
+
+    async fn count_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<u64, String> {
+        let mut sql = format!(
+            "SELECT COUNT(*) FROM events WHERE pubkey IN ({}) AND created_at BETWEEN ? AND ?",
+            placeholders(pubkeys.len())
+        );
+        let mut args: Vec<Value> = pubkeys.iter().map(|p| Value::Text(p.to_string())).collect();
+        args.push(Value::Integer(since.unwrap_or(0) as i64));
+        args.push(Value::Integer(until.unwrap_or(i64::MAX as u64) as i64));
+        if let Some(kinds) = kinds {
+            sql += &format!(" AND kind IN ({})", placeholders(kinds.len()));
+            args.extend(kinds.iter().map(|k| Value::Integer(*k as i64)));
+        }
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(&sql, params_from_iter(args), |row| row.get::<_, i64>(0))
+            .map(|n| n as u64)
+            .map_err(|e| e.to_string())
+    }
+
+    async fn delete_event_by_ids(&self, ids: Vec<EventId>) -> Result<(), String> {
+        let sql = format!(
+            "DELETE FROM events WHERE id IN ({})",
+            placeholders(ids.len())
+        );
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(&sql, params_from_iter(ids.iter().map(|id| id.as_str())))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn adjust_gauges(&self, connections: i64, subscriptions: i64) -> Result<Gauges, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "UPDATE gauges SET connections = connections + ?, subscriptions = subscriptions + ?
+                 RETURNING connections, subscriptions",
+                params![connections, subscriptions],
+                |row| {
+                    Ok(Gauges {
+                        connections: row.get(0)?,
+                        subscriptions: row.get(1)?,
+                    })
+                },
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    async fn write_labels(&self, _ev: &Event, entries: &[LabelEntry]) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        for e in entries {
+            conn.execute(
+                "INSERT OR REPLACE INTO labels VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    e.namespace,
+                    e.value,
+                    e.target_tag,
+                    e.target,
+                    e.labeler,
+                    e.label_event
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    async fn get_label_targets(
+        &self,
+        namespace: &str,
+        value: &str,
+    ) -> Result<Vec<LabelEntry>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT namespace, value, target_tag, target, labeler, label_event
+                 FROM labels WHERE namespace = ? AND value = ?",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![namespace, value], |row| {
+                Ok(LabelEntry {
+                    namespace: row.get(0)?,
+                    value: row.get(1)?,
+                    target_tag: row.get(2)?,
+                    target: row.get(3)?,
+                    labeler: row.get(4)?,
+                    label_event: row.get(5)?,
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn add_rejection(&self, kind: &str, reason: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO rejections VALUES (?, ?, 1)
+                 ON CONFLICT (kind, reason) DO UPDATE SET count = count + 1",
+                params![kind, reason],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn add_language(&self, language: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO languages VALUES (?, 1)
+                 ON CONFLICT (language) DO UPDATE SET count = count + 1",
+                params![language],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get_stats(&self) -> Result<Stats, String> {
+        let conn = self.conn.lock().unwrap();
+        let (connections, subscriptions) = conn
+            .query_row("SELECT connections, subscriptions FROM gauges", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .map_err(|e| e.to_string())?;
+        let mut stats = Stats {
+            connections,
+            subscriptions,
+            ..Stats::default()
+        };
+        let mut stmt = conn
+            .prepare("SELECT kind, reason, count FROM rejections")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get(2)?,
+                ))
+            })
+            .map_err(|e| e.to_string())?;
+        for row in rows {
+            let (kind, reason, count) = row.map_err(|e| e.to_string())?;
+            stats
+                .rejections
+                .entry(kind)
+                .or_default()
+                .insert(reason, count);
+        }
+        let mut stmt = conn
+            .prepare("SELECT language, count FROM languages")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?;
+        stats.languages = rows.collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+        Ok(stats)
+    }
+
+    async fn write_report(&self, report: &Report) -> Result<(), String> {
+        let json = serde_json::to_string(report).unwrap();
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO reports (id, json, created_at, status) VALUES (?, ?, ?, 'open')",
+                params![report.id, json, report.created_at as i64],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get_open_reports(&self) -> Result<Vec<Report>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT json FROM reports WHERE status = 'open' ORDER BY created_at")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+        let mut reports = vec![];
+        for json in rows {
+            let json = json.map_err(|e| e.to_string())?;
+            reports.push(serde_json::from_str(&json).map_err(|e| e.to_string())?);
+        }
+        Ok(reports)
+    }
+
+    async fn resolve_report(&self, id: &str, action: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE reports SET status = ? WHERE id = ?",
+                params![action, id],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn add_ban(&self, pubkey: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO bans (pubkey) VALUES (?)",
+                params![pubkey],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn remove_ban(&self, pubkey: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM bans WHERE pubkey = ?", params![pubkey])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn is_banned(&self, pubkey: &str) -> Result<bool, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM bans WHERE pubkey = ?",
+                params![pubkey],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|r| r.is_some())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn add_banned_event(&self, id: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO banned_events (id) VALUES (?)",
+                params![id],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn remove_banned_event(&self, id: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM banned_events WHERE id = ?", params![id])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get_denylist(&self) -> Result<Denylist, String> {
+        let conn = self.conn.lock().unwrap();
+        let column = |sql: &str| -> Result<Vec<String>, String> {
+            let mut stmt = conn.prepare(sql).map_err(|e| e.to_string())?;
+            let rows = stmt
+                .query_map([], |row| row.get(0))
+                .map_err(|e| e.to_string())?;
+            rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+        };
+        Ok(Denylist::new(
+            column("SELECT pubkey FROM bans")?,
+            column("SELECT id FROM banned_events")?,
+        ))
+    }
+
+    async fn add_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO allowlist (pubkey) VALUES (?)",
+                params![pubkey],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn remove_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM allowlist WHERE pubkey = ?", params![pubkey])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT pubkey FROM allowlist")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn add_content_rule(&self, rule: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO content_rules (rule) VALUES (?)",
+                params![rule],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get_content_rules(&self) -> Result<Vec<String>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT rule FROM content_rules")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn add_member(&self, pubkey: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO members (pubkey) VALUES (?)",
+                params![pubkey],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn is_member(&self, pubkey: &str) -> Result<bool, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM members WHERE pubkey = ?",
+                params![pubkey],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|r| r.is_some())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn write_invoice(&self, payment_hash: &str, pubkey: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO invoices (payment_hash, pubkey) VALUES (?, ?)",
+                params![payment_hash, pubkey],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get_invoice_pubkey(&self, payment_hash: &str) -> Result<Option<String>, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT pubkey FROM invoices WHERE payment_hash = ?",
+                params![payment_hash],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    async fn mark_greeted(&self, conn_id: &str) -> Result<bool, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO greeted (conn_id) VALUES (?)",
+                params![conn_id],
+            )
+            .map(|n| n == 1)
+            .map_err(|e| e.to_string())
+    }
+
+    async fn increment_counter(&self, key: &str, ttl: u64) -> Result<u64, String> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM counters WHERE expires_at < ?", params![now])
+            .map_err(|e| e.to_string())?;
+        conn.query_row(
+            "INSERT INTO counters (key, count, expires_at) VALUES (?, 1, ?)
+             ON CONFLICT (key) DO UPDATE SET count = count + 1
+             RETURNING count",
+            params![key, now + ttl],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    async fn write_connection(
+        &self,
+        conn_id: &str,
+        challenge: &str,
+        source_ip: Option<&str>,
+    ) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO connections (conn_id, challenge, source_ip) VALUES (?, ?, ?)",
+                params![conn_id, challenge, source_ip],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get_connection(&self, conn_id: &str) -> Result<Option<auth::Connection>, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT challenge, auth_pubkey, source_ip FROM connections WHERE conn_id = ?",
+                params![conn_id],
+                |row| {
+                    Ok(auth::Connection {
+                        challenge: row.get(0)?,
+                        auth_pubkey: row.get(1)?,
+                        source_ip: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    async fn set_auth_pubkey(&self, conn_id: &str, pubkey: &str) -> Result<(), String> {
+        let n = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE connections SET auth_pubkey = ? WHERE conn_id = ?",
+                params![pubkey, conn_id],
+            )
+            .map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err(format!("no connection record for {conn_id}"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteStore;
+    use crate::message::Event;
+    use crate::store::EventStore;
+    use crate::types::{EventId, Pubkey, Signature};
+
+    fn build_event(id: &str, pubkey: &str, created_at: u64, kind: u64) -> Event {
+        Event {
+            id: EventId::padded(id),
+            pubkey: Pubkey::padded(pubkey),
+            created_at,
+            kind,
+            tags: vec![vec!["t".into(), "nostr".into()]],
+            content: "".into(),
+            sig: Signature::padded(""),
+        }
+    }
+
+    #[tokio::test]
+    async fn events01() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store
+            .write_event(&build_event("a1", "a", 1, 1))
+            .await
+            .unwrap();
+        store
+            .write_event(&build_event("a2", "a", 2, 7))
+            .await
+            .unwrap();
+        store
+            .write_event(&build_event("b3", "b", 3, 1))
+            .await
+            .unwrap();
+        store
+            .write_event(&build_event("e4", "b", 4, 20001))
+            .await
+            .unwrap();
+
+        let evs = store
+            .get_event_by_pubkeys(
+                &[Pubkey::padded("a"), Pubkey::padded("b")],
+                Some(vec![1]),
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let ids: Vec<&str> = evs.iter().map(|e| &e.id[..2]).collect();
+        assert_eq!(vec!["b3", "a1"], ids);
+        let count = store
+            .count_event_by_pubkeys(
+                &[Pubkey::padded("a"), Pubkey::padded("b")],
+                None,
+                Some(2),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(3, count);
+
+        let evs = store
+            .get_event_by_ids(&[EventId::padded("a2")])
+            .await
+            .unwrap();
+        assert_eq!(build_event("a2", "a", 2, 7), evs[0]);
+        assert_eq!(4, store.get_event_ids_by_tag("t", "nostr").unwrap().len());
+
+        store
+            .delete_event_by_ids(vec![EventId::padded("a2")])
+            .await
+            .unwrap();
+        assert_eq!(None, store.get_event(&EventId::padded("a2")).unwrap());
+        assert_eq!(3, store.get_event_ids_by_tag("t", "nostr").unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn replaceable01() {
+        use crate::hook::{HookContext, Hooks};
+        use std::collections::HashSet;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        let hooks = Hooks::select(&HashSet::from(["replaceable".to_string()]), false).unwrap();
+        for ev in [
+            build_event("a1", "a", 2, 0),
+            build_event("a2", "a", 3, 0),
+            build_event("a3", "a", 1, 0),
+            build_event("a4", "a", 1, 10002),
+            build_event("b1", "b", 1, 0),
+        ] {
+            store.write_event(&ev).await.unwrap();
+            hooks
+                .post_event_write_hook(&HookContext::new(&store), &ev)
+                .await;
+        }
+        let mut ids: Vec<String> = store
+            .get_event_by_pubkeys(
+                &[Pubkey::padded("a"), Pubkey::padded("b")],
+                None,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        ids.sort();
+        assert_eq!(vec!["a2", "a4", "b1"], ids);
+    }
+
+    #[tokio::test]
+    async fn relay_list01() {
+        use crate::message::Filter;
+        use crate::store::QueryPlan;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        for ev in [
+            build_event("a1", "a", 1, 10002),
+            build_event("a2", "a", 2, 10002),
+            build_event("b1", "b", 1, 1),
+        ] {
+            store.write_event(&ev).await.unwrap();
+        }
+        let relay_list = store.get_relay_list(&Pubkey::padded("a")).await.unwrap();
+        assert_eq!(Some(EventId::padded("a2")), relay_list.map(|e| e.id));
+        assert_eq!(
+            None,
+            store.get_relay_list(&Pubkey::padded("b")).await.unwrap()
+        );
+
+        let filter: Filter = serde_json::from_str(&format!(
+            r#"{{"authors": ["{}", "{}"], "kinds": [10002]}}"#,
+            Pubkey::padded("a"),
+            Pubkey::padded("b")
+        ))
+        .unwrap();
+        let QueryPlan::ByPubkeys(plan) = filter.query_plan() else {
+            panic!("expected a pubkey lookup");
+        };
+        let evs = plan.exec(&store).await.unwrap();
+        assert_eq!(
+            vec![EventId::padded("a2")],
+            evs.into_iter().map(|e| e.id).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn channels01() {
+        use crate::message::Filter;
+        use crate::store::QueryPlan;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        let channel = EventId::padded("c1");
+        let message = |id: &str, created_at: u64, e: &EventId| Event {
+            tags: vec![vec!["e".into(), e.to_string(), "".into(), "root".into()]],
+            ..build_event(id, "a", created_at, 42)
+        };
+        for ev in [
+            message("a1", 1, &channel),
+            message("a2", 2, &channel),
+            message("a3", 3, &EventId::padded("c2")),
+        ] {
+            store.write_event(&ev).await.unwrap();
+        }
+
+        let filter: Filter =
+            serde_json::from_str(&format!(r##"{{"kinds": [42], "#e": ["{channel}"]}}"##)).unwrap();
+        let QueryPlan::ByChannels(plan) = filter.query_plan() else {
+            panic!("expected a channel lookup");
+        };
+        let ids: Vec<String> = plan
+            .exec(&store)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        assert_eq!(vec!["a2", "a1"], ids);
+    }
+
+    #[tokio::test]
+    async fn id_prefixes01() {
+        use crate::message::Filter;
+        use crate::store::QueryPlan;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        for ev in [
+            build_event("abcd01", "a", 1, 1),
+            build_event("abcd02", "a", 2, 1),
+            build_event("abce01", "a", 3, 1),
+        ] {
+            store.write_event(&ev).await.unwrap();
+        }
+
+        let store = &store;
+        let ids = |json: String| async move {
+            let filter: Filter = serde_json::from_str(&json).unwrap();
+            let QueryPlan::ByIds(plan) = filter.query_plan() else {
+                panic!("expected an id lookup");
+            };
+            let evs = plan.exec(store).await.unwrap();
+            evs.iter()
+                .map(|e| e.id[..6].to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["abcd02", "abcd01"],
+            ids(r#"{"ids": ["abcd"]}"#.into()).await
+        );
+        let full = EventId::padded("abce01");
+        assert_eq!(
+            vec!["abcd02", "abce01"],
+            ids(format!(r#"{{"ids": ["abcd02", "{full}"]}}"#)).await
+        );
+        // Too short to be looked up.
+        assert!(ids(r#"{"ids": ["ab"]}"#.into()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn author_prefixes01() {
+        use crate::message::Filter;
+        use crate::store::QueryPlan;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        for ev in [
+            build_event("a1", "abcd1", 1, 1),
+            build_event("a2", "abcd2", 2, 7),
+            build_event("a3", "abce1", 3, 1),
+            build_event("a4", "abcd1", 4, 1),
+        ] {
+            store.write_event(&ev).await.unwrap();
+        }
+
+        let full = Pubkey::padded("abce1");
+        let filter: Filter = serde_json::from_str(&format!(
+            r#"{{"authors": ["abcd", "{full}", "ab"], "kinds": [1], "limit": 2}}"#
+        ))
+        .unwrap();
+        let QueryPlan::ByPubkeys(plan) = filter.query_plan() else {
+            panic!("expected an author lookup");
+        };
+        let ids: Vec<String> = plan
+            .exec(&store)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        assert_eq!(vec!["a4", "a3"], ids);
+        assert!(!matches!(filter.count_plan(), QueryPlan::Count(_)));
+    }
+
+    #[tokio::test]
+    async fn timelines01() {
+        use crate::message::Filter;
+        use crate::store::QueryPlan;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        for ev in [
+            build_event("a1", "a", 1, 1),
+            build_event("a2", "b", 2, 7),
+            build_event("a3", "c", 3, 1),
+        ] {
+            store.write_event(&ev).await.unwrap();
+        }
+
+        let filter: Filter = serde_json::from_str(r#"{"kinds": [1], "limit": 50}"#).unwrap();
+        let QueryPlan::ByKinds(plan) = filter.query_plan() else {
+            panic!("expected a kind lookup");
+        };
+        let ids: Vec<String> = plan
+            .exec(&store)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        assert_eq!(vec!["a3", "a1"], ids);
+
+        let filter: Filter = serde_json::from_str(r#"{"since": 1, "limit": 5}"#).unwrap();
+        let QueryPlan::ByTime(plan) = filter.query_plan() else {
+            panic!("expected a time range lookup");
+        };
+        let ids: Vec<String> = plan
+            .exec(&store)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        assert_eq!(vec!["a3", "a2"], ids);
+    }
+
+    #[tokio::test]
+    async fn scan01() {
+        use crate::message::Filter;
+        use crate::store::{QueryByScan, QueryPlan};
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        for ev in [
+            build_event("a1", "a", 1, 1),
+            build_event("a2", "a", 2, 1),
+            build_event("a3", "a", 3, 1),
+        ] {
+            store.write_event(&ev).await.unwrap();
+        }
+
+        let filter: Filter = serde_json::from_str(r##"{"#t": ["nostr"]}"##).unwrap();
+        assert!(matches!(filter.query_plan(), QueryPlan::NoPlan(_)));
+        // Only the newest two items are read.
+        let evs = QueryByScan::new(&filter, 2, 10).exec(&store).await.unwrap();
+        let ids: Vec<&str> = evs.iter().map(|e| &e.id[..2]).collect();
+        assert_eq!(vec!["a3", "a2"], ids);
+    }
+
+    #[tokio::test]
+    async fn recipients01() {
+        use crate::message::{Filter, KIND_GIFT_WRAP};
+        use crate::store::QueryPlan;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        let wrap = |id: &str, created_at: u64, p: &str| Event {
+            tags: vec![vec!["p".into(), Pubkey::padded(p).to_string()]],
+            ..build_event(id, "a", created_at, KIND_GIFT_WRAP)
+        };
+        for ev in [
+            wrap("a1", 1, "b1"),
+            wrap("a2", 2, "b2"),
+            wrap("a3", 3, "b1"),
+        ] {
+            store.write_event(&ev).await.unwrap();
+        }
+
+        let json = format!(
+            r##"{{"kinds": [1059], "#p": ["{}"]}}"##,
+            Pubkey::padded("b1")
+        );
+        let filter: Filter = serde_json::from_str(&json).unwrap();
+        let QueryPlan::ByRecipients(plan) = filter.query_plan() else {
+            panic!("expected a recipient lookup");
+        };
+        let ids: Vec<String> = plan
+            .exec(&store)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        assert_eq!(vec!["a3", "a1"], ids);
+    }
+
+    #[tokio::test]
+    async fn reports01() {
+        use crate::hook::{HookContext, Hooks};
+        use crate::report::{self, Action, KIND_REPORT};
+        use std::collections::HashSet;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        let hooks = Hooks::select(&HashSet::from(["nip56".to_string()]), false).unwrap();
+        let spam = build_event("b1", "b", 1, 1);
+        let report = |id: &str| Event {
+            tags: vec![
+                vec!["p".into(), spam.pubkey.to_string()],
+                vec!["e".into(), spam.id.to_string(), "spam".into()],
+            ],
+            ..build_event(id, "a", 2, KIND_REPORT)
+        };
+        for ev in [spam.clone(), report("a1"), report("a2")] {
+            store.write_event(&ev).await.unwrap();
+            hooks
+                .post_event_write_hook(&HookContext::new(&store), &ev)
+                .await;
+        }
+        let reports = store.get_open_reports().await.unwrap();
+        assert_eq!(2, reports.len());
+        assert_eq!("spam", reports[0].report_type);
+
+        report::resolve(&store, &reports[0].id, Action::Dismiss)
+            .await
+            .unwrap();
+        assert_eq!(1, store.get_open_reports().await.unwrap().len());
+        assert!(store.get_event(&spam.id).unwrap().is_some());
+
+        report::resolve(&store, &reports[1].id, Action::Ban)
+            .await
+            .unwrap();
+        assert!(store.get_open_reports().await.unwrap().is_empty());
+        assert!(store.get_event(&spam.id).unwrap().is_none());
+        assert!(store.is_banned(&spam.pubkey).await.unwrap());
+        assert!(!store.get_denylist().await.unwrap().allows(&spam));
+        assert!(report::resolve(&store, &reports[1].id, Action::Ban)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn address01() {
+        use crate::hook::{HookContext, Hooks};
+        use crate::message::Filter;
+        use crate::store::QueryPlan;
+        use std::collections::HashSet;
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        let article = |id: &str, created_at: u64, d: &str| Event {
+            tags: vec![vec!["d".into(), d.into()]],
+            ..build_event(id, "a", created_at, 30023)
+        };
+        let hooks = Hooks::select(&HashSet::from(["nip33".to_string()]), false).unwrap();
+        for ev in [
+            article("a1", 2, "x"),
+            article("a2", 1, "x"),
+            article("a3", 1, "y"),
+        ] {
+            store.write_event(&ev).await.unwrap();
+            hooks
+                .post_event_write_hook(&HookContext::new(&store), &ev)
+                .await;
+        }
+        assert_eq!(None, store.get_event(&EventId::padded("a2")).unwrap());
+
+        let filter: Filter = serde_json::from_str(&format!(
+            r##"{{"authors": ["{}"], "kinds": [30023], "#d": ["x", "y"]}}"##,
+            Pubkey::padded("a")
+        ))
+        .unwrap();
+        let QueryPlan::ByAddress(plan) = filter.query_plan() else {
+            panic!("expected an address lookup");
+        };
+        let mut ids: Vec<String> = plan
+            .exec(&store)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id[..2].to_string())
+            .collect();
+        ids.sort();
+        assert_eq!(vec!["a1", "a3"], ids);
+    }
+
+    #[tokio::test]
+    async fn subscriptions01() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let filters = vec![serde_json::from_str(r#"{"kinds":[1]}"#).unwrap()];
+        store
+            .write_subscription("c1", "s1", &filters)
+            .await
+            .unwrap();
+        store
+            .write_subscription("c1", "s2", &filters)
+            .await
+            .unwrap();
+        store
+            .write_subscription("c2", "s3", &filters)
+            .await
+            .unwrap();
+        assert!(!store
+            .write_subscription("c2", "s1", &filters)
+            .await
+            .unwrap());
+        // A REQ reusing the sub_id replaces the filters.
+        let replacement = vec![serde_json::from_str(r#"{"kinds":[7]}"#).unwrap()];
+        assert!(store
+            .write_subscription("c1", "s1", &replacement)
+            .await
+            .unwrap());
+        store
+            .delete_subscriptions("c2", vec!["s1".to_string()])
+            .await
+            .unwrap();
+        let subs = store.get_all_subscriptions().await;
+        assert_eq!(3, subs.len());
+        assert!(subs.contains(&("s1".to_string(), "c1".to_string(), replacement)));
+        let mut ids = store.get_subscription_ids("c1").await.unwrap();
+        ids.sort();
+        assert_eq!(vec!["s1", "s2"], ids);
+
+        assert_eq!(2, store.close_connection("c1").await.unwrap());
+        let subs = store.get_all_subscriptions().await;
+        assert_eq!(1, subs.len());
+        assert_eq!(
+            ("s3".to_string(), "c2".to_string()),
+            (subs[0].0.clone(), subs[0].1.clone())
+        );
+
+        assert!(store.mark_greeted("c2").await.unwrap());
+        assert!(!store.mark_greeted("c2").await.unwrap());
+
+        store
+            .write_connection("c2", "chal", Some("192.0.2.1"))
+            .await
+            .unwrap();
+        store.set_auth_pubkey("c2", "b").await.unwrap();
+        let record = store.get_connection("c2").await.unwrap().unwrap();
+        assert_eq!(
+            ("chal", Some("b"), Some("192.0.2.1")),
+            (
+                &*record.challenge,
+                record.auth_pubkey.as_deref(),
+                record.source_ip.as_deref()
+            )
+        );
+        assert!(store.set_auth_pubkey("c9", "b").await.is_err());
+        store.close_connection("c2").await.unwrap();
+        assert_eq!(None, store.get_connection("c2").await.unwrap());
+
+        assert_eq!(1, store.increment_counter("rate#c2", 60).await.unwrap());
+        assert_eq!(2, store.increment_counter("rate#c2", 60).await.unwrap());
+
+        let gauges = store.adjust_gauges(1, 2).await.unwrap();
+        assert_eq!((1, 2), (gauges.connections, gauges.subscriptions));
+
+        store.add_rejection("event", "blocked").await.unwrap();
+        store.add_rejection("event", "blocked").await.unwrap();
+        let stats = store.get_stats().await.unwrap();
+        assert_eq!(1, stats.connections);
+        assert_eq!(2, stats.rejections["event"]["blocked"]);
+        store.add_language("ja").await.unwrap();
+        assert_eq!(1, store.get_stats().await.unwrap().languages["ja"]);
+    }
+}
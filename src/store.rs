@@ -0,0 +1,1211 @@
+use crate::auth::Connection;
+use crate::denylist::Denylist;
+use crate::label::LabelEntry;
+use crate::message::{Event, Filter};
+use crate::metrics::{Gauges, Stats};
+use crate::nip65::KIND_RELAY_LIST;
+use crate::reject::RejectReason;
+use crate::report::Report;
+use crate::types::{EventId, Pubkey};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+/// Events of one author and kind read to find the versions of an address.
+const ADDRESS_SCAN_LIMIT: i32 = 1000;
+
+/// Persistence used by the relay for events and subscriptions.
+#[async_trait]
+pub trait EventStore: Sync {
+    /// Rejects events the store cannot hold.
+    fn check_event(&self, _ev: &Event) -> Result<(), RejectReason> {
+        Ok(())
+    }
+
+    async fn write_event(&self, ev: &Event) -> Result<(), String>;
+
+    /// Whether `write_event` already keeps only the newest version of the
+    /// replaceable `ev`, which leaves the `replaceable` hook nothing to do.
+    fn replaces_on_write(&self, _ev: &Event) -> bool {
+        false
+    }
+
+    /// Capacity units the store has consumed so far, 0 for stores that do
+    /// not count them.
+    fn consumed_capacity(&self) -> f64 {
+        0.0
+    }
+
+    /// Stores the filters of `sub_id` of `conn_id`, replacing those of an
+    /// earlier REQ with the same sub_id on that connection (NIP-01). Returns
+    /// whether a subscription was replaced.
+    async fn write_subscription(
+        &self,
+        conn_id: &str,
+        sub_id: &str,
+        filters: &[Filter],
+    ) -> Result<bool, String>;
+
+    /// Deletes the subscriptions `sub_ids` of `conn_id`; other connections
+    /// may use the same ids.
+    async fn delete_subscriptions(&self, conn_id: &str, sub_ids: Vec<String>)
+        -> Result<(), String>;
+
+    /// Deletes every subscription owned by `conn_id` and returns how many
+    /// were deleted.
+    async fn close_connection(&self, conn_id: &str) -> Result<usize, String>;
+
+    /// Every live subscription as `(sub_id, conn_id, filters)`.
+    async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)>;
+
+    /// Subscriptions that may match `ev`, as `(sub_id, conn_id, filters)`;
+    /// the filters still have to be checked against the event.
+    ///
+    /// The default returns every subscription.
+    async fn get_subscriptions_for(&self, _ev: &Event) -> Vec<(String, String, Vec<Filter>)> {
+        self.get_all_subscriptions().await
+    }
+
+    /// Ids of the subscriptions owned by `conn_id`.
+    ///
+    /// The default scans every subscription.
+    async fn get_subscription_ids(&self, conn_id: &str) -> Result<Vec<String>, String> {
+        Ok(self
+            .get_all_subscriptions()
+            .await
+            .into_iter()
+            .filter(|(_, c, _)| c == conn_id)
+            .map(|(sub_id, _, _)| sub_id)
+            .collect())
+    }
+
+    async fn get_event_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, String>;
+
+    /// Whether event `id` is stored.
+    async fn has_event(&self, id: &EventId) -> Result<bool, String> {
+        Ok(!self
+            .get_event_by_ids(std::slice::from_ref(id))
+            .await?
+            .is_empty())
+    }
+
+    /// Events of the authors whose pubkey starts with one of `prefixes`,
+    /// newest first.
+    async fn get_event_by_pubkey_prefixes(
+        &self,
+        _prefixes: &[String],
+        _kinds: Option<Vec<u64>>,
+        _since: Option<u64>,
+        _until: Option<u64>,
+        _limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Err("author prefix lookup is not supported".to_string())
+    }
+
+    /// Up to `limit` events whose id starts with one of `prefixes`.
+    async fn get_event_by_id_prefixes(
+        &self,
+        _prefixes: &[String],
+        _limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Err("id prefix lookup is not supported".to_string())
+    }
+
+    async fn get_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String>;
+
+    async fn delete_event_by_ids(&self, ids: Vec<EventId>) -> Result<(), String>;
+
+    /// Every stored version of the NIP-33 address `(pubkey, kind, d)`.
+    ///
+    /// The default reads the author's events of `kind` and keeps those with
+    /// the `d` tag.
+    async fn get_event_by_address(
+        &self,
+        pubkey: &Pubkey,
+        kind: u64,
+        d: &str,
+    ) -> Result<Vec<Event>, String> {
+        let evs = self
+            .get_event_by_pubkeys(
+                std::slice::from_ref(pubkey),
+                Some(vec![kind]),
+                None,
+                None,
+                Some(ADDRESS_SCAN_LIMIT),
+            )
+            .await?;
+        Ok(evs.into_iter().filter(|ev| ev.d_tag() == d).collect())
+    }
+
+    /// Events of the NIP-28 `channels`, looked up by the id of their
+    /// creation event.
+    async fn get_event_by_channels(
+        &self,
+        _channels: &[EventId],
+        _kinds: Option<Vec<u64>>,
+        _since: Option<u64>,
+        _until: Option<u64>,
+        _limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Err("channel index is not supported".to_string())
+    }
+
+    /// NIP-59 gift wraps addressed to `recipients`.
+    async fn get_event_by_recipients(
+        &self,
+        _recipients: &[Pubkey],
+        _since: Option<u64>,
+        _until: Option<u64>,
+        _limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Err("recipient index is not supported".to_string())
+    }
+
+    /// Events of `kinds` by any author, newest first.
+    async fn get_event_by_kinds(
+        &self,
+        _kinds: &[u64],
+        _since: Option<u64>,
+        _until: Option<u64>,
+        _limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Err("kind index is not supported".to_string())
+    }
+
+    /// Events of any author and kind between `since` and `until`, newest
+    /// first.
+    async fn get_event_by_time(
+        &self,
+        _since: Option<u64>,
+        _until: Option<u64>,
+        _limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        Err("time index is not supported".to_string())
+    }
+
+    /// Events found among at most `max_items` stored items, in no
+    /// particular order.
+    async fn scan_events(&self, _max_items: usize) -> Result<Vec<Event>, String> {
+        Err("scan is not supported".to_string())
+    }
+
+    /// The newest NIP-65 relay list of `pubkey`.
+    ///
+    /// The default reads the author's events of that kind.
+    async fn get_relay_list(&self, pubkey: &Pubkey) -> Result<Option<Event>, String> {
+        let evs = self
+            .get_event_by_pubkeys(
+                std::slice::from_ref(pubkey),
+                Some(vec![KIND_RELAY_LIST]),
+                None,
+                None,
+                None,
+            )
+            .await?;
+        Ok(newest_version(evs))
+    }
+
+    /// Number of events by `pubkeys` matching the kinds and time range,
+    /// for NIP-45 COUNT.
+    async fn count_event_by_pubkeys(
+        &self,
+        _pubkeys: &[Pubkey],
+        _kinds: Option<Vec<u64>>,
+        _since: Option<u64>,
+        _until: Option<u64>,
+    ) -> Result<u64, String> {
+        Err("count is not supported".to_string())
+    }
+
+    /// Adds the deltas to the relay-wide gauges and returns the new values.
+    async fn adjust_gauges(
+        &self,
+        _connections: i64,
+        _subscriptions: i64,
+    ) -> Result<Gauges, String> {
+        Err("gauges are not supported".to_string())
+    }
+
+    /// Indexes the NIP-32 labels carried by `ev` so they can be looked up by label.
+    async fn write_labels(&self, _ev: &Event, _entries: &[LabelEntry]) -> Result<(), String> {
+        Err("label index is not supported".to_string())
+    }
+
+    /// Label entries recorded for `namespace`/`value`.
+    async fn get_label_targets(
+        &self,
+        _namespace: &str,
+        _value: &str,
+    ) -> Result<Vec<LabelEntry>, String> {
+        Err("label index is not supported".to_string())
+    }
+
+    /// Queues a NIP-56 report for the operators.
+    async fn write_report(&self, _report: &Report) -> Result<(), String> {
+        Err("moderation is not supported".to_string())
+    }
+
+    /// Reports not resolved yet, oldest first.
+    async fn get_open_reports(&self) -> Result<Vec<Report>, String> {
+        Err("moderation is not supported".to_string())
+    }
+
+    /// Closes report `id` with the `action` taken.
+    async fn resolve_report(&self, _id: &str, _action: &str) -> Result<(), String> {
+        Err("moderation is not supported".to_string())
+    }
+
+    /// Refuses further events by `pubkey`.
+    async fn add_ban(&self, _pubkey: &str) -> Result<(), String> {
+        Err("moderation is not supported".to_string())
+    }
+
+    /// Lifts the ban on `pubkey`.
+    async fn remove_ban(&self, _pubkey: &str) -> Result<(), String> {
+        Err("moderation is not supported".to_string())
+    }
+
+    /// Whether `pubkey` has been banned; false when bans are not kept.
+    async fn is_banned(&self, _pubkey: &str) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// Refuses event `id` and leaves it out of REQ results.
+    async fn add_banned_event(&self, _id: &str) -> Result<(), String> {
+        Err("moderation is not supported".to_string())
+    }
+
+    /// Lifts the ban on event `id`.
+    async fn remove_banned_event(&self, _id: &str) -> Result<(), String> {
+        Err("moderation is not supported".to_string())
+    }
+
+    /// Banned pubkeys and event ids; empty when bans are not kept.
+    async fn get_denylist(&self) -> Result<Denylist, String> {
+        Ok(Denylist::default())
+    }
+
+    /// Lets `pubkey` publish as a local user.
+    async fn add_allowed_pubkey(&self, _pubkey: &str) -> Result<(), String> {
+        Err("allowlist is not supported".to_string())
+    }
+
+    /// Takes `pubkey` off the store's allowlist.
+    async fn remove_allowed_pubkey(&self, _pubkey: &str) -> Result<(), String> {
+        Err("allowlist is not supported".to_string())
+    }
+
+    /// Pubkeys the store allows besides `NOSTR_ALLOWED_PUBKEYS`; none when
+    /// the store keeps no allowlist.
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>, String> {
+        Ok(vec![])
+    }
+
+    /// Adds a content filter rule (`word#<word>`, `pattern#<regex>` or
+    /// `domain#<domain>`).
+    async fn add_content_rule(&self, _rule: &str) -> Result<(), String> {
+        Err("content rules are not supported".to_string())
+    }
+
+    /// Content filter rules besides the environment's; none when the store
+    /// keeps no rules.
+    async fn get_content_rules(&self) -> Result<Vec<String>, String> {
+        Ok(vec![])
+    }
+
+    /// Lets `pubkey`, who paid the admission fee, publish.
+    async fn add_member(&self, _pubkey: &str) -> Result<(), String> {
+        Err("payments are not supported".to_string())
+    }
+
+    /// Whether `pubkey` has paid the admission fee; false when members are
+    /// not kept.
+    async fn is_member(&self, _pubkey: &str) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// Records that the invoice `payment_hash` was issued for `pubkey`.
+    async fn write_invoice(&self, _payment_hash: &str, _pubkey: &str) -> Result<(), String> {
+        Err("payments are not supported".to_string())
+    }
+
+    /// The pubkey the invoice `payment_hash` was issued for.
+    async fn get_invoice_pubkey(&self, _payment_hash: &str) -> Result<Option<String>, String> {
+        Ok(None)
+    }
+
+    /// Records that `conn_id` has been greeted; true only the first time.
+    async fn mark_greeted(&self, _conn_id: &str) -> Result<bool, String> {
+        Err("greeting state is not supported".to_string())
+    }
+
+    /// Adds one to counter `key`, kept for at least `ttl` seconds, and
+    /// returns the new count.
+    async fn increment_counter(&self, _key: &str, _ttl: u64) -> Result<u64, String> {
+        Err("counters are not supported".to_string())
+    }
+
+    /// Records a new connection, the NIP-42 challenge issued to it and the
+    /// address it was opened from.
+    async fn write_connection(
+        &self,
+        _conn_id: &str,
+        _challenge: &str,
+        _source_ip: Option<&str>,
+    ) -> Result<(), String> {
+        Err("connection records are not supported".to_string())
+    }
+
+    /// The record of `conn_id`, None if it has none.
+    async fn get_connection(&self, _conn_id: &str) -> Result<Option<Connection>, String> {
+        Err("connection records are not supported".to_string())
+    }
+
+    /// Records that `conn_id` has authenticated as `pubkey`.
+    async fn set_auth_pubkey(&self, _conn_id: &str, _pubkey: &str) -> Result<(), String> {
+        Err("connection records are not supported".to_string())
+    }
+
+    /// Counts one rejected `kind` command (`event` or `req`) for `reason`.
+    async fn add_rejection(&self, _kind: &str, _reason: &str) -> Result<(), String> {
+        Err("statistics are not supported".to_string())
+    }
+
+    /// Current gauges and rejection counts.
+    async fn get_stats(&self) -> Result<Stats, String> {
+        Err("statistics are not supported".to_string())
+    }
+
+    /// Counts events copied to the archive after expiring.
+    async fn add_archived(&self, _count: i64) -> Result<(), String> {
+        Err("statistics are not supported".to_string())
+    }
+
+    /// Counts one stored note detected to be written in `language`.
+    async fn add_language(&self, _language: &str) -> Result<(), String> {
+        Err("statistics are not supported".to_string())
+    }
+}
+
+/// Store wrapper that serves reads from `inner` but only logs writes, for
+/// checking what a hook would change without changing it.
+pub struct DryRunStore<'a> {
+    inner: &'a dyn EventStore,
+    label: &'a str,
+}
+
+impl<'a> DryRunStore<'a> {
+    pub fn new(inner: &'a dyn EventStore, label: &'a str) -> DryRunStore<'a> {
+        DryRunStore { inner, label }
+    }
+}
+
+#[async_trait]
+impl EventStore for DryRunStore<'_> {
+    fn check_event(&self, ev: &Event) -> Result<(), RejectReason> {
+        self.inner.check_event(ev)
+    }
+
+    fn replaces_on_write(&self, ev: &Event) -> bool {
+        self.inner.replaces_on_write(ev)
+    }
+
+    fn consumed_capacity(&self) -> f64 {
+        self.inner.consumed_capacity()
+    }
+
+    async fn write_event(&self, ev: &Event) -> Result<(), String> {
+        println!("dry-run {}: would write event {}", self.label, ev.id);
+        Ok(())
+    }
+
+    async fn write_subscription(
+        &self,
+        conn_id: &str,
+        sub_id: &str,
+        _filters: &[Filter],
+    ) -> Result<bool, String> {
+        println!(
+            "dry-run {}: would write subscription {conn_id}/{sub_id}",
+            self.label
+        );
+        Ok(false)
+    }
+
+    async fn delete_subscriptions(
+        &self,
+        conn_id: &str,
+        sub_ids: Vec<String>,
+    ) -> Result<(), String> {
+        println!(
+            "dry-run {}: would delete subscriptions {conn_id}/{sub_ids:?}",
+            self.label
+        );
+        Ok(())
+    }
+
+    async fn close_connection(&self, conn_id: &str) -> Result<usize, String> {
+        println!("dry-run {}: would close connection {conn_id}", self.label);
+        Ok(0)
+    }
+
+    async fn get_all_subscriptions(&self) -> Vec<(String, String, Vec<Filter>)> {
+        self.inner.get_all_subscriptions().await
+    }
+
+    async fn get_subscriptions_for(&self, ev: &Event) -> Vec<(String, String, Vec<Filter>)> {
+        self.inner.get_subscriptions_for(ev).await
+    }
+
+    async fn get_subscription_ids(&self, conn_id: &str) -> Result<Vec<String>, String> {
+        self.inner.get_subscription_ids(conn_id).await
+    }
+
+    async fn get_event_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, String> {
+        self.inner.get_event_by_ids(ids).await
+    }
+
+    async fn has_event(&self, id: &EventId) -> Result<bool, String> {
+        self.inner.has_event(id).await
+    }
+
+    async fn get_event_by_id_prefixes(
+        &self,
+        prefixes: &[String],
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        self.inner.get_event_by_id_prefixes(prefixes, limit).await
+    }
+
+    async fn get_event_by_pubkey_prefixes(
+        &self,
+        prefixes: &[String],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        self.inner
+            .get_event_by_pubkey_prefixes(prefixes, kinds, since, until, limit)
+            .await
+    }
+
+    async fn get_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        self.inner
+            .get_event_by_pubkeys(pubkeys, kinds, since, until, limit)
+            .await
+    }
+
+    async fn delete_event_by_ids(&self, ids: Vec<EventId>) -> Result<(), String> {
+        println!("dry-run {}: would delete events {ids:?}", self.label);
+        Ok(())
+    }
+
+    async fn get_event_by_address(
+        &self,
+        pubkey: &Pubkey,
+        kind: u64,
+        d: &str,
+    ) -> Result<Vec<Event>, String> {
+        self.inner.get_event_by_address(pubkey, kind, d).await
+    }
+
+    async fn get_event_by_channels(
+        &self,
+        channels: &[EventId],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        self.inner
+            .get_event_by_channels(channels, kinds, since, until, limit)
+            .await
+    }
+
+    async fn get_event_by_recipients(
+        &self,
+        recipients: &[Pubkey],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        self.inner
+            .get_event_by_recipients(recipients, since, until, limit)
+            .await
+    }
+
+    async fn get_event_by_kinds(
+        &self,
+        kinds: &[u64],
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        self.inner
+            .get_event_by_kinds(kinds, since, until, limit)
+            .await
+    }
+
+    async fn get_event_by_time(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Event>, String> {
+        self.inner.get_event_by_time(since, until, limit).await
+    }
+
+    async fn scan_events(&self, max_items: usize) -> Result<Vec<Event>, String> {
+        self.inner.scan_events(max_items).await
+    }
+
+    async fn get_relay_list(&self, pubkey: &Pubkey) -> Result<Option<Event>, String> {
+        self.inner.get_relay_list(pubkey).await
+    }
+
+    async fn count_event_by_pubkeys(
+        &self,
+        pubkeys: &[Pubkey],
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<u64, String> {
+        self.inner
+            .count_event_by_pubkeys(pubkeys, kinds, since, until)
+            .await
+    }
+
+    async fn adjust_gauges(&self, connections: i64, subscriptions: i64) -> Result<Gauges, String> {
+        println!(
+            "dry-run {}: would adjust gauges by {connections}/{subscriptions}",
+            self.label
+        );
+        Ok(Gauges::default())
+    }
+
+    async fn write_labels(&self, ev: &Event, entries: &[LabelEntry]) -> Result<(), String> {
+        println!(
+            "dry-run {}: would index {} labels of {}",
+            self.label,
+            entries.len(),
+            ev.id
+        );
+        Ok(())
+    }
+
+    async fn get_label_targets(
+        &self,
+        namespace: &str,
+        value: &str,
+    ) -> Result<Vec<LabelEntry>, String> {
+        self.inner.get_label_targets(namespace, value).await
+    }
+
+    async fn write_report(&self, report: &Report) -> Result<(), String> {
+        println!("dry-run {}: would queue report {}", self.label, report.id);
+        Ok(())
+    }
+
+    async fn get_open_reports(&self) -> Result<Vec<Report>, String> {
+        self.inner.get_open_reports().await
+    }
+
+    async fn resolve_report(&self, id: &str, action: &str) -> Result<(), String> {
+        println!(
+            "dry-run {}: would resolve report {id} with {action}",
+            self.label
+        );
+        Ok(())
+    }
+
+    async fn add_ban(&self, pubkey: &str) -> Result<(), String> {
+        println!("dry-run {}: would ban {pubkey}", self.label);
+        Ok(())
+    }
+
+    async fn remove_ban(&self, pubkey: &str) -> Result<(), String> {
+        println!("dry-run {}: would unban {pubkey}", self.label);
+        Ok(())
+    }
+
+    async fn is_banned(&self, pubkey: &str) -> Result<bool, String> {
+        self.inner.is_banned(pubkey).await
+    }
+
+    async fn add_banned_event(&self, id: &str) -> Result<(), String> {
+        println!("dry-run {}: would ban event {id}", self.label);
+        Ok(())
+    }
+
+    async fn remove_banned_event(&self, id: &str) -> Result<(), String> {
+        println!("dry-run {}: would unban event {id}", self.label);
+        Ok(())
+    }
+
+    async fn get_denylist(&self) -> Result<Denylist, String> {
+        self.inner.get_denylist().await
+    }
+
+    async fn add_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        println!("dry-run {}: would allow {pubkey}", self.label);
+        Ok(())
+    }
+
+    async fn remove_allowed_pubkey(&self, pubkey: &str) -> Result<(), String> {
+        println!("dry-run {}: would disallow {pubkey}", self.label);
+        Ok(())
+    }
+
+    async fn get_allowed_pubkeys(&self) -> Result<Vec<String>, String> {
+        self.inner.get_allowed_pubkeys().await
+    }
+
+    async fn add_content_rule(&self, rule: &str) -> Result<(), String> {
+        println!("dry-run {}: would add content rule {rule}", self.label);
+        Ok(())
+    }
+
+    async fn get_content_rules(&self) -> Result<Vec<String>, String> {
+        self.inner.get_content_rules().await
+    }
+
+    async fn add_member(&self, pubkey: &str) -> Result<(), String> {
+        println!("dry-run {}: would admit {pubkey}", self.label);
+        Ok(())
+    }
+
+    async fn is_member(&self, pubkey: &str) -> Result<bool, String> {
+        self.inner.is_member(pubkey).await
+    }
+
+    async fn write_invoice(&self, payment_hash: &str, pubkey: &str) -> Result<(), String> {
+        println!(
+            "dry-run {}: would record invoice {payment_hash} for {pubkey}",
+            self.label
+        );
+        Ok(())
+    }
+
+    async fn get_invoice_pubkey(&self, payment_hash: &str) -> Result<Option<String>, String> {
+        self.inner.get_invoice_pubkey(payment_hash).await
+    }
+
+    async fn mark_greeted(&self, conn_id: &str) -> Result<bool, String> {
+        println!("dry-run {}: would mark {conn_id} greeted", self.label);
+        Ok(false)
+    }
+
+    async fn increment_counter(&self, key: &str, _ttl: u64) -> Result<u64, String> {
+        println!("dry-run {}: would count {key}", self.label);
+        Ok(0)
+    }
+
+    async fn write_connection(
+        &self,
+        conn_id: &str,
+        _challenge: &str,
+        _source_ip: Option<&str>,
+    ) -> Result<(), String> {
+        println!("dry-run {}: would record connection {conn_id}", self.label);
+        Ok(())
+    }
+
+    async fn get_connection(&self, conn_id: &str) -> Result<Option<Connection>, String> {
+        self.inner.get_connection(conn_id).await
+    }
+
+    async fn set_auth_pubkey(&self, conn_id: &str, pubkey: &str) -> Result<(), String> {
+        println!(
+            "dry-run {}: would record {conn_id} authenticated as {pubkey}",
+            self.label
+        );
+        Ok(())
+    }
+
+    async fn add_rejection(&self, kind: &str, reason: &str) -> Result<(), String> {
+        println!(
+            "dry-run {}: would count a {kind} rejection for {reason}",
+            self.label
+        );
+        Ok(())
+    }
+
+    async fn get_stats(&self) -> Result<Stats, String> {
+        self.inner.get_stats().await
+    }
+
+    async fn add_archived(&self, count: i64) -> Result<(), String> {
+        println!("dry-run {}: would count {count} archived", self.label);
+        Ok(())
+    }
+
+    async fn add_language(&self, language: &str) -> Result<(), String> {
+        println!("dry-run {}: would count a note in {language}", self.label);
+        Ok(())
+    }
+}
+
+pub struct QueryByIds<'a> {
+    filter: &'a Filter,
+    ids: Vec<EventId>,
+    /// Ids shorter than 64 characters.
+    prefixes: Vec<String>,
+    limit: Option<i32>,
+}
+
+impl<'a> QueryByIds<'a> {
+    pub fn new(
+        filter: &'a Filter,
+        ids: Vec<EventId>,
+        prefixes: Vec<String>,
+        limit: Option<i32>,
+    ) -> QueryByIds<'a> {
+        QueryByIds {
+            filter,
+            ids,
+            prefixes,
+            limit,
+        }
+    }
+
+    pub async fn read(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        if self.prefixes.is_empty() {
+            return store.get_event_by_ids(&self.ids).await;
+        }
+        let mut evs = store
+            .get_event_by_id_prefixes(&self.prefixes, self.limit)
+            .await?;
+        if !self.ids.is_empty() {
+            evs.extend(store.get_event_by_ids(&self.ids).await?);
+        }
+        let mut seen = HashSet::new();
+        evs.retain(|e| seen.insert(e.id.clone()));
+        Ok(evs)
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, true)
+    }
+
+    /// Like `exec`, but keeps expired events, for maintenance tools.
+    pub async fn exec_including_expired(
+        &self,
+        store: &dyn EventStore,
+    ) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, false)
+    }
+}
+
+/// Events matching `filter`, leaving out those past their NIP-40 expiration
+/// that the store has not removed yet when `skip_expired` is set.
+///
+/// The stores apply the limit before this, so it may return fewer events
+/// than the limit even when more match; `read` returns what the store did.
+pub fn filter_match(
+    filter: &Filter,
+    evs: &Result<Vec<Event>, String>,
+    skip_expired: bool,
+) -> Result<Vec<Event>, String> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    match evs {
+        Ok(ret) => {
+            let vmatch = ret
+                .iter()
+                .filter_map(|e| {
+                    if filter.event_match(e) && !(skip_expired && e.is_expired(now)) {
+                        Some(e.clone())
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            Ok(vmatch)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub struct QueryByPubkeys<'a> {
+    filter: &'a Filter,
+    authors: Vec<Pubkey>,
+    /// Authors shorter than 64 characters.
+    prefixes: Vec<String>,
+    kinds: Option<Vec<u64>>,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<i32>,
+}
+
+impl<'a> QueryByPubkeys<'a> {
+    pub fn new(
+        filter: &'a Filter,
+        authors: Vec<Pubkey>,
+        prefixes: Vec<String>,
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> QueryByPubkeys<'a> {
+        QueryByPubkeys {
+            filter,
+            authors,
+            prefixes,
+            kinds,
+            since,
+            until,
+            limit,
+        }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, true)
+    }
+
+    /// Like `exec`, but keeps expired events, for maintenance tools.
+    pub async fn exec_including_expired(
+        &self,
+        store: &dyn EventStore,
+    ) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, false)
+    }
+
+    pub async fn read(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        if self.prefixes.is_empty() {
+            return self.query_authors(store).await;
+        }
+        let mut evs = store
+            .get_event_by_pubkey_prefixes(
+                &self.prefixes,
+                self.kinds.clone(),
+                self.since,
+                self.until,
+                self.limit,
+            )
+            .await?;
+        if !self.authors.is_empty() {
+            evs.extend(self.query_authors(store).await?);
+        }
+        let mut seen = HashSet::new();
+        evs.retain(|e| seen.insert(e.id.clone()));
+        evs.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        evs.truncate(self.limit.unwrap_or(100).max(1) as usize);
+        Ok(evs)
+    }
+
+    async fn query_authors(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        // Outbox-model clients ask for relay lists of many authors at once.
+        if self.kinds.as_deref() == Some(&[KIND_RELAY_LIST]) {
+            let mut evs = vec![];
+            for author in &self.authors {
+                evs.extend(store.get_relay_list(author).await?);
+            }
+            return Ok(evs);
+        }
+        store
+            .get_event_by_pubkeys(
+                &self.authors,
+                self.kinds.clone(),
+                self.since,
+                self.until,
+                self.limit,
+            )
+            .await
+    }
+}
+
+/// Looks up NIP-28 channel timelines by the channel's creation event id.
+pub struct QueryByChannels<'a> {
+    filter: &'a Filter,
+    channels: Vec<EventId>,
+    kinds: Option<Vec<u64>>,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<i32>,
+}
+
+impl<'a> QueryByChannels<'a> {
+    pub fn new(
+        filter: &'a Filter,
+        channels: Vec<EventId>,
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> QueryByChannels<'a> {
+        QueryByChannels {
+            filter,
+            channels,
+            kinds,
+            since,
+            until,
+            limit,
+        }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, true)
+    }
+
+    pub async fn read(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        store
+            .get_event_by_channels(
+                &self.channels,
+                self.kinds.clone(),
+                self.since,
+                self.until,
+                self.limit,
+            )
+            .await
+    }
+}
+
+/// Looks up NIP-59 gift wraps by their recipient.
+pub struct QueryByRecipients<'a> {
+    filter: &'a Filter,
+    recipients: Vec<Pubkey>,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<i32>,
+}
+
+impl<'a> QueryByRecipients<'a> {
+    pub fn new(
+        filter: &'a Filter,
+        recipients: Vec<Pubkey>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> QueryByRecipients<'a> {
+        QueryByRecipients {
+            filter,
+            recipients,
+            since,
+            until,
+            limit,
+        }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, true)
+    }
+
+    pub async fn read(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        store
+            .get_event_by_recipients(&self.recipients, self.since, self.until, self.limit)
+            .await
+    }
+}
+
+/// Looks up global timelines of kinds.
+pub struct QueryByKinds<'a> {
+    filter: &'a Filter,
+    kinds: Vec<u64>,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<i32>,
+}
+
+impl<'a> QueryByKinds<'a> {
+    pub fn new(
+        filter: &'a Filter,
+        kinds: Vec<u64>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> QueryByKinds<'a> {
+        QueryByKinds {
+            filter,
+            kinds,
+            since,
+            until,
+            limit,
+        }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, true)
+    }
+
+    pub async fn read(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        store
+            .get_event_by_kinds(&self.kinds, self.since, self.until, self.limit)
+            .await
+    }
+}
+
+/// Looks up the events of a time range.
+pub struct QueryByTime<'a> {
+    filter: &'a Filter,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<i32>,
+}
+
+impl<'a> QueryByTime<'a> {
+    pub fn new(
+        filter: &'a Filter,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<i32>,
+    ) -> QueryByTime<'a> {
+        QueryByTime {
+            filter,
+            since,
+            until,
+            limit,
+        }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, true)
+    }
+
+    pub async fn read(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        store
+            .get_event_by_time(self.since, self.until, self.limit)
+            .await
+    }
+}
+
+/// Answers a filter no index serves by scanning a bounded number of items,
+/// so it may miss matching events.
+pub struct QueryByScan<'a> {
+    filter: &'a Filter,
+    max_items: usize,
+    limit: i32,
+}
+
+impl<'a> QueryByScan<'a> {
+    pub fn new(filter: &'a Filter, max_items: usize, limit: i32) -> QueryByScan<'a> {
+        QueryByScan {
+            filter,
+            max_items,
+            limit,
+        }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = store.scan_events(self.max_items).await;
+
+        let mut evs = filter_match(self.filter, &ret, true)?;
+        evs.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        evs.truncate(self.limit.max(0) as usize);
+        Ok(evs)
+    }
+}
+
+/// Looks up the latest version of NIP-33 addresses.
+pub struct QueryByAddress<'a> {
+    filter: &'a Filter,
+    addresses: Vec<(Pubkey, u64, String)>,
+}
+
+impl<'a> QueryByAddress<'a> {
+    pub fn new(filter: &'a Filter, addresses: Vec<(Pubkey, u64, String)>) -> QueryByAddress<'a> {
+        QueryByAddress { filter, addresses }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let ret = self.read(store).await;
+
+        filter_match(self.filter, &ret, true)
+    }
+
+    pub async fn read(&self, store: &dyn EventStore) -> Result<Vec<Event>, String> {
+        let mut latest = vec![];
+        for (pubkey, kind, d) in &self.addresses {
+            let evs = store.get_event_by_address(pubkey, *kind, d).await?;
+            latest.extend(newest_version(evs));
+        }
+        Ok(latest)
+    }
+}
+
+/// The version of an address that replaces the others: the newest, and of
+/// those created at the same second the one with the lowest id.
+pub fn newest_version(evs: Vec<Event>) -> Option<Event> {
+    evs.into_iter().min_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then_with(|| a.id.cmp(&b.id))
+    })
+}
+
+/// Counts events by author without reading them.
+pub struct CountByPubkeys {
+    authors: Vec<Pubkey>,
+    kinds: Option<Vec<u64>>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl CountByPubkeys {
+    pub fn new(
+        authors: Vec<Pubkey>,
+        kinds: Option<Vec<u64>>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> CountByPubkeys {
+        CountByPubkeys {
+            authors,
+            kinds,
+            since,
+            until,
+        }
+    }
+
+    pub async fn exec(&self, store: &dyn EventStore) -> Result<u64, String> {
+        store
+            .count_event_by_pubkeys(&self.authors, self.kinds.clone(), self.since, self.until)
+            .await
+    }
+}
+
+pub enum QueryPlan<'a> {
+    ByIds(QueryByIds<'a>),
+    ByPubkeys(QueryByPubkeys<'a>),
+    ByAddress(QueryByAddress<'a>),
+    ByChannels(QueryByChannels<'a>),
+    ByRecipients(QueryByRecipients<'a>),
+    ByKinds(QueryByKinds<'a>),
+    ByTime(QueryByTime<'a>),
+    /// Only when `NOSTR_MAX_SCAN_ITEMS` is set.
+    BoundedScan(QueryByScan<'a>),
+    /// Only from `Filter::count_plan`.
+    Count(CountByPubkeys),
+    NoPlan(RejectReason),
+}
@@ -0,0 +1,70 @@
+use crate::message::Event;
+use aws_lambda_events::dynamodb;
+use aws_lambda_events::dynamodb::attributes::AttributeValue;
+
+/// Events newly written to the event table, in stream order.
+///
+/// Rewrites of a stored event (MODIFY) and the tag, label and other items
+/// sharing the table are skipped, so each event is fanned out once. Each
+/// event comes with the number of `tags#<n>` items its tags were moved to,
+/// which `Ddb::with_tags` reads back.
+pub fn inserted_events(stream: &dynamodb::Event) -> Vec<(Event, usize)> {
+    stream
+        .records
+        .iter()
+        .filter(|r| r.event_name == "INSERT")
+        .filter(|r| {
+            matches!(r.change.new_image.get("type"), Some(AttributeValue::String(t)) if t == "event")
+        })
+        .filter_map(|r| {
+            let ev = match r.change.new_image.get("json") {
+                Some(AttributeValue::String(json)) => serde_json::from_str(json).ok(),
+                Some(AttributeValue::Binary(bytes)) => Event::from_zstd(bytes).ok(),
+                _ => None,
+            }?;
+            let chunks = match r.change.new_image.get("tag_chunks") {
+                Some(AttributeValue::Number(n)) => *n as usize,
+                _ => 0,
+            };
+            Some((ev, chunks))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::inserted_events;
+    use crate::message::test_event;
+
+    #[test]
+    fn inserted_events01() {
+        let ev = test_event();
+        let json = serde_json::to_string(&ev).unwrap();
+        let record = |name: &str, item_type: &str| {
+            serde_json::json!({
+                "awsRegion": "ap-northeast-1",
+                "eventID": "1",
+                "eventName": name,
+                "dynamodb": {
+                    "ApproximateCreationDateTime": 1676118868.0,
+                    "NewImage": {"type": {"S": item_type}, "json": {"S": json}},
+                    "SizeBytes": 1,
+                },
+            })
+        };
+        let mut chunked = record("INSERT", "event");
+        chunked["dynamodb"]["NewImage"]["tag_chunks"] = serde_json::json!({"N": "3"});
+        let stream: aws_lambda_events::dynamodb::Event =
+            serde_json::from_value(serde_json::json!({
+                "Records": [
+                    record("INSERT", "event"),
+                    record("INSERT", "tags#0"),
+                    record("MODIFY", "event"),
+                    chunked,
+                ]
+            }))
+            .unwrap();
+
+        assert_eq!(vec![(ev.clone(), 0), (ev, 3)], inserted_events(&stream));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::message::{CommandResult, Event, EventMsg};
+use crate::reject::RejectReason;
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// Delivers frames to websocket connections.
+///
+/// Only `post_connection` has to be implemented; the nostr messages are
+/// built on top of it.
+#[async_trait]
+pub trait Transport: Sync {
+    async fn post_connection(&self, conn_id: &str, data: &str) -> bool;
+
+    /// Like `post_connection`, but tells failures worth retrying (`Err`)
+    /// apart from frames the connection will never take (`Ok(false)`).
+    async fn try_post_connection(&self, conn_id: &str, data: &str) -> Result<bool, String> {
+        Ok(self.post_connection(conn_id, data).await)
+    }
+
+    async fn reply_event(&self, sub: &str, conn: &str, ev: &Event) -> bool {
+        let msg = event_frame(sub, ev);
+        println!("reply_event: {sub}/{conn}: {msg}");
+        self.post_connection(conn, &msg).await
+    }
+
+    async fn send_nip20msg(&self, conn: &str, event_id: &str, success: bool, msg: &str) -> bool {
+        let obj = [
+            CommandResult::String("OK".to_string()),
+            CommandResult::String(event_id.to_string()),
+            CommandResult::Bool(success),
+            CommandResult::String(msg.to_string()),
+        ];
+        let msg = serde_json::to_string(&obj).unwrap();
+        self.post_connection(conn, &msg).await
+    }
+
+    /// Refuses an event or AUTH with an OK false carrying `reason`.
+    async fn send_rejection(&self, conn: &str, event_id: &str, reason: &RejectReason) -> bool {
+        self.send_nip20msg(conn, event_id, false, &reason.to_string())
+            .await
+    }
+
+    async fn send_nip15eose(&self, conn: &str, sub_id: &str) -> bool {
+        let msg = format!(r#"["EOSE", "{sub_id}"]"#);
+        self.post_connection(conn, &msg).await
+    }
+
+    /// Tells the client that `sub_id` was refused or ended by the relay.
+    async fn send_closed(&self, conn: &str, sub_id: &str, reason: &RejectReason) -> bool {
+        let msg = serde_json::to_string(&["CLOSED", sub_id, &reason.to_string()]).unwrap();
+        self.post_connection(conn, &msg).await
+    }
+
+    /// Answers a NIP-45 COUNT.
+    async fn send_count(&self, conn: &str, sub_id: &str, count: u64) -> bool {
+        let msg = serde_json::json!(["COUNT", sub_id, { "count": count }]).to_string();
+        self.post_connection(conn, &msg).await
+    }
+
+    /// Sends the NIP-42 challenge.
+    async fn send_auth(&self, conn: &str, challenge: &str) -> bool {
+        let msg = serde_json::to_string(&["AUTH", challenge]).unwrap();
+        self.post_connection(conn, &msg).await
+    }
+
+    async fn send_notice(&self, conn: &str, notice: &str) -> bool {
+        let msg = serde_json::to_string(&["NOTICE", notice]).unwrap();
+        self.post_connection(conn, &msg).await
+    }
+
+    /// Sends the frames held back so far; most transports hold none.
+    async fn flush(&self) {}
+}
+
+/// `["EVENT", sub, ev]`.
+pub fn event_frame(sub: &str, ev: &Event) -> String {
+    let obj = [
+        EventMsg::String("EVENT".to_string()),
+        EventMsg::String(sub.to_string()),
+        EventMsg::Event(ev.clone()),
+    ];
+    serde_json::to_string(&obj).unwrap()
+}
+
+/// Holds back the frame addressed to the sender so it can be returned in the
+/// route response instead of a separate management API call.
+///
+/// Only a lone frame is held: once the sender gets a second one, both go out
+/// through `inner` in order, as do frames to other connections.
+pub struct RouteReply<'a> {
+    inner: &'a dyn Transport,
+    conn_id: String,
+    held: Mutex<Held>,
+}
+
+enum Held {
+    Nothing,
+    Frame(String),
+    Flushed,
+}
+
+impl<'a> RouteReply<'a> {
+    /// Holds frames to `conn_id` only when `hold` is set; otherwise every
+    /// frame goes through `inner`.
+    pub fn new(inner: &'a dyn Transport, conn_id: &str, hold: bool) -> RouteReply<'a> {
+        RouteReply {
+            inner,
+            conn_id: conn_id.to_string(),
+            held: Mutex::new(if hold { Held::Nothing } else { Held::Flushed }),
+        }
+    }
+
+    /// Whether `NOSTR_ROUTE_RESPONSE` enables the route response.
+    pub fn enabled() -> bool {
+        crate::policy::env_flag("NOSTR_ROUTE_RESPONSE")
+    }
+
+    /// The frame to return in the route response, if one is held.
+    pub fn into_body(self) -> Option<String> {
+        match self.held.into_inner().unwrap() {
+            Held::Frame(frame) => Some(frame),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for RouteReply<'_> {
+    async fn post_connection(&self, conn_id: &str, data: &str) -> bool {
+        if conn_id != self.conn_id {
+            return self.inner.post_connection(conn_id, data).await;
+        }
+        let previous = {
+            let mut held = self.held.lock().unwrap();
+            match std::mem::replace(&mut *held, Held::Flushed) {
+                Held::Nothing => {
+                    *held = Held::Frame(data.to_string());
+                    return true;
+                }
+                Held::Frame(frame) => Some(frame),
+                Held::Flushed => None,
+            }
+        };
+        if let Some(frame) = previous {
+            self.inner.post_connection(conn_id, &frame).await;
+        }
+        self.inner.post_connection(conn_id, data).await
+    }
+
+    /// Sends the held frame now, and every later frame as it comes.
+    async fn flush(&self) {
+        let held = std::mem::replace(&mut *self.held.lock().unwrap(), Held::Flushed);
+        if let Held::Frame(frame) = held {
+            self.inner.post_connection(&self.conn_id, &frame).await;
+        }
+    }
+}
+
+/// Transport that records every frame instead of sending it, for tests.
+#[derive(Default)]
+pub struct MemoryTransport {
+    frames: Mutex<Vec<(String, String)>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Frames posted to `conn_id`, in the order they were sent.
+    pub fn frames(&self, conn_id: &str) -> Vec<String> {
+        self.frames
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(conn, _)| conn == conn_id)
+            .map(|(_, data)| data.clone())
+            .collect()
+    }
+
+    /// Every frame posted so far as `(connection_id, frame)` pairs.
+    pub fn all_frames(&self) -> Vec<(String, String)> {
+        self.frames.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn post_connection(&self, conn_id: &str, data: &str) -> bool {
+        self.frames
+            .lock()
+            .unwrap()
+            .push((conn_id.to_string(), data.to_string()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryTransport, RouteReply, Transport};
+
+    #[tokio::test]
+    async fn route_reply01() {
+        let api = MemoryTransport::new();
+        let reply = RouteReply::new(&api, "conn01", true);
+        reply.send_notice("conn01", "hello").await;
+        reply.send_notice("conn02", "other").await;
+        assert_eq!(1, api.all_frames().len());
+        assert_eq!(Some(r#"["NOTICE","hello"]"#.to_string()), reply.into_body());
+
+        let reply = RouteReply::new(&api, "conn01", true);
+        reply.send_nip15eose("conn01", "sub01").await;
+        reply.send_nip15eose("conn01", "sub02").await;
+        assert_eq!(None, reply.into_body());
+        assert_eq!(
+            vec![r#"["EOSE", "sub01"]"#, r#"["EOSE", "sub02"]"#],
+            api.frames("conn01")
+        );
+
+        let reply = RouteReply::new(&api, "conn04", true);
+        reply.send_notice("conn04", "first").await;
+        reply.flush().await;
+        reply.send_notice("conn04", "second").await;
+        assert_eq!(None, reply.into_body());
+        assert_eq!(2, api.frames("conn04").len());
+
+        let reply = RouteReply::new(&api, "conn03", false);
+        reply.send_notice("conn03", "hi").await;
+        assert_eq!(None, reply.into_body());
+        assert_eq!(1, api.frames("conn03").len());
+    }
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Defines a string newtype holding exactly `$bytes` bytes as lowercase hex.
+macro_rules! hex_newtype {
+    ($(#[$doc:meta])* $name:ident, $bytes:expr) => {
+        $(#[$doc])*
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[serde(try_from = "String", into = "String")]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            /// `prefix` followed by zeros, for test fixtures.
+            #[cfg(test)]
+            pub(crate) fn padded(prefix: &str) -> $name {
+                format!("{prefix:0<width$}", width = $bytes * 2).parse().unwrap()
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                check_hex(stringify!($name), s, $bytes)?;
+                Ok($name(s.to_string()))
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = String;
+
+            fn try_from(s: String) -> Result<Self, Self::Error> {
+                check_hex(stringify!($name), &s, $bytes)?;
+                Ok($name(s))
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(v: $name) -> String {
+                v.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl std::ops::Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl PartialEq<String> for $name {
+            fn eq(&self, other: &String) -> bool {
+                &self.0 == other
+            }
+        }
+    };
+}
+
+hex_newtype!(
+    /// The sha256 of an event's canonical form.
+    EventId,
+    32
+);
+hex_newtype!(
+    /// An x-only secp256k1 public key.
+    Pubkey,
+    32
+);
+hex_newtype!(
+    /// A BIP-340 Schnorr signature.
+    Signature,
+    64
+);
+
+fn check_hex(name: &str, s: &str, bytes: usize) -> Result<(), String> {
+    if s.len() != bytes * 2 {
+        return Err(format!("{name} must be {} hex characters", bytes * 2));
+    }
+    if !is_lower_hex(s) {
+        return Err(format!("{name} must be lowercase hex"));
+    }
+    Ok(())
+}
+
+/// Whether `s` only contains `0-9a-f`, as ids, pubkeys and their prefixes do.
+pub fn is_lower_hex(s: &str) -> bool {
+    s.bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventId, Pubkey, Signature};
+
+    #[test]
+    fn hex_newtypes() {
+        let id = "a".repeat(64);
+        assert_eq!(id, id.parse::<EventId>().unwrap().to_string());
+        assert!("a".repeat(63).parse::<EventId>().is_err());
+        assert!("A".repeat(64).parse::<Pubkey>().is_err());
+        assert!("g".repeat(64).parse::<Pubkey>().is_err());
+        assert!("0".repeat(128).parse::<Signature>().is_ok());
+
+        let json = format!("\"{id}\"");
+        let parsed: EventId = serde_json::from_str(&json).unwrap();
+        assert_eq!(json, serde_json::to_string(&parsed).unwrap());
+        assert!(serde_json::from_str::<EventId>("\"abc\"").is_err());
+    }
+}
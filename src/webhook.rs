@@ -0,0 +1,126 @@
+//! POSTing accepted events as JSON to operator endpoints, so that services
+//! outside AWS can react to relay traffic.
+use crate::message::Event;
+use secp256k1::hashes::hmac::{Hmac, HmacEngine};
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use std::time::{Duration, SystemTime};
+
+/// Seconds to wait for an endpoint to answer.
+const TIMEOUT: u64 = 5;
+
+pub struct Webhooks {
+    client: reqwest::Client,
+    urls: Vec<String>,
+    secret: Option<String>,
+    attempts: u32,
+}
+
+impl Webhooks {
+    /// Reads `NOSTR_WEBHOOK_URLS` (comma separated), `NOSTR_WEBHOOK_SECRET`
+    /// and `NOSTR_WEBHOOK_ATTEMPTS`; None when no endpoint is set.
+    pub fn from_env() -> Option<Webhooks> {
+        let urls: Vec<String> = std::env::var("NOSTR_WEBHOOK_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if urls.is_empty() {
+            return None;
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(TIMEOUT))
+            .build()
+            .ok()?;
+        Some(Webhooks {
+            client,
+            urls,
+            secret: std::env::var("NOSTR_WEBHOOK_SECRET")
+                .ok()
+                .filter(|v| !v.is_empty()),
+            attempts: std::env::var("NOSTR_WEBHOOK_ATTEMPTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3)
+                .max(1),
+        })
+    }
+
+    /// POSTs `ev` to every endpoint at once; the errors of the endpoints
+    /// that never took it.
+    pub async fn deliver(&self, ev: &Event) -> Vec<String> {
+        let body = serde_json::to_string(ev).unwrap();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let posts = self.urls.iter().map(|url| self.post(url, &body, timestamp));
+        futures_util::future::join_all(posts)
+            .await
+            .into_iter()
+            .filter_map(Result::err)
+            .collect()
+    }
+
+    /// Retries failures and 429 or 5xx answers, doubling the wait.
+    async fn post(&self, url: &str, body: &str, timestamp: u64) -> Result<(), String> {
+        let mut attempt = 0;
+        loop {
+            let mut req = self
+                .client
+                .post(url)
+                .header("content-type", "application/json")
+                .header("x-nostr-timestamp", timestamp.to_string())
+                .body(body.to_string());
+            if let Some(secret) = &self.secret {
+                req = req.header("x-nostr-signature", signature(secret, timestamp, body));
+            }
+            let err = match req.send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(()),
+                Ok(resp) if !retried(resp.status().as_u16()) => {
+                    return Err(format!("{url}: {}", resp.status()));
+                }
+                Ok(resp) => resp.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            attempt += 1;
+            if attempt >= self.attempts {
+                return Err(format!("{url}: {err} after {attempt} attempts"));
+            }
+            tokio::time::sleep(retry_delay(attempt)).await;
+        }
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>` with `secret`,
+/// sent in `X-Nostr-Signature`. The timestamp is signed too so that a
+/// captured request cannot be replayed later.
+pub fn signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(format!("{timestamp}.{body}").as_bytes());
+    format!("sha256={}", Hmac::<sha256::Hash>::from_engine(engine))
+}
+
+fn retried(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_millis(200 << attempt.min(5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retried, retry_delay, signature};
+    use std::time::Duration;
+
+    #[test]
+    fn signature01() {
+        assert_eq!(
+            "sha256=5b8b5175150d1d87caa3b23d0f3d260d6652d6dda83cf502abd86f383d2abd9f",
+            signature("secret", 1676118868, r#"{"id":"1d01"}"#)
+        );
+        assert!(retried(503) && retried(429) && !retried(404));
+        assert_eq!(Duration::from_millis(400), retry_delay(1));
+    }
+}